@@ -12,31 +12,75 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! CLI for converting file system to FDT
+//! CLI for converting file system to FDT, and comparing FDTs
 
-use clap::Parser;
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
 use fsfdt::FsFdt;
 use libfdt::Fdt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 const FDT_MAX_SIZE: usize = 1_000_000_usize;
 
 /// Option parser
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// File system path (directory path) to parse from
-    fs_path: PathBuf,
+    #[arg(required = true)]
+    fs_path: Option<PathBuf>,
 
     /// FDT file path for writing
-    fdt_file_path: PathBuf,
+    #[arg(required = true)]
+    fdt_file_path: Option<PathBuf>,
 }
 
-fn main() {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the structural differences between two FDT files, one per line.
+    ///
+    /// Like diff(1), exits with 1 if the FDTs differ.
+    Diff {
+        /// Old FDT file path
+        old: PathBuf,
+
+        /// New FDT file path
+        new: PathBuf,
+    },
+}
+
+fn main() -> Result<ExitCode> {
     let opt = Opt::parse();
 
+    if let Some(Command::Diff { old, new }) = opt.command {
+        return diff(&old, &new);
+    }
+
+    // Both are required by clap in the absence of a subcommand.
+    let (fs_path, fdt_file_path) = (opt.fs_path.unwrap(), opt.fdt_file_path.unwrap());
     let mut data = vec![0_u8; FDT_MAX_SIZE];
-    let fdt = Fdt::from_fs(&opt.fs_path, &mut data).unwrap();
+    let fdt = Fdt::from_fs(&fs_path, &mut data).unwrap();
     fdt.pack().unwrap();
-    fs::write(&opt.fdt_file_path, fdt.as_slice()).unwrap();
+    fs::write(fdt_file_path, fdt.as_slice()).unwrap();
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn diff(old_path: &Path, new_path: &Path) -> Result<ExitCode> {
+    let old_data = fs::read(old_path).with_context(|| format!("Failed to read {old_path:?}"))?;
+    let old = Fdt::from_slice(&old_data).map_err(|e| anyhow!("Invalid FDT {old_path:?}, {e:?}"))?;
+    let new_data = fs::read(new_path).with_context(|| format!("Failed to read {new_path:?}"))?;
+    let new = Fdt::from_slice(&new_data).map_err(|e| anyhow!("Invalid FDT {new_path:?}, {e:?}"))?;
+
+    let changes = old.diff(new).map_err(|e| anyhow!("Failed to compare FDTs, {e:?}"))?;
+    for change in &changes {
+        println!("{change}");
+    }
+
+    Ok(if changes.is_empty() { ExitCode::SUCCESS } else { ExitCode::from(1) })
 }
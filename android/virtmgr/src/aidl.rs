@@ -15,13 +15,14 @@
 //! Implementation of the AIDL interface of the VirtualizationService.
 
 use crate::{get_calling_pid, get_calling_uid, get_this_pid};
-use crate::atom::{write_vm_booted_stats, write_vm_creation_stats};
+use crate::atom::{write_vm_booted_stats, write_vm_creation_stats, GlobalServiceStatsWriter};
 use crate::composite::make_composite_image;
-use crate::crosvm::{AudioConfig, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, UsbConfig, VmContext, VmInstance, VmState};
+use crate::crosvm::{AudioConfig, CrosvmBackend, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, UsbConfig, VmContext, VmInstance, VmState};
 use crate::debug_config::DebugConfig;
 use crate::dt_overlay::{create_device_tree_overlay, VM_DT_OVERLAY_MAX_SIZE, VM_DT_OVERLAY_PATH};
 use crate::payload::{add_microdroid_payload_images, add_microdroid_system_images, add_microdroid_vendor_image};
 use crate::selinux::{getfilecon, SeContext};
+use crate::snapshot::{extract_snapshot, SnapshotSource};
 use android_os_permissions_aidl::aidl::android::os::IPermissionController;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
     Certificate::Certificate,
@@ -47,6 +48,10 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
 };
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IGlobalVmContext::IGlobalVmContext;
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVirtualizationServiceInternal::IVirtualizationServiceInternal;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVirtualMachineProvider::{
+        BnVirtualMachineProvider, IVirtualMachineProvider,
+};
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVcpuController::IVcpuController;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::{
         BnVirtualMachineService, IVirtualMachineService,
 };
@@ -176,7 +181,12 @@ fn get_current_sdk() -> Result<u32> {
 
 pub fn remove_temporary_files(path: &PathBuf) -> Result<()> {
     for dir_entry in read_dir(path)? {
-        remove_file(dir_entry?.path())?;
+        let dir_entry = dir_entry?;
+        if dir_entry.file_type()?.is_dir() {
+            remove_dir_all(dir_entry.path())?;
+        } else {
+            remove_file(dir_entry.path())?;
+        }
     }
     Ok(())
 }
@@ -228,6 +238,32 @@ impl IVirtualizationService for VirtualizationService {
             console_out_fd,
             console_in_fd,
             log_fd,
+            None,
+            &mut is_protected,
+        );
+        write_vm_creation_stats(config, is_protected, &ret);
+        ret
+    }
+
+    /// Creates (but does not start) a new VM restored from a snapshot of a VM with the same
+    /// configuration, assigning it the next available CID.
+    ///
+    /// Returns a binder `IVirtualMachine` object referring to it, as a handle for the client.
+    fn restoreVm(
+        &self,
+        config: &VirtualMachineConfig,
+        snapshot_fd: &ParcelFileDescriptor,
+        console_out_fd: Option<&ParcelFileDescriptor>,
+        console_in_fd: Option<&ParcelFileDescriptor>,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let mut is_protected = false;
+        let ret = self.create_vm_internal(
+            config,
+            console_out_fd,
+            console_in_fd,
+            log_fd,
+            Some(snapshot_fd),
             &mut is_protected,
         );
         write_vm_creation_stats(config, is_protected, &ret);
@@ -301,6 +337,34 @@ impl IVirtualizationService for VirtualizationService {
         GLOBAL_SERVICE.debugListVms()
     }
 
+    /// Stop the running VM with the given CID, which may belong to a different client. This method
+    /// is only intended for debug purposes, and as such is only permitted from the shell user.
+    fn debugStopVm(&self, cid: i32) -> binder::Result<()> {
+        // Delegate to the global service, which gets the VM from the virtmgr owning it, including
+        // checking the debug permission.
+        GLOBAL_SERVICE.debugStopVm(cid)
+    }
+
+    /// Suspend the running VM with the given CID, which may belong to a different client.
+    fn debugSuspendVm(&self, cid: i32) -> binder::Result<()> {
+        GLOBAL_SERVICE.debugSuspendVm(cid)
+    }
+
+    /// Resume the suspended VM with the given CID, which may belong to a different client.
+    fn debugResumeVm(&self, cid: i32) -> binder::Result<()> {
+        GLOBAL_SERVICE.debugResumeVm(cid)
+    }
+
+    /// Get the size of the memory balloon of the running VM with the given CID.
+    fn debugGetMemoryBalloon(&self, cid: i32) -> binder::Result<i64> {
+        GLOBAL_SERVICE.debugGetMemoryBalloon(cid)
+    }
+
+    /// Set the size of the memory balloon of the running VM with the given CID.
+    fn debugSetMemoryBalloon(&self, cid: i32, num_bytes: i64) -> binder::Result<()> {
+        GLOBAL_SERVICE.debugSetMemoryBalloon(cid, num_bytes)
+    }
+
     /// Get a list of assignable device types.
     fn getAssignableDevices(&self) -> binder::Result<Vec<AssignableDevice>> {
         // Delegate to the global service, including checking the permission.
@@ -391,6 +455,20 @@ impl IGlobalVmContext for EarlyVmContext {
             Some("Early VM doesn't support setting host console name"),
         ))
     }
+
+    fn setGuestResources(&self, _memory_mib: i32, _online_vcpus: i32) -> binder::Result<()> {
+        // Early VMs aren't listed by debugListVms, so there is nowhere to report them.
+        Ok(())
+    }
+
+    fn registerVirtualMachine(
+        &self,
+        _name: &str,
+        _provider: &Strong<dyn IVirtualMachineProvider>,
+    ) -> binder::Result<()> {
+        // Early VMs aren't listed by debugListVms, so debug tools can't look them up.
+        Ok(())
+    }
 }
 
 fn find_partition(path: &Path) -> binder::Result<String> {
@@ -447,7 +525,8 @@ impl VirtualizationService {
             .context(format!("Could not start RpcServer on port {port}"))
             .or_service_specific_exception(-1)?;
         vm_server.start();
-        Ok((VmContext::new(Strong::new(Box::new(context)), vm_server), cid, temp_dir))
+        let stats_writer = Arc::new(GlobalServiceStatsWriter);
+        Ok((VmContext::new(Strong::new(Box::new(context)), vm_server, stats_writer), cid, temp_dir))
     }
 
     fn create_vm_context(
@@ -467,7 +546,12 @@ impl VirtualizationService {
             match RpcServer::new_vsock(service, cid, port) {
                 Ok(vm_server) => {
                     vm_server.start();
-                    return Ok((VmContext::new(vm_context, vm_server), cid, temp_dir));
+                    let stats_writer = Arc::new(GlobalServiceStatsWriter);
+                    return Ok((
+                        VmContext::new(vm_context, vm_server, stats_writer),
+                        cid,
+                        temp_dir,
+                    ));
                 }
                 Err(err) => {
                     warn!("Could not start RpcServer on port {}: {}", port, err);
@@ -484,6 +568,7 @@ impl VirtualizationService {
         console_out_fd: Option<&ParcelFileDescriptor>,
         console_in_fd: Option<&ParcelFileDescriptor>,
         log_fd: Option<&ParcelFileDescriptor>,
+        snapshot_fd: Option<&ParcelFileDescriptor>,
         is_protected: &mut bool,
     ) -> binder::Result<Strong<dyn IVirtualMachine>> {
         let requester_uid = get_calling_uid();
@@ -705,7 +790,7 @@ impl VirtualizationService {
             .or_binder_exception(ExceptionCode::BAD_PARCELABLE)?;
 
         // Actually start the VM.
-        let crosvm_config = CrosvmConfig {
+        let mut crosvm_config = CrosvmConfig {
             cid,
             name: config.name.clone(),
             bootloader: maybe_clone_file(&config.bootloader)?,
@@ -744,7 +829,27 @@ impl VirtualizationService {
             audio_config,
             no_balloon: config.noBalloon,
             usb_config,
+            restore: None,
         };
+        if let Some(snapshot_fd) = snapshot_fd {
+            if *is_protected {
+                return Err(anyhow!("Protected VMs can't be restored from a snapshot"))
+                    .with_log()
+                    .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+            }
+            let snapshot = clone_file(snapshot_fd)?;
+            let vmm_state = temporary_directory.join("restore");
+            SnapshotSource::new(&crosvm_config)
+                .context("Failed to duplicate VM images")
+                .and_then(|source| source.config())
+                .and_then(|snapshot_config| {
+                    extract_snapshot(snapshot, &snapshot_config, &vmm_state)
+                })
+                .context("Failed to restore VM from snapshot")
+                .with_log()
+                .or_service_specific_exception(-1)?;
+            crosvm_config.restore = Some(vmm_state);
+        }
         let instance = Arc::new(
             VmInstance::new(
                 crosvm_config,
@@ -752,12 +857,19 @@ impl VirtualizationService {
                 requester_uid,
                 requester_debug_pid,
                 vm_context,
+                Arc::new(CrosvmBackend),
             )
             .with_context(|| format!("Failed to create VM with config {:?}", config))
             .with_log()
             .or_service_specific_exception(-1)?,
         );
         state.add_vm(Arc::downgrade(&instance));
+        let provider = VirtualMachineProvider::create(Arc::downgrade(&instance));
+        if let Err(e) =
+            instance.vm_context.global_context.registerVirtualMachine(&instance.name, &provider)
+        {
+            warn!("Failed to register VM with CID {} for debugging: {:?}", instance.cid, e);
+        }
         Ok(VirtualMachine::create(instance))
     }
 }
@@ -1325,11 +1437,45 @@ fn check_label_for_file(file: &File, name: &str) -> Result<()> {
 #[derive(Debug)]
 struct VirtualMachine {
     instance: Arc<VmInstance>,
+    /// Whether the VM is stopped when the handle is dropped. This is only the case for the handle
+    /// returned to the client which created the VM, not for handles obtained for debugging.
+    stop_on_drop: bool,
 }
 
 impl VirtualMachine {
     fn create(instance: Arc<VmInstance>) -> Strong<dyn IVirtualMachine> {
-        BnVirtualMachine::new_binder(VirtualMachine { instance }, BinderFeatures::default())
+        let vm = VirtualMachine { instance, stop_on_drop: true };
+        BnVirtualMachine::new_binder(vm, BinderFeatures::default())
+    }
+}
+
+/// Implementation of the AIDL `IVirtualMachineProvider` interface, through which
+/// VirtualizationService gets handles to a VM for debugging without keeping the VM alive.
+#[derive(Debug)]
+struct VirtualMachineProvider {
+    instance: Weak<VmInstance>,
+}
+
+impl VirtualMachineProvider {
+    fn create(instance: Weak<VmInstance>) -> Strong<dyn IVirtualMachineProvider> {
+        BnVirtualMachineProvider::new_binder(
+            VirtualMachineProvider { instance },
+            BinderFeatures::default(),
+        )
+    }
+}
+
+impl Interface for VirtualMachineProvider {}
+
+impl IVirtualMachineProvider for VirtualMachineProvider {
+    fn getVirtualMachine(&self) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let instance = self
+            .instance
+            .upgrade()
+            .context("VM no longer exists")
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+        let vm = VirtualMachine { instance, stop_on_drop: false };
+        Ok(BnVirtualMachine::new_binder(vm, BinderFeatures::default()))
     }
 }
 
@@ -1394,6 +1540,30 @@ impl IVirtualMachine for VirtualMachine {
             .or_service_specific_exception(-1)
     }
 
+    fn setGuestMemoryMib(&self, memory_mib: i32) -> binder::Result<()> {
+        let memory_mib = u32::try_from(memory_mib)
+            .with_context(|| format!("Invalid guest memory {memory_mib} MiB"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance
+            .set_guest_memory(memory_mib)
+            .with_context(|| {
+                format!("Error setting guest memory for VM with CID {}", self.instance.cid)
+            })
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn setOnlineVcpus(&self, count: i32) -> binder::Result<()> {
+        let count = u32::try_from(count)
+            .with_context(|| format!("Invalid number of vCPUs {count}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance
+            .set_online_vcpus(count)
+            .with_context(|| format!("Error setting vCPUs for VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
     fn connectVsock(&self, port: i32) -> binder::Result<ParcelFileDescriptor> {
         if !matches!(&*self.instance.vm_state.lock().unwrap(), VmState::Running { .. }) {
             return Err(anyhow!("VM is not running")).or_service_specific_exception(-1);
@@ -1428,11 +1598,35 @@ impl IVirtualMachine for VirtualMachine {
             .with_log()
             .or_service_specific_exception(-1)
     }
+
+    fn snapshot(&self, snapshot_fd: &ParcelFileDescriptor) -> binder::Result<()> {
+        self.instance
+            .snapshot(clone_file(snapshot_fd)?)
+            .with_context(|| format!("Error snapshotting VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn getEventLog(&self) -> binder::Result<String> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        self.instance
+            .event_log
+            .to_json()
+            .with_context(|| {
+                format!("Error serializing event log of VM with CID {}", self.instance.cid)
+            })
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
 }
 
 impl Drop for VirtualMachine {
     fn drop(&mut self) {
         debug!("Dropping {:?}", self);
+        if !self.stop_on_drop {
+            return;
+        }
         if let Err(e) = self.instance.kill() {
             debug!("Error stopping dropped VM with CID {}: {:?}", self.instance.cid, e);
         }
@@ -1496,7 +1690,7 @@ impl VirtualMachineCallbacks {
     }
 
     /// Add a new callback to the set.
-    fn add(&self, callback: Strong<dyn IVirtualMachineCallback>) {
+    pub(crate) fn add(&self, callback: Strong<dyn IVirtualMachineCallback>) {
         self.0.lock().unwrap().push(callback);
     }
 }
@@ -1848,6 +2042,18 @@ impl IVirtualMachineService for VirtualMachineService {
     fn requestAttestation(&self, csr: &[u8], test_mode: bool) -> binder::Result<Vec<Certificate>> {
         GLOBAL_SERVICE.requestAttestation(csr, get_calling_uid() as i32, test_mode)
     }
+
+    fn setVcpuController(&self, controller: &Strong<dyn IVcpuController>) -> binder::Result<()> {
+        let cid = self.cid;
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            info!("VM with CID {} registered a vCPU controller", cid);
+            *vm.vcpu_controller.lock().unwrap() = Some(controller.clone());
+            Ok(())
+        } else {
+            error!("setVcpuController is called from an unknown CID {}", cid);
+            Err(anyhow!("cannot find a VM with CID {}", cid)).or_service_specific_exception(-1)
+        }
+    }
 }
 
 fn is_secretkeeper_supported() -> bool {
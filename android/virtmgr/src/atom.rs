@@ -35,6 +35,7 @@ use binder::ParcelFileDescriptor;
 use log::{info, warn};
 use microdroid_payload_config::VmPayloadConfig;
 use statslog_virtualization_rust::vm_creation_requested;
+use std::fmt;
 use std::thread;
 use std::time::{Duration, SystemTime};
 use zip::ZipArchive;
@@ -191,8 +192,28 @@ pub fn write_vm_booted_stats(
     });
 }
 
+/// Writes the atoms of a VM to statsd.
+pub trait StatsWriter: fmt::Debug + Send + Sync {
+    /// Writes the atom of a VM having exited, blocking until it is written.
+    fn write_vm_exited(&self, atom: &AtomVmExited);
+}
+
+/// Writes the atoms through VirtualizationServiceInternal, which has access to statsd.
+#[derive(Debug)]
+pub struct GlobalServiceStatsWriter;
+
+impl StatsWriter for GlobalServiceStatsWriter {
+    fn write_vm_exited(&self, atom: &AtomVmExited) {
+        info!("Writing VmExited atom into statsd.");
+        GLOBAL_SERVICE.atomVmExited(atom).unwrap_or_else(|e| {
+            warn!("Failed to write VmExited atom: {e}");
+        });
+    }
+}
+
 /// Write the stats of VM exit to statsd
 pub fn write_vm_exited_stats_sync(
+    stats_writer: &dyn StatsWriter,
     uid: i32,
     vm_identifier: &str,
     reason: DeathReason,
@@ -219,8 +240,5 @@ pub fn write_vm_exited_stats_sync(
         exitSignal: exit_signal.unwrap_or_default(),
     };
 
-    info!("Writing VmExited atom into statsd.");
-    GLOBAL_SERVICE.atomVmExited(&atom).unwrap_or_else(|e| {
-        warn!("Failed to write VmExited atom: {e}");
-    });
+    stats_writer.write_vm_exited(&atom);
 }
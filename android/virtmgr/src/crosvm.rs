@@ -15,22 +15,25 @@
 //! Functions for running instances of `crosvm`.
 
 use crate::aidl::{remove_temporary_files, Cid, GLOBAL_SERVICE, VirtualMachineCallbacks};
-use crate::atom::{get_num_cpus, write_vm_exited_stats_sync};
+use crate::atom::{get_num_cpus, write_vm_exited_stats_sync, StatsWriter};
 use crate::debug_config::DebugConfig;
-use anyhow::{anyhow, bail, Context, Error, Result};
+use crate::event_log::{EventLog, VmEvent};
+use crate::snapshot::{write_snapshot, SnapshotSource};
+use crate::vmm::{VmmBackend, VmmProcess};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use binder::ParcelFileDescriptor;
 use command_fds::CommandFdExt;
 use libc::{sysconf, _SC_CLK_TCK};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use semver::{Version, VersionReq};
 use nix::{fcntl::OFlag, unistd::pipe2, unistd::Uid, unistd::User};
 use regex::{Captures, Regex};
 use rustutils::system_properties;
 use shared_child::SharedChild;
 use std::borrow::Cow;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::fmt;
-use std::fs::{read_to_string, File};
+use std::fs::{read_to_string, remove_dir_all, File};
 use std::io::{self, Read};
 use std::mem;
 use std::num::{NonZeroU16, NonZeroU32};
@@ -52,12 +55,13 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IGlobalVmContext::IGlobalVmContext;
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IBoundDevice::IBoundDevice;
 use binder::Strong;
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVcpuController::IVcpuController;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
 use tombstoned_client::{TombstonedConnection, DebuggerdDumpType};
 use rpcbinder::RpcServer;
 
 /// external/crosvm
-use vm_control::{BalloonControlCommand, VmRequest, VmResponse};
+use vm_control::{BalloonControlCommand, SnapshotCommand, VmRequest, VmResponse};
 
 const CROSVM_PATH: &str = "/apex/com.android.virt/bin/crosvm";
 
@@ -77,6 +81,9 @@ const CROSVM_CRASH_STATUS: i32 = 33;
 const CROSVM_WATCHDOG_REBOOT_STATUS: i32 = 36;
 /// The size of memory (in MiB) reserved for ramdump
 const RAMDUMP_RESERVED_MIB: u32 = 17;
+/// The least memory (in MiB) which the guest can be left with by `VmInstance::set_guest_memory`.
+const MIN_GUEST_MEMORY_MIB: u32 = 128;
+const MIB: u64 = 1 << 20;
 
 const MILLIS_PER_SEC: i64 = 1000;
 
@@ -135,6 +142,7 @@ pub struct CrosvmConfig {
     pub audio_config: Option<AudioConfig>,
     pub no_balloon: bool,
     pub usb_config: UsbConfig,
+    pub restore: Option<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -261,9 +269,9 @@ pub enum VmState {
     },
     /// The VM has been started.
     Running {
-        /// The crosvm child process.
-        child: Arc<SharedChild>,
-        /// The thread waiting for crosvm to finish.
+        /// The VMM process running the VM.
+        child: Arc<dyn VmmProcess>,
+        /// The thread waiting for the VMM to finish.
         monitor_vm_exit_thread: Option<JoinHandle<()>>,
     },
     /// The VM died or was killed.
@@ -306,8 +314,12 @@ impl VmState {
                 if let Some(tap_file) = &config.tap { Some(tap_file.try_clone()?) } else { None };
 
             // If this fails and returns an error, `self` will be left in the `Failed` state.
-            let child =
-                Arc::new(run_vm(config, &instance.crosvm_control_socket_path, failure_pipe_write)?);
+            let child = instance.backend.launch(
+                config,
+                &instance.temporary_directory,
+                failure_pipe_write,
+            )?;
+            instance.event_log.record(VmEvent::Started { pid: child.id() });
 
             let instance_monitor_status = instance.clone();
             let child_monitor_status = child.clone();
@@ -345,12 +357,18 @@ pub struct VmContext {
     pub(crate) global_context: Strong<dyn IGlobalVmContext>,
     #[allow(dead_code)] // Keeps the server alive
     vm_server: RpcServer,
+    /// Where the stats of the VM are written.
+    stats_writer: Arc<dyn StatsWriter>,
 }
 
 impl VmContext {
     /// Construct new VmContext.
-    pub fn new(global_context: Strong<dyn IGlobalVmContext>, vm_server: RpcServer) -> VmContext {
-        VmContext { global_context, vm_server }
+    pub fn new(
+        global_context: Strong<dyn IGlobalVmContext>,
+        vm_server: RpcServer,
+        stats_writer: Arc<dyn StatsWriter>,
+    ) -> VmContext {
+        VmContext { global_context, vm_server, stats_writer }
     }
 }
 
@@ -364,8 +382,8 @@ pub struct VmInstance {
     pub(crate) vm_context: VmContext,
     /// The CID assigned to the VM for vsock communication.
     pub cid: Cid,
-    /// Path to crosvm control socket
-    crosvm_control_socket_path: PathBuf,
+    /// The VMM which runs the VM.
+    backend: Arc<dyn VmmBackend>,
     /// The name of the VM.
     pub name: String,
     /// Whether the VM is a protected VM.
@@ -379,9 +397,13 @@ pub struct VmInstance {
     pub requester_debug_pid: i32,
     /// Callbacks to clients of the VM.
     pub callbacks: VirtualMachineCallbacks,
+    /// Events in the lifetime of the VM, for clients to diagnose what happened to it.
+    pub event_log: EventLog,
     /// VirtualMachineService binder object for the VM.
     #[allow(dead_code)]
     pub vm_service: Mutex<Option<Strong<dyn IVirtualMachineService>>>,
+    /// Onlines and offlines the vCPUs from inside the guest, once the guest has registered it.
+    pub vcpu_controller: Mutex<Option<Strong<dyn IVcpuController>>>,
     /// Recorded metrics of VM such as timestamp or cpu / memory usage.
     pub vm_metric: Mutex<VmMetric>,
     /// The latest lifecycle state which the payload reported itself to be in.
@@ -390,6 +412,23 @@ pub struct VmInstance {
     payload_state_updated: Condvar,
     /// The human readable name of requester_uid
     requester_uid_name: String,
+    /// The parts of the config which a VM restored from a snapshot of this VM must share.
+    snapshot_source: SnapshotSource,
+    /// The memory and vCPUs which the guest can currently use.
+    guest_resources: Mutex<GuestResources>,
+}
+
+/// The memory and vCPUs which the guest can use, out of those the VM was started with.
+#[derive(Debug)]
+struct GuestResources {
+    /// Memory the VM was started with, in MiB. The balloon can only take memory away from this.
+    max_memory_mib: u32,
+    /// Memory the guest can currently use, in MiB.
+    memory_mib: u32,
+    /// Number of vCPUs the VM was started with.
+    max_vcpus: u32,
+    /// Number of vCPUs currently online.
+    online_vcpus: u32,
 }
 
 impl fmt::Display for VmInstance {
@@ -411,9 +450,25 @@ impl VmInstance {
         requester_uid: u32,
         requester_debug_pid: i32,
         vm_context: VmContext,
+        backend: Arc<dyn VmmBackend>,
     ) -> Result<VmInstance, Error> {
-        validate_config(&config)?;
+        backend.validate_config(&config)?;
         let cid = config.cid;
+        let snapshot_source = SnapshotSource::new(&config)?;
+        let max_vcpus = config
+            .cpus
+            .map(NonZeroU32::get)
+            .or_else(|| {
+                // crosvm is given the number of host CPUs when following the host CPU topology.
+                config.host_cpu_topology.then(get_num_cpus).flatten()?.try_into().ok()
+            })
+            .unwrap_or(1);
+        let guest_resources = GuestResources {
+            max_memory_mib: config.memory_mib.get(),
+            memory_mib: config.memory_mib.get(),
+            max_vcpus,
+            online_vcpus: max_vcpus,
+        };
         let name = config.name.clone();
         let protected = config.protected;
         let requester_uid_name = User::from_uid(Uid::from_raw(requester_uid))
@@ -424,31 +479,39 @@ impl VmInstance {
             vm_state: Mutex::new(VmState::NotStarted { config: Box::new(config) }),
             vm_context,
             cid,
-            crosvm_control_socket_path: temporary_directory.join("crosvm.sock"),
+            backend,
             name,
             protected,
             temporary_directory,
             requester_uid,
             requester_debug_pid,
             callbacks: Default::default(),
+            event_log: Default::default(),
             vm_service: Mutex::new(None),
+            vcpu_controller: Mutex::new(None),
             vm_metric: Mutex::new(Default::default()),
             payload_state: Mutex::new(PayloadState::Starting),
             payload_state_updated: Condvar::new(),
             requester_uid_name,
+            snapshot_source,
+            guest_resources: Mutex::new(guest_resources),
         };
         info!("{} created", &instance);
         Ok(instance)
     }
 
-    /// Starts an instance of `crosvm` to manage the VM. The `crosvm` instance will be killed when
-    /// the `VmInstance` is dropped.
+    /// Starts an instance of the VMM to manage the VM. The VMM instance will be killed when the
+    /// `VmInstance` is dropped.
     pub fn start(self: &Arc<Self>) -> Result<(), Error> {
         let mut vm_metric = self.vm_metric.lock().unwrap();
         vm_metric.start_timestamp = Some(SystemTime::now());
         let ret = self.vm_state.lock().unwrap().start(self.clone());
-        if ret.is_ok() {
-            info!("{} started", &self);
+        match &ret {
+            Ok(()) => {
+                info!("{} started", &self);
+                self.report_guest_resources(&self.guest_resources.lock().unwrap());
+            }
+            Err(e) => self.event_log.record(VmEvent::StartFailed { error: format!("{e:#}") }),
         }
         ret.with_context(|| format!("{} failed to start", &self))
     }
@@ -458,20 +521,19 @@ impl VmInstance {
     /// callbacks, and removing temporary files for the VM.
     fn monitor_vm_exit(
         &self,
-        child: Arc<SharedChild>,
+        child: Arc<dyn VmmProcess>,
         mut failure_pipe_read: File,
         vfio_devices: Vec<VfioDevice>,
         tap: Option<File>,
     ) {
         let result = child.wait();
         match &result {
-            Err(e) => error!("Error waiting for crosvm({}) instance to die: {}", child.id(), e),
+            Err(e) => error!("Error waiting for VMM({}) instance to die: {}", child.id(), e),
             Ok(status) => {
-                info!("crosvm({}) exited with status {}", child.id(), status);
-                if let Some(exit_status_code) = status.code() {
-                    if exit_status_code == CROSVM_WATCHDOG_REBOOT_STATUS {
-                        info!("detected vcpu stall on crosvm");
-                    }
+                info!("VMM({}) exited with status {}", child.id(), status);
+                if self.backend.exit_death_reason(status) == DeathReason::WATCHDOG_REBOOT {
+                    info!("detected vcpu stall on VMM({})", child.id());
+                    self.event_log.record(VmEvent::WatchdogReboot);
                 }
             }
         }
@@ -502,13 +564,25 @@ impl VmInstance {
 
         self.handle_ramdump().unwrap_or_else(|e| error!("Error handling ramdump: {}", e));
 
-        let death_reason = death_reason(&result, &failure_reason);
+        let death_reason = death_reason(&result, &failure_reason, self.backend.as_ref());
         let exit_signal = exit_signal(&result);
-
+        let peak_rss = self.vm_metric.lock().unwrap().rss.as_ref().map(|rss| (rss.vm, rss.crosvm));
+
+        // Record the exit before calling the callbacks, so that clients see it in the log as soon
+        // as they learn that the VM died.
+        self.event_log.record(VmEvent::Exited {
+            exit_code: result.as_ref().ok().and_then(ExitStatus::code),
+            signal: exit_signal,
+            death_reason: format!("{:?}", death_reason),
+            failure_reason: (!failure_reason.is_empty()).then(|| failure_reason.to_string()),
+            peak_rss_vm_kib: peak_rss.map(|(vm, _)| vm),
+            peak_rss_vmm_kib: peak_rss.map(|(_, vmm)| vmm),
+        });
         self.callbacks.callback_on_died(self.cid, death_reason);
 
         let vm_metric = self.vm_metric.lock().unwrap();
         write_vm_exited_stats_sync(
+            self.vm_context.stats_writer.as_ref(),
             self.requester_uid as i32,
             &self.name,
             death_reason,
@@ -534,7 +608,7 @@ impl VmInstance {
 
     /// Waits until payload is started, or timeout expires. When timeout occurs, kill
     /// the VM to prevent indefinite hangup and update the payload_state accordingly.
-    fn monitor_payload_hangup(&self, child: Arc<SharedChild>) {
+    fn monitor_payload_hangup(&self, child: Arc<dyn VmmProcess>) {
         debug!("Starting to monitor hangup for Microdroid({})", child.id());
         let (state, result) = self
             .payload_state_updated
@@ -557,7 +631,7 @@ impl VmInstance {
         }
     }
 
-    fn monitor_vm_status(&self, child: Arc<SharedChild>) {
+    fn monitor_vm_status(&self, child: Arc<dyn VmmProcess>) {
         let pid = child.id();
 
         loop {
@@ -605,21 +679,22 @@ impl VmInstance {
         if new_state > *state_locked {
             *state_locked = new_state;
             self.payload_state_updated.notify_all();
+            self.event_log.record(VmEvent::PayloadStateChanged { state: format!("{new_state:?}") });
             Ok(())
         } else {
             bail!("Invalid payload state transition from {:?} to {:?}", *state_locked, new_state)
         }
     }
 
-    /// Kills the crosvm instance, if it is running.
+    /// Kills the VMM instance, if it is running.
     pub fn kill(&self) -> Result<(), Error> {
         let monitor_vm_exit_thread = {
             let vm_state = &mut *self.vm_state.lock().unwrap();
             if let VmState::Running { child, monitor_vm_exit_thread } = vm_state {
                 let id = child.id();
-                debug!("Killing crosvm({})", id);
-                // TODO: Talk to crosvm to shutdown cleanly.
-                child.kill().with_context(|| format!("Error killing crosvm({id}) instance"))?;
+                debug!("Killing VMM({})", id);
+                child.stop().with_context(|| format!("Error killing VMM({id}) instance"))?;
+                self.event_log.record(VmEvent::StopRequested);
                 monitor_vm_exit_thread.take()
             } else {
                 bail!("VM is not running")
@@ -637,41 +712,94 @@ impl VmInstance {
         Ok(())
     }
 
+    /// Returns the VMM process running the VM, or an error if the VM isn't running.
+    fn running_process(&self) -> Result<Arc<dyn VmmProcess>, Error> {
+        match &*self.vm_state.lock().unwrap() {
+            VmState::Running { child, .. } => Ok(child.clone()),
+            _ => bail!("VM is not running"),
+        }
+    }
+
     /// Responds to memory-trimming notifications by inflating the virtio
     /// balloon to reclaim guest memory.
     pub fn get_memory_balloon(&self) -> Result<u64, Error> {
-        let request = VmRequest::BalloonCommand(BalloonControlCommand::Stats {});
-        let result =
-            match vm_control::client::handle_request(&request, &self.crosvm_control_socket_path) {
-                Ok(VmResponse::BalloonStats { stats: _, balloon_actual }) => balloon_actual,
-                Ok(VmResponse::Err(e)) => {
-                    // ENOTSUP is returned when the balloon protocol is not initialized. This
-                    // can occur for numerous reasons: Guest is still booting, guest doesn't
-                    // support ballooning, host doesn't support ballooning. We don't log or
-                    // raise an error in this case: trim is just a hint and we can ignore it.
-                    if e.errno() != libc::ENOTSUP {
-                        bail!("Errno return when requesting balloon stats: {}", e.errno())
-                    }
-                    0
-                }
-                e => bail!("Error requesting balloon stats: {:?}", e),
-            };
-        Ok(result)
+        self.running_process()?.get_memory_balloon()
     }
 
     /// Responds to memory-trimming notifications by inflating the virtio
     /// balloon to reclaim guest memory.
     pub fn set_memory_balloon(&self, num_bytes: u64) -> Result<(), Error> {
-        let command = BalloonControlCommand::Adjust { num_bytes, wait_for_success: false };
-        if let Err(e) = vm_control::client::handle_request(
-            &VmRequest::BalloonCommand(command),
-            &self.crosvm_control_socket_path,
-        ) {
-            bail!("Error sending balloon adjustment: {:?}", e);
+        let mut resources = self.guest_resources.lock().unwrap();
+        self.running_process()?.set_memory_balloon(num_bytes)?;
+        self.event_log.record(VmEvent::BalloonAdjusted { num_bytes });
+        let balloon_mib = u32::try_from(num_bytes / MIB).unwrap_or(u32::MAX);
+        resources.memory_mib = resources.max_memory_mib.saturating_sub(balloon_mib);
+        self.report_guest_resources(&resources);
+        Ok(())
+    }
+
+    /// Changes the memory which the guest can use to `memory_mib`, by inflating or deflating the
+    /// virtio balloon. It must be between `MIN_GUEST_MEMORY_MIB` and the memory the VM was started
+    /// with.
+    pub fn set_guest_memory(&self, memory_mib: u32) -> Result<(), Error> {
+        let mut resources = self.guest_resources.lock().unwrap();
+        let min_memory_mib = min(MIN_GUEST_MEMORY_MIB, resources.max_memory_mib);
+        ensure!(
+            (min_memory_mib..=resources.max_memory_mib).contains(&memory_mib),
+            "Guest memory must be between {} and {} MiB, not {}",
+            min_memory_mib,
+            resources.max_memory_mib,
+            memory_mib
+        );
+        let num_bytes = u64::from(resources.max_memory_mib - memory_mib) * MIB;
+        self.running_process()?.set_memory_balloon(num_bytes)?;
+        self.event_log.record(VmEvent::BalloonAdjusted { num_bytes });
+        resources.memory_mib = memory_mib;
+        self.report_guest_resources(&resources);
+        Ok(())
+    }
+
+    /// Onlines or offlines vCPUs so that `count` of them are online. It must be between 1 and the
+    /// number of vCPUs the VM was started with. crosvm can't hot-plug or unplug vCPUs, so this is
+    /// done by the guest, through the `IVcpuController` it has registered.
+    pub fn set_online_vcpus(&self, count: u32) -> Result<(), Error> {
+        let mut resources = self.guest_resources.lock().unwrap();
+        ensure!(
+            (1..=resources.max_vcpus).contains(&count),
+            "Number of online vCPUs must be between 1 and {}, not {}",
+            resources.max_vcpus,
+            count
+        );
+        self.running_process()?;
+        let controller = self
+            .vcpu_controller
+            .lock()
+            .unwrap()
+            .clone()
+            .context("The guest can't online or offline vCPUs")?;
+        if count != resources.online_vcpus {
+            controller
+                .setOnlineVcpus(count as i32)
+                .context("Failed to online or offline vCPUs in the guest")?;
+            self.event_log.record(VmEvent::OnlineVcpusChanged { count });
+            resources.online_vcpus = count;
+            self.report_guest_resources(&resources);
         }
         Ok(())
     }
 
+    /// Reports the memory and vCPUs which the guest can use to VirtualizationService, which lists
+    /// them in `debugListVms`.
+    fn report_guest_resources(&self, resources: &GuestResources) {
+        let result = self
+            .vm_context
+            .global_context
+            .setGuestResources(resources.memory_mib as i32, resources.online_vcpus as i32);
+        if let Err(e) = result {
+            warn!("Failed to report guest resources of {}: {:?}", self, e);
+        }
+    }
+
     /// Checks if ramdump has been created. If so, send it to tombstoned.
     fn handle_ramdump(&self) -> Result<(), Error> {
         let ramdump_path = self.temporary_directory.join("ramdump");
@@ -705,24 +833,43 @@ impl VmInstance {
 
     /// Suspends the VM
     pub fn suspend(&self) -> Result<(), Error> {
-        match vm_control::client::handle_request(
-            &VmRequest::SuspendVcpus,
-            &self.crosvm_control_socket_path,
-        ) {
-            Ok(VmResponse::Ok) => Ok(()),
-            e => bail!("Failed to suspend VM: {e:?}"),
-        }
+        self.running_process()?.suspend()?;
+        self.event_log.record(VmEvent::Suspended);
+        Ok(())
     }
 
     /// Resumes the suspended VM
     pub fn resume(&self) -> Result<(), Error> {
-        match vm_control::client::handle_request(
-            &VmRequest::ResumeVcpus,
-            &self.crosvm_control_socket_path,
-        ) {
-            Ok(VmResponse::Ok) => Ok(()),
-            e => bail!("Failed to resume: {e:?}"),
+        self.running_process()?.resume()?;
+        self.event_log.record(VmEvent::Resumed);
+        Ok(())
+    }
+
+    /// Writes a snapshot of the VM to `output`, from which a VM with the same config can be
+    /// restored.
+    pub fn snapshot(&self, output: File) -> Result<(), Error> {
+        if self.protected {
+            bail!("Protected VMs can't be snapshotted");
+        }
+        let process = self.running_process()?;
+        let vmm_state = self.temporary_directory.join("snapshot");
+        // Keep the VM suspended until its images are identified, so that its writable disks match
+        // the saved state.
+        process.suspend()?;
+        let result = process.snapshot(&vmm_state).and_then(|()| self.snapshot_source.config());
+        let result = process
+            .resume()
+            .and(result)
+            .and_then(|snapshot_config| write_snapshot(output, &snapshot_config, &vmm_state));
+        if result.is_ok() {
+            self.event_log.record(VmEvent::Snapshotted);
         }
+        if vmm_state.exists() {
+            remove_dir_all(&vmm_state).unwrap_or_else(|e| {
+                error!("Error removing snapshot state {:?}: {}", vmm_state, e);
+            });
+        }
+        result
     }
 }
 
@@ -809,7 +956,11 @@ fn get_rss(pid: u32) -> Result<Rss> {
     Ok(Rss { vm: rss_vm_total, crosvm: rss_crosvm_total })
 }
 
-fn death_reason(result: &Result<ExitStatus, io::Error>, mut failure_reason: &str) -> DeathReason {
+fn death_reason(
+    result: &Result<ExitStatus, io::Error>,
+    mut failure_reason: &str,
+    backend: &dyn VmmBackend,
+) -> DeathReason {
     if let Some((reason, info)) = failure_reason.split_once('|') {
         // Separator indicates extra context information is present after the failure name.
         error!("Failure info: {info}");
@@ -839,15 +990,7 @@ fn death_reason(result: &Result<ExitStatus, io::Error>, mut failure_reason: &str
             "HANGUP" => return DeathReason::HANGUP,
             _ => {}
         }
-        match status.code() {
-            None => DeathReason::KILLED,
-            Some(0) => DeathReason::SHUTDOWN,
-            Some(CROSVM_START_ERROR_STATUS) => DeathReason::START_FAILED,
-            Some(CROSVM_REBOOT_STATUS) => DeathReason::REBOOT,
-            Some(CROSVM_CRASH_STATUS) => DeathReason::CRASH,
-            Some(CROSVM_WATCHDOG_REBOOT_STATUS) => DeathReason::WATCHDOG_REBOOT,
-            Some(_) => DeathReason::UNKNOWN,
-        }
+        backend.exit_death_reason(status)
     } else {
         DeathReason::INFRASTRUCTURE_ERROR
     }
@@ -883,6 +1026,124 @@ fn vfio_argument_for_platform_device(device: &VfioDevice) -> Result<String, Erro
     }
 }
 
+/// The crosvm VMM.
+#[derive(Debug, Default)]
+pub struct CrosvmBackend;
+
+impl VmmBackend for CrosvmBackend {
+    fn validate_config(&self, config: &CrosvmConfig) -> Result<()> {
+        validate_config(config)
+    }
+
+    fn launch(
+        &self,
+        config: CrosvmConfig,
+        temporary_directory: &Path,
+        failure_pipe_write: File,
+    ) -> Result<Arc<dyn VmmProcess>> {
+        let control_socket_path = temporary_directory.join("crosvm.sock");
+        let child = run_vm(config, &control_socket_path, failure_pipe_write)?;
+        Ok(Arc::new(CrosvmProcess { child, control_socket_path }))
+    }
+
+    fn exit_death_reason(&self, status: &ExitStatus) -> DeathReason {
+        match status.code() {
+            None => DeathReason::KILLED,
+            Some(0) => DeathReason::SHUTDOWN,
+            Some(CROSVM_START_ERROR_STATUS) => DeathReason::START_FAILED,
+            Some(CROSVM_REBOOT_STATUS) => DeathReason::REBOOT,
+            Some(CROSVM_CRASH_STATUS) => DeathReason::CRASH,
+            Some(CROSVM_WATCHDOG_REBOOT_STATUS) => DeathReason::WATCHDOG_REBOOT,
+            Some(_) => DeathReason::UNKNOWN,
+        }
+    }
+}
+
+/// A crosvm process, controlled through its control socket.
+#[derive(Debug)]
+struct CrosvmProcess {
+    child: SharedChild,
+    control_socket_path: PathBuf,
+}
+
+impl CrosvmProcess {
+    fn handle_request(&self, request: &VmRequest) -> Result<VmResponse, ()> {
+        vm_control::client::handle_request(request, &self.control_socket_path)
+    }
+}
+
+impl VmmProcess for CrosvmProcess {
+    fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    fn wait(&self) -> io::Result<ExitStatus> {
+        self.child.wait()
+    }
+
+    fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    fn stop(&self) -> Result<()> {
+        // TODO: Talk to crosvm to shutdown cleanly.
+        Ok(self.child.kill()?)
+    }
+
+    fn get_memory_balloon(&self) -> Result<u64> {
+        let request = VmRequest::BalloonCommand(BalloonControlCommand::Stats {});
+        let result = match self.handle_request(&request) {
+            Ok(VmResponse::BalloonStats { stats: _, balloon_actual }) => balloon_actual,
+            Ok(VmResponse::Err(e)) => {
+                // ENOTSUP is returned when the balloon protocol is not initialized. This
+                // can occur for numerous reasons: Guest is still booting, guest doesn't
+                // support ballooning, host doesn't support ballooning. We don't log or
+                // raise an error in this case: trim is just a hint and we can ignore it.
+                if e.errno() != libc::ENOTSUP {
+                    bail!("Errno return when requesting balloon stats: {}", e.errno())
+                }
+                0
+            }
+            e => bail!("Error requesting balloon stats: {:?}", e),
+        };
+        Ok(result)
+    }
+
+    fn set_memory_balloon(&self, num_bytes: u64) -> Result<()> {
+        let command = BalloonControlCommand::Adjust { num_bytes, wait_for_success: false };
+        if let Err(e) = self.handle_request(&VmRequest::BalloonCommand(command)) {
+            bail!("Error sending balloon adjustment: {:?}", e);
+        }
+        Ok(())
+    }
+
+    fn suspend(&self) -> Result<()> {
+        match self.handle_request(&VmRequest::SuspendVcpus) {
+            Ok(VmResponse::Ok) => Ok(()),
+            e => bail!("Failed to suspend VM: {e:?}"),
+        }
+    }
+
+    fn resume(&self) -> Result<()> {
+        match self.handle_request(&VmRequest::ResumeVcpus) {
+            Ok(VmResponse::Ok) => Ok(()),
+            e => bail!("Failed to resume: {e:?}"),
+        }
+    }
+
+    fn snapshot(&self, vmm_state: &Path) -> Result<()> {
+        let command = SnapshotCommand::Take {
+            snapshot_path: vmm_state.to_owned(),
+            compress_memory: false,
+            encrypt: false,
+        };
+        match self.handle_request(&VmRequest::Snapshot(command)) {
+            Ok(VmResponse::Ok) => Ok(()),
+            e => bail!("Failed to snapshot VM: {e:?}"),
+        }
+    }
+}
+
 /// Starts an instance of `crosvm` to manage a new VM.
 fn run_vm(
     config: CrosvmConfig,
@@ -1163,6 +1424,10 @@ fn run_vm(
         command.arg("--hugepages");
     }
 
+    if let Some(restore) = &config.restore {
+        command.arg("--restore").arg(restore);
+    }
+
     if config.boost_uclamp {
         command.arg("--boost-uclamp");
     }
@@ -1290,3 +1555,427 @@ fn create_crosvm_control_listener(crosvm_control_socket_path: &Path) -> Result<O
     socket::listen(&fd, socket::Backlog::new(127).unwrap()).context("listen failed")?;
     Ok(fd)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::extract_snapshot;
+    use crate::vmm::fake::FakeBackend;
+    use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::ErrorCode::ErrorCode;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualMachineCallback::{
+        BnVirtualMachineCallback, IVirtualMachineCallback,
+    };
+    use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::AtomVmExited::AtomVmExited;
+    use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IGlobalVmContext::BnGlobalVmContext;
+    use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVirtualMachineProvider::IVirtualMachineProvider;
+    use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVcpuController::BnVcpuController;
+    use binder::{BinderFeatures, Interface};
+    use std::io::{Seek, Write};
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc::{self, Receiver, Sender};
+
+    const TEST_CID: Cid = 2048;
+    const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+    struct TestGlobalVmContext;
+
+    impl Interface for TestGlobalVmContext {}
+
+    impl IGlobalVmContext for TestGlobalVmContext {
+        fn getCid(&self) -> binder::Result<i32> {
+            Ok(TEST_CID as i32)
+        }
+
+        fn getTemporaryDirectory(&self) -> binder::Result<String> {
+            Ok(String::new())
+        }
+
+        fn setHostConsoleName(&self, _pathname: &str) -> binder::Result<()> {
+            Ok(())
+        }
+
+        fn setGuestResources(&self, _memory_mib: i32, _online_vcpus: i32) -> binder::Result<()> {
+            Ok(())
+        }
+
+        fn registerVirtualMachine(
+            &self,
+            _name: &str,
+            _provider: &Strong<dyn IVirtualMachineProvider>,
+        ) -> binder::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Forwards the reasons reported by `onDied` to a channel.
+    struct TestCallback(Mutex<Sender<DeathReason>>);
+
+    impl Interface for TestCallback {}
+
+    impl IVirtualMachineCallback for TestCallback {
+        fn onPayloadStarted(&self, _cid: i32) -> binder::Result<()> {
+            Ok(())
+        }
+
+        fn onPayloadReady(&self, _cid: i32) -> binder::Result<()> {
+            Ok(())
+        }
+
+        fn onPayloadFinished(&self, _cid: i32, _exit_code: i32) -> binder::Result<()> {
+            Ok(())
+        }
+
+        fn onError(&self, _cid: i32, _error_code: ErrorCode, _message: &str) -> binder::Result<()> {
+            Ok(())
+        }
+
+        fn onDied(&self, cid: i32, reason: DeathReason) -> binder::Result<()> {
+            assert_eq!(cid, TEST_CID as i32);
+            self.0.lock().unwrap().send(reason).unwrap();
+            Ok(())
+        }
+    }
+
+    /// Forwards the atoms written when the VM exits to a channel.
+    #[derive(Debug)]
+    struct TestStatsWriter(Mutex<Sender<AtomVmExited>>);
+
+    impl StatsWriter for TestStatsWriter {
+        fn write_vm_exited(&self, atom: &AtomVmExited) {
+            // The receiver is gone if the test isn't interested in the stats.
+            let _ = self.0.lock().unwrap().send(atom.clone());
+        }
+    }
+
+    /// Records the number of vCPUs last requested to be online, as the guest would online them.
+    #[derive(Default)]
+    struct TestVcpuController(Arc<Mutex<Option<i32>>>);
+
+    impl Interface for TestVcpuController {}
+
+    impl IVcpuController for TestVcpuController {
+        fn setOnlineVcpus(&self, count: i32) -> binder::Result<()> {
+            *self.0.lock().unwrap() = Some(count);
+            Ok(())
+        }
+    }
+
+    fn test_config() -> Result<CrosvmConfig> {
+        Ok(CrosvmConfig {
+            cid: TEST_CID,
+            name: "test_vm".to_owned(),
+            bootloader: None,
+            kernel: Some(tempfile::tempfile()?),
+            initrd: None,
+            disks: vec![],
+            params: None,
+            protected: false,
+            debug_config: DebugConfig::new_with_debug_level(DebugLevel::NONE),
+            memory_mib: NonZeroU32::new(256).unwrap(),
+            cpus: None,
+            host_cpu_topology: false,
+            console_out_fd: None,
+            console_in_fd: None,
+            log_fd: None,
+            ramdump: None,
+            indirect_files: vec![],
+            platform_version: VersionReq::STAR,
+            detect_hangup: false,
+            gdb_port: None,
+            vfio_devices: vec![],
+            dtbo: None,
+            device_tree_overlay: None,
+            display_config: None,
+            input_device_options: vec![],
+            hugepages: false,
+            tap: None,
+            console_input_device: None,
+            boost_uclamp: false,
+            gpu_config: None,
+            audio_config: None,
+            no_balloon: false,
+            usb_config: UsbConfig { controller: false },
+            restore: None,
+        })
+    }
+
+    /// Creates a VM run by `backend`, along with a channel receiving the reasons for its death.
+    fn create_instance(
+        backend: Arc<dyn VmmBackend>,
+        temporary_directory: &Path,
+    ) -> Result<(Arc<VmInstance>, Receiver<DeathReason>)> {
+        create_instance_with_config(backend, temporary_directory, test_config()?)
+    }
+
+    fn create_instance_with_config(
+        backend: Arc<dyn VmmBackend>,
+        temporary_directory: &Path,
+        config: CrosvmConfig,
+    ) -> Result<(Arc<VmInstance>, Receiver<DeathReason>)> {
+        let (stats_writer, _) = mpsc::channel();
+        let stats_writer = Arc::new(TestStatsWriter(Mutex::new(stats_writer)));
+        create_instance_with_stats_writer(backend, temporary_directory, config, stats_writer)
+    }
+
+    fn create_instance_with_stats_writer(
+        backend: Arc<dyn VmmBackend>,
+        temporary_directory: &Path,
+        config: CrosvmConfig,
+        stats_writer: Arc<dyn StatsWriter>,
+    ) -> Result<(Arc<VmInstance>, Receiver<DeathReason>)> {
+        let global_context =
+            BnGlobalVmContext::new_binder(TestGlobalVmContext, BinderFeatures::default());
+        let socket = UnixListener::bind(temporary_directory.join("vm_service.sock"))?;
+        let vm_server = RpcServer::new_bound_socket(global_context.as_binder(), socket.into())?;
+        vm_server.start();
+        let vm_context = VmContext::new(global_context, vm_server, stats_writer);
+        let instance =
+            VmInstance::new(config, temporary_directory.to_owned(), 0, 0, vm_context, backend)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let callback = BnVirtualMachineCallback::new_binder(
+            TestCallback(Mutex::new(sender)),
+            BinderFeatures::default(),
+        );
+        instance.callbacks.add(callback);
+        Ok((Arc::new(instance), receiver))
+    }
+
+    #[test]
+    fn test_start_and_kill_vm() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let (instance, died) = create_instance(backend.clone(), temporary_directory.path())?;
+        assert!(matches!(*instance.vm_state.lock().unwrap(), VmState::NotStarted { .. }));
+        assert!(instance.suspend().is_err());
+
+        instance.start()?;
+        assert!(matches!(*instance.vm_state.lock().unwrap(), VmState::Running { .. }));
+        assert!(instance.start().is_err());
+
+        instance.kill()?;
+        assert!(matches!(*instance.vm_state.lock().unwrap(), VmState::Dead));
+        assert_eq!(died.recv_timeout(CALLBACK_TIMEOUT)?, DeathReason::KILLED);
+        assert!(backend.last_process().unwrap().try_wait()?.is_some());
+        assert!(instance.kill().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_writes_stats() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let (sender, atoms) = mpsc::channel();
+        let stats_writer = Arc::new(TestStatsWriter(Mutex::new(sender)));
+        let (instance, _died) = create_instance_with_stats_writer(
+            backend.clone(),
+            temporary_directory.path(),
+            test_config()?,
+            stats_writer,
+        )?;
+        instance.start()?;
+
+        backend.last_process().unwrap().exit(1, None);
+        let atom = atoms.recv_timeout(CALLBACK_TIMEOUT)?;
+        assert_eq!(atom.deathReason, DeathReason::CRASH);
+        assert_eq!(atom.vmIdentifier, instance.name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_running_vm() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let (instance, _died) = create_instance(backend.clone(), temporary_directory.path())?;
+        instance.start()?;
+        let process = backend.last_process().unwrap();
+
+        instance.set_memory_balloon(4096)?;
+        assert_eq!(instance.get_memory_balloon()?, 4096);
+        instance.suspend()?;
+        assert!(process.is_suspended());
+        instance.resume()?;
+        assert!(!process.is_suspended());
+
+        instance.kill()?;
+        assert!(instance.get_memory_balloon().is_err());
+        assert!(instance.resume().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_guest_memory() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let (instance, _died) = create_instance(backend.clone(), temporary_directory.path())?;
+        assert!(instance.set_guest_memory(128).is_err());
+        instance.start()?;
+        let process = backend.last_process().unwrap();
+
+        instance.set_guest_memory(MIN_GUEST_MEMORY_MIB)?;
+        assert_eq!(process.balloon(), 128 * MIB);
+        instance.set_guest_memory(256)?;
+        assert_eq!(process.balloon(), 0);
+        assert!(instance.set_guest_memory(MIN_GUEST_MEMORY_MIB - 1).is_err());
+        assert!(instance.set_guest_memory(257).is_err());
+        assert_eq!(process.balloon(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_online_vcpus() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let config = CrosvmConfig { cpus: NonZeroU32::new(4), ..test_config()? };
+        let (instance, _died) =
+            create_instance_with_config(backend.clone(), temporary_directory.path(), config)?;
+        instance.start()?;
+        assert!(instance.set_online_vcpus(2).is_err(), "The guest has no vCPU controller yet");
+
+        let controller = TestVcpuController::default();
+        let online_vcpus = controller.0.clone();
+        *instance.vcpu_controller.lock().unwrap() =
+            Some(BnVcpuController::new_binder(controller, BinderFeatures::default()));
+        instance.set_online_vcpus(4)?;
+        assert_eq!(*online_vcpus.lock().unwrap(), None);
+        instance.set_online_vcpus(2)?;
+        assert_eq!(*online_vcpus.lock().unwrap(), Some(2));
+        assert!(instance.set_online_vcpus(0).is_err());
+        assert!(instance.set_online_vcpus(5).is_err());
+        assert_eq!(*online_vcpus.lock().unwrap(), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_vm() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let disk = tempfile::tempfile()?;
+        let config_with_disk = || -> Result<CrosvmConfig> {
+            let disks = vec![DiskFile { image: disk.try_clone()?, writable: true }];
+            Ok(CrosvmConfig { disks, ..test_config()? })
+        };
+        let (instance, _died) = create_instance_with_config(
+            backend.clone(),
+            temporary_directory.path(),
+            config_with_disk()?,
+        )?;
+        assert!(instance.snapshot(tempfile::tempfile()?).is_err());
+        instance.start()?;
+
+        let mut snapshot = tempfile::tempfile()?;
+        instance.snapshot(snapshot.try_clone()?)?;
+        assert!(!temporary_directory.path().join("snapshot").exists());
+        assert!(!backend.last_process().unwrap().is_suspended());
+        instance.kill()?;
+
+        snapshot.rewind()?;
+        let restore_directory = tempfile::tempdir()?;
+        let vmm_state = restore_directory.path().join("restore");
+        let snapshot_config = SnapshotSource::new(&config_with_disk()?)?.config()?;
+        extract_snapshot(snapshot.try_clone()?, &snapshot_config, &vmm_state)?;
+        assert!(vmm_state.join("state").exists());
+
+        // A VM can't be restored once its disk has been written to since the snapshot.
+        (&disk).write_all(b"data")?;
+        snapshot.rewind()?;
+        let vmm_state = restore_directory.path().join("restore_modified");
+        let snapshot_config = SnapshotSource::new(&config_with_disk()?)?.config()?;
+        assert!(extract_snapshot(snapshot, &snapshot_config, &vmm_state).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_exit_reports_death_reason() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let (instance, died) = create_instance(backend.clone(), temporary_directory.path())?;
+        instance.start()?;
+
+        backend.last_process().unwrap().exit(0, None);
+        assert_eq!(died.recv_timeout(CALLBACK_TIMEOUT)?, DeathReason::SHUTDOWN);
+        assert!(matches!(*instance.vm_state.lock().unwrap(), VmState::Dead));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_exit_reports_failure_reason() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let (instance, died) = create_instance(backend.clone(), temporary_directory.path())?;
+        instance.start()?;
+
+        backend.last_process().unwrap().exit(1, Some("MICRODROID_PAYLOAD_HAS_CHANGED|apk"));
+        assert_eq!(
+            died.recv_timeout(CALLBACK_TIMEOUT)?,
+            DeathReason::MICRODROID_PAYLOAD_HAS_CHANGED
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_log() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::default());
+        let (instance, died) = create_instance(backend, temporary_directory.path())?;
+        instance.start()?;
+        instance.update_payload_state(PayloadState::Started)?;
+        instance.suspend()?;
+        instance.kill()?;
+        died.recv_timeout(CALLBACK_TIMEOUT)?;
+
+        let log: serde_json::Value = serde_json::from_str(&instance.event_log.to_json()?)?;
+        let events: Vec<_> = log["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            ["started", "payload_state_changed", "suspended", "stop_requested", "exited"]
+        );
+        let exited = log["events"].as_array().unwrap().last().unwrap();
+        assert_eq!(exited["signal"], libc::SIGKILL);
+        assert_eq!(exited["death_reason"], "KILLED");
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_failing_to_launch() -> Result<()> {
+        let temporary_directory = tempfile::tempdir()?;
+        let backend = Arc::new(FakeBackend::failing());
+        let (instance, died) = create_instance(backend, temporary_directory.path())?;
+
+        assert!(instance.start().is_err());
+        assert!(matches!(*instance.vm_state.lock().unwrap(), VmState::Failed));
+        assert!(instance.kill().is_err());
+        assert!(died.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_death_reason() {
+        let status = |code: i32| Ok(ExitStatus::from_raw(code << 8));
+        let reason = |result: io::Result<ExitStatus>, failure_reason: &str| {
+            death_reason(&result, failure_reason, &CrosvmBackend)
+        };
+
+        assert_eq!(reason(status(0), ""), DeathReason::SHUTDOWN);
+        assert_eq!(reason(status(CROSVM_START_ERROR_STATUS), ""), DeathReason::START_FAILED);
+        assert_eq!(reason(status(CROSVM_REBOOT_STATUS), ""), DeathReason::REBOOT);
+        assert_eq!(reason(status(CROSVM_CRASH_STATUS), ""), DeathReason::CRASH);
+        assert_eq!(reason(status(CROSVM_WATCHDOG_REBOOT_STATUS), ""), DeathReason::WATCHDOG_REBOOT);
+        assert_eq!(reason(status(2), ""), DeathReason::UNKNOWN);
+        assert_eq!(reason(Ok(ExitStatus::from_raw(libc::SIGKILL)), ""), DeathReason::KILLED);
+        assert_eq!(reason(status(CROSVM_CRASH_STATUS), "HANGUP"), DeathReason::HANGUP);
+        assert_eq!(
+            reason(status(CROSVM_CRASH_STATUS), "PVM_FIRMWARE_PUBLIC_KEY_MISMATCH|details"),
+            DeathReason::PVM_FIRMWARE_PUBLIC_KEY_MISMATCH
+        );
+        assert_eq!(
+            reason(Err(io::Error::other("wait failed")), "HANGUP"),
+            DeathReason::INFRASTRUCTURE_ERROR
+        );
+    }
+}
@@ -110,7 +110,7 @@ struct OwnedFdt {
 
 impl OwnedFdt {
     fn from_overlay_onto_new_fdt(overlay_file_path: &Path) -> Result<Self> {
-        let mut overlay_buf = match fs::read(overlay_file_path) {
+        let overlay_buf = match fs::read(overlay_file_path) {
             Ok(fdt) => fdt,
             Err(error) if error.kind() == ErrorKind::NotFound => Default::default(),
             Err(error) => {
@@ -127,16 +127,13 @@ impl OwnedFdt {
             .context("Failed to create an empty device tree")?;
 
         if !overlay_buf.is_empty() {
-            let overlay_fdt = Fdt::from_mut_slice(overlay_buf.as_mut_slice())
+            let overlay_fdt = Fdt::from_slice(overlay_buf.as_slice())
                 .map_err(Error::msg)
-                .with_context(|| "Malformed {overlay_file_path:?}")?;
+                .with_context(|| format!("Malformed {overlay_file_path:?}"))?;
 
-            // SAFETY: Return immediately if error happens. Damaged fdt_buf and fdt are discarded.
-            unsafe {
-                fdt.apply_overlay(overlay_fdt).map_err(Error::msg).with_context(|| {
-                    "Failed to overlay {overlay_file_path:?} onto empty device tree"
-                })?;
-            }
+            fdt.merge_overlay(overlay_fdt).map_err(Error::msg).with_context(|| {
+                format!("Failed to overlay {overlay_file_path:?} onto empty device tree")
+            })?;
         }
 
         Ok(Self { buffer: fdt_buf })
@@ -0,0 +1,157 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log of the events in the lifetime of a VM, so that clients can find out what happened to it
+//! without going through logcat.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most events kept for a VM. Older events are dropped beyond this, so that a long-running VM
+/// whose balloon is adjusted repeatedly doesn't grow the log indefinitely.
+const MAX_EVENTS: usize = 1024;
+
+/// An event in the lifetime of a VM.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum VmEvent {
+    /// The VMM was launched.
+    Started { pid: u32 },
+    /// The VM failed to start.
+    StartFailed { error: String },
+    /// The payload reported that it moved to a new state.
+    PayloadStateChanged { state: String },
+    /// The memory balloon was inflated or deflated to the given size.
+    BalloonAdjusted { num_bytes: u64 },
+    /// vCPUs were onlined or offlined so that the given number of them are online.
+    OnlineVcpusChanged { count: u32 },
+    /// The vCPUs of the VM were suspended.
+    Suspended,
+    /// The vCPUs of the VM were resumed.
+    Resumed,
+    /// A snapshot of the VM was taken.
+    Snapshotted,
+    /// The VM was asked to stop.
+    StopRequested,
+    /// The VMM detected that a vCPU stalled, and the watchdog rebooted the VM.
+    WatchdogReboot,
+    /// The VMM exited.
+    Exited {
+        /// Exit code of the VMM, if it exited normally.
+        exit_code: Option<i32>,
+        /// Signal which terminated the VMM, if any.
+        signal: Option<i32>,
+        /// Why the VM died, as reported to the callbacks of the VM.
+        death_reason: String,
+        /// The reason for the failure which the VM reported, if any.
+        failure_reason: Option<String>,
+        /// Peak RSS of the guest, in KiB.
+        peak_rss_vm_kib: Option<i64>,
+        /// Peak RSS of the VMM, in KiB.
+        peak_rss_vmm_kib: Option<i64>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    /// Time of the event, in milliseconds since the Unix epoch.
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: VmEvent,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Events {
+    events: VecDeque<Entry>,
+    /// Number of the oldest events which were dropped to stay within `MAX_EVENTS`.
+    dropped_events: usize,
+}
+
+/// The events in the lifetime of a VM, oldest first.
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: Mutex<Events>,
+}
+
+impl EventLog {
+    /// Appends an event to the log, timestamped with the current time.
+    pub fn record(&self, event: VmEvent) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis().try_into().unwrap_or(u64::MAX));
+        let mut events = self.events.lock().unwrap();
+        if events.events.len() == MAX_EVENTS {
+            events.events.pop_front();
+            events.dropped_events += 1;
+        }
+        events.events.push_back(Entry { timestamp_ms, event });
+    }
+
+    /// Returns the log as a JSON object, with the events in an `events` array.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(&*self.events.lock().unwrap())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_event_log_json() -> Result<()> {
+        let log = EventLog::default();
+        log.record(VmEvent::Started { pid: 42 });
+        log.record(VmEvent::Suspended);
+        log.record(VmEvent::Exited {
+            exit_code: None,
+            signal: Some(9),
+            death_reason: "KILLED".to_owned(),
+            failure_reason: None,
+            peak_rss_vm_kib: Some(1024),
+            peak_rss_vmm_kib: Some(2048),
+        });
+
+        let json: Value = serde_json::from_str(&log.to_json()?)?;
+        let events = json["events"].as_array().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "started");
+        assert_eq!(events[0]["pid"], 42);
+        assert!(events[0]["timestamp_ms"].is_u64());
+        assert_eq!(events[1]["event"], "suspended");
+        assert_eq!(events[2]["event"], "exited");
+        assert_eq!(events[2]["signal"], 9);
+        assert_eq!(events[2]["exit_code"], Value::Null);
+        assert_eq!(json["dropped_events"], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_log_drops_oldest_events() -> Result<()> {
+        let log = EventLog::default();
+        for num_bytes in 0..(MAX_EVENTS as u64 + 2) {
+            log.record(VmEvent::BalloonAdjusted { num_bytes });
+        }
+
+        let json: Value = serde_json::from_str(&log.to_json()?)?;
+        let events = json["events"].as_array().unwrap();
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0]["num_bytes"], 2);
+        assert_eq!(json["dropped_events"], 2);
+        Ok(())
+    }
+}
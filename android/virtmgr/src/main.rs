@@ -20,8 +20,11 @@ mod composite;
 mod crosvm;
 mod debug_config;
 mod dt_overlay;
+mod event_log;
 mod payload;
 mod selinux;
+mod snapshot;
+mod vmm;
 
 use crate::aidl::{GLOBAL_SERVICE, VirtualizationService};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::BnVirtualizationService;
@@ -0,0 +1,348 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshots of running VMs, from which new VMs with the same config can be restored.
+//!
+//! A snapshot is a ZIP archive holding a description of the VM config, and the files in which the
+//! VMM saved the state of the VM.
+
+use crate::crosvm::CrosvmConfig;
+use anyhow::{bail, ensure, Context, Result};
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Seek, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Version of the snapshot format.
+const SNAPSHOT_VERSION: u32 = 2;
+/// Name of the archive entry describing the snapshot.
+const MANIFEST_ENTRY: &str = "manifest.json";
+/// Directory of the archive holding the state saved by the VMM.
+const VMM_STATE_DIR: &str = "vmm";
+/// Size of the chunks in which images are read to compute their digests.
+const DIGEST_CHUNK_SIZE: usize = 1 << 20;
+
+/// The parts of a VM config which must be the same for a VM restored from a snapshot as for the VM
+/// the snapshot was taken of.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SnapshotConfig {
+    protected: bool,
+    memory_mib: u32,
+    cpus: Option<u32>,
+    host_cpu_topology: bool,
+    params: Option<String>,
+    balloon: bool,
+    usb_controller: bool,
+    images: Vec<ImageInfo>,
+}
+
+/// What an image is used for by the VM.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ImageRole {
+    Bootloader,
+    Kernel,
+    Initrd,
+    Disk,
+}
+
+/// Identifies the contents of an image of a VM, e.g. its kernel or one of its disks.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct ImageInfo {
+    role: ImageRole,
+    writable: bool,
+    size: u64,
+    /// SHA-256 digest of the contents of the image, in hex.
+    sha256: String,
+}
+
+#[derive(Debug)]
+struct Image {
+    role: ImageRole,
+    writable: bool,
+    file: File,
+}
+
+impl Image {
+    fn info(&self) -> Result<ImageInfo> {
+        let size = self.file.metadata()?.len();
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; DIGEST_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(buf.len() as u64) as usize;
+            // Reading at an offset leaves the file position, which is shared with the VMM, as is.
+            self.file.read_exact_at(&mut buf[..len], offset)?;
+            hasher.update(&buf[..len]);
+            offset += len as u64;
+        }
+        Ok(ImageInfo {
+            role: self.role,
+            writable: self.writable,
+            size,
+            sha256: hex::encode(hasher.finish()),
+        })
+    }
+}
+
+/// The config and images of a VM, from which the [`SnapshotConfig`] of the VM is made. Its images
+/// are only read when it is, as they may be large and writable disks change while the VM runs.
+#[derive(Debug)]
+pub struct SnapshotSource {
+    config: SnapshotConfig,
+    images: Vec<Image>,
+}
+
+impl SnapshotSource {
+    /// Keeps the parts of `config` which are part of a snapshot, along with duplicates of the file
+    /// descriptors of its images.
+    pub fn new(config: &CrosvmConfig) -> io::Result<Self> {
+        let mut images = Vec::new();
+        for (role, file) in [
+            (ImageRole::Bootloader, &config.bootloader),
+            (ImageRole::Kernel, &config.kernel),
+            (ImageRole::Initrd, &config.initrd),
+        ] {
+            if let Some(file) = file {
+                images.push(Image { role, writable: false, file: file.try_clone()? });
+            }
+        }
+        for disk in &config.disks {
+            let file = disk.image.try_clone()?;
+            images.push(Image { role: ImageRole::Disk, writable: disk.writable, file });
+        }
+        let config = SnapshotConfig {
+            protected: config.protected,
+            memory_mib: config.memory_mib.get(),
+            cpus: config.cpus.map(|cpus| cpus.get()),
+            host_cpu_topology: config.host_cpu_topology,
+            params: config.params.clone(),
+            balloon: !config.no_balloon,
+            usb_controller: config.usb_config.controller,
+            images: Vec::new(),
+        };
+        Ok(SnapshotSource { config, images })
+    }
+
+    /// Returns the config of the VM, identifying its images by their current size and digest.
+    pub fn config(&self) -> Result<SnapshotConfig> {
+        let images = self.images.iter().map(Image::info).collect::<Result<_>>()?;
+        Ok(SnapshotConfig { images, ..self.config.clone() })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Manifest {
+    version: u32,
+    config: SnapshotConfig,
+}
+
+/// Writes a snapshot of a VM with the given config to `output`, from the state which the VMM saved
+/// in the `vmm_state` directory.
+pub fn write_snapshot(output: File, config: &SnapshotConfig, vmm_state: &Path) -> Result<()> {
+    // The state includes the memory of the VM, which is too large to compress on the fly.
+    let options =
+        FileOptions::default().compression_method(CompressionMethod::Stored).large_file(true);
+    let mut writer = ZipWriter::new(output);
+
+    let manifest = Manifest { version: SNAPSHOT_VERSION, config: config.clone() };
+    writer.start_file(MANIFEST_ENTRY, options)?;
+    serde_json::to_writer(&mut writer, &manifest)?;
+
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(vmm_state.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let name = Path::new(VMM_STATE_DIR).join(&path);
+            let name = name.to_str().with_context(|| format!("Invalid file name {path:?}"))?;
+            if entry.file_type()?.is_dir() {
+                writer.add_directory(name, options)?;
+                dirs.push(path);
+            } else {
+                writer.start_file(name, options)?;
+                io::copy(&mut File::open(entry.path())?, &mut writer)?;
+            }
+        }
+    }
+
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+/// Extracts the state saved by the VMM from the snapshot in `input` into the `vmm_state`
+/// directory, after checking that the snapshot was taken of a VM with the given config.
+pub fn extract_snapshot(input: File, config: &SnapshotConfig, vmm_state: &Path) -> Result<()> {
+    ensure!(!config.protected, "Protected VMs can't be restored from a snapshot");
+    let mut archive = ZipArchive::new(input).context("Invalid snapshot")?;
+    check_manifest(&mut archive, config)?;
+
+    fs::create_dir(vmm_state)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let Some(name) = file.enclosed_name().map(Path::to_owned) else {
+            bail!("Invalid file name {} in snapshot", file.name());
+        };
+        let Ok(path) = name.strip_prefix(VMM_STATE_DIR) else {
+            continue;
+        };
+        let path = vmm_state.join(path);
+        if file.is_dir() {
+            fs::create_dir_all(path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            io::copy(&mut file, &mut File::create(path)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_manifest<R: io::Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    config: &SnapshotConfig,
+) -> Result<()> {
+    let manifest: Manifest = serde_json::from_reader(
+        archive.by_name(MANIFEST_ENTRY).context("Snapshot has no manifest")?,
+    )
+    .context("Invalid snapshot manifest")?;
+    ensure!(
+        manifest.version == SNAPSHOT_VERSION,
+        "Unsupported snapshot version {}",
+        manifest.version
+    );
+    ensure!(
+        manifest.config.images == config.images,
+        "Snapshot was taken of a VM with different images: {:?}",
+        manifest.config.images
+    );
+    ensure!(
+        manifest.config == *config,
+        "Snapshot was taken of a VM with a different config: {:?}",
+        manifest.config
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> SnapshotConfig {
+        SnapshotConfig {
+            protected: false,
+            memory_mib: 256,
+            cpus: Some(1),
+            host_cpu_topology: false,
+            params: None,
+            balloon: true,
+            usb_controller: false,
+            images: vec![
+                image_info(ImageRole::Kernel, false, "kernel"),
+                image_info(ImageRole::Disk, false, "rootfs"),
+                image_info(ImageRole::Disk, true, "data"),
+            ],
+        }
+    }
+
+    fn image_info(role: ImageRole, writable: bool, contents: &str) -> ImageInfo {
+        let mut hasher = Sha256::new();
+        hasher.update(contents.as_bytes());
+        let sha256 = hex::encode(hasher.finish());
+        ImageInfo { role, writable, size: contents.len() as u64, sha256 }
+    }
+
+    fn write_test_snapshot(config: &SnapshotConfig) -> Result<File> {
+        let vmm_state = tempfile::tempdir()?;
+        fs::write(vmm_state.path().join("memory"), b"memory")?;
+        fs::create_dir(vmm_state.path().join("devices"))?;
+        fs::write(vmm_state.path().join("devices").join("block"), b"block")?;
+
+        let mut snapshot = tempfile::tempfile()?;
+        write_snapshot(snapshot.try_clone()?, config, vmm_state.path())?;
+        snapshot.rewind()?;
+        Ok(snapshot)
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() -> Result<()> {
+        let snapshot = write_test_snapshot(&test_config())?;
+        let dir = tempfile::tempdir()?;
+        let vmm_state = dir.path().join("restore");
+
+        extract_snapshot(snapshot, &test_config(), &vmm_state)?;
+        assert_eq!(fs::read(vmm_state.join("memory"))?, b"memory");
+        assert_eq!(fs::read(vmm_state.join("devices").join("block"))?, b"block");
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_with_different_config() -> Result<()> {
+        let snapshot = write_test_snapshot(&test_config())?;
+        let dir = tempfile::tempdir()?;
+        let config = SnapshotConfig { memory_mib: 512, ..test_config() };
+
+        assert!(extract_snapshot(snapshot, &config, &dir.path().join("restore")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_with_different_image() -> Result<()> {
+        let snapshot = write_test_snapshot(&test_config())?;
+        let dir = tempfile::tempdir()?;
+        let mut config = test_config();
+        config.images[2] = image_info(ImageRole::Disk, true, "changed");
+
+        let err = extract_snapshot(snapshot, &config, &dir.path().join("restore")).unwrap_err();
+        assert!(err.to_string().contains("different images"), "{err:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_info() -> Result<()> {
+        let mut file = tempfile::tempfile()?;
+        // Span several chunks, the last of which is partial.
+        let contents = "0123456789".repeat(DIGEST_CHUNK_SIZE / 4);
+        file.write_all(contents.as_bytes())?;
+        let image = Image { role: ImageRole::Disk, writable: true, file };
+
+        assert_eq!(image.info()?, image_info(ImageRole::Disk, true, &contents));
+        // The position of the file, which the VMM uses, isn't moved.
+        assert_eq!((&image.file).stream_position()?, contents.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_protected_vm() -> Result<()> {
+        let config = SnapshotConfig { protected: true, ..test_config() };
+        let snapshot = write_test_snapshot(&config)?;
+        let dir = tempfile::tempdir()?;
+
+        assert!(extract_snapshot(snapshot, &config, &dir.path().join("restore")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_invalid_snapshot() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        assert!(extract_snapshot(tempfile::tempfile()?, &test_config(), dir.path()).is_err());
+        Ok(())
+    }
+}
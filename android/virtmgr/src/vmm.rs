@@ -0,0 +1,249 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Abstraction over the virtual machine monitor (VMM) which runs the VMs.
+
+use crate::crosvm::CrosvmConfig;
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
+use anyhow::Result;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+/// A VMM which can launch VMs.
+pub trait VmmBackend: fmt::Debug + Send + Sync {
+    /// Checks that the config can be used to launch a VM with this VMM.
+    fn validate_config(&self, config: &CrosvmConfig) -> Result<()>;
+
+    /// Launches a VM with the given config. The VMM may keep files such as control sockets in
+    /// `temporary_directory`, and writes the reason for the VM failing, if any, to
+    /// `failure_pipe_write`.
+    fn launch(
+        &self,
+        config: CrosvmConfig,
+        temporary_directory: &Path,
+        failure_pipe_write: File,
+    ) -> Result<Arc<dyn VmmProcess>>;
+
+    /// Returns why the VM died given the exit status of the VMM, when the VM didn't report a
+    /// failure reason itself.
+    fn exit_death_reason(&self, status: &ExitStatus) -> DeathReason;
+}
+
+/// A VMM process running a single VM.
+pub trait VmmProcess: fmt::Debug + Send + Sync {
+    /// Returns the PID of the VMM process.
+    fn id(&self) -> u32;
+
+    /// Waits for the VMM to exit.
+    fn wait(&self) -> io::Result<ExitStatus>;
+
+    /// Returns the exit status of the VMM if it has exited, without blocking.
+    fn try_wait(&self) -> io::Result<Option<ExitStatus>>;
+
+    /// Stops the VM. The VMM exits asynchronously; use `wait` to find out when it has.
+    fn stop(&self) -> Result<()>;
+
+    /// Returns the current size of the memory balloon, in bytes.
+    fn get_memory_balloon(&self) -> Result<u64>;
+
+    /// Inflates or deflates the memory balloon to the given size, in bytes.
+    fn set_memory_balloon(&self, num_bytes: u64) -> Result<()>;
+
+    /// Suspends the vCPUs of the VM.
+    fn suspend(&self) -> Result<()>;
+
+    /// Resumes the vCPUs of a suspended VM.
+    fn resume(&self) -> Result<()>;
+
+    /// Saves the state of the VM in the `vmm_state` directory, which the VMM creates. A VM can
+    /// then be restored from it by launching it with `CrosvmConfig::restore` set to the directory.
+    fn snapshot(&self, vmm_state: &Path) -> Result<()>;
+}
+
+/// A VMM which runs nothing, so that the lifecycle of VMs can be tested without a hypervisor.
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use anyhow::bail;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::{Condvar, Mutex, MutexGuard};
+
+    /// Backend launching [`FakeProcess`]es.
+    #[derive(Debug, Default)]
+    pub struct FakeBackend {
+        /// The processes launched so far, oldest first.
+        processes: Mutex<Vec<Arc<FakeProcess>>>,
+        /// Whether launching a VM should fail.
+        fail_launch: bool,
+    }
+
+    impl FakeBackend {
+        /// Returns a backend which fails to launch any VM.
+        pub fn failing() -> Self {
+            FakeBackend { fail_launch: true, ..Default::default() }
+        }
+
+        /// Returns the last process launched by the backend.
+        pub fn last_process(&self) -> Option<Arc<FakeProcess>> {
+            self.processes.lock().unwrap().last().cloned()
+        }
+    }
+
+    impl VmmBackend for FakeBackend {
+        fn validate_config(&self, _config: &CrosvmConfig) -> Result<()> {
+            Ok(())
+        }
+
+        fn launch(
+            &self,
+            _config: CrosvmConfig,
+            _temporary_directory: &Path,
+            failure_pipe_write: File,
+        ) -> Result<Arc<dyn VmmProcess>> {
+            if self.fail_launch {
+                bail!("Fake VMM failed to launch");
+            }
+            let process = Arc::new(FakeProcess::new(failure_pipe_write));
+            self.processes.lock().unwrap().push(process.clone());
+            Ok(process)
+        }
+
+        fn exit_death_reason(&self, status: &ExitStatus) -> DeathReason {
+            match status.code() {
+                None => DeathReason::KILLED,
+                Some(0) => DeathReason::SHUTDOWN,
+                Some(_) => DeathReason::CRASH,
+            }
+        }
+    }
+
+    /// A VM which only keeps track of the requests made to it, and exits when told to.
+    #[derive(Debug)]
+    pub struct FakeProcess {
+        state: Mutex<FakeProcessState>,
+        exited: Condvar,
+    }
+
+    #[derive(Debug)]
+    struct FakeProcessState {
+        failure_pipe_write: Option<File>,
+        status: Option<ExitStatus>,
+        balloon: u64,
+        suspended: bool,
+    }
+
+    impl FakeProcess {
+        fn new(failure_pipe_write: File) -> Self {
+            let state = FakeProcessState {
+                failure_pipe_write: Some(failure_pipe_write),
+                status: None,
+                balloon: 0,
+                suspended: false,
+            };
+            FakeProcess { state: Mutex::new(state), exited: Condvar::new() }
+        }
+
+        /// Makes the VMM exit with the given status code, after the VM reports `failure_reason`
+        /// if it is given.
+        pub fn exit(&self, code: i32, failure_reason: Option<&str>) {
+            let mut state = self.state.lock().unwrap();
+            if let (Some(pipe), Some(reason)) = (&mut state.failure_pipe_write, failure_reason) {
+                pipe.write_all(reason.as_bytes()).unwrap();
+            }
+            Self::finish(&mut state, ExitStatus::from_raw(code << 8));
+            self.exited.notify_all();
+        }
+
+        /// Returns whether the vCPUs of the VM are suspended.
+        pub fn is_suspended(&self) -> bool {
+            self.state.lock().unwrap().suspended
+        }
+
+        /// Returns the current size of the memory balloon, in bytes.
+        pub fn balloon(&self) -> u64 {
+            self.state.lock().unwrap().balloon
+        }
+
+        fn finish(state: &mut FakeProcessState, status: ExitStatus) {
+            if state.status.is_none() {
+                state.status = Some(status);
+                // Closing the pipe lets the reader know that the VM won't report anything else.
+                state.failure_pipe_write = None;
+            }
+        }
+
+        fn check_running(&self) -> Result<MutexGuard<'_, FakeProcessState>> {
+            let state = self.state.lock().unwrap();
+            if state.status.is_some() {
+                bail!("Fake VMM has exited");
+            }
+            Ok(state)
+        }
+    }
+
+    impl VmmProcess for FakeProcess {
+        fn id(&self) -> u32 {
+            // Metrics are collected from /proc, so point them at a process which exists.
+            std::process::id()
+        }
+
+        fn wait(&self) -> io::Result<ExitStatus> {
+            let state =
+                self.exited.wait_while(self.state.lock().unwrap(), |s| s.status.is_none()).unwrap();
+            Ok(state.status.unwrap())
+        }
+
+        fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+            Ok(self.state.lock().unwrap().status)
+        }
+
+        fn stop(&self) -> Result<()> {
+            Self::finish(&mut self.state.lock().unwrap(), ExitStatus::from_raw(libc::SIGKILL));
+            self.exited.notify_all();
+            Ok(())
+        }
+
+        fn get_memory_balloon(&self) -> Result<u64> {
+            Ok(self.check_running()?.balloon)
+        }
+
+        fn set_memory_balloon(&self, num_bytes: u64) -> Result<()> {
+            self.check_running()?.balloon = num_bytes;
+            Ok(())
+        }
+
+        fn suspend(&self) -> Result<()> {
+            self.check_running()?.suspended = true;
+            Ok(())
+        }
+
+        fn resume(&self) -> Result<()> {
+            self.check_running()?.suspended = false;
+            Ok(())
+        }
+
+        fn snapshot(&self, vmm_state: &Path) -> Result<()> {
+            let state = self.check_running()?;
+            fs::create_dir(vmm_state)?;
+            fs::write(vmm_state.join("state"), format!("{:?}", *state))?;
+            Ok(())
+        }
+    }
+}
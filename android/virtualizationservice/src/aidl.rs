@@ -59,7 +59,8 @@ use virtualizationmaintenance::{
     IVirtualizationReconciliationCallback::IVirtualizationReconciliationCallback,
 };
 use virtualizationservice::{
-    AssignableDevice::AssignableDevice, VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    AssignableDevice::AssignableDevice, IVirtualMachine::IVirtualMachine,
+    VirtualMachineDebugInfo::VirtualMachineDebugInfo,
 };
 use virtualizationservice_internal::{
     AtomVmBooted::AtomVmBooted,
@@ -69,6 +70,7 @@ use virtualizationservice_internal::{
     IGlobalVmContext::{BnGlobalVmContext, IGlobalVmContext},
     IVfioHandler::VfioDev::VfioDev,
     IVfioHandler::{BpVfioHandler, IVfioHandler},
+    IVirtualMachineProvider::IVirtualMachineProvider,
     IVirtualizationServiceInternal::IVirtualizationServiceInternal,
     IVmnic::{BpVmnic, IVmnic},
 };
@@ -196,6 +198,27 @@ impl VirtualizationServiceInternal {
 
         service
     }
+
+    /// Gets a handle to the running VM with the given CID from the virtmgr which owns it, after
+    /// checking that the caller may debug VMs. The handle must not be passed on to the caller, as
+    /// it can't be sent over the RPC session between a client and its own virtmgr.
+    fn get_vm_for_debug(&self, cid: i32) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        check_debug_access()?;
+
+        let provider = {
+            let state = &*self.state.lock().unwrap();
+            let instance = state.held_contexts.get(&(cid as Cid)).and_then(Weak::upgrade);
+            instance.and_then(|vm| {
+                let vm = vm.lock().unwrap();
+                vm.vm_provider.clone()
+            })
+        };
+        // Don't hold any lock while calling into virtmgr.
+        provider
+            .with_context(|| format!("No VM with CID {cid}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?
+            .getVirtualMachine()
+    }
 }
 
 impl Interface for VirtualizationServiceInternal {}
@@ -290,16 +313,39 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
                 let vm = vm.lock().unwrap();
                 VirtualMachineDebugInfo {
                     cid: vm.cid as i32,
+                    name: vm.name.clone(),
                     temporaryDirectory: vm.get_temp_dir().to_string_lossy().to_string(),
                     requesterUid: vm.requester_uid as i32,
                     requesterPid: vm.requester_debug_pid,
                     hostConsoleName: vm.host_console_name.clone(),
+                    guestMemoryMib: vm.guest_memory_mib,
+                    onlineVcpus: vm.online_vcpus,
                 }
             })
             .collect();
         Ok(cids)
     }
 
+    fn debugStopVm(&self, cid: i32) -> binder::Result<()> {
+        self.get_vm_for_debug(cid)?.stop()
+    }
+
+    fn debugSuspendVm(&self, cid: i32) -> binder::Result<()> {
+        self.get_vm_for_debug(cid)?.suspend()
+    }
+
+    fn debugResumeVm(&self, cid: i32) -> binder::Result<()> {
+        self.get_vm_for_debug(cid)?.resume()
+    }
+
+    fn debugGetMemoryBalloon(&self, cid: i32) -> binder::Result<i64> {
+        self.get_vm_for_debug(cid)?.getMemoryBalloon()
+    }
+
+    fn debugSetMemoryBalloon(&self, cid: i32, num_bytes: i64) -> binder::Result<()> {
+        self.get_vm_for_debug(cid)?.setMemoryBalloon(num_bytes)
+    }
+
     fn enableTestAttestation(&self) -> binder::Result<()> {
         check_manage_access()?;
         check_use_custom_virtual_machine()?;
@@ -666,6 +712,14 @@ struct GlobalVmInstance {
     requester_debug_pid: pid_t,
     /// Name of the host console.
     host_console_name: Option<String>,
+    /// Memory which the guest can currently use, in MiB, as last reported by virtmgr.
+    guest_memory_mib: i32,
+    /// Number of vCPUs online, as last reported by virtmgr.
+    online_vcpus: i32,
+    /// Name of the VM, as registered by virtmgr.
+    name: Option<String>,
+    /// Gives access to the VM for the `debug*Vm` methods, as registered by virtmgr.
+    vm_provider: Option<Strong<dyn IVirtualMachineProvider>>,
 }
 
 impl GlobalVmInstance {
@@ -867,6 +921,24 @@ impl IGlobalVmContext for GlobalVmContext {
         self.instance.lock().unwrap().host_console_name = Some(pathname.to_string());
         Ok(())
     }
+
+    fn setGuestResources(&self, memory_mib: i32, online_vcpus: i32) -> binder::Result<()> {
+        let mut instance = self.instance.lock().unwrap();
+        instance.guest_memory_mib = memory_mib;
+        instance.online_vcpus = online_vcpus;
+        Ok(())
+    }
+
+    fn registerVirtualMachine(
+        &self,
+        name: &str,
+        provider: &Strong<dyn IVirtualMachineProvider>,
+    ) -> binder::Result<()> {
+        let mut instance = self.instance.lock().unwrap();
+        instance.name = Some(name.to_owned());
+        instance.vm_provider = Some(provider.clone());
+        Ok(())
+    }
 }
 
 fn handle_stream_connection_tombstoned() -> Result<()> {
@@ -15,9 +15,13 @@
 //! Command to create or update an idsig for APK
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::IVirtualizationService;
+use apkverify::{HashAlgorithm, V4Signature, V4Signer};
 use binder::ParcelFileDescriptor;
-use anyhow::{Context, Error};
-use std::fs::{File, OpenOptions};
+use anyhow::{anyhow, bail, Context, Error};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use rustutils::system_properties;
+use std::fs::{self, File, OpenOptions};
 use std::path::Path;
 
 /// Creates or update the idsig file by digesting the input APK file.
@@ -27,13 +31,7 @@ pub fn command_create_idsig(
     idsig: &Path,
 ) -> Result<(), Error> {
     let apk_file = File::open(apk).with_context(|| format!("Failed to open {:?}", apk))?;
-    let idsig_file = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .read(true)
-        .write(true)
-        .open(idsig)
-        .with_context(|| format!("Failed to create/open {:?}", idsig))?;
+    let idsig_file = open_idsig(idsig)?;
     service
         .createOrUpdateIdsigFile(
             &ParcelFileDescriptor::new(apk_file),
@@ -42,3 +40,55 @@ pub fn command_create_idsig(
         .with_context(|| format!("Failed to create/update idsig for {:?}", apk))?;
     Ok(())
 }
+
+/// Creates the idsig file by digesting the input APK file, and signs it with the given private
+/// key and certificate. Unlike `command_create_idsig`, this is done locally so that the private
+/// key doesn't leave the process.
+pub fn command_create_signed_idsig(
+    apk: &Path,
+    idsig: &Path,
+    key: &Path,
+    cert: &Path,
+) -> Result<(), Error> {
+    let key = fs::read(key).with_context(|| format!("Failed to read {:?}", key))?;
+    let private_key = PKey::private_key_from_pem(&key)
+        .or_else(|_| PKey::private_key_from_pkcs8(&key))
+        .context("Failed to parse the private key")?;
+    let cert = fs::read(cert).with_context(|| format!("Failed to read {:?}", cert))?;
+    let certificate = X509::from_pem(&cert)
+        .or_else(|_| X509::from_der(&cert))
+        .context("Failed to parse the certificate")?;
+    let signer = V4Signer { private_key, certificate, additional_data: vec![] };
+
+    let mut apk_file = File::open(apk).with_context(|| format!("Failed to open {:?}", apk))?;
+    if !apk_file.metadata()?.is_file() {
+        bail!("{:?} is not a regular file", apk);
+    }
+    let mut sig = V4Signature::create_signed(
+        &mut apk_file,
+        get_current_sdk()?,
+        4096,
+        &[],
+        HashAlgorithm::SHA256,
+        &signer,
+    )
+    .with_context(|| format!("Failed to create signed idsig for {:?}", apk))?;
+    sig.write_into(&mut open_idsig(idsig)?).context("Failed to write idsig")?;
+    Ok(())
+}
+
+fn open_idsig(idsig: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(idsig)
+        .with_context(|| format!("Failed to create/open {:?}", idsig))
+}
+
+fn get_current_sdk() -> Result<u32, Error> {
+    let current_sdk = system_properties::read("ro.build.version.sdk")?;
+    let current_sdk = current_sdk.ok_or_else(|| anyhow!("SDK version missing"))?;
+    current_sdk.parse().context("Malformed SDK version")
+}
@@ -21,13 +21,14 @@ mod run;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     CpuTopology::CpuTopology, IVirtualizationService::IVirtualizationService,
     PartitionType::PartitionType, VirtualMachineAppConfig::DebugLevel::DebugLevel,
+    VirtualMachineDebugInfo::VirtualMachineDebugInfo,
 };
 #[cfg(not(llpvm_changes))]
 use anyhow::anyhow;
 use anyhow::{bail, Context, Error};
 use binder::{ProcessState, Strong};
 use clap::{Args, Parser};
-use create_idsig::command_create_idsig;
+use create_idsig::{command_create_idsig, command_create_signed_idsig};
 use create_partition::command_create_partition;
 use run::{command_run, command_run_app, command_run_microdroid};
 use serde::Serialize;
@@ -72,6 +73,16 @@ pub struct CommonConfig {
     /// Boost uclamp to stablise results for benchmarks.
     #[arg(short, long)]
     boost_uclamp: bool,
+
+    /// Path to a snapshot to restore the VM from, instead of booting it. The VM must be configured
+    /// the same way as the VM which the snapshot was taken of, with images of the same contents.
+    #[arg(long)]
+    restore: Option<PathBuf>,
+
+    /// Path at which to save a snapshot of the VM when this command is interrupted or terminated
+    /// (SIGINT, SIGTERM or SIGHUP), before stopping the VM. It can be restored with --restore.
+    #[arg(long)]
+    snapshot_on_exit: Option<PathBuf>,
 }
 
 impl CommonConfig {
@@ -344,12 +355,46 @@ enum Opt {
 
         /// Path to idsig of the APK
         path: PathBuf,
+
+        /// Path to the private key (PEM or PKCS#8 DER) to sign the idsig with. The idsig is then
+        /// created locally instead of by the virtualization service.
+        #[arg(long, requires = "cert")]
+        key: Option<PathBuf>,
+
+        /// Path to the certificate (PEM or DER) of the private key given with --key
+        #[arg(long, requires = "key")]
+        cert: Option<PathBuf>,
     },
     /// Connect to the serial console of a VM
     Console {
         /// CID of the VM
         cid: Option<i32>,
     },
+    /// Stop (or kill) a running VM immediately. Both are the same, as not all guests can be asked
+    /// to shut down, so shut the guest down from inside for a clean shutdown
+    #[command(visible_alias = "kill")]
+    Stop {
+        /// CID or name of the VM
+        vm: String,
+    },
+    /// Suspend the vCPUs of a running VM
+    Suspend {
+        /// CID or name of the VM
+        vm: String,
+    },
+    /// Resume a suspended VM
+    Resume {
+        /// CID or name of the VM
+        vm: String,
+    },
+    /// Get or set the size of the memory balloon of a running VM
+    Balloon {
+        /// CID or name of the VM
+        vm: String,
+        /// Size to inflate or deflate the balloon to, in bytes. If unspecified, prints the current
+        /// size of the balloon.
+        num_bytes: Option<u64>,
+    },
 }
 
 fn parse_debug_level(s: &str) -> Result<DebugLevel, String> {
@@ -409,10 +454,17 @@ fn main() -> Result<(), Error> {
         Opt::CreatePartition { path, size, partition_type } => {
             command_create_partition(get_service()?.as_ref(), &path, size, partition_type)
         }
-        Opt::CreateIdsig { apk, path } => {
+        Opt::CreateIdsig { apk, path, key: Some(key), cert: Some(cert) } => {
+            command_create_signed_idsig(&apk, &path, &key, &cert)
+        }
+        Opt::CreateIdsig { apk, path, .. } => {
             command_create_idsig(get_service()?.as_ref(), &apk, &path)
         }
         Opt::Console { cid } => command_console(cid),
+        Opt::Stop { vm } => command_stop(get_service()?.as_ref(), &vm),
+        Opt::Suspend { vm } => command_suspend(get_service()?.as_ref(), &vm),
+        Opt::Resume { vm } => command_resume(get_service()?.as_ref(), &vm),
+        Opt::Balloon { vm, num_bytes } => command_balloon(get_service()?.as_ref(), &vm, num_bytes),
     }
 }
 
@@ -492,6 +544,57 @@ fn command_console(cid: Option<i32>) -> Result<(), Error> {
     Err(Command::new("microcom").arg(host_console_name).exec().into())
 }
 
+/// Get the CID of the running VM with the given CID or name.
+fn get_cid(service: &dyn IVirtualizationService, vm: &str) -> Result<i32, Error> {
+    match vm.parse::<i32>() {
+        Ok(cid) => Ok(cid),
+        Err(_) => find_cid(&service.debugListVms().context("Failed to get list of VMs")?, vm),
+    }
+}
+
+/// Find the CID of the only VM in `vms` named `name`.
+fn find_cid(vms: &[VirtualMachineDebugInfo], name: &str) -> Result<i32, Error> {
+    let mut matching = vms.iter().filter(|vm_info| vm_info.name.as_deref() == Some(name));
+    let vm_info = matching.next().with_context(|| format!("No VM named {}", name))?;
+    if matching.next().is_some() {
+        bail!("More than one VM is named {}, use its CID instead", name);
+    }
+    Ok(vm_info.cid)
+}
+
+/// Stop a running VM.
+fn command_stop(service: &dyn IVirtualizationService, vm: &str) -> Result<(), Error> {
+    service.debugStopVm(get_cid(service, vm)?).context("Failed to stop VM")
+}
+
+/// Suspend a running VM.
+fn command_suspend(service: &dyn IVirtualizationService, vm: &str) -> Result<(), Error> {
+    service.debugSuspendVm(get_cid(service, vm)?).context("Failed to suspend VM")
+}
+
+/// Resume a suspended VM.
+fn command_resume(service: &dyn IVirtualizationService, vm: &str) -> Result<(), Error> {
+    service.debugResumeVm(get_cid(service, vm)?).context("Failed to resume VM")
+}
+
+/// Print the size of the memory balloon of a running VM, or change it to `num_bytes`.
+fn command_balloon(
+    service: &dyn IVirtualizationService,
+    vm: &str,
+    num_bytes: Option<u64>,
+) -> Result<(), Error> {
+    let cid = get_cid(service, vm)?;
+    if let Some(num_bytes) = num_bytes {
+        let num_bytes = i64::try_from(num_bytes).context("Balloon size is too large")?;
+        service.debugSetMemoryBalloon(cid, num_bytes).context("Failed to set memory balloon")?;
+    } else {
+        let num_bytes =
+            service.debugGetMemoryBalloon(cid).context("Failed to get memory balloon")?;
+        println!("Memory balloon: {} bytes", num_bytes);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -502,4 +605,35 @@ mod tests {
         // Check that the command parsing has been configured in a valid way.
         Opt::command().debug_assert();
     }
+
+    #[test]
+    fn kill_is_stop() {
+        let opt = Opt::try_parse_from(["vm", "kill", "my_vm"]).unwrap();
+        assert!(matches!(opt, Opt::Stop { vm } if vm == "my_vm"));
+    }
+
+    fn vm_info(cid: i32, name: Option<&str>) -> VirtualMachineDebugInfo {
+        VirtualMachineDebugInfo { cid, name: name.map(str::to_owned), ..Default::default() }
+    }
+
+    #[test]
+    fn find_cid_by_name() {
+        let vms = [vm_info(2048, Some("foo")), vm_info(2049, None), vm_info(2050, Some("bar"))];
+        assert_eq!(find_cid(&vms, "bar").unwrap(), 2050);
+    }
+
+    #[test]
+    fn find_cid_with_no_match() {
+        let vms = [vm_info(2048, Some("foo")), vm_info(2049, None)];
+        let err = find_cid(&vms, "bar").unwrap_err();
+        assert_eq!(err.to_string(), "No VM named bar");
+        assert!(find_cid(&[], "foo").is_err());
+    }
+
+    #[test]
+    fn find_cid_with_ambiguous_name() {
+        let vms = [vm_info(2048, Some("foo")), vm_info(2049, Some("foo"))];
+        let err = find_cid(&vms, "foo").unwrap_err();
+        assert_eq!(err.to_string(), "More than one VM is named foo, use its CID instead");
+    }
 }
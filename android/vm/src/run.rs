@@ -37,8 +37,11 @@ use std::fs::File;
 use std::io;
 use std::io::{Read, Write};
 use std::os::fd::AsFd;
+use std::os::raw::c_int;
 use std::path::{Path, PathBuf};
-use vmclient::{ErrorCode, VmInstance};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use vmclient::{DeathReason, ErrorCode, VmInstance};
 use vmconfig::{get_debug_level, open_parcel_file, VmConfig};
 use zip::ZipArchive;
 
@@ -203,6 +206,8 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        config.common.restore.as_deref(),
+        config.common.snapshot_on_exit.as_deref(),
     )
 }
 
@@ -284,6 +289,8 @@ pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        config.common.restore.as_deref(),
+        config.common.snapshot_on_exit.as_deref(),
     )
 }
 
@@ -306,7 +313,12 @@ fn run(
     console_out_path: Option<&Path>,
     console_in_path: Option<&Path>,
     log_path: Option<&Path>,
+    restore_path: Option<&Path>,
+    snapshot_path: Option<&Path>,
 ) -> Result<(), Error> {
+    if snapshot_path.is_some() {
+        handle_termination_signals()?;
+    }
     let console_out = if let Some(console_out_path) = console_out_path {
         Some(File::create(console_out_path).with_context(|| {
             format!("Failed to open console output file {:?}", console_out_path)
@@ -331,14 +343,22 @@ fn run(
         Some(duplicate_fd(io::stdout())?)
     };
     let callback = Box::new(Callback {});
-    let vm = VmInstance::create(service, config, console_out, console_in, log, Some(callback))
-        .context("Failed to create VM")?;
+    let vm = if let Some(restore_path) = restore_path {
+        let snapshot = File::open(restore_path)
+            .with_context(|| format!("Failed to open snapshot file {:?}", restore_path))?;
+        VmInstance::restore(service, config, snapshot, console_out, console_in, log, Some(callback))
+            .context("Failed to restore VM")?
+    } else {
+        VmInstance::create(service, config, console_out, console_in, log, Some(callback))
+            .context("Failed to create VM")?
+    };
     vm.start().context("Failed to start VM")?;
 
     let debug_level = get_debug_level(config).unwrap_or(DebugLevel::NONE);
 
     println!(
-        "Created {} from {} with CID {}, state is {}.",
+        "{} {} from {} with CID {}, state is {}.",
+        if restore_path.is_some() { "Restored" } else { "Created" },
         if debug_level == DebugLevel::FULL { "debuggable VM" } else { "VM" },
         payload_config,
         vm.cid(),
@@ -347,11 +367,60 @@ fn run(
 
     // Wait until the VM or VirtualizationService dies. If we just returned immediately then the
     // IVirtualMachine Binder object would be dropped and the VM would be killed.
-    let death_reason = vm.wait_for_death();
+    let death_reason = if let Some(snapshot_path) = snapshot_path {
+        wait_for_death_or_snapshot(&vm, snapshot_path)?
+    } else {
+        vm.wait_for_death()
+    };
     println!("VM ended: {:?}", death_reason);
+    // The log is gone if VirtualizationService died, so failing to get it isn't an error.
+    match vm.vm.getEventLog() {
+        Ok(event_log) => println!("VM event log: {}", event_log),
+        Err(e) => eprintln!("Failed to get VM event log: {:?}", e),
+    }
+    Ok(())
+}
+
+/// Set by the signal handler when the command is asked to terminate.
+static TERMINATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_termination(_signal: c_int) {
+    TERMINATION_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGINT, SIGTERM and SIGHUP set `TERMINATION_REQUESTED` instead of terminating the
+/// command, so that the VM can be snapshotted first.
+fn handle_termination_signals() -> Result<(), Error> {
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: The handler only stores to an atomic, which is async-signal-safe.
+        let previous = unsafe { libc::signal(signal, request_termination as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            return Err(io::Error::last_os_error()).context("Failed to install signal handler");
+        }
+    }
     Ok(())
 }
 
+/// Waits until the VM dies, or the command is asked to terminate. In the latter case, writes a
+/// snapshot of the VM to `snapshot_path` and then stops the VM.
+fn wait_for_death_or_snapshot(vm: &VmInstance, snapshot_path: &Path) -> Result<DeathReason, Error> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    loop {
+        if let Some(death_reason) = vm.wait_for_death_with_timeout(POLL_INTERVAL) {
+            return Ok(death_reason);
+        }
+        if TERMINATION_REQUESTED.load(Ordering::SeqCst) {
+            let snapshot = File::create(snapshot_path)
+                .with_context(|| format!("Failed to create snapshot file {:?}", snapshot_path))?;
+            vm.snapshot(snapshot).context("Failed to snapshot VM")?;
+            println!("Saved snapshot of VM to {:?}", snapshot_path);
+            vm.vm.stop().context("Failed to stop VM")?;
+            return Ok(vm.wait_for_death());
+        }
+    }
+}
+
 fn parse_extra_apk_list(apk: &Path, config_path: &str) -> Result<Vec<PathBuf>, Error> {
     let mut archive = ZipArchive::new(File::open(apk)?)?;
     let config_file = archive.by_name(config_path)?;
@@ -23,16 +23,21 @@
 
 #![cfg_attr(test, allow(unused))]
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use apkverify::{HashAlgorithm, V4Signature};
 use clap::{arg, Arg, ArgAction, Command};
-use dm::loopdevice;
+use dm::linear::DmLinearTargetBuilder;
+use dm::loopdevice::{self, LoopConfigOptions};
+use dm::table::DmTableBuilder;
 use dm::util;
 use dm::verity::{DmVerityHashAlgorithm, DmVerityTargetBuilder};
 use itertools::Itertools;
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use std::fmt::Debug;
-use std::fs;
-use std::os::unix::fs::FileTypeExt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 
 #[cfg(not(test))]
@@ -100,21 +105,36 @@ fn enable_verity<P: AsRef<Path> + Debug>(
 ) -> Result<VerityResult> {
     // Attach the apk file to a loop device if the apk file is a regular file. If not (i.e. block
     // device), we only need to get the size and use the block device as it is.
+    let mut padded_device: Option<String> = None;
     let (data_device, apk_size) = if fs::metadata(&apk)?.file_type().is_block_device() {
         (apk.as_ref().to_path_buf(), util::blkgetsize64(apk.as_ref())?)
     } else {
         let apk_size = fs::metadata(&apk)?.len();
-        if apk_size % BLOCK_SIZE != 0 {
-            bail!("The size of {:?} is not multiple of {}.", &apk, BLOCK_SIZE)
-        }
-        (
-            loopdevice::attach(
-                &apk, 0, apk_size, /* direct_io */ true, /* writable */ false,
+        if apk_size & (BLOCK_SIZE - 1) == 0 {
+            (
+                loopdevice::attach(
+                    &apk, 0, apk_size, /* direct_io */ true, /* writable */ false,
+                )
+                .context("Failed to attach APK to a loop device")?,
+                apk_size,
             )
-            .context("Failed to attach APK to a loop device")?,
-            apk_size,
-        )
+        } else {
+            let padded_name = padded_device_name(name);
+            let padded = attach_zero_padded(&apk, apk_size, &padded_name)
+                .context(format!("Failed to zero-pad {:?}", &apk))?;
+            padded_device = Some(padded_name);
+            padded
+        }
     };
+    // If any of the following steps fails, remove the zero-padded device so that it doesn't leak.
+    // The loop devices under it are detached along with it.
+    let padded_device = scopeguard::guard(padded_device, |padded_device| {
+        if let Some(padded_name) = padded_device {
+            if let Err(e) = dm::DeviceMapper::new().and_then(|dm| dm.delete_device(&padded_name)) {
+                eprintln!("Failed to remove {padded_name}: {e:?}");
+            }
+        }
+    });
 
     // Parse the idsig file to locate the merkle tree in it, then attach the file to a loop device
     // with the offset so that the start of the merkle tree becomes the beginning of the loop
@@ -152,15 +172,81 @@ fn enable_verity<P: AsRef<Path> + Debug>(
     let mapper_device =
         dm.create_verity_device(name, &target).context("Failed to create dm-verity device")?;
 
+    // The zero-padded device is now the data device of the dm-verity device.
+    scopeguard::ScopeGuard::into_inner(padded_device);
     Ok(VerityResult { data_device, hash_device, mapper_device })
 }
 
+fn padded_device_name(name: &str) -> String {
+    format!("{}-padded", name)
+}
+
+// Makes a read-only block device out of the `apk` file whose size isn't a multiple of the block
+// size, by padding its last block with zeroes. This is how the last block is hashed into the
+// merkle tree. Returns the path to the device and its size.
+//
+// A loop device can't expose a partial block, so the last block of the APK is copied into a
+// memory-backed file. The device is then made of the two loop devices by a linear mapping. The
+// copy doesn't need to be trusted, as it is verified by dm-verity like the rest of the APK.
+fn attach_zero_padded<P: AsRef<Path> + Debug>(
+    apk: P,
+    apk_size: u64,
+    name: &str,
+) -> Result<(PathBuf, u64)> {
+    let aligned_size = apk_size & !(BLOCK_SIZE - 1);
+
+    // The loop devices are detached as soon as they are closed, i.e. once the mapper device on top
+    // of them is removed, or right away if it fails to be created.
+    let apk_device = if aligned_size > 0 {
+        let options = LoopConfigOptions { direct_io: true, autoclear: true, ..Default::default() };
+        Some(
+            loopdevice::attach_with_options(&apk, 0, aligned_size, &options)
+                .context("Failed to attach APK to a loop device")?,
+        )
+    } else {
+        None
+    };
+
+    let mut last_block = vec![0; BLOCK_SIZE as usize];
+    File::open(&apk)?
+        .read_exact_at(&mut last_block[..(apk_size - aligned_size) as usize], aligned_size)
+        .context("Failed to read the last block")?;
+    let memfd = memfd_create(c"apkdmverity_last_block", MemFdCreateFlag::MFD_CLOEXEC)?;
+    let mut last_block_file = File::from(memfd);
+    last_block_file.write_all(&last_block)?;
+    let options = LoopConfigOptions { autoclear: true, ..Default::default() };
+    let last_block_device = loopdevice::attach_with_options(
+        format!("/proc/self/fd/{}", last_block_file.as_raw_fd()),
+        0,
+        BLOCK_SIZE,
+        &options,
+    )
+    .context("Failed to attach the last block to a loop device")?;
+
+    let apk_target;
+    let mut table = DmTableBuilder::default();
+    if let Some(apk_device) = &apk_device {
+        apk_target =
+            DmLinearTargetBuilder::default().data_device(&apk_device.path, aligned_size).build()?;
+        table.target(&apk_target);
+    }
+    let last_block_target = DmLinearTargetBuilder::default()
+        .data_device(&last_block_device.path, BLOCK_SIZE)
+        .build()?;
+    let table = table.target(&last_block_target).build()?;
+
+    let dm = dm::DeviceMapper::new()?;
+    let device = dm.create_table_device(name, &table, /* writable */ false)?;
+    Ok((device, aligned_size + BLOCK_SIZE))
+}
+
 #[cfg(test)]
 rdroidtest::test_main!();
 
 #[cfg(test)]
 mod tests {
     use crate::*;
+    use dm::verity::DmVerityStatus;
     use rdroidtest::{ignore_if, rdroidtest};
     use std::fs::{File, OpenOptions};
     use std::io::Write;
@@ -215,23 +301,88 @@ mod tests {
     ) {
         let test_dir = tempfile::TempDir::new().unwrap();
         let (apk_path, idsig_path) = prepare_inputs(test_dir.path(), apk, idsig);
+        run_test_with_inputs(&apk_path, &idsig_path, name, roothash, check);
+    }
 
+    // Same as `run_test`, except that the APK isn't padded to a multiple of the block size.
+    fn run_unaligned_test(apk: &[u8], idsig: &[u8], name: &str, check: fn(TestContext)) {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let apk_path = test_dir.path().join("test.apk");
+        let idsig_path = test_dir.path().join("test.apk.idsig");
+        fs::write(&apk_path, apk).unwrap();
+        fs::write(&idsig_path, idsig).unwrap();
+        run_test_with_inputs(&apk_path, &idsig_path, name, None, check);
+    }
+
+    fn run_test_with_inputs(
+        apk_path: &Path,
+        idsig_path: &Path,
+        name: &str,
+        roothash: Option<&[u8]>,
+        check: fn(TestContext),
+    ) {
         // Run the program and register clean-ups.
-        let ret = enable_verity(&apk_path, &idsig_path, name, roothash).unwrap();
+        let ret = enable_verity(apk_path, idsig_path, name, roothash).unwrap();
         let ret = scopeguard::guard(ret, |ret| {
-            loopdevice::detach(ret.data_device).unwrap();
-            loopdevice::detach(ret.hash_device).unwrap();
             let dm = dm::DeviceMapper::new().unwrap();
-            dm.delete_device_deferred(name).unwrap();
+            if ret.data_device.starts_with("/dev/mapper") {
+                // The loop devices of a zero-padded APK are detached along with its mapper device.
+                dm.delete_device_deferred(name).unwrap();
+                dm.delete_device_deferred(&padded_device_name(name)).unwrap();
+            } else {
+                loopdevice::detach(ret.data_device).unwrap();
+                dm.delete_device_deferred(name).unwrap();
+            }
+            loopdevice::detach(ret.hash_device).unwrap();
         });
 
         check(TestContext {
-            data_backing_file: &apk_path,
-            hash_backing_file: &idsig_path,
+            data_backing_file: apk_path,
+            hash_backing_file: idsig_path,
             result: &ret,
         });
     }
 
+    // Returns `apk` with a zip comment added so that its last block holds `tail` bytes. `apk`
+    // must not have a comment yet.
+    fn resize_apk(apk: &[u8], tail: u64) -> Vec<u8> {
+        let size = apk.len() as u64;
+        let mut new_size = (size & !(BLOCK_SIZE - 1)) + tail;
+        if new_size < size {
+            new_size += BLOCK_SIZE;
+        }
+        let comment_size = (new_size - size) as u16;
+
+        let mut resized = apk.to_vec();
+        // The comment size is the last field of the end of central directory record.
+        resized[apk.len() - 2..].copy_from_slice(&comment_size.to_le_bytes());
+        resized.resize(new_size as usize, b'c');
+        resized
+    }
+
+    // Generates the idsig file of `apk` without signing it.
+    fn create_idsig(apk: &[u8]) -> Vec<u8> {
+        let mut sig = V4Signature::create(
+            &mut std::io::Cursor::new(apk),
+            /* current_sdk */ 31,
+            BLOCK_SIZE as usize,
+            &[],
+            HashAlgorithm::SHA256,
+        )
+        .unwrap();
+        let mut idsig = std::io::Cursor::new(Vec::new());
+        sig.write_into(&mut idsig).unwrap();
+        idsig.into_inner()
+    }
+
+    fn check_zero_padded(ctx: TestContext) {
+        let verity = fs::read(&ctx.result.mapper_device).unwrap();
+        let mut original = fs::read(ctx.data_backing_file).unwrap();
+        original.resize((original.len() as u64).next_multiple_of(BLOCK_SIZE) as usize, 0);
+        assert_eq!(verity.len(), original.len()); // fail fast
+        assert_eq!(verity.as_slice(), original.as_slice());
+    }
+
     #[rdroidtest]
     #[ignore_if(should_skip())]
     fn correct_inputs() {
@@ -242,6 +393,9 @@ mod tests {
             let original = fs::read(&ctx.result.data_device).unwrap();
             assert_eq!(verity.len(), original.len()); // fail fast
             assert_eq!(verity.as_slice(), original.as_slice());
+
+            let dm = dm::DeviceMapper::new().unwrap();
+            assert_eq!(DmVerityStatus::Verified, dm.verity_status("correct").unwrap());
         });
     }
 
@@ -260,6 +414,9 @@ mod tests {
 
         run_test(modified_apk.as_slice(), idsig.as_ref(), "incorrect_apk", |ctx| {
             fs::read(&ctx.result.mapper_device).expect_err("Should fail");
+
+            let dm = dm::DeviceMapper::new().unwrap();
+            assert_eq!(DmVerityStatus::Corrupted, dm.verity_status("incorrect_apk").unwrap());
         });
     }
 
@@ -403,6 +560,70 @@ mod tests {
         );
     }
 
+    // The last block of an APK whose size isn't a multiple of the block size is zero-padded, as
+    // in the idsig file.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn unaligned_apk() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+        assert_ne!(0, apk.len() as u64 & (BLOCK_SIZE - 1));
+
+        run_unaligned_test(apk.as_ref(), idsig.as_ref(), "unaligned_apk", |ctx| {
+            check_zero_padded(ctx);
+
+            let dm = dm::DeviceMapper::new().unwrap();
+            assert_eq!(DmVerityStatus::Verified, dm.verity_status("unaligned_apk").unwrap());
+        });
+    }
+
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn unaligned_apk_boundaries() {
+        let apk = include_bytes!("../testdata/test.apk");
+
+        for tail in [0, 1, BLOCK_SIZE - 1] {
+            let apk = resize_apk(apk, tail);
+            let idsig = create_idsig(&apk);
+            let name = format!("unaligned_apk_{}", tail);
+            run_unaligned_test(&apk, &idsig, &name, check_zero_padded);
+        }
+    }
+
+    // A single byte change in the last block of an unaligned APK causes an IO error
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn incorrect_unaligned_apk() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let idsig = include_bytes!("../testdata/test.apk.idsig");
+
+        let mut modified_apk = apk.to_vec();
+        *modified_apk.last_mut().unwrap() ^= 1;
+
+        run_unaligned_test(&modified_apk, idsig.as_ref(), "incorrect_unaligned_apk", |ctx| {
+            fs::read(&ctx.result.mapper_device).expect_err("Should fail");
+        });
+    }
+
+    // The zero-padded device of an unaligned APK is removed if the dm-verity device can't be made.
+    #[rdroidtest]
+    #[ignore_if(should_skip())]
+    fn unaligned_apk_with_invalid_idsig() {
+        let apk = include_bytes!("../testdata/test.apk");
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let apk_path = test_dir.path().join("test.apk");
+        let idsig_path = test_dir.path().join("test.apk.idsig");
+        fs::write(&apk_path, apk).unwrap();
+        fs::write(&idsig_path, b"not an idsig").unwrap();
+
+        let name = "unaligned_apk_with_invalid_idsig";
+        assert!(enable_verity(&apk_path, &idsig_path, name, None).is_err());
+
+        let dm = dm::DeviceMapper::new().unwrap();
+        dm.device_info(&padded_device_name(name)).expect_err("Padded device should be removed");
+    }
+
     #[rdroidtest]
     fn verify_command() {
         // Check that the command parsing has been configured in a valid way.
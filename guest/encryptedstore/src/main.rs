@@ -16,11 +16,14 @@
 
 //! `encryptedstore` is a program that (as the name indicates) provides encrypted storage
 //! solution in a VM. This is based on dm-crypt & requires the (64 bytes') key & the backing device.
+//! Optionally, the storage can use authenticated encryption, stacking dm-crypt on dm-integrity.
 //! It uses dm_rust lib.
 
 use anyhow::{ensure, Context, Result};
-use clap::arg;
-use dm::{crypt::CipherType, util};
+use clap::{arg, Arg, ArgAction};
+use dm::crypt::{CipherType, DmCryptTargetBuilder};
+use dm::integrity::{self, DmIntegrityTargetBuilder};
+use dm::util;
 use log::{error, info};
 use std::ffi::CString;
 use std::fs::{create_dir_all, OpenOptions};
@@ -32,6 +35,8 @@ use std::process::Command;
 
 const MK2FS_BIN: &str = "/system/bin/mke2fs";
 const UNFORMATTED_STORAGE_MAGIC: &str = "UNFORMATTED-STORAGE";
+// Block size of the storage with authenticated encryption. This is also the block size of ext4.
+const AUTHENTICATED_BLOCK_SIZE: u64 = 4096;
 
 fn main() {
     android_logger::init_once(
@@ -54,8 +59,9 @@ fn try_main() -> Result<()> {
     let blkdevice = Path::new(matches.get_one::<String>("blkdevice").unwrap());
     let key = matches.get_one::<String>("key").unwrap();
     let mountpoint = Path::new(matches.get_one::<String>("mountpoint").unwrap());
+    let authenticated = matches.get_flag("authenticated-encryption");
     // Note this error context is used in MicrodroidTests.
-    encryptedstore_init(blkdevice, key, mountpoint).with_context(|| {
+    encryptedstore_init(blkdevice, key, mountpoint, authenticated).with_context(|| {
         format!(
             "Unable to initialize encryptedstore on {:?} & mount at {:?}",
             blkdevice, mountpoint
@@ -69,10 +75,22 @@ fn clap_command() -> clap::Command {
         arg!(--blkdevice <FILE> "the block device backing the encrypted storage").required(true),
         arg!(--key <KEY> "key (in hex) equivalent to 32 bytes)").required(true),
         arg!(--mountpoint <MOUNTPOINT> "mount point for the storage").required(true),
+        Arg::new("authenticated-encryption")
+            .long("authenticated-encryption")
+            .action(ArgAction::SetTrue)
+            .help(
+                "use authenticated encryption, which detects tampering of the storage. A \
+                formatted storage is only opened if it was formatted with the same encryption",
+            ),
     ])
 }
 
-fn encryptedstore_init(blkdevice: &Path, key: &str, mountpoint: &Path) -> Result<()> {
+fn encryptedstore_init(
+    blkdevice: &Path,
+    key: &str,
+    mountpoint: &Path,
+    authenticated_encryption: bool,
+) -> Result<()> {
     ensure!(
         std::fs::metadata(blkdevice)
             .with_context(|| format!("Failed to get metadata of {:?}", blkdevice))?
@@ -84,8 +102,15 @@ fn encryptedstore_init(blkdevice: &Path, key: &str, mountpoint: &Path) -> Result
 
     let needs_formatting =
         needs_formatting(blkdevice).context("Unable to check if formatting is required")?;
-    let crypt_device =
-        enable_crypt(blkdevice, key, "cryptdev").context("Unable to map crypt device")?;
+    if !needs_formatting {
+        check_encryption(blkdevice, authenticated_encryption)?;
+    }
+    let crypt_device = if authenticated_encryption {
+        enable_authenticated_crypt(blkdevice, key, needs_formatting)
+            .context("Unable to map authenticated crypt device")?
+    } else {
+        enable_crypt(blkdevice, key, "cryptdev").context("Unable to map crypt device")?
+    };
 
     // We might need to format it with filesystem if this is a "seen-for-the-first-time" device.
     if needs_formatting {
@@ -100,6 +125,20 @@ fn encryptedstore_init(blkdevice: &Path, key: &str, mountpoint: &Path) -> Result
     Ok(())
 }
 
+// The host can write the storage, so the encryption is never chosen from its content. The superblock
+// of dm-integrity only tells whether the storage was formatted with authenticated encryption, which
+// must be the requested encryption.
+fn check_encryption(data_device: &Path, authenticated_encryption: bool) -> Result<()> {
+    let authenticated =
+        integrity::is_formatted(data_device).context("Unable to check the encryption mode")?;
+    if authenticated_encryption {
+        ensure!(authenticated, "The storage isn't formatted with authenticated encryption");
+    } else {
+        ensure!(!authenticated, "The storage is formatted with authenticated encryption");
+    }
+    Ok(())
+}
+
 fn set_root_dir_permissions(mountpoint: &Path) -> Result<()> {
     // mke2fs hardwires the root dir permissions as 0o755 which doesn't match what we want.
     // We want to allow full access by both root and the payload group, and no access by anything
@@ -114,7 +153,7 @@ fn enable_crypt(data_device: &Path, key: &str, name: &str) -> Result<PathBuf> {
     let key = hex::decode(key).context("Unable to decode hex key")?;
 
     // Create the dm-crypt spec
-    let target = dm::crypt::DmCryptTargetBuilder::default()
+    let target = DmCryptTargetBuilder::default()
         .data_device(data_device, dev_size)
         .cipher(CipherType::AES256HCTR2)
         .key(&key)
@@ -126,6 +165,93 @@ fn enable_crypt(data_device: &Path, key: &str, name: &str) -> Result<PathBuf> {
     dm.create_crypt_device(name, &target).context("Failed to create dm-crypt device")
 }
 
+// Maps a dm-crypt device using AES-GCM on top of a dm-integrity device, which stores the IV and the
+// authentication tag of each block. If `needs_formatting`, the dm-integrity device is formatted
+// first.
+fn enable_authenticated_crypt(
+    data_device: &Path,
+    key: &str,
+    needs_formatting: bool,
+) -> Result<PathBuf> {
+    let key = hex::decode(key).context("Unable to decode hex key")?;
+    let cipher = CipherType::AES256GCM;
+    // Unwrap is safe because GCM is an AEAD cipher.
+    let tag_size = cipher.aead_tag_size().unwrap();
+    let dm = dm::DeviceMapper::new()?;
+
+    if needs_formatting {
+        // The kernel formats the device when an integrity target is first created on it. A
+        // temporary device of a single block is created for that purpose only.
+        zero_integrity_superblock(data_device)?;
+        let target = DmIntegrityTargetBuilder::default()
+            .data_device(data_device, AUTHENTICATED_BLOCK_SIZE)
+            .tag_size(tag_size)
+            .block_size(AUTHENTICATED_BLOCK_SIZE as u32)
+            .build()
+            .context("Couldn't build the DMIntegrity target")?;
+        dm.create_integrity_device("integritydev-format", &target)
+            .context("Failed to format the dm-integrity device")?;
+        dm.delete_device("integritydev-format")?;
+    }
+
+    let size = integrity::provided_data_size(data_device)? & !(AUTHENTICATED_BLOCK_SIZE - 1);
+    let target = DmIntegrityTargetBuilder::default()
+        .data_device(data_device, size)
+        .tag_size(tag_size)
+        .block_size(AUTHENTICATED_BLOCK_SIZE as u32)
+        .build()
+        .context("Couldn't build the DMIntegrity target")?;
+    let integrity_device = dm
+        .create_integrity_device("integritydev", &target)
+        .context("Failed to create dm-integrity device")?;
+
+    let sector_size = format!("sector_size:{}", AUTHENTICATED_BLOCK_SIZE);
+    let target = DmCryptTargetBuilder::default()
+        .data_device(&integrity_device, size)
+        .cipher(cipher)
+        .key(&key)
+        .opt_param(&sector_size)
+        .build()
+        .context("Couldn't build the DMCrypt target")?;
+    let crypt_device =
+        dm.create_crypt_device("cryptdev", &target).context("Failed to create dm-crypt device")?;
+
+    if needs_formatting {
+        // Blocks that were never written have no valid tag, so reading them fails. Write all of
+        // them once, as mke2fs may read some.
+        info!("Initializing the authentication tags of the crypt device");
+        zero_device(&crypt_device, size)?;
+    }
+    Ok(crypt_device)
+}
+
+// The kernel formats a device as dm-integrity only if its superblock area is zeroed.
+fn zero_integrity_superblock(data_device: &Path) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(data_device)
+        .with_context(|| format!("Failed to open {:?}", data_device))?;
+    file.write_all(&[0; AUTHENTICATED_BLOCK_SIZE as usize])?;
+    Ok(())
+}
+
+fn zero_device(device: &Path, size: u64) -> Result<()> {
+    const CHUNK_SIZE: u64 = 1 << 20;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .with_context(|| format!("Failed to open {:?}", device))?;
+    let zeroes = [0; CHUNK_SIZE as usize];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(CHUNK_SIZE);
+        file.write_all(&zeroes[..len as usize])?;
+        remaining -= len;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
 // The disk contains UNFORMATTED_STORAGE_MAGIC to indicate we need to format the crypt device.
 // This function looks for it, zeroing it, if present.
 fn needs_formatting(data_device: &Path) -> Result<bool> {
@@ -211,4 +337,27 @@ mod tests {
         // Check that the command parsing has been configured in a valid way.
         clap_command().debug_assert();
     }
+
+    fn storage_with_header(header: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(header).unwrap();
+        file.write_all(&[0xa5; AUTHENTICATED_BLOCK_SIZE as usize]).unwrap();
+        file
+    }
+
+    #[test]
+    fn authenticated_encryption_refuses_length_preserving_storage() {
+        let storage = storage_with_header(&[0x5a; 8]);
+        let err = check_encryption(storage.path(), true).unwrap_err();
+        assert_eq!(err.to_string(), "The storage isn't formatted with authenticated encryption");
+        check_encryption(storage.path(), false).unwrap();
+    }
+
+    #[test]
+    fn length_preserving_encryption_refuses_authenticated_storage() {
+        let storage = storage_with_header(b"integrt\0");
+        let err = check_encryption(storage.path(), false).unwrap_err();
+        assert_eq!(err.to_string(), "The storage is formatted with authenticated encryption");
+        check_encryption(storage.path(), true).unwrap();
+    }
 }
@@ -19,6 +19,7 @@ mod instance;
 mod ioutil;
 mod payload;
 mod swap;
+mod vcpu_controller;
 mod verify;
 mod vm_payload_service;
 mod vm_secret;
@@ -33,6 +34,7 @@ use android_system_virtualization_payload::aidl::android::system::virtualization
 
 use crate::dice::dice_derivation;
 use crate::instance::{InstanceDisk, MicrodroidData};
+use crate::vcpu_controller::register_vcpu_controller;
 use crate::verify::verify_payload;
 use crate::vm_payload_service::register_vm_payload_service;
 use anyhow::{anyhow, bail, ensure, Context, Error, Result};
@@ -40,7 +42,7 @@ use binder::Strong;
 use dice_driver::DiceDriver;
 use keystore2_crypto::ZVec;
 use libc::VMADDR_CID_HOST;
-use log::{error, info};
+use log::{error, info, warn};
 use microdroid_metadata::{Metadata, PayloadMetadata};
 use microdroid_payload_config::{ApkConfig, OsConfig, Task, TaskType, VmPayloadConfig};
 use nix::mount::{umount2, MntFlags};
@@ -214,6 +216,11 @@ fn try_main() -> Result<()> {
         .context("cannot connect to VirtualMachineService")
         .map_err(|e| MicrodroidError::FailedToConnectToVirtualizationService(e.to_string()))?;
 
+    // Onlining and offlining vCPUs is optional, so the VM can still run without it.
+    if let Err(e) = register_vcpu_controller(&service) {
+        warn!("vCPUs can't be onlined or offlined: {:?}", e);
+    }
+
     match try_run_payload(&service, vm_payload_service_fd) {
         Ok(code) => {
             if code == 0 {
@@ -340,14 +347,6 @@ fn try_run_payload(
         umount2("/microdroid_resources", MntFlags::MNT_DETACH)?;
     }
 
-    // Run encryptedstore binary to prepare the storage
-    let encryptedstore_child = if Path::new(ENCRYPTEDSTORE_BACKING_DEVICE).exists() {
-        info!("Preparing encryptedstore ...");
-        Some(prepare_encryptedstore(&vm_secret).context("encryptedstore run")?)
-    } else {
-        None
-    };
-
     let mut zipfuse = Zipfuse::default();
 
     // Before reading a file from the APK, start zipfuse
@@ -369,6 +368,18 @@ fn try_run_payload(
 
     let config = load_config(payload_metadata).context("Failed to load payload metadata")?;
 
+    // Run encryptedstore binary to prepare the storage. This needs the payload config, which
+    // chooses the encryption, as the host can write the storage.
+    let encryptedstore_child = if Path::new(ENCRYPTEDSTORE_BACKING_DEVICE).exists() {
+        info!("Preparing encryptedstore ...");
+        Some(
+            prepare_encryptedstore(&vm_secret, config.authenticated_encrypted_storage)
+                .context("encryptedstore run")?,
+        )
+    } else {
+        None
+    };
+
     let task = config
         .task
         .as_ref()
@@ -479,7 +490,10 @@ fn get_vms_rpc_binder() -> Result<Strong<dyn IVirtualMachineService>> {
     // The host is running a VirtualMachineService for this VM on a port equal
     // to the CID of this VM.
     let port = vsock::get_local_cid().context("Could not determine local CID")?;
-    RpcSession::new()
+    let session = RpcSession::new();
+    // The host calls back into the VM through the IVcpuController it registers.
+    session.set_max_incoming_threads(1);
+    session
         .setup_vsock_client(VMADDR_CID_HOST, port)
         .context("Could not connect to IVirtualMachineService")
 }
@@ -609,6 +623,7 @@ fn load_config(payload_metadata: PayloadMetadata) -> Result<VmPayloadConfig> {
                 export_tombstones: None,
                 enable_authfs: false,
                 hugepages: false,
+                authenticated_encrypted_storage: false,
             })
         }
         _ => bail!("Failed to match config against a config type."),
@@ -703,7 +718,7 @@ fn find_library_path(name: &str) -> Result<String> {
     Ok(path)
 }
 
-fn prepare_encryptedstore(vm_secret: &VmSecret) -> Result<Child> {
+fn prepare_encryptedstore(vm_secret: &VmSecret, authenticated: bool) -> Result<Child> {
     let mut key = ZVec::new(ENCRYPTEDSTORE_KEYSIZE)?;
     vm_secret.derive_encryptedstore_key(&mut key)?;
     let mut cmd = Command::new(ENCRYPTEDSTORE_BIN);
@@ -711,7 +726,9 @@ fn prepare_encryptedstore(vm_secret: &VmSecret) -> Result<Child> {
         .arg(ENCRYPTEDSTORE_BACKING_DEVICE)
         .arg("--key")
         .arg(hex::encode(&*key))
-        .args(["--mountpoint", ENCRYPTEDSTORE_MOUNTPOINT])
-        .spawn()
-        .context("encryptedstore failed")
+        .args(["--mountpoint", ENCRYPTEDSTORE_MOUNTPOINT]);
+    if authenticated {
+        cmd.arg("--authenticated-encryption");
+    }
+    cmd.spawn().context("encryptedstore failed")
 }
@@ -0,0 +1,141 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Implementation of the AIDL interface `IVcpuController`, through which the host onlines and
+//! offlines the vCPUs of the VM.
+
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVcpuController::{
+    BnVcpuController, IVcpuController,
+};
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
+use anyhow::{ensure, Context, Result};
+use avflog::LogResult;
+use binder::{BinderFeatures, ExceptionCode, Interface, IntoBinderResult, Strong};
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CPU_SYSFS_DIR: &str = "/sys/devices/system/cpu";
+
+/// Implementation of `IVcpuController`.
+struct VcpuController {
+    /// Directory of the vCPUs in sysfs.
+    cpu_dir: PathBuf,
+}
+
+impl Interface for VcpuController {}
+
+impl IVcpuController for VcpuController {
+    fn setOnlineVcpus(&self, count: i32) -> binder::Result<()> {
+        let count = usize::try_from(count)
+            .with_context(|| format!("Invalid number of vCPUs {count}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        info!("Onlining {} vCPUs", count);
+        set_online_vcpus(&self.cpu_dir, count).with_log().or_service_specific_exception(-1)
+    }
+}
+
+/// Registers an `IVcpuController` with the host, so that it can online and offline the vCPUs.
+pub(crate) fn register_vcpu_controller(
+    vm_service: &Strong<dyn IVirtualMachineService>,
+) -> Result<()> {
+    let controller = BnVcpuController::new_binder(
+        VcpuController { cpu_dir: PathBuf::from(CPU_SYSFS_DIR) },
+        BinderFeatures::default(),
+    );
+    vm_service.setVcpuController(&controller).context("Failed to register the vCPU controller")
+}
+
+/// Onlines the first `count` vCPUs in `cpu_dir` and offlines the others. vCPU 0 can't be offlined,
+/// so `count` must be at least 1.
+fn set_online_vcpus(cpu_dir: &Path, count: usize) -> Result<()> {
+    let num_vcpus = num_vcpus(cpu_dir)?;
+    ensure!(
+        (1..=num_vcpus).contains(&count),
+        "Number of online vCPUs must be between 1 and {}, not {}",
+        num_vcpus,
+        count
+    );
+    for cpu in 1..num_vcpus {
+        let online = cpu_dir.join(format!("cpu{cpu}")).join("online");
+        let value = if cpu < count { "1" } else { "0" };
+        fs::write(&online, value).with_context(|| format!("Failed to write {:?}", online))?;
+    }
+    Ok(())
+}
+
+/// Returns the number of vCPUs, which have a `cpu<N>` directory in `cpu_dir`.
+fn num_vcpus(cpu_dir: &Path) -> Result<usize> {
+    let mut num_vcpus = 0;
+    for entry in fs::read_dir(cpu_dir).with_context(|| format!("Failed to read {:?}", cpu_dir))? {
+        let name = entry?.file_name();
+        let is_vcpu = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("cpu"))
+            .is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()));
+        if is_vcpu {
+            num_vcpus += 1;
+        }
+    }
+    Ok(num_vcpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_cpu_dir(num_vcpus: usize) -> Result<tempfile::TempDir> {
+        let cpu_dir = tempfile::TempDir::new()?;
+        // vCPU 0 has no online attribute, as it can't be offlined.
+        fs::create_dir(cpu_dir.path().join("cpu0"))?;
+        for cpu in 1..num_vcpus {
+            let vcpu_dir = cpu_dir.path().join(format!("cpu{cpu}"));
+            fs::create_dir(&vcpu_dir)?;
+            fs::write(vcpu_dir.join("online"), "1")?;
+        }
+        fs::create_dir(cpu_dir.path().join("cpufreq"))?;
+        fs::write(cpu_dir.path().join("possible"), format!("0-{}", num_vcpus - 1))?;
+        Ok(cpu_dir)
+    }
+
+    fn online(cpu_dir: &Path, cpu: usize) -> Result<String> {
+        Ok(fs::read_to_string(cpu_dir.join(format!("cpu{cpu}/online")))?)
+    }
+
+    #[test]
+    fn test_set_online_vcpus() -> Result<()> {
+        let cpu_dir = fake_cpu_dir(4)?;
+        let cpu_dir = cpu_dir.path();
+        assert_eq!(num_vcpus(cpu_dir)?, 4);
+
+        set_online_vcpus(cpu_dir, 2)?;
+        assert_eq!(online(cpu_dir, 1)?, "1");
+        assert_eq!(online(cpu_dir, 2)?, "0");
+        assert_eq!(online(cpu_dir, 3)?, "0");
+
+        set_online_vcpus(cpu_dir, 4)?;
+        assert_eq!(online(cpu_dir, 2)?, "1");
+        assert_eq!(online(cpu_dir, 3)?, "1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_online_vcpus_out_of_range() -> Result<()> {
+        let cpu_dir = fake_cpu_dir(4)?;
+        assert!(set_online_vcpus(cpu_dir.path(), 0).is_err());
+        assert!(set_online_vcpus(cpu_dir.path(), 5).is_err());
+        assert_eq!(online(cpu_dir.path(), 3)?, "1");
+        Ok(())
+    }
+}
@@ -19,7 +19,7 @@ use protobuf::Message;
 use std::fs::File;
 use std::io::{self, Read};
 use thiserror::Error;
-use vbmeta::VbMetaImage;
+use vbmeta::{HashtreeDescriptor, VbMetaImage};
 use zip::result::ZipError;
 use zip::ZipArchive;
 
@@ -65,6 +65,9 @@ pub enum ApexVerificationError {
     /// The APEX payload was not verified with the apex_pubkey.
     #[error("APEX pubkey mismatch")]
     ApexPubkeyMismatch,
+    /// The APEX payload doesn't match the root digest of its hashtree.
+    #[error("APEX payload doesn't match its hashtree: {0}")]
+    PayloadHashtreeMismatch(#[from] vbmeta::HashtreeVerificationError),
 }
 
 /// Information extracted from the APEX during AVB verification.
@@ -85,30 +88,48 @@ pub struct ApexVerificationResult {
 /// It doesn't verify that that is the correct key, nor does it verify that the payload matches
 /// the signed root hash - that is handled by dm-verity once apexd has mounted the APEX.
 pub fn verify(path: &str) -> Result<ApexVerificationResult, ApexVerificationError> {
+    verify_apex(path, false)
+}
+
+/// Like [`verify`], but also recomputes the hashtree of the payload and checks it against the
+/// signed root hash, so that a corrupted APEX is rejected before it is mounted. This reads the
+/// whole payload.
+pub fn verify_payload(path: &str) -> Result<ApexVerificationResult, ApexVerificationError> {
+    verify_apex(path, true)
+}
+
+fn verify_apex(
+    path: &str,
+    check_payload: bool,
+) -> Result<ApexVerificationResult, ApexVerificationError> {
     let apex_file = File::open(path).map_err(ApexParseError::Io)?;
     let ApexZipInfo { public_key, image_offset, image_size, manifest } =
         get_apex_zip_info(&apex_file)?;
-    let vbmeta = VbMetaImage::verify_reader_region(apex_file, image_offset, image_size)?;
-    let root_digest = find_root_digest(&vbmeta)?;
+    let vbmeta = VbMetaImage::verify_reader_region(&apex_file, image_offset, image_size)?;
+    let hashtree = find_hashtree(&vbmeta)?;
     let vbmeta_public_key =
         vbmeta.public_key().ok_or(ApexParseError::VbmetaMissingData("public key"))?;
     if vbmeta_public_key != public_key {
         return Err(ApexVerificationError::ApexPubkeyMismatch);
     }
+    if check_payload {
+        hashtree.verify_reader_region(&apex_file, image_offset)?;
+    }
     let (name, version) = if cfg!(dice_changes) {
         let ApexManifestInfo { name, version } = decode_manifest(&manifest)?;
         (Some(name), Some(version))
     } else {
         (None, None)
     };
+    let root_digest = hashtree.root_digest().to_vec();
     Ok(ApexVerificationResult { name, version, public_key, root_digest })
 }
 
-fn find_root_digest(vbmeta: &VbMetaImage) -> Result<Vec<u8>, ApexParseError> {
+fn find_hashtree(vbmeta: &VbMetaImage) -> Result<HashtreeDescriptor<'_>, ApexParseError> {
     // APEXs use the root digest from the first hashtree descriptor to describe the payload.
-    for descriptor in vbmeta.descriptors()?.iter() {
+    for descriptor in vbmeta.descriptors()? {
         if let vbmeta::Descriptor::Hashtree(_) = descriptor {
-            return Ok(descriptor.to_hashtree()?.root_digest().to_vec());
+            return Ok(descriptor.to_hashtree()?);
         }
     }
     Err(ApexParseError::DescriptorNotHashtree)
@@ -165,6 +186,10 @@ fn from_zip_error(err: ZipError, name: &'static str) -> ApexParseError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::FileExt;
+    use tempfile::TempDir;
+    use vbmeta::HashtreeVerificationError;
 
     #[test]
     fn apex_verification_returns_valid_result() {
@@ -232,4 +257,36 @@ mod tests {
             e => panic!("Unexpected error {e}"),
         }
     }
+
+    #[test]
+    fn apex_payload_verification_returns_valid_result() {
+        let res = verify_payload("apex.apexd_test.apex").unwrap();
+        assert_eq!(
+            hex::encode(res.root_digest),
+            "54265da77ae1fd619e39809ad99fedc576bb20c0c7a8002190fa64438436299f"
+        );
+    }
+
+    #[test]
+    fn apex_corrupted_payload_fails_payload_verification() {
+        let test_dir = TempDir::new().unwrap();
+        let path = test_dir.path().join("corrupted.apex");
+        fs::copy("apex.apexd_test.apex", &path).unwrap();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let ApexZipInfo { image_offset, .. } = get_apex_zip_info(&file).unwrap();
+
+        // Flip a byte in the second block of the payload.
+        let mut data = [0; 1];
+        file.read_exact_at(&mut data, image_offset + 4096 + 10).unwrap();
+        file.write_all_at(&[!data[0]], image_offset + 4096 + 10).unwrap();
+
+        let path = path.to_str().unwrap();
+        assert!(verify(path).is_ok());
+        match verify_payload(path).unwrap_err() {
+            ApexVerificationError::PayloadHashtreeMismatch(
+                HashtreeVerificationError::Mismatch { blocks },
+            ) => assert_eq!(blocks, 1..2),
+            e => panic!("Unexpected error {e}"),
+        }
+    }
 }
@@ -16,15 +16,19 @@
 
 //! Algorithms used for APK Signature Scheme.
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use bssl_sys::DSA_do_verify;
 use byteorder::{LittleEndian, ReadBytesExt};
 use bytes::{Buf, Bytes};
+use foreign_types::ForeignType;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
-use openssl::hash::MessageDigest;
-use openssl::pkey::{self, PKey};
+use openssl::dsa::{Dsa, DsaSig};
+use openssl::error::ErrorStack;
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::pkey::{self, HasPublic, PKey, PKeyRef};
 use openssl::rsa::Padding;
-use openssl::sign::Verifier;
+use openssl::sign::{RsaPssSaltlen, Signer, Verifier};
 use serde::{Deserialize, Serialize};
 use std::io::Read;
 
@@ -32,8 +36,6 @@ use crate::bytes_ext::ReadFromBytes;
 
 /// [Signature Algorithm IDs]: https://source.android.com/docs/security/apksigning/v2#signature-algorithm-ids
 /// [SignatureAlgorithm.java]: (tools/apksig/src/main/java/com/android/apksig/internal/apk/SignatureAlgorithm.java)
-///
-/// Some of the algorithms are not implemented. See b/197052981.
 #[derive(
     Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq, FromPrimitive, ToPrimitive,
 )]
@@ -95,21 +97,55 @@ impl SignatureAlgorithmID {
     pub(crate) fn new_verifier<'a>(
         &self,
         public_key: &'a PKey<pkey::Public>,
-    ) -> Result<Verifier<'a>> {
-        ensure!(
-            !matches!(
-                self,
-                SignatureAlgorithmID::DsaWithSha256 | SignatureAlgorithmID::VerityDsaWithSha256
-            ),
-            "Algorithm '{:?}' is not supported in openssl to build this verifier (b/197052981).",
-            self
-        );
+    ) -> Result<SignatureVerifier<'a>> {
         ensure!(public_key.id() == self.pkey_id(), "Public key has the wrong ID");
-        let mut verifier = Verifier::new(self.new_message_digest(), public_key)?;
+        let message_digest = self.new_message_digest();
+        if public_key.id() == pkey::Id::DSA {
+            // DSA isn't supported by the EVP interface of BoringSSL (b/197052981).
+            let hasher = Hasher::new(message_digest)?;
+            return Ok(SignatureVerifier::Dsa { hasher, key: public_key.dsa()? });
+        }
+        let mut verifier = Verifier::new(message_digest, public_key)?;
         if public_key.id() == pkey::Id::RSA {
-            verifier.set_rsa_padding(self.rsa_padding())?;
+            let padding = self.rsa_padding();
+            verifier.set_rsa_padding(padding)?;
+            if padding == Padding::PKCS1_PSS {
+                // The salt is as long as the digest and MGF1 uses the same digest as the signature.
+                verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                verifier.set_rsa_mgf1_md(message_digest)?;
+            }
+        }
+        Ok(SignatureVerifier::Evp(verifier))
+    }
+
+    /// Returns the algorithm used to sign with `key`, following the choice of apksig for the
+    /// signature schemes that accept a single algorithm per key, e.g. v4.
+    pub(crate) fn for_signing_key<T: HasPublic>(key: &PKeyRef<T>) -> Result<Self> {
+        Ok(match key.id() {
+            pkey::Id::RSA if key.bits() <= 3072 => SignatureAlgorithmID::RsaPkcs1V15WithSha256,
+            pkey::Id::RSA => SignatureAlgorithmID::RsaPkcs1V15WithSha512,
+            pkey::Id::EC if key.bits() <= 256 => SignatureAlgorithmID::EcdsaWithSha256,
+            pkey::Id::EC => SignatureAlgorithmID::EcdsaWithSha512,
+            id => bail!("Unsupported key type for signing: {:?}", id),
+        })
+    }
+
+    pub(crate) fn new_signer<'a>(
+        &self,
+        private_key: &'a PKeyRef<pkey::Private>,
+    ) -> Result<Signer<'a>> {
+        ensure!(private_key.id() == self.pkey_id(), "Private key has the wrong ID");
+        let message_digest = self.new_message_digest();
+        let mut signer = Signer::new(message_digest, private_key)?;
+        if private_key.id() == pkey::Id::RSA {
+            let padding = self.rsa_padding();
+            signer.set_rsa_padding(padding)?;
+            if padding == Padding::PKCS1_PSS {
+                signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                signer.set_rsa_mgf1_md(message_digest)?;
+            }
         }
-        Ok(verifier)
+        Ok(signer)
     }
 
     /// Returns the message digest corresponding to the signature algorithm
@@ -129,14 +165,6 @@ impl SignatureAlgorithmID {
         }
     }
 
-    /// DSA is not directly supported in openssl today. See b/197052981.
-    pub(crate) fn is_supported(&self) -> bool {
-        !matches!(
-            self,
-            SignatureAlgorithmID::DsaWithSha256 | SignatureAlgorithmID::VerityDsaWithSha256,
-        )
-    }
-
     fn pkey_id(&self) -> pkey::Id {
         match self {
             SignatureAlgorithmID::RsaPssWithSha256
@@ -187,6 +215,52 @@ impl SignatureAlgorithmID {
     }
 }
 
+/// Verifies a signature made with one of the supported `SignatureAlgorithmID`s.
+pub(crate) enum SignatureVerifier<'a> {
+    /// RSA and ECDSA signatures are verified through the EVP interface.
+    Evp(Verifier<'a>),
+    /// DSA signatures are verified on the digest of the signed data.
+    Dsa { hasher: Hasher, key: Dsa<pkey::Public> },
+}
+
+impl SignatureVerifier<'_> {
+    /// Feeds more signed data into the verifier.
+    pub(crate) fn update(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            SignatureVerifier::Evp(verifier) => verifier.update(data)?,
+            SignatureVerifier::Dsa { hasher, .. } => hasher.update(data)?,
+        }
+        Ok(())
+    }
+
+    /// Returns whether `signature` is valid for the signed data fed so far.
+    pub(crate) fn verify(&mut self, signature: &[u8]) -> Result<bool> {
+        match self {
+            SignatureVerifier::Evp(verifier) => Ok(verifier.verify(signature)?),
+            SignatureVerifier::Dsa { hasher, key } => verify_dsa(key, &hasher.finish()?, signature),
+        }
+    }
+}
+
+fn verify_dsa(key: &Dsa<pkey::Public>, digest: &[u8], signature: &[u8]) -> Result<bool> {
+    let Ok(signature) = DsaSig::from_der(signature) else {
+        return Ok(false);
+    };
+    // SAFETY: All the pointers are valid for the duration of the call, and DSA_do_verify only
+    // reads from them.
+    let ret =
+        unsafe { DSA_do_verify(digest.as_ptr(), digest.len(), signature.as_ptr(), key.as_ptr()) };
+    match ret {
+        1 => Ok(true),
+        0 => {
+            // Drop the reason why the signature is invalid from the error queue.
+            let _ = ErrorStack::get();
+            Ok(false)
+        }
+        _ => Err(ErrorStack::get().into()),
+    }
+}
+
 /// The rank of the content digest algorithm in this enum is used to help pick
 /// v4 apk digest.
 /// According to APK Signature Scheme v4, [apk digest] is the first available
@@ -196,13 +270,64 @@ impl SignatureAlgorithmID {
 ///
 /// [apk digest]: https://source.android.com/docs/security/features/apksigning/v4#apk-digest
 /// [v3 verification]: https://source.android.com/docs/security/apksigning/v3#v3-verification
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub(crate) enum ContentDigestAlgorithm {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ContentDigestAlgorithm {
+    /// SHA2-256 over 1 MB chunks.
     ChunkedSha256 = 1,
+    /// SHA2-256 over 4 KB chunks, in the same way fs-verity operates.
     VerityChunkedSha256,
+    /// SHA2-512 over 1 MB chunks.
     ChunkedSha512,
 }
 
+/// Restricts which signatures are acceptable when verifying an APK or picking its v4 apk digest.
+/// Among the acceptable signatures, the strongest one is used. The default policy accepts all the
+/// supported algorithms with keys of any size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlgorithmPolicy {
+    /// Minimum size of RSA keys, in bits.
+    pub min_rsa_key_bits: u32,
+    /// Minimum size of EC keys, in bits.
+    pub min_ec_key_bits: u32,
+    /// Minimum size of DSA keys, in bits.
+    pub min_dsa_key_bits: u32,
+    /// Content digest algorithms that signatures may use.
+    pub allowed_digests: Vec<ContentDigestAlgorithm>,
+}
+
+impl Default for AlgorithmPolicy {
+    fn default() -> Self {
+        Self {
+            min_rsa_key_bits: 0,
+            min_ec_key_bits: 0,
+            min_dsa_key_bits: 0,
+            allowed_digests: vec![
+                ContentDigestAlgorithm::ChunkedSha256,
+                ContentDigestAlgorithm::VerityChunkedSha256,
+                ContentDigestAlgorithm::ChunkedSha512,
+            ],
+        }
+    }
+}
+
+impl AlgorithmPolicy {
+    /// Returns whether a signature made with `algorithm_id` by `public_key` is acceptable.
+    pub(crate) fn allows(
+        &self,
+        algorithm_id: SignatureAlgorithmID,
+        public_key: &PKey<pkey::Public>,
+    ) -> bool {
+        let min_key_bits = match public_key.id() {
+            pkey::Id::RSA => self.min_rsa_key_bits,
+            pkey::Id::EC => self.min_ec_key_bits,
+            pkey::Id::DSA => self.min_dsa_key_bits,
+            _ => return false,
+        };
+        public_key.bits() >= min_key_bits
+            && self.allowed_digests.contains(&algorithm_id.content_digest_algorithm())
+    }
+}
+
 /// Hash algorithms.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive, ToPrimitive, Default)]
 #[repr(u32)]
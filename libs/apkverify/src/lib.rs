@@ -19,12 +19,22 @@
 mod algorithms;
 mod bytes_ext;
 mod hashtree;
+mod lineage;
 mod sigutil;
 #[allow(dead_code)]
 pub mod testing;
+mod v2;
 mod v3;
 mod v4;
 
-pub use algorithms::{HashAlgorithm, SignatureAlgorithmID};
-pub use v3::{extract_signed_data, verify, SignedData};
-pub use v4::{get_apk_digest, V4Signature};
+pub use algorithms::{
+    AlgorithmPolicy, ContentDigestAlgorithm, HashAlgorithm, SignatureAlgorithmID,
+};
+pub use lineage::{LineageNode, SignerCapabilities, SigningCertificateLineage};
+pub use sigutil::DigestMode;
+pub use v3::{
+    extract_all_signed_data, extract_signed_data, extract_signed_data_from_reader, verify,
+    verify_all_signers, verify_all_signers_with_policy, verify_reader, verify_with_policy,
+    MultiSignerData, SignatureScheme, SignedData,
+};
+pub use v4::{get_apk_digest, get_apk_digest_with_policy, V4Signature, V4Signer};
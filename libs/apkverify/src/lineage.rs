@@ -0,0 +1,252 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verifies the proof-of-rotation attribute of APK Signature Scheme v3, a.k.a. the signing
+//! certificate lineage.
+//!
+//! [proof-of-rotation]: https://source.android.com/docs/security/features/apksigning/v3#proof-of-rotation-and-self-trusted-old-certs-structures
+
+use anyhow::{ensure, Context, Result};
+use bitflags::bitflags;
+use bytes::{Buf, Bytes};
+use num_traits::FromPrimitive;
+use openssl::x509::X509;
+
+use crate::algorithms::SignatureAlgorithmID;
+use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+
+/// ID of the additional attribute in the v3 signed data that holds the proof-of-rotation.
+pub const PROOF_OF_ROTATION_ATTR_ID: u32 = 0x3ba06f8c;
+
+/// The only version of the lineage format. See `V3SigningCertificateLineage.CURRENT_VERSION` in
+/// apksig.
+const LINEAGE_VERSION: u32 = 1;
+
+bitflags! {
+    /// Capabilities that a newer signing certificate grants to a past signing certificate in the
+    /// lineage. See `SigningCertificateLineage.SignerCapabilities` in apksig.
+    #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+    pub struct SignerCapabilities: u32 {
+        /// The past certificate can access the data of the app installed with the new one.
+        const INSTALLED_DATA = 1 << 0;
+        /// The past certificate can share a user ID with the new one.
+        const SHARED_USER_ID = 1 << 1;
+        /// The past certificate is trusted for signature permissions.
+        const PERMISSION = 1 << 2;
+        /// An app signed with the past certificate can be installed over the new one.
+        const ROLLBACK = 1 << 3;
+        /// The past certificate is trusted for authentication, e.g. with the account manager.
+        const AUTH = 1 << 4;
+    }
+}
+
+/// A verified signing certificate lineage, ordered from the oldest to the newest certificate.
+#[derive(Clone, Debug)]
+pub struct SigningCertificateLineage {
+    nodes: Vec<LineageNode>,
+}
+
+/// A certificate in the signing certificate lineage.
+#[derive(Clone, Debug)]
+pub struct LineageNode {
+    certificate: Bytes,
+    capabilities: SignerCapabilities,
+    signature_algorithm_id: Option<SignatureAlgorithmID>,
+}
+
+/// A node as it is encoded in the proof-of-rotation attribute. Nothing in it is verified yet.
+struct RawNode {
+    signed_data: LengthPrefixed<Bytes>,
+    flags: u32,
+    signature_algorithm_id: u32,
+    signature: LengthPrefixed<Bytes>,
+}
+
+/// The part of a node that is signed by the previous certificate in the lineage.
+struct RawNodeSignedData {
+    certificate: LengthPrefixed<Bytes>,
+    parent_signature_algorithm_id: u32,
+}
+
+impl SigningCertificateLineage {
+    /// Parses the value of the proof-of-rotation attribute and verifies that each certificate in
+    /// the lineage was signed by the previous one.
+    pub(crate) fn verify(mut buf: Bytes) -> Result<Self> {
+        ensure!(buf.remaining() >= 4, "Proof-of-rotation attribute too short to contain a version");
+        let version = buf.get_u32_le();
+        ensure!(version == LINEAGE_VERSION, "Unsupported signing certificate lineage: {}", version);
+
+        let raw_nodes = buf.read::<Vec<LengthPrefixed<RawNode>>>()?;
+        ensure!(!raw_nodes.is_empty(), "Signing certificate lineage is empty");
+
+        let mut nodes: Vec<LineageNode> = Vec::with_capacity(raw_nodes.len());
+        for (i, raw_node) in raw_nodes.iter().enumerate() {
+            let node_number = i + 1;
+            let signed_data: RawNodeSignedData = raw_node.signed_data.slice(..).read()?;
+            if let Some(parent) = nodes.last() {
+                ensure!(
+                    signed_data.parent_signature_algorithm_id
+                        == parent.signature_algorithm_id.map_or(0, |id| id.to_u32()),
+                    "Signature algorithm ID mismatch for certificate #{} in the signing \
+                    certificate lineage",
+                    node_number
+                );
+                parent.verify_signature(raw_node).with_context(|| {
+                    format!(
+                        "Unable to verify signature of certificate #{} in the signing \
+                        certificate lineage",
+                        node_number
+                    )
+                })?;
+            }
+            let certificate = signed_data.certificate.into_inner();
+            X509::from_der(&certificate).with_context(|| {
+                format!("Malformed certificate #{} in the signing certificate lineage", node_number)
+            })?;
+            ensure!(
+                !nodes.iter().any(|node| node.certificate == certificate),
+                "Duplicate certificate #{} in the signing certificate lineage",
+                node_number
+            );
+            nodes.push(LineageNode {
+                certificate,
+                capabilities: SignerCapabilities::from_bits_retain(raw_node.flags),
+                signature_algorithm_id: SignatureAlgorithmID::from_u32(
+                    raw_node.signature_algorithm_id,
+                ),
+            });
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Returns the certificates of the lineage, ordered from the oldest to the newest.
+    pub fn nodes(&self) -> &[LineageNode] {
+        &self.nodes
+    }
+
+    /// Returns the newest certificate in the lineage, encoded in DER form.
+    pub fn last_certificate_der(&self) -> &[u8] {
+        // `verify` ensures that the lineage isn't empty.
+        &self.nodes.last().unwrap().certificate
+    }
+}
+
+impl LineageNode {
+    /// Returns the X.509 certificate of this node, encoded in DER form.
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    /// Returns the capabilities that the newer certificates in the lineage grant to this one.
+    pub fn capabilities(&self) -> SignerCapabilities {
+        self.capabilities
+    }
+
+    /// Returns the algorithm this certificate used to sign the next node of the lineage, if any.
+    pub fn signature_algorithm_id(&self) -> Option<SignatureAlgorithmID> {
+        self.signature_algorithm_id
+    }
+
+    /// Verifies the signature of the next node in the lineage with the key of this node.
+    fn verify_signature(&self, next: &RawNode) -> Result<()> {
+        let public_key = X509::from_der(&self.certificate)?.public_key()?;
+        let mut verifier = self
+            .signature_algorithm_id
+            .context("Unsupported signature algorithm")?
+            .new_verifier(&public_key)?;
+        verifier.update(&next.signed_data)?;
+        ensure!(verifier.verify(&next.signature)?, "Signature is invalid.");
+        Ok(())
+    }
+}
+
+impl ReadFromBytes for RawNode {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self {
+            signed_data: buf.read()?,
+            flags: buf.read()?,
+            signature_algorithm_id: buf.read()?,
+            signature: buf.read()?,
+        })
+    }
+}
+
+impl ReadFromBytes for RawNodeSignedData {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self { certificate: buf.read()?, parent_signature_algorithm_id: buf.read()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::v3::extract_signed_data;
+    use bytes::BytesMut;
+
+    const APK_WITH_LINEAGE: &str = "tests/data/v3-only-unknown-additional-attr.apk";
+
+    fn proof_of_rotation() -> Bytes {
+        let signed_data = extract_signed_data(APK_WITH_LINEAGE, 31).unwrap();
+        signed_data.find_additional_attribute(PROOF_OF_ROTATION_ATTR_ID).unwrap()
+    }
+
+    #[test]
+    fn verify_lineage() {
+        let lineage = SigningCertificateLineage::verify(proof_of_rotation()).unwrap();
+
+        assert_eq!(2, lineage.nodes().len());
+        let expected_capabilities = SignerCapabilities::INSTALLED_DATA
+            | SignerCapabilities::SHARED_USER_ID
+            | SignerCapabilities::PERMISSION
+            | SignerCapabilities::AUTH;
+        for node in lineage.nodes() {
+            assert_eq!(expected_capabilities, node.capabilities());
+        }
+        assert_eq!(
+            Some(SignatureAlgorithmID::RsaPkcs1V15WithSha256),
+            lineage.nodes()[0].signature_algorithm_id()
+        );
+        assert_eq!(lineage.nodes()[1].certificate_der(), lineage.last_certificate_der());
+    }
+
+    #[test]
+    fn lineage_with_corrupted_signature_is_rejected() {
+        let mut attr = BytesMut::from(proof_of_rotation().as_ref());
+        // The signature of the last node is at the end of the attribute.
+        let last = attr.len() - 1;
+        attr[last] ^= 1;
+
+        let res = SigningCertificateLineage::verify(attr.freeze());
+        assert!(res.is_err());
+        crate::testing::assert_contains(
+            &res.unwrap_err().to_string(),
+            "Unable to verify signature of certificate #2",
+        );
+    }
+
+    #[test]
+    fn lineage_with_unsupported_version_is_rejected() {
+        let mut attr = BytesMut::from(proof_of_rotation().as_ref());
+        attr[0] = 2;
+
+        let res = SigningCertificateLineage::verify(attr.freeze());
+        assert!(res.is_err());
+        crate::testing::assert_contains(
+            &res.unwrap_err().to_string(),
+            "Unsupported signing certificate lineage",
+        );
+    }
+}
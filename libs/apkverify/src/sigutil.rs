@@ -24,7 +24,8 @@ use openssl::hash::{DigestBytes, Hasher, MessageDigest};
 use std::cmp::min;
 use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Take};
 
-use crate::algorithms::SignatureAlgorithmID;
+use crate::algorithms::{ContentDigestAlgorithm, SignatureAlgorithmID};
+use crate::hashtree::HashTree;
 
 const APK_SIG_BLOCK_MIN_SIZE: u32 = 32;
 const APK_SIG_BLOCK_MAGIC: u128 = 0x3234206b636f6c4220676953204b5041;
@@ -33,6 +34,8 @@ const CHUNK_SIZE_BYTES: u64 = 1024 * 1024;
 const CHUNK_HEADER_TOP: &[u8] = &[0x5a];
 const CHUNK_HEADER_MID: &[u8] = &[0xa5];
 
+const VERITY_BLOCK_SIZE: usize = 4096;
+
 /// The [APK structure] has four major sections:
 ///
 /// | Zip contents | APK Signing Block | Central directory | EOCD(End of Central Directory) |
@@ -42,6 +45,9 @@ const CHUNK_HEADER_MID: &[u8] = &[0xa5];
 /// [APK structure]: https://source.android.com/docs/security/apksigning/v2#apk-signing-block
 pub struct ApkSections<R> {
     inner: R,
+    /// The APK Signing Block, the central directory and the EOCD, when read with
+    /// `DigestMode::Streaming`.
+    tail: Option<Vec<u8>>,
     signing_block_offset: u32,
     signing_block_size: u32,
     central_directory_offset: u32,
@@ -50,13 +56,38 @@ pub struct ApkSections<R> {
     eocd_size: u32,
 }
 
+/// How the sections of an APK are read when computing its content digests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DigestMode {
+    /// Each section is read from the source whenever it is needed.
+    #[default]
+    Seek,
+    /// The APK Signing Block, the central directory and the EOCD are read into memory once, when
+    /// the APK is opened. Digests are then computed by reading the zip entries sequentially,
+    /// without reading the central directory from the source again. This suits sources where
+    /// reads are expensive, such as block devices.
+    Streaming,
+}
+
 impl<R: Read + Seek> ApkSections<R> {
-    pub fn new(mut reader: R) -> Result<ApkSections<R>> {
+    pub fn new(mut reader: R, mode: DigestMode) -> Result<ApkSections<R>> {
         let zip_sections = zip_sections(&mut reader)?;
         let (signing_block_offset, signing_block_size) =
             find_signing_block(&mut reader, zip_sections.central_directory_offset)?;
+        let tail = match mode {
+            DigestMode::Seek => None,
+            DigestMode::Streaming => {
+                let tail_size =
+                    zip_sections.eocd_offset + zip_sections.eocd_size - signing_block_offset;
+                let mut tail = vec![0u8; tail_size as usize];
+                reader.seek(SeekFrom::Start(signing_block_offset as u64))?;
+                reader.read_exact(&mut tail)?;
+                Some(tail)
+            }
+        };
         Ok(ApkSections {
             inner: reader,
+            tail,
             signing_block_offset,
             signing_block_size,
             central_directory_offset: zip_sections.central_directory_offset,
@@ -81,10 +112,18 @@ impl<R: Read + Seek> ApkSections<R> {
     ///    order the chunks appear in the APK.
     ///
     /// (see https://source.android.com/security/apksigning/v2#integrity-protected-contents)
+    ///
+    /// For the verity algorithms, the digest is computed as described in `compute_verity_digest`
+    /// instead.
     pub(crate) fn compute_digest(
         &mut self,
         signature_algorithm_id: SignatureAlgorithmID,
     ) -> Result<Vec<u8>> {
+        if signature_algorithm_id.content_digest_algorithm()
+            == ContentDigestAlgorithm::VerityChunkedSha256
+        {
+            return self.compute_verity_digest();
+        }
         let digester = Digester { message_digest: signature_algorithm_id.new_message_digest() };
         let mut digests_of_chunks = BytesMut::new();
         let mut chunk_count = 0u32;
@@ -108,11 +147,42 @@ impl<R: Read + Seek> ApkSections<R> {
         Ok(digester.digest(&digests_of_chunks, CHUNK_HEADER_TOP, chunk_count)?.as_ref().into())
     }
 
+    /// Computes the digest of the verity algorithms over APK contents, central directory, and
+    /// EOCD. The digest is the root hash of the fs-verity merkle tree of the three sections (using
+    /// SHA2-256 over 4 KB blocks and no salt), followed by their total size as a little-endian
+    /// uint64.
+    fn compute_verity_digest(&mut self) -> Result<Vec<u8>> {
+        let mut eocd = vec![];
+        self.eocd_for_verification()?.read_to_end(&mut eocd)?;
+        let central_directory =
+            self.bytes(self.central_directory_offset, self.central_directory_size)?;
+        let size = self.signing_block_offset as usize + central_directory.len() + eocd.len();
+        let mut data =
+            self.zip_entries()?.chain(Cursor::new(central_directory)).chain(Cursor::new(eocd));
+        let message_digest = MessageDigest::sha256();
+        let mut root_hash =
+            HashTree::from(&mut data, size, &[], VERITY_BLOCK_SIZE, message_digest)?.root_hash;
+        if size <= VERITY_BLOCK_SIZE {
+            // Unlike fs-verity, apksig always builds at least one level of the merkle tree.
+            let mut hasher = Hasher::new(message_digest)?;
+            hasher.update(&root_hash)?;
+            hasher.update(&vec![0; VERITY_BLOCK_SIZE - root_hash.len()])?;
+            root_hash = hasher.finish()?.to_vec();
+        }
+        root_hash.extend_from_slice(&(size as u64).to_le_bytes());
+        Ok(root_hash)
+    }
+
     fn zip_entries(&mut self) -> Result<Take<Box<dyn Read + '_>>> {
         scoped_read(&mut self.inner, 0, self.signing_block_offset as u64)
     }
 
     fn central_directory(&mut self) -> Result<Take<Box<dyn Read + '_>>> {
+        if let Some(tail) = &self.tail {
+            let start = (self.central_directory_offset - self.signing_block_offset) as usize;
+            let central_directory = &tail[start..start + self.central_directory_size as usize];
+            return Ok(Read::take(Box::new(central_directory), self.central_directory_size as u64));
+        }
         scoped_read(
             &mut self.inner,
             self.central_directory_offset as u64,
@@ -133,6 +203,11 @@ impl<R: Read + Seek> ApkSections<R> {
     }
 
     fn bytes(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        if let Some(tail) = &self.tail {
+            // All the sections but the zip entries are in the tail.
+            let start = (offset - self.signing_block_offset) as usize;
+            return Ok(tail[start..start + size as usize].to_vec());
+        }
         self.inner.seek(SeekFrom::Start(offset as u64))?;
         let mut buf = vec![0u8; size as usize];
         self.inner.read_exact(&mut buf)?;
@@ -233,17 +308,45 @@ fn find_signature_scheme_block(buf: Bytes, block_id: u32) -> Result<Bytes> {
 mod tests {
     use super::*;
     use byteorder::LittleEndian;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::mem::size_of_val;
+    use std::ops::Range;
 
     use crate::v3::APK_SIGNATURE_SCHEME_V3_BLOCK_ID;
 
     const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
 
+    /// Records the ranges of the in-memory APK that are read.
+    struct RecordingReader {
+        inner: Cursor<Vec<u8>>,
+        reads: Vec<Range<u64>>,
+    }
+
+    impl RecordingReader {
+        fn new(apk_path: &str) -> Self {
+            Self { inner: Cursor::new(fs::read(apk_path).unwrap()), reads: vec![] }
+        }
+    }
+
+    impl Read for RecordingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let start = self.inner.position();
+            let size = self.inner.read(buf)?;
+            self.reads.push(start..start + size as u64);
+            Ok(size)
+        }
+    }
+
+    impl Seek for RecordingReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
     #[test]
     fn test_apk_sections() {
         let apk_file = File::open("tests/data/v3-only-with-ecdsa-sha512-p521.apk").unwrap();
-        let apk_sections = ApkSections::new(apk_file).unwrap();
+        let apk_sections = ApkSections::new(apk_file, DigestMode::Seek).unwrap();
         let mut reader = &apk_sections.inner;
 
         // Checks APK Signing Block.
@@ -274,7 +377,7 @@ mod tests {
     #[test]
     fn test_apk_digest() {
         let apk_file = File::open("tests/data/v3-only-with-dsa-sha256-1024.apk").unwrap();
-        let mut apk_sections = ApkSections::new(apk_file).unwrap();
+        let mut apk_sections = ApkSections::new(apk_file, DigestMode::Seek).unwrap();
         let digest = apk_sections.compute_digest(SignatureAlgorithmID::DsaWithSha256).unwrap();
         assert_eq!(
             "0df2426ea33aedaf495d88e5be0c6a1663ff0a81c5ed12d5b2929ae4b4300f2f",
@@ -282,10 +385,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_apk_verity_digest() {
+        let apk_file = File::open("tests/data/v3-only-with-dsa-sha256-1024.apk").unwrap();
+        let mut apk_sections = ApkSections::new(apk_file, DigestMode::Seek).unwrap();
+        let digest =
+            apk_sections.compute_digest(SignatureAlgorithmID::VerityDsaWithSha256).unwrap();
+        assert_eq!(
+            "d060b9ff6181f47ebaabbf6e25033ba114c950d627203a91565df8fe96a18fae\
+            d020000000000000",
+            hex::encode(&digest[..])
+        );
+    }
+
+    #[test]
+    fn test_apk_digest_streaming() {
+        let apk_path = "tests/data/v3-only-with-rsa-pkcs1-sha512-4096.apk";
+        let apk = Cursor::new(fs::read(apk_path).unwrap());
+        let mut seek_sections = ApkSections::new(apk, DigestMode::Seek).unwrap();
+        let apk = Cursor::new(fs::read(apk_path).unwrap());
+        let mut streaming_sections = ApkSections::new(apk, DigestMode::Streaming).unwrap();
+
+        for algorithm_id in [
+            SignatureAlgorithmID::RsaPkcs1V15WithSha256,
+            SignatureAlgorithmID::RsaPkcs1V15WithSha512,
+            SignatureAlgorithmID::VerityRsaPkcs1V15WithSha256,
+        ] {
+            assert_eq!(
+                seek_sections.compute_digest(algorithm_id).unwrap(),
+                streaming_sections.compute_digest(algorithm_id).unwrap(),
+                "Digest mismatch for {:?}",
+                algorithm_id
+            );
+        }
+        assert_eq!(
+            seek_sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID).unwrap(),
+            streaming_sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_apk_digest_streaming_only_reads_zip_entries() {
+        let reader = RecordingReader::new("tests/data/v3-only-with-ecdsa-sha512-p521.apk");
+        let mut apk_sections = ApkSections::new(reader, DigestMode::Streaming).unwrap();
+        apk_sections.inner.reads.clear();
+
+        apk_sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID).unwrap();
+        apk_sections.compute_digest(SignatureAlgorithmID::EcdsaWithSha512).unwrap();
+
+        let zip_entries = 0..apk_sections.signing_block_offset as u64;
+        let reads = &apk_sections.inner.reads;
+        assert!(!reads.is_empty());
+        assert!(
+            reads.iter().all(|r| zip_entries.start <= r.start && r.end <= zip_entries.end),
+            "Reads {:?} should be within the zip entries {:?}",
+            reads,
+            zip_entries
+        );
+    }
+
     #[test]
     fn test_apk_sections_cannot_find_signature() {
         let apk_file = File::open("tests/data/v2-only-two-signers.apk").unwrap();
-        let mut apk_sections = ApkSections::new(apk_file).unwrap();
+        let mut apk_sections = ApkSections::new(apk_file, DigestMode::Seek).unwrap();
         let result = apk_sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID);
 
         assert!(result.is_err());
@@ -301,7 +463,7 @@ mod tests {
     #[test]
     fn test_apk_sections_find_signature() {
         let apk_file = File::open("tests/data/v3-only-with-dsa-sha256-1024.apk").unwrap();
-        let mut apk_sections = ApkSections::new(apk_file).unwrap();
+        let mut apk_sections = ApkSections::new(apk_file, DigestMode::Seek).unwrap();
         let signature = apk_sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID).unwrap();
 
         let expected_v3_signature_block_size = 1289; // Only for this specific APK
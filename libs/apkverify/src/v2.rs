@@ -0,0 +1,83 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verifies APK Signature Scheme V2
+//!
+//! The v2 block is only used when the APK doesn't have a v3 block. Its format is the same as the
+//! v3 format, except that neither the signer nor the signed data have an SDK range.
+//!
+//! [v2 verification]: https://source.android.com/docs/security/apksigning/v2#v2-verification
+
+use anyhow::{ensure, Result};
+use bytes::{Buf, Bytes};
+use std::io::{Read, Seek};
+
+use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+use crate::sigutil::ApkSections;
+use crate::v3::{SignatureScheme, SignedData, Signer};
+
+pub const APK_SIGNATURE_SCHEME_V2_BLOCK_ID: u32 = 0x7109871a;
+
+/// ID of the additional attribute in the v2 signed data which lists the newer signature schemes
+/// the APK was also signed with. See `V2SchemeConstants.STRIPPING_PROTECTION_ATTR_ID` in apksig.
+const STRIPPING_PROTECTION_ATTR_ID: u32 = 0xbeeff00d;
+const SIGNATURE_SCHEME_V3_ID: u32 = 3;
+
+type V2Signers = LengthPrefixed<Vec<LengthPrefixed<V2Signer>>>;
+
+/// A v2 signer, which is read into the same `Signer` struct as v3 signers.
+struct V2Signer(Signer);
+
+/// Returns the v2 signers of the APK, if the APK has a v2 block.
+pub(crate) fn extract_signers<R: Read + Seek>(
+    sections: &mut ApkSections<R>,
+) -> Result<Vec<Signer>> {
+    let mut block = sections.find_signature(APK_SIGNATURE_SCHEME_V2_BLOCK_ID)?;
+    let signers = block.read::<V2Signers>()?.into_inner();
+    Ok(signers.into_iter().map(|s| s.into_inner().0).collect())
+}
+
+/// Reads the v2 signed data, which doesn't contain the min and max SDK versions.
+pub(crate) fn read_signed_data(buf: &mut Bytes) -> Result<SignedData> {
+    Ok(SignedData::new_v2(buf.read()?, buf.read()?, buf.read()?))
+}
+
+/// Fails if the signed data claims that the APK was also signed with APK Signature Scheme v3.
+/// This must only be called when the v3 block could not be found, i.e. it has been stripped.
+pub(crate) fn check_stripping_protection(signed_data: &SignedData) -> Result<()> {
+    if let Some(mut attr) = signed_data.find_additional_attribute(STRIPPING_PROTECTION_ATTR_ID) {
+        ensure!(attr.remaining() >= 4, "Malformed stripping protection attribute");
+        ensure!(
+            attr.get_u32_le() != SIGNATURE_SCHEME_V3_ID,
+            "APK was signed with APK Signature Scheme v3 but the v3 block was stripped"
+        );
+    }
+    Ok(())
+}
+
+impl ReadFromBytes for V2Signer {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        // v2 signers don't have an SDK range; they apply to all SDK versions.
+        Ok(Self(Signer {
+            scheme: SignatureScheme::V2,
+            signed_data: buf.read()?,
+            min_sdk: 0,
+            max_sdk: u32::MAX,
+            signatures: buf.read()?,
+            public_key: buf.read()?,
+        }))
+    }
+}
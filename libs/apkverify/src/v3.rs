@@ -14,45 +14,73 @@
  * limitations under the License.
  */
 
-//! Verifies APK Signature Scheme V3
+//! Verifies APK Signature Scheme V3 and V3.1
 //!
 //! [v3 verification]: https://source.android.com/security/apksigning/v3#verification
+//!
+//! The v3.1 block has the same format as the v3 block. It is used to target the key rotation to
+//! a given SDK version, and takes precedence over the v3 block on the SDK versions it supports.
 
 use anyhow::{ensure, Context, Result};
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
 use openssl::pkey::{self, PKey};
 use openssl::x509::X509;
 use std::fs::File;
-use std::io::{Read, Seek};
+use std::io::{self, ErrorKind, Read, Seek};
 use std::ops::RangeInclusive;
 use std::path::Path;
 
-use crate::algorithms::SignatureAlgorithmID;
+use crate::algorithms::{AlgorithmPolicy, SignatureAlgorithmID};
 use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
-use crate::sigutil::ApkSections;
+use crate::lineage::{SigningCertificateLineage, PROOF_OF_ROTATION_ATTR_ID};
+use crate::sigutil::{ApkSections, DigestMode};
+use crate::v2;
 
 pub const APK_SIGNATURE_SCHEME_V3_BLOCK_ID: u32 = 0xf05368c0;
+pub const APK_SIGNATURE_SCHEME_V31_BLOCK_ID: u32 = 0x1b93ad61;
+
+/// ID of the additional attribute in the v3 signed data holding the min SDK version of the v3.1
+/// signers. See `V3SchemeConstants.ROTATION_MIN_SDK_VERSION_ATTR_ID` in apksig.
+const ROTATION_MIN_SDK_VERSION_ATTR_ID: u32 = 0x559f8b02;
+/// ID of the additional attribute in the v3.1 signed data which tells that the signer targets the
+/// development release of its min SDK version. See
+/// `V3SchemeConstants.ROTATION_ON_DEV_RELEASE_ATTR_ID` in apksig.
+const ROTATION_ON_DEV_RELEASE_ATTR_ID: u32 = 0xc2a6b3ba;
 
 type Signers = LengthPrefixed<Vec<LengthPrefixed<Signer>>>;
 
+/// The APK Signature Scheme that an APK was verified with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// [APK Signature Scheme v2](https://source.android.com/docs/security/apksigning/v2)
+    V2,
+    /// [APK Signature Scheme v3](https://source.android.com/docs/security/apksigning/v3)
+    V3,
+    /// APK Signature Scheme v3.1, i.e. the v3 scheme with SDK-targeted key rotation.
+    V31,
+}
+
 #[derive(Debug)]
 pub(crate) struct Signer {
-    signed_data: LengthPrefixed<Bytes>, // not verified yet
-    min_sdk: u32,
-    max_sdk: u32,
-    signatures: LengthPrefixed<Vec<LengthPrefixed<Signature>>>,
-    public_key: PKey<pkey::Public>,
+    pub(crate) scheme: SignatureScheme,
+    pub(crate) signed_data: LengthPrefixed<Bytes>, // not verified yet
+    pub(crate) min_sdk: u32,
+    pub(crate) max_sdk: u32,
+    pub(crate) signatures: LengthPrefixed<Vec<LengthPrefixed<Signature>>>,
+    pub(crate) public_key: PKey<pkey::Public>,
 }
 
-/// Contains the signed data part of an APK v3 signature.
+/// Contains the signed data part of an APK v2, v3 or v3.1 signature.
 #[derive(Debug)]
 pub struct SignedData {
+    scheme: SignatureScheme,
     digests: LengthPrefixed<Vec<LengthPrefixed<Digest>>>,
     certificates: LengthPrefixed<Vec<LengthPrefixed<X509Certificate>>>,
     min_sdk: u32,
     max_sdk: u32,
-    #[allow(dead_code)]
     additional_attributes: LengthPrefixed<Vec<LengthPrefixed<AdditionalAttributes>>>,
+    /// Only set once the signed data and the proof-of-rotation attribute have been verified.
+    lineage: Option<SigningCertificateLineage>,
 }
 
 #[derive(Debug)]
@@ -63,7 +91,7 @@ pub(crate) struct Signature {
 }
 
 #[derive(Debug)]
-struct Digest {
+pub(crate) struct Digest {
     signature_algorithm_id: Option<SignatureAlgorithmID>,
     digest: LengthPrefixed<Bytes>,
 }
@@ -71,38 +99,189 @@ struct Digest {
 type X509Certificate = Bytes;
 type AdditionalAttributes = Bytes;
 
-/// Verifies APK Signature Scheme v3 signatures of the provided APK and returns the SignedData from
-/// the signature.
+/// Contains the signed data of all the signers of an APK that support the current SDK version.
+#[derive(Debug)]
+pub struct MultiSignerData {
+    scheme: SignatureScheme,
+    signers: Vec<SignedData>,
+}
+
+impl MultiSignerData {
+    /// Returns the APK Signature Scheme the signers belong to.
+    pub fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+
+    /// Returns the signed data of each signer, in the order they appear in the APK.
+    pub fn signers(&self) -> &[SignedData] {
+        &self.signers
+    }
+
+    /// Returns the signing certificate of each signer, encoded in DER form.
+    pub fn certificates_der(&self) -> Result<Vec<&[u8]>> {
+        self.signers.iter().map(SignedData::first_certificate_der).collect()
+    }
+}
+
+/// Verifies the APK Signature Scheme v3.1 or v3 signatures of the provided APK, falling back to
+/// the v2 signatures when the APK has no v3 block, and returns the SignedData from the signature.
+/// `SignedData::scheme` tells which scheme was used. The APK must have exactly one signer
+/// supporting `current_sdk`; use `verify_all_signers` for APKs with more than one signer.
 pub fn verify<P: AsRef<Path>>(apk_path: P, current_sdk: u32) -> Result<SignedData> {
+    verify_with_policy(apk_path, current_sdk, &AlgorithmPolicy::default())
+}
+
+/// Same as `verify`, except that only the signatures acceptable under `policy` are considered.
+pub fn verify_with_policy<P: AsRef<Path>>(
+    apk_path: P,
+    current_sdk: u32,
+    policy: &AlgorithmPolicy,
+) -> Result<SignedData> {
     let apk = File::open(apk_path.as_ref())?;
-    let (signer, mut sections) = extract_signer_and_apk_sections(apk, current_sdk)?;
-    signer.verify(&mut sections)
+    let (signer, mut sections) =
+        extract_signer_and_apk_sections(apk, current_sdk, DigestMode::Seek)?;
+    signer.verify(&mut sections, policy)
+}
+
+/// Same as `verify`, except that the APK is read from `apk`, and the APK sections are read as
+/// specified by `mode` when computing the digest.
+pub fn verify_reader<R: Read + Seek>(
+    apk: R,
+    current_sdk: u32,
+    mode: DigestMode,
+) -> Result<SignedData> {
+    let (signer, mut sections) = extract_signer_and_apk_sections(apk, current_sdk, mode)?;
+    signer.verify(&mut sections, &AlgorithmPolicy::default())
 }
 
 /// Extracts the SignedData from the signature of the given APK. (The signature is not verified.)
 pub fn extract_signed_data<P: AsRef<Path>>(apk_path: P, current_sdk: u32) -> Result<SignedData> {
     let apk = File::open(apk_path.as_ref())?;
-    let (signer, _) = extract_signer_and_apk_sections(apk, current_sdk)?;
+    extract_signed_data_from_reader(apk, current_sdk)
+}
+
+/// Same as `extract_signed_data`, except that the APK is read from `apk`.
+pub fn extract_signed_data_from_reader<R: Read + Seek>(
+    apk: R,
+    current_sdk: u32,
+) -> Result<SignedData> {
+    let (signer, _) = extract_signer_and_apk_sections(apk, current_sdk, DigestMode::Seek)?;
     signer.parse_signed_data()
 }
 
+/// Same as `verify`, except that every signer supporting `current_sdk` is verified and returned.
+pub fn verify_all_signers<P: AsRef<Path>>(
+    apk_path: P,
+    current_sdk: u32,
+) -> Result<MultiSignerData> {
+    verify_all_signers_with_policy(apk_path, current_sdk, &AlgorithmPolicy::default())
+}
+
+/// Same as `verify_all_signers`, except that only the signatures acceptable under `policy` are
+/// considered.
+pub fn verify_all_signers_with_policy<P: AsRef<Path>>(
+    apk_path: P,
+    current_sdk: u32,
+    policy: &AlgorithmPolicy,
+) -> Result<MultiSignerData> {
+    let apk = File::open(apk_path.as_ref())?;
+    let (signers, mut sections) =
+        extract_signers_and_apk_sections(apk, current_sdk, DigestMode::Seek)?;
+    let scheme = signers[0].scheme;
+    let signers =
+        signers.iter().map(|signer| signer.verify(&mut sections, policy)).collect::<Result<_>>()?;
+    Ok(MultiSignerData { scheme, signers })
+}
+
+/// Same as `extract_signed_data`, except that the SignedData of every signer supporting
+/// `current_sdk` is returned. (The signatures are not verified.)
+pub fn extract_all_signed_data<P: AsRef<Path>>(
+    apk_path: P,
+    current_sdk: u32,
+) -> Result<MultiSignerData> {
+    let apk = File::open(apk_path.as_ref())?;
+    let (signers, _) = extract_signers_and_apk_sections(apk, current_sdk, DigestMode::Seek)?;
+    let scheme = signers[0].scheme;
+    let signers = signers.iter().map(Signer::parse_signed_data).collect::<Result<_>>()?;
+    Ok(MultiSignerData { scheme, signers })
+}
+
 pub(crate) fn extract_signer_and_apk_sections<R: Read + Seek>(
     apk: R,
     current_sdk: u32,
+    mode: DigestMode,
 ) -> Result<(Signer, ApkSections<R>)> {
-    let mut sections = ApkSections::new(apk)?;
-    let mut block = sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID).context(
-        "Fallback to v2 when v3 block not found is not yet implemented.", // b/197052981
-    )?;
-    let signers = block.read::<Signers>()?.into_inner();
-    let mut supported =
-        signers.into_iter().filter(|s| s.sdk_range().contains(&current_sdk)).collect::<Vec<_>>();
+    let (mut signers, sections) = extract_signers_and_apk_sections(apk, current_sdk, mode)?;
     ensure!(
-        supported.len() == 1,
-        "APK Signature Scheme V3 only supports one signer: {} signers found.",
-        supported.len()
+        signers.len() == 1,
+        "APK Signature Scheme {:?} only supports one signer: {} signers found.",
+        signers[0].scheme,
+        signers.len()
     );
-    Ok((supported.pop().unwrap().into_inner(), sections))
+    Ok((signers.pop().unwrap(), sections))
+}
+
+/// Returns the signers supporting `current_sdk` from the v3.1 block, or from the v3 block if the
+/// v3.1 block has none, or from the v2 block if the APK has no v3 block. At least one signer is
+/// returned.
+pub(crate) fn extract_signers_and_apk_sections<R: Read + Seek>(
+    apk: R,
+    current_sdk: u32,
+    mode: DigestMode,
+) -> Result<(Vec<Signer>, ApkSections<R>)> {
+    let mut sections = ApkSections::new(apk, mode)?;
+    let v31_signers = find_signers(&mut sections, SignatureScheme::V31, current_sdk)?;
+    let v31_block_found = v31_signers.is_some();
+    if let Some(signers) = v31_signers.filter(|signers| !signers.is_empty()) {
+        return Ok((signers, sections));
+    }
+    let Some(signers) = find_signers(&mut sections, SignatureScheme::V3, current_sdk)? else {
+        let signers = v2::extract_signers(&mut sections)?;
+        ensure!(!signers.is_empty(), "APK Signature Scheme V2 has no signers: 0 signers found.");
+        return Ok((signers, sections));
+    };
+    ensure!(
+        !signers.is_empty(),
+        "APK Signature Scheme V3 has no signer supporting SDK {}: 0 signers found.",
+        current_sdk
+    );
+    if !v31_block_found {
+        for signer in signers.iter() {
+            signer.check_v31_stripping_protection(current_sdk)?;
+        }
+    }
+    Ok((signers, sections))
+}
+
+/// Returns the signers supporting `current_sdk` in the block of the given v3 or v3.1 scheme, or
+/// `None` if the APK doesn't have the block.
+fn find_signers<R: Read + Seek>(
+    sections: &mut ApkSections<R>,
+    scheme: SignatureScheme,
+    current_sdk: u32,
+) -> Result<Option<Vec<Signer>>> {
+    let block_id = match scheme {
+        SignatureScheme::V31 => APK_SIGNATURE_SCHEME_V31_BLOCK_ID,
+        _ => APK_SIGNATURE_SCHEME_V3_BLOCK_ID,
+    };
+    let mut block = match sections.find_signature(block_id) {
+        Ok(block) => block,
+        Err(e) if is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut supported = vec![];
+    for signer in block.read::<Signers>()?.into_inner() {
+        let mut signer = signer.into_inner();
+        signer.scheme = scheme;
+        if signer.supports_sdk(current_sdk)? {
+            supported.push(signer);
+        }
+    }
+    Ok(Some(supported))
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == ErrorKind::NotFound)
 }
 
 impl Signer {
@@ -110,22 +289,64 @@ impl Signer {
         self.min_sdk..=self.max_sdk
     }
 
-    /// Selects the signature that has the strongest supported `SignatureAlgorithmID`.
+    /// Returns whether the signer should be used on `current_sdk`. A v3.1 signer targeting the
+    /// development release of its min SDK version is not used on the release of that version. As
+    /// the SDK version doesn't tell whether the platform is a development release, it is always
+    /// considered a release.
+    fn supports_sdk(&self, current_sdk: u32) -> Result<bool> {
+        if !self.sdk_range().contains(&current_sdk) {
+            return Ok(false);
+        }
+        if self.scheme == SignatureScheme::V31 && current_sdk == self.min_sdk {
+            let signed_data = self.parse_signed_data()?;
+            return Ok(signed_data
+                .find_additional_attribute(ROTATION_ON_DEV_RELEASE_ATTR_ID)
+                .is_none());
+        }
+        Ok(true)
+    }
+
+    /// Fails if the v3 signer claims that the APK has v3.1 signers supporting `current_sdk`. This
+    /// must only be called when the v3.1 block could not be found, i.e. it has been stripped.
+    fn check_v31_stripping_protection(&self, current_sdk: u32) -> Result<()> {
+        let signed_data = self.parse_signed_data()?;
+        if let Some(mut attr) =
+            signed_data.find_additional_attribute(ROTATION_MIN_SDK_VERSION_ATTR_ID)
+        {
+            ensure!(attr.remaining() >= 4, "Malformed rotation min SDK version attribute");
+            let rotation_min_sdk = attr.get_u32_le();
+            ensure!(
+                current_sdk < rotation_min_sdk,
+                "APK was signed with APK Signature Scheme v3.1 targeting SDK {} but the v3.1 \
+                block was stripped",
+                rotation_min_sdk
+            );
+        }
+        Ok(())
+    }
+
+    /// Selects the signature that has the strongest supported `SignatureAlgorithmID` among the
+    /// ones acceptable under `policy`.
     /// The strongest signature is used in both v3 verification and v4 apk digest computation.
-    pub(crate) fn strongest_signature(&self) -> Result<&Signature> {
-        Ok(self
+    pub(crate) fn strongest_signature(&self, policy: &AlgorithmPolicy) -> Result<&Signature> {
+        let supported = self
             .signatures
             .iter()
-            .filter(|sig| sig.signature_algorithm_id.map_or(false, |algo| algo.is_supported()))
+            .filter(|sig| sig.signature_algorithm_id.is_some())
+            .collect::<Vec<_>>();
+        ensure!(!supported.is_empty(), "No supported APK signatures found");
+        Ok(supported
+            .into_iter()
+            .filter(|sig| policy.allows(sig.signature_algorithm_id.unwrap(), &self.public_key))
             .max_by_key(|sig| sig.signature_algorithm_id.unwrap().content_digest_algorithm())
-            .context("No supported APK signatures found; DSA is not supported")?)
+            .context("No APK signatures acceptable under the algorithm policy found")?)
     }
 
     pub(crate) fn find_digest_by_algorithm(
         &self,
         algorithm_id: SignatureAlgorithmID,
     ) -> Result<Box<[u8]>> {
-        let signed_data: SignedData = self.signed_data.slice(..).read()?;
+        let signed_data = self.parse_signed_data()?;
         let digest = signed_data.find_digest_by_algorithm(algorithm_id)?;
         Ok(digest.digest.as_ref().to_vec().into_boxed_slice())
     }
@@ -143,20 +364,34 @@ impl Signer {
 
     /// Returns the signed data, converted from bytes.
     fn parse_signed_data(&self) -> Result<SignedData> {
-        self.signed_data.slice(..).read()
+        let mut buf = self.signed_data.slice(..);
+        match self.scheme {
+            SignatureScheme::V2 => v2::read_signed_data(&mut buf),
+            SignatureScheme::V3 | SignatureScheme::V31 => {
+                let mut signed_data: SignedData = buf.read()?;
+                signed_data.scheme = self.scheme;
+                Ok(signed_data)
+            }
+        }
     }
 
-    /// The steps in this method implements APK Signature Scheme v3 verification step 3.
-    fn verify<R: Read + Seek>(&self, sections: &mut ApkSections<R>) -> Result<SignedData> {
+    /// The steps in this method implements APK Signature Scheme v3 verification step 3. The v2
+    /// verification is the same, except that v2 signers do not have an SDK range and that step 8
+    /// is replaced by the check against v3 signature stripping.
+    fn verify<R: Read + Seek>(
+        &self,
+        sections: &mut ApkSections<R>,
+        policy: &AlgorithmPolicy,
+    ) -> Result<SignedData> {
         // 1. Choose the strongest supported signature algorithm ID from signatures.
-        let strongest = self.strongest_signature()?;
+        let strongest = self.strongest_signature(policy)?;
 
         // 2. Verify the corresponding signature from signatures against signed data using public
         // key.
         self.verify_signature(strongest)?;
 
         // It is now safe to parse signed data.
-        let verified_signed_data = self.parse_signed_data()?;
+        let mut verified_signed_data = self.parse_signed_data()?;
 
         // 3. Verify the min and max SDK versions in the signed data match those specified for the
         //    signer.
@@ -198,26 +433,80 @@ impl Signer {
             "Public key mismatch between certificate and signature record"
         );
 
-        // TODO(b/245914104)
         // 8. If the proof-of-rotation attribute exists for the signer verify that the
         // struct is valid and this signer is the last certificate in the list.
+        if self.scheme != SignatureScheme::V2 {
+            if let Some(attr) =
+                verified_signed_data.find_additional_attribute(PROOF_OF_ROTATION_ATTR_ID)
+            {
+                let lineage = SigningCertificateLineage::verify(attr)?;
+                ensure!(
+                    lineage.last_certificate_der()
+                        == verified_signed_data.first_certificate_der()?,
+                    "Terminal certificate in the proof-of-rotation record does not match the APK \
+                    signing certificate"
+                );
+                verified_signed_data.lineage = Some(lineage);
+            }
+        }
+
+        // For v2, verify that the APK was not stripped of a v3 signature.
+        if self.scheme == SignatureScheme::V2 {
+            v2::check_stripping_protection(&verified_signed_data)?;
+        }
 
         Ok(verified_signed_data)
     }
 }
 
 impl SignedData {
+    pub(crate) fn new_v2(
+        digests: LengthPrefixed<Vec<LengthPrefixed<Digest>>>,
+        certificates: LengthPrefixed<Vec<LengthPrefixed<X509Certificate>>>,
+        additional_attributes: LengthPrefixed<Vec<LengthPrefixed<AdditionalAttributes>>>,
+    ) -> Self {
+        // v2 signers don't have an SDK range; they apply to all SDK versions.
+        Self {
+            scheme: SignatureScheme::V2,
+            digests,
+            certificates,
+            min_sdk: 0,
+            max_sdk: u32::MAX,
+            additional_attributes,
+            lineage: None,
+        }
+    }
+
+    /// Returns the APK Signature Scheme this signed data belongs to.
+    pub fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+
     /// Returns the first X.509 certificate in the signed data, encoded in DER form. (All other
-    /// certificates are ignored for v3; this certificate describes the public key that was actually
-    /// used to sign the APK.)
+    /// certificates are ignored for v2/v3; this certificate describes the public key that was
+    /// actually used to sign the APK.)
     pub fn first_certificate_der(&self) -> Result<&[u8]> {
         Ok(self.certificates.first().context("No certificates listed")?)
     }
 
+    /// Returns the verified signing certificate lineage from the proof-of-rotation attribute, if
+    /// the signer has one. This is always `None` for signed data that wasn't verified.
+    pub fn lineage(&self) -> Option<&SigningCertificateLineage> {
+        self.lineage.as_ref()
+    }
+
     fn sdk_range(&self) -> RangeInclusive<u32> {
         self.min_sdk..=self.max_sdk
     }
 
+    /// Returns the value of the additional attribute with the given ID, if any.
+    pub(crate) fn find_additional_attribute(&self, id: u32) -> Option<Bytes> {
+        self.additional_attributes.iter().find_map(|attr| {
+            let mut attr = attr.slice(..);
+            (attr.remaining() >= 4 && attr.get_u32_le() == id).then_some(attr)
+        })
+    }
+
     fn find_digest_by_algorithm(&self, algorithm_id: SignatureAlgorithmID) -> Result<&Digest> {
         Ok(self
             .digests
@@ -233,6 +522,7 @@ impl SignedData {
 impl ReadFromBytes for Signer {
     fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
         Ok(Self {
+            scheme: SignatureScheme::V3,
             signed_data: buf.read()?,
             min_sdk: buf.read()?,
             max_sdk: buf.read()?,
@@ -245,11 +535,13 @@ impl ReadFromBytes for Signer {
 impl ReadFromBytes for SignedData {
     fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
         Ok(Self {
+            scheme: SignatureScheme::V3,
             digests: buf.read()?,
             certificates: buf.read()?,
             min_sdk: buf.read()?,
             max_sdk: buf.read()?,
             additional_attributes: buf.read()?,
+            lineage: None,
         })
     }
 }
@@ -22,12 +22,15 @@ use anyhow::{anyhow, bail, ensure, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
 use std::fs;
 use std::io::{copy, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-use crate::algorithms::{HashAlgorithm, SignatureAlgorithmID};
+use crate::algorithms::{AlgorithmPolicy, HashAlgorithm, SignatureAlgorithmID};
 use crate::hashtree::*;
+use crate::sigutil::DigestMode;
 use crate::v3::extract_signer_and_apk_sections;
 
 /// Gets the v4 [apk_digest]. If `verify` is true, we verify that digest computed
@@ -40,9 +43,21 @@ pub fn get_apk_digest<R: Read + Seek>(
     current_sdk: u32,
     verify: bool,
 ) -> Result<(SignatureAlgorithmID, Box<[u8]>)> {
-    let (signer, mut sections) = extract_signer_and_apk_sections(apk, current_sdk)?;
+    get_apk_digest_with_policy(apk, current_sdk, verify, &AlgorithmPolicy::default())
+}
+
+/// Same as `get_apk_digest`, except that the digest is picked among the signatures acceptable
+/// under `policy`.
+pub fn get_apk_digest_with_policy<R: Read + Seek>(
+    apk: R,
+    current_sdk: u32,
+    verify: bool,
+    policy: &AlgorithmPolicy,
+) -> Result<(SignatureAlgorithmID, Box<[u8]>)> {
+    let (signer, mut sections) =
+        extract_signer_and_apk_sections(apk, current_sdk, DigestMode::Seek)?;
     let strongest_algorithm_id = signer
-        .strongest_signature()?
+        .strongest_signature(policy)?
         .signature_algorithm_id
         .context("Strongest signature should contain a valid signature algorithm.")?;
     let extracted_digest = signer.find_digest_by_algorithm(strongest_algorithm_id)?;
@@ -104,6 +119,16 @@ pub struct SigningInfo {
     pub signature: Box<[u8]>,
 }
 
+/// `V4Signer` holds what is needed to sign an idsig file.
+pub struct V4Signer {
+    /// Private key of the signer.
+    pub private_key: PKey<Private>,
+    /// Certificate of the signer. Its public key must match `private_key`.
+    pub certificate: X509,
+    /// A free-form binary data that is signed along with the idsig file.
+    pub additional_data: Vec<u8>,
+}
+
 /// Version of the idsig file format
 #[derive(Debug, PartialEq, Eq, FromPrimitive, ToPrimitive, Default)]
 #[repr(u32)]
@@ -141,7 +166,7 @@ impl<R: Read + Seek> V4Signature<R> {
     }
 
     /// Read a stream for an APK file and creates a corresponding `V4Signature` struct that digests
-    /// the APK file. Note that the signing is not done.
+    /// the APK file. Note that the signing is not done, see `create_signed` for that.
     /// Important: callers of this function are expected to verify the validity of the passed |apk|.
     /// To be more specific, they should check that |apk| corresponds to a regular file, as calling
     /// lseek on directory fds is not defined in the standard, and on ext4 it will return (off_t)-1
@@ -181,11 +206,89 @@ impl<R: Read + Seek> V4Signature<R> {
             get_apk_digest(apk, current_sdk, /*verify=*/ false)?;
         ret.signing_info.signature_algorithm_id = signature_algorithm_id;
         ret.signing_info.apk_digest = apk_digest;
-        // TODO(jiyong): add a signature to the signing_info struct
 
         Ok(ret)
     }
 
+    /// Same as `create`, except that the created `V4Signature` is also signed by `signer`. The
+    /// same expectations on |apk| apply.
+    pub fn create_signed(
+        apk: &mut R,
+        current_sdk: u32,
+        block_size: usize,
+        salt: &[u8],
+        algorithm: HashAlgorithm,
+        signer: &V4Signer,
+    ) -> Result<V4Signature<Cursor<Vec<u8>>>> {
+        let start = apk.stream_position()?;
+        let apk_size = apk.seek(SeekFrom::End(0))?;
+        apk.seek(SeekFrom::Start(start))?;
+
+        let mut ret = Self::create(apk, current_sdk, block_size, salt, algorithm)?;
+        ret.sign(apk_size, signer)?;
+        Ok(ret)
+    }
+
+    /// Verifies the signature in the signing info, given the size of the APK file this idsig
+    /// file is for. Note that this neither checks that the signer is trusted nor that the APK
+    /// digest matches the APK.
+    pub fn verify_signature(&self, apk_size: u64) -> Result<()> {
+        let certificate = X509::from_der(&self.signing_info.x509_certificate)
+            .context("Malformed certificate in the signing info")?;
+        let public_key = certificate.public_key()?;
+        ensure!(
+            public_key.public_key_to_der()? == self.signing_info.public_key.as_ref(),
+            "Public key mismatch between the certificate and the signing info"
+        );
+        let mut verifier = self.signing_info.signature_algorithm_id.new_verifier(&public_key)?;
+        verifier.update(&self.signed_data(apk_size)?)?;
+        ensure!(verifier.verify(&self.signing_info.signature)?, "Signature is invalid.");
+        Ok(())
+    }
+
+    /// Fills the signing info with the certificate of `signer` and its signature.
+    fn sign(&mut self, apk_size: u64, signer: &V4Signer) -> Result<()> {
+        let public_key = signer.certificate.public_key()?;
+        ensure!(
+            public_key.public_eq(&signer.private_key),
+            "Certificate does not match the private key"
+        );
+        let signature_algorithm_id = SignatureAlgorithmID::for_signing_key(&signer.private_key)?;
+
+        self.signing_info.x509_certificate = signer.certificate.to_der()?.into_boxed_slice();
+        self.signing_info.additional_data = signer.additional_data.clone().into_boxed_slice();
+        self.signing_info.public_key = public_key.public_key_to_der()?.into_boxed_slice();
+        self.signing_info.signature_algorithm_id = signature_algorithm_id;
+
+        let mut openssl_signer = signature_algorithm_id.new_signer(&signer.private_key)?;
+        openssl_signer.update(&self.signed_data(apk_size)?)?;
+        self.signing_info.signature = openssl_signer.sign_to_vec()?.into_boxed_slice();
+        Ok(())
+    }
+
+    /// Returns the data covered by the signature in the signing info. See
+    /// `V4Signature.getSignedData` in apksig.
+    fn signed_data(&self, apk_size: u64) -> Result<Vec<u8>> {
+        let mut w = Cursor::new(Vec::new());
+        // Size of the signed data. Since we don't know the size yet, fill the place with 0. The
+        // exact size will then be written below.
+        w.write_u32::<LittleEndian>(0)?;
+
+        w.write_u64::<LittleEndian>(apk_size)?;
+        w.write_u32::<LittleEndian>(self.hashing_info.hash_algorithm.to_u32().unwrap())?;
+        w.write_u8(self.hashing_info.log2_blocksize)?;
+        write_sized_array(&mut w, &self.hashing_info.salt)?;
+        write_sized_array(&mut w, &self.hashing_info.raw_root_hash)?;
+        write_sized_array(&mut w, &self.signing_info.apk_digest)?;
+        write_sized_array(&mut w, &self.signing_info.x509_certificate)?;
+        write_sized_array(&mut w, &self.signing_info.additional_data)?;
+
+        let mut data = w.into_inner();
+        let size = data.len() as u32;
+        data[..4].copy_from_slice(&size.to_le_bytes());
+        Ok(data)
+    }
+
     /// Writes the data into a writer
     pub fn write_into<W: Write + Seek>(&mut self, mut w: &mut W) -> Result<()> {
         // Writes the header part
@@ -311,10 +414,59 @@ fn log2(n: usize) -> u8 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509NameBuilder;
     use std::io::Cursor;
 
     const TEST_APK_PATH: &str = "tests/data/v4-digest-v3-Sha256withEC.apk";
 
+    fn create_signer(private_key: PKey<Private>) -> V4Signer {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "v4 signer").unwrap();
+        let name = name.build();
+
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&private_key).unwrap();
+        certificate.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        certificate.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        certificate.sign(&private_key, MessageDigest::sha256()).unwrap();
+
+        V4Signer {
+            private_key,
+            certificate: certificate.build(),
+            additional_data: b"additional data".to_vec(),
+        }
+    }
+
+    fn create_ec_signer(nid: Nid) -> V4Signer {
+        let group = EcGroup::from_curve_name(nid).unwrap();
+        create_signer(PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap())
+    }
+
+    fn create_rsa_signer(bits: u32) -> V4Signer {
+        create_signer(PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap())
+    }
+
+    /// Creates a signed idsig for the test APK, writes it and parses it back.
+    fn create_signed_and_parse(signer: &V4Signer) -> V4Signature<Cursor<Vec<u8>>> {
+        let mut input = Cursor::new(fs::read(TEST_APK_PATH).unwrap());
+        let mut created =
+            V4Signature::create_signed(&mut input, 31, 4096, &[], HashAlgorithm::SHA256, signer)
+                .unwrap();
+
+        let mut output = Cursor::new(Vec::new());
+        created.write_into(&mut output).unwrap();
+        output.set_position(0);
+        V4Signature::from_idsig(output).unwrap()
+    }
+
     #[test]
     fn parse_idsig_file() {
         let parsed = V4Signature::from_idsig_path(format!("{}.idsig", TEST_APK_PATH)).unwrap();
@@ -347,6 +499,18 @@ mod tests {
         assert_eq!(648, parsed.merkle_tree_offset);
     }
 
+    #[test]
+    fn verify_signature_of_idsig_file() {
+        let parsed = V4Signature::from_idsig_path(format!("{}.idsig", TEST_APK_PATH)).unwrap();
+        let apk_size = fs::metadata(TEST_APK_PATH).unwrap().len();
+
+        parsed.verify_signature(apk_size).unwrap();
+
+        let res = parsed.verify_signature(apk_size + 1);
+        assert!(res.is_err());
+        crate::testing::assert_contains(&res.unwrap_err().to_string(), "Signature is invalid");
+    }
+
     /// Parse an idsig file into V4Signature and write it. The written date must be the same as
     /// the input file.
     #[test]
@@ -383,4 +547,56 @@ mod tests {
             golden.merkle_tree().unwrap().as_slice()
         );
     }
+
+    /// Sign the idsig of an APK, write it and parse it back. The signing info should be
+    /// verifiable and match the signer.
+    #[test]
+    fn sign_and_parse() {
+        let apk_size = fs::metadata(TEST_APK_PATH).unwrap().len();
+        let golden = V4Signature::from_idsig_path(format!("{}.idsig", TEST_APK_PATH)).unwrap();
+
+        for (signer, signature_algorithm_id) in [
+            (create_ec_signer(Nid::X9_62_PRIME256V1), SignatureAlgorithmID::EcdsaWithSha256),
+            (create_ec_signer(Nid::SECP384R1), SignatureAlgorithmID::EcdsaWithSha512),
+            (create_rsa_signer(2048), SignatureAlgorithmID::RsaPkcs1V15WithSha256),
+            (create_rsa_signer(4096), SignatureAlgorithmID::RsaPkcs1V15WithSha512),
+        ] {
+            let parsed = create_signed_and_parse(&signer);
+            parsed.verify_signature(apk_size).unwrap();
+
+            let si = &parsed.signing_info;
+            assert_eq!(signature_algorithm_id, si.signature_algorithm_id);
+            assert_eq!(golden.signing_info.apk_digest, si.apk_digest);
+            assert_eq!(signer.certificate.to_der().unwrap(), si.x509_certificate.as_ref());
+            assert_eq!(signer.private_key.public_key_to_der().unwrap(), si.public_key.as_ref());
+            assert_eq!(signer.additional_data, si.additional_data.as_ref());
+            assert_eq!(golden.hashing_info.raw_root_hash, parsed.hashing_info.raw_root_hash);
+        }
+    }
+
+    #[test]
+    fn signed_idsig_with_tampered_additional_data_is_rejected() {
+        let apk_size = fs::metadata(TEST_APK_PATH).unwrap().len();
+        let mut parsed = create_signed_and_parse(&create_ec_signer(Nid::X9_62_PRIME256V1));
+        parsed.signing_info.additional_data = b"tampered data".to_vec().into_boxed_slice();
+
+        let res = parsed.verify_signature(apk_size);
+        assert!(res.is_err());
+        crate::testing::assert_contains(&res.unwrap_err().to_string(), "Signature is invalid");
+    }
+
+    #[test]
+    fn sign_with_mismatched_certificate_fails() {
+        let mut signer = create_ec_signer(Nid::X9_62_PRIME256V1);
+        signer.certificate = create_ec_signer(Nid::X9_62_PRIME256V1).certificate;
+
+        let mut input = Cursor::new(fs::read(TEST_APK_PATH).unwrap());
+        let res =
+            V4Signature::create_signed(&mut input, 31, 4096, &[], HashAlgorithm::SHA256, &signer);
+        assert!(res.is_err());
+        crate::testing::assert_contains(
+            &res.err().unwrap().to_string(),
+            "Certificate does not match the private key",
+        );
+    }
 }
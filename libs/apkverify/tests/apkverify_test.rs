@@ -16,14 +16,17 @@
 
 use anyhow::Result;
 use apkverify::{
-    extract_signed_data, get_apk_digest, testing::assert_contains, verify, SignatureAlgorithmID,
+    extract_all_signed_data, extract_signed_data, extract_signed_data_from_reader, get_apk_digest,
+    get_apk_digest_with_policy, testing::assert_contains, verify, verify_all_signers,
+    verify_all_signers_with_policy, verify_reader, verify_with_policy, AlgorithmPolicy,
+    ContentDigestAlgorithm, DigestMode, SignatureAlgorithmID, SignatureScheme,
 };
 use apkzip::zip_sections;
 use byteorder::{LittleEndian, ReadBytesExt};
 use log::info;
 use openssl::x509::X509;
 use std::fmt::Write;
-use std::io::{Seek, SeekFrom};
+use std::io::{Cursor, Seek, SeekFrom};
 use std::{fs, matches, path::Path};
 
 const KEY_NAMES_DSA: &[&str] = &["1024", "2048", "3072"];
@@ -76,6 +79,103 @@ fn test_verify_truncated_cd() {
     ));
 }
 
+#[test]
+fn apk_signed_with_v2_only_is_valid() {
+    setup();
+    let path = "tests/data/v2-only-one-signer.apk";
+    validate_apk(path, SignatureAlgorithmID::RsaPkcs1V15WithSha256);
+
+    let signed_data = verify(path, SDK_INT).unwrap();
+    assert_eq!(SignatureScheme::V2, signed_data.scheme());
+}
+
+#[test]
+fn apk_signed_with_v3_is_verified_with_v3() {
+    setup();
+    let signed_data = verify("tests/data/v3-only-with-stamp.apk", SDK_INT).unwrap();
+    assert_eq!(SignatureScheme::V3, signed_data.scheme());
+}
+
+#[test]
+fn test_verify_v2_two_signers() {
+    setup();
+    let path = "tests/data/v2-only-two-signers.apk";
+
+    let res = verify(path, SDK_INT);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "2 signers found");
+
+    let verified = verify_all_signers(path, SDK_INT).unwrap();
+    assert_eq!(SignatureScheme::V2, verified.scheme());
+    let certs = verified.certificates_der().unwrap();
+    assert_eq!(2, certs.len());
+    assert_ne!(certs[0], certs[1]);
+
+    let extracted = extract_all_signed_data(path, SDK_INT).unwrap();
+    assert_eq!(certs, extracted.certificates_der().unwrap());
+}
+
+#[test]
+fn test_verify_all_signers_with_policy() {
+    setup();
+    let path = "tests/data/v2-only-two-signers.apk";
+    let policy = AlgorithmPolicy {
+        allowed_digests: vec![ContentDigestAlgorithm::ChunkedSha512],
+        ..Default::default()
+    };
+
+    let res = verify_all_signers_with_policy(path, SDK_INT, &policy);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "acceptable under the algorithm policy");
+
+    let verified = verify_all_signers_with_policy(path, SDK_INT, &AlgorithmPolicy::default())
+        .expect("Default policy should accept both signers");
+    assert_eq!(2, verified.certificates_der().unwrap().len());
+}
+
+#[test]
+fn test_verify_v2_with_stripped_v3() {
+    setup();
+    let res = verify("tests/data/v2-only-v3-stripped.apk", SDK_INT);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "v3 block was stripped");
+}
+
+#[test]
+fn test_verify_reader() {
+    setup();
+    for path in ["tests/data/v3-only-with-stamp.apk", "tests/data/v2-only-one-signer.apk"] {
+        let expected = verify(path, SDK_INT).unwrap();
+        for mode in [DigestMode::Seek, DigestMode::Streaming] {
+            let apk = Cursor::new(fs::read(path).unwrap());
+            let signed_data = verify_reader(apk, SDK_INT, mode).unwrap();
+            assert_eq!(expected.scheme(), signed_data.scheme());
+            assert_eq!(
+                expected.first_certificate_der().unwrap(),
+                signed_data.first_certificate_der().unwrap()
+            );
+        }
+
+        let apk = Cursor::new(fs::read(path).unwrap());
+        let signed_data = extract_signed_data_from_reader(apk, SDK_INT).unwrap();
+        assert_eq!(
+            expected.first_certificate_der().unwrap(),
+            signed_data.first_certificate_der().unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_verify_reader_digest_mismatch() {
+    setup();
+    let path = "tests/data/v3-only-with-rsa-pkcs1-sha512-8192-digest-mismatch.apk";
+    for mode in [DigestMode::Seek, DigestMode::Streaming] {
+        let res = verify_reader(Cursor::new(fs::read(path).unwrap()), SDK_INT, mode);
+        assert!(res.is_err());
+        assert_contains(&res.unwrap_err().to_string(), "Digest mismatch");
+    }
+}
+
 #[test]
 fn apex_signed_with_v3_rsa_pkcs1_sha512_is_valid() {
     setup();
@@ -83,15 +183,67 @@ fn apex_signed_with_v3_rsa_pkcs1_sha512_is_valid() {
 }
 
 #[test]
-fn apks_signed_with_v3_dsa_sha256_are_not_supported() {
+fn apks_signed_with_v3_dsa_sha256_are_valid() {
     setup();
     for key_name in KEY_NAMES_DSA.iter() {
-        let res = verify(format!("tests/data/v3-only-with-dsa-sha256-{}.apk", key_name), SDK_INT);
-        assert!(res.is_err(), "DSA algorithm is not supported for verification. See b/197052981.");
-        assert_contains(&res.unwrap_err().to_string(), "No supported APK signatures found");
+        validate_apk(
+            format!("tests/data/v3-only-with-dsa-sha256-{}.apk", key_name),
+            SignatureAlgorithmID::DsaWithSha256,
+        );
     }
 }
 
+#[test]
+fn test_verify_v3_dsa_sig_does_not_verify() {
+    setup();
+    let res = verify("tests/data/v3-only-with-dsa-sha256-2048-sig-does-not-verify.apk", SDK_INT);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "Signature is invalid");
+}
+
+#[test]
+fn test_verify_v3_dsa_digest_mismatch() {
+    setup();
+    let res = verify("tests/data/v3-only-with-dsa-sha256-3072-digest-mismatch.apk", SDK_INT);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "Digest mismatch");
+}
+
+#[test]
+fn test_verify_with_policy_rejects_small_keys() {
+    setup();
+    let policy = AlgorithmPolicy { min_rsa_key_bits: 2048, ..Default::default() };
+
+    let res =
+        verify_with_policy("tests/data/v3-only-with-rsa-pkcs1-sha256-1024.apk", SDK_INT, &policy);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "acceptable under the algorithm policy");
+
+    verify_with_policy("tests/data/v3-only-with-rsa-pkcs1-sha256-2048.apk", SDK_INT, &policy)
+        .expect("RSA 2048 keys should be acceptable");
+}
+
+#[test]
+fn test_verify_with_policy_rejects_disallowed_digests() {
+    setup();
+    let policy = AlgorithmPolicy {
+        allowed_digests: vec![ContentDigestAlgorithm::ChunkedSha512],
+        ..Default::default()
+    };
+
+    let res = verify_with_policy("tests/data/v3-only-with-ecdsa-sha256-p256.apk", SDK_INT, &policy);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "acceptable under the algorithm policy");
+
+    verify_with_policy("tests/data/v3-only-with-ecdsa-sha512-p256.apk", SDK_INT, &policy)
+        .expect("SHA-512 digests should be acceptable");
+
+    let apk = fs::File::open("tests/data/v3-only-with-ecdsa-sha256-p256.apk").unwrap();
+    let res = get_apk_digest_with_policy(apk, SDK_INT, /* verify= */ false, &policy);
+    assert!(res.is_err());
+    assert_contains(&res.unwrap_err().to_string(), "acceptable under the algorithm policy");
+}
+
 #[test]
 fn apks_signed_with_v3_ecdsa_sha256_are_valid() {
     setup();
@@ -139,7 +291,9 @@ fn apks_signed_with_v3_rsa_pkcs1_sha512_are_valid() {
 #[test]
 fn test_verify_v3_sig_min_max_sdk() {
     setup();
-    // The Signer for this APK has min_sdk=24, max_sdk=32.
+    // The v3 Signer for this APK has min_sdk=24, max_sdk=32.
+    // The v3.1 Signer for this APK has min_sdk=32, max_sdk=MAX and targets the development release
+    // of SDK 32, so it is only used from SDK 33.
     let path = "tests/data/v31-rsa-2048_2-tgt-33-1-tgt-28.apk";
 
     let res = verify(path, 23);
@@ -147,14 +301,44 @@ fn test_verify_v3_sig_min_max_sdk() {
     assert_contains(&res.unwrap_err().to_string(), "0 signers found");
 
     let res = verify(path, 24);
-    assert!(res.is_ok());
+    assert_eq!(SignatureScheme::V3, res.unwrap().scheme());
 
     let res = verify(path, 32);
-    assert!(res.is_ok());
+    assert_eq!(SignatureScheme::V3, res.unwrap().scheme());
 
     let res = verify(path, 33);
+    assert_eq!(SignatureScheme::V31, res.unwrap().scheme());
+}
+
+#[test]
+fn test_verify_v31_rotated_signer() {
+    setup();
+    let path = "tests/data/v31-rsa-2048_2-tgt-33-1-tgt-28.apk";
+
+    let v3_signed_data = verify(path, 32).unwrap();
+    let v31_signed_data = verify(path, 33).unwrap();
+    assert_ne!(
+        v3_signed_data.first_certificate_der().unwrap(),
+        v31_signed_data.first_certificate_der().unwrap()
+    );
+    let lineage = v31_signed_data.lineage().expect("The v3.1 signer should have a lineage");
+    assert_eq!(
+        v3_signed_data.first_certificate_der().unwrap(),
+        lineage.nodes()[0].certificate_der()
+    );
+}
+
+#[test]
+fn test_verify_v3_with_stripped_v31() {
+    setup();
+    let path = "tests/data/v31-rsa-2048_2-tgt-33-1-tgt-28-v31-stripped.apk";
+
+    let res = verify(path, 31);
+    assert_eq!(SignatureScheme::V3, res.unwrap().scheme());
+
+    let res = verify(path, 32);
     assert!(res.is_err());
-    assert_contains(&res.unwrap_err().to_string(), "0 signers found");
+    assert_contains(&res.unwrap_err().to_string(), "v3.1 block was stripped");
 }
 
 #[test]
@@ -263,6 +447,20 @@ fn apk_signed_with_v3_unknown_pair_in_apk_sig_block_is_valid() {
     );
 }
 
+#[test]
+fn apk_signed_with_v3_lineage_has_verified_lineage() {
+    setup();
+    let path = "tests/data/v3-only-unknown-additional-attr.apk";
+
+    let signed_data = verify(path, SDK_INT).unwrap();
+    let lineage = signed_data.lineage().expect("Lineage should be verified");
+    assert_eq!(2, lineage.nodes().len());
+    assert_eq!(signed_data.first_certificate_der().unwrap(), lineage.last_certificate_der());
+
+    let signed_data = extract_signed_data(path, SDK_INT).unwrap();
+    assert!(signed_data.lineage().is_none(), "Unverified signed data should not have a lineage");
+}
+
 #[test]
 fn apk_signed_with_v3_ignorable_unsupported_sig_algs_is_valid() {
     setup();
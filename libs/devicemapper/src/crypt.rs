@@ -17,6 +17,7 @@
 /// `crypt` module implements the "crypt" target in the device mapper framework. Specifically,
 /// it provides `DmCryptTargetBuilder` struct which is used to construct a `DmCryptTarget`
 /// struct which is then given to `DeviceMapper` to create a mapper device.
+use crate::table::DmTarget;
 use crate::DmTargetSpec;
 
 use anyhow::{ensure, Context, Result};
@@ -42,6 +43,11 @@ pub enum CipherType {
     /// supported by inline encryption hardware. Note that (status quo) `encryptedstore` in VMs
     /// is the only user of this module & inline encryption is not supported by guest kernel.
     AES256XTS,
+    /// AES256 with GCM mode and random IVs. GCM is an authenticated encryption (AEAD) mode: the
+    /// random IV and the authentication tag of each sector are stored by an integrity target,
+    /// which the crypt target must be stacked on. Unlike the length-preserving modes, tampering
+    /// with the data is detected, at the cost of extra disk space and I/O.
+    AES256GCM,
 }
 impl CipherType {
     fn get_kernel_crypto_name(&self) -> &str {
@@ -50,6 +56,18 @@ impl CipherType {
             // which basically is the sector number.
             CipherType::AES256HCTR2 => "aes-hctr2-plain64",
             CipherType::AES256XTS => "aes-xts-plain64",
+            // The IV of each sector is random since it can be stored along the tag.
+            CipherType::AES256GCM => "capi:gcm(aes)-random",
+        }
+    }
+
+    /// Returns the size in bytes of the integrity data that this cipher stores for each sector, if
+    /// this is an AEAD cipher. The integrity target underneath must use this tag size.
+    pub fn aead_tag_size(&self) -> Option<u32> {
+        match *self {
+            CipherType::AES256HCTR2 | CipherType::AES256XTS => None,
+            // 12 bytes of IV followed by the 16 bytes authentication tag.
+            CipherType::AES256GCM => Some(28),
         }
     }
 
@@ -60,6 +78,8 @@ impl CipherType {
             // XTS requires key of twice the length of the underlying block cipher
             // i.e., 64B for AES256
             CipherType::AES256XTS => 64,
+            // AES-256-GCM takes a 32-byte key
+            CipherType::AES256GCM => 32,
         }
     }
 
@@ -70,6 +90,37 @@ impl CipherType {
 
 pub struct DmCryptTarget(Box<[u8]>);
 
+/// Configuration of a crypt target, as returned by `DeviceMapper::crypt_info`. The key is
+/// deliberately left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmCryptInfo {
+    /// The cipher in the kernel crypto API format, e.g. "aes-xts-plain64".
+    pub cipher: String,
+    /// The sector count added to the sector number before creating the IV.
+    pub iv_offset: u64,
+    /// The data device, as "<major>:<minor>".
+    pub device: String,
+    /// Starting sector within the data device where the encrypted data begins.
+    pub offset: u64,
+    /// The optional parameters.
+    pub opt_params: Vec<String>,
+}
+
+impl DmCryptInfo {
+    pub(crate) fn parse(table: &str) -> Result<Self> {
+        // <cipher> <key> <iv_offset> <device> <offset> [<#opt_params> <opt_params>]
+        let mut fields = table.split(' ');
+        let mut next_field = |name| fields.next().context(format!("{} is missing", name));
+        let cipher = next_field("cipher")?.to_owned();
+        next_field("key")?;
+        let iv_offset = next_field("iv_offset")?.parse().context("invalid iv_offset")?;
+        let device = next_field("device")?.to_owned();
+        let offset = next_field("offset")?.parse().context("invalid offset")?;
+        let opt_params = fields.skip(1).map(str::to_owned).collect();
+        Ok(DmCryptInfo { cipher, iv_offset, device, offset, opt_params })
+    }
+}
+
 impl DmCryptTarget {
     /// Flatten into slice
     pub fn as_slice(&self) -> &[u8] {
@@ -77,6 +128,12 @@ impl DmCryptTarget {
     }
 }
 
+impl DmTarget for DmCryptTarget {
+    fn as_slice(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
 pub struct DmCryptTargetBuilder<'a> {
     cipher: CipherType,
     key: Option<&'a [u8]>,
@@ -158,6 +215,14 @@ impl<'a> DmCryptTargetBuilder<'a> {
         );
         let key = hex::encode(self.key.unwrap());
 
+        // AEAD ciphers store their IVs and tags in the integrity target underneath.
+        let mut opt_params = self.opt_params.clone();
+        let integrity_param =
+            self.cipher.aead_tag_size().map(|size| format!("integrity:{}:aead", size));
+        if let Some(param) = &integrity_param {
+            opt_params.push(param);
+        }
+
         // Step2: serialize the information according to the spec, which is ...
         // DmTargetSpec{...}
         // <cipher> <key> <iv_offset> <device path> \
@@ -169,7 +234,7 @@ impl<'a> DmCryptTargetBuilder<'a> {
         write!(&mut body, "{} ", self.iv_offset)?;
         write!(&mut body, "{} ", device_path)?;
         write!(&mut body, "{} ", self.offset)?;
-        write!(&mut body, "{} {} ", self.opt_params.len(), self.opt_params.join(" "))?;
+        write!(&mut body, "{} {} ", opt_params.len(), opt_params.join(" "))?;
         write!(&mut body, "\0")?; // null terminator
 
         let size = size_of::<DmTargetSpec>() + body.len();
@@ -0,0 +1,218 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// `integrity` module implements the "integrity" target in the device mapper framework.
+/// Specifically, it provides `DmIntegrityTargetBuilder` struct which is used to construct a
+/// `DmIntegrityTarget` struct which is then given to `DeviceMapper` to create a mapper device.
+///
+/// The integrity target is used in standalone mode: the integrity tags are provided by the target
+/// stacked on top of it, e.g. a crypt target using an AEAD cipher.
+use crate::table::DmTarget;
+use crate::DmTargetSpec;
+
+use anyhow::{ensure, Context, Result};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+use zerocopy::AsBytes;
+
+const SECTOR_SIZE: u64 = 512;
+
+// The UAPI for the integrity target is at:
+// Documentation/admin-guide/device-mapper/dm-integrity.rst
+
+// The superblock of a formatted integrity device is at its beginning. See `struct superblock` in
+// drivers/md/dm-integrity.c. Its fields are little endian.
+const SB_MAGIC: &[u8; 8] = b"integrt\0";
+const SB_PROVIDED_DATA_SECTORS_OFFSET: usize = 16;
+const SB_SIZE: usize = 512;
+
+/// How the integrity target keeps the data and the tags consistent on a crash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegrityMode {
+    /// Data and tags are written to a journal first. This is the default.
+    #[default]
+    Journal,
+    /// Dirty regions are tracked in a bitmap and their tags are recalculated after a crash.
+    Bitmap,
+    /// Data and tags are written directly. A crash may leave them inconsistent.
+    Direct,
+}
+
+impl IntegrityMode {
+    fn get_kernel_name(&self) -> &str {
+        match *self {
+            IntegrityMode::Journal => "J",
+            IntegrityMode::Bitmap => "B",
+            IntegrityMode::Direct => "D",
+        }
+    }
+}
+
+pub struct DmIntegrityTarget(Box<[u8]>);
+
+impl DmIntegrityTarget {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl DmTarget for DmIntegrityTarget {
+    fn as_slice(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+pub struct DmIntegrityTargetBuilder<'a> {
+    device_path: Option<&'a Path>,
+    device_size: u64,
+    tag_size: u32,
+    mode: IntegrityMode,
+    block_size: u32,
+    opt_params: Vec<&'a str>,
+}
+
+impl<'a> Default for DmIntegrityTargetBuilder<'a> {
+    fn default() -> Self {
+        DmIntegrityTargetBuilder {
+            device_path: None,
+            device_size: 0,
+            tag_size: 0,
+            mode: IntegrityMode::default(),
+            block_size: SECTOR_SIZE as u32,
+            opt_params: Vec::new(),
+        }
+    }
+}
+
+impl<'a> DmIntegrityTargetBuilder<'a> {
+    /// Sets the device that will be used as the data device, and the size of the mapper device.
+    /// For a formatted device, the size can't exceed the one returned by `provided_data_size`.
+    pub fn data_device(&mut self, p: &'a Path, size: u64) -> &mut Self {
+        self.device_path = Some(p);
+        self.device_size = size;
+        self
+    }
+
+    /// Sets the size in bytes of the integrity tag of each block. It must match the size of the
+    /// tags provided by the target stacked on top, e.g. `CipherType::aead_tag_size`.
+    pub fn tag_size(&mut self, tag_size: u32) -> &mut Self {
+        self.tag_size = tag_size;
+        self
+    }
+
+    /// Sets how the data and the tags are kept consistent on a crash.
+    pub fn mode(&mut self, mode: IntegrityMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the size in bytes of the blocks protected by a tag. This is only used when the device
+    /// is formatted.
+    pub fn block_size(&mut self, block_size: u32) -> &mut Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Add additional optional parameter
+    pub fn opt_param(&mut self, param: &'a str) -> &mut Self {
+        self.opt_params.push(param);
+        self
+    }
+
+    /// Constructs a `DmIntegrityTarget`.
+    pub fn build(&self) -> Result<DmIntegrityTarget> {
+        // The `DmIntegrityTarget` struct actually is a flattened data consisting of a header and
+        // body. The format of the header is `dm_target_spec` as defined in
+        // include/uapi/linux/dm-ioctl.h.
+        let device_path = self
+            .device_path
+            .context("data device is not set")?
+            .to_str()
+            .context("data device path is not encoded in utf8")?;
+
+        ensure!(self.tag_size > 0, "tag size is not set");
+        ensure!(
+            self.block_size.is_power_of_two() && (512..=4096).contains(&self.block_size),
+            "Invalid block size:{}",
+            self.block_size
+        );
+        ensure!(
+            self.device_size & (self.block_size as u64 - 1) == 0,
+            "device size:{} is not a multiple of the block size:{}",
+            self.device_size,
+            self.block_size
+        );
+        let block_size = format!("block_size:{}", self.block_size);
+
+        // Step2: serialize the information according to the spec, which is ...
+        // DmTargetSpec{...}
+        // <device path> <reserved sectors> <tag size> <mode> [<#opt_params> <opt_params>]
+        let mut opt_params = vec![block_size.as_str()];
+        opt_params.extend_from_slice(&self.opt_params);
+        let mut body = String::new();
+        use std::fmt::Write;
+        write!(&mut body, "{} ", device_path)?;
+        write!(&mut body, "0 ")?; // reserved sectors
+        write!(&mut body, "{} ", self.tag_size)?;
+        write!(&mut body, "{} ", self.mode.get_kernel_name())?;
+        write!(&mut body, "{} {}", opt_params.len(), opt_params.join(" "))?;
+        write!(&mut body, "\0")?; // null terminator
+
+        let size = size_of::<DmTargetSpec>() + body.len();
+        let aligned_size = (size + 7) & !7; // align to 8 byte boundaries
+        let padding = aligned_size - size;
+
+        let mut header = DmTargetSpec::new("integrity")?;
+        header.sector_start = 0;
+        header.length = self.device_size / SECTOR_SIZE; // number of 512-byte sectors
+        header.next = aligned_size as u32;
+
+        let mut buf = Vec::with_capacity(aligned_size);
+        buf.write_all(header.as_bytes())?;
+        buf.write_all(body.as_bytes())?;
+        buf.write_all(vec![0; padding].as_slice())?;
+
+        Ok(DmIntegrityTarget(buf.into_boxed_slice()))
+    }
+}
+
+/// Returns whether `device` has been formatted as an integrity device. Devices are formatted by
+/// the kernel the first time an integrity target is created on them, provided that they start
+/// with zeroes.
+pub fn is_formatted(device: &Path) -> Result<bool> {
+    Ok(read_superblock(device)?.starts_with(SB_MAGIC))
+}
+
+/// Returns the size in bytes of the data that a formatted integrity device can hold. This is the
+/// maximum size of a `DmIntegrityTarget` on that device.
+pub fn provided_data_size(device: &Path) -> Result<u64> {
+    let sb = read_superblock(device)?;
+    ensure!(sb.starts_with(SB_MAGIC), "{:?} is not formatted as an integrity device", device);
+    let field = &sb[SB_PROVIDED_DATA_SECTORS_OFFSET..SB_PROVIDED_DATA_SECTORS_OFFSET + 8];
+    // Unwrap is safe because the slice is 8 bytes long.
+    Ok(u64::from_le_bytes(field.try_into().unwrap()) * SECTOR_SIZE)
+}
+
+fn read_superblock(device: &Path) -> Result<[u8; SB_SIZE]> {
+    let mut sb = [0; SB_SIZE];
+    File::open(device)
+        .and_then(|mut f| f.read_exact(&mut sb))
+        .with_context(|| format!("Failed to read the superblock of {:?}", device))?;
+    Ok(sb)
+}
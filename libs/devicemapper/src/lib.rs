@@ -15,9 +15,9 @@
  */
 
 // `dm` module implements part of the `device-mapper` ioctl interfaces. It currently supports
-// creation and deletion of the mapper device. It doesn't support other operations like querying
-// the status of the mapper device. And there's no plan to extend the support unless it is
-// required.
+// creation and deletion of the mapper device, and querying the devices and their status. Other
+// operations like renaming or reloading the table of a device aren't supported. And there's no
+// plan to extend the support unless it is required.
 //
 // Why in-house development? [`devicemapper`](https://crates.io/crates/devicemapper) is a public
 // Rust implementation of the device mapper APIs. However, it doesn't provide any abstraction for
@@ -31,34 +31,51 @@
 #![allow(missing_docs)]
 #![cfg_attr(test, allow(unused))]
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 /// Exposes DmCryptTarget & related builder
 pub mod crypt;
+/// Exposes DmIntegrityTarget & related builder
+pub mod integrity;
+/// Exposes DmLinearTarget & related builder
+pub mod linear;
+/// Exposes DmSnapshotTarget, DmSnapshotOriginTarget & related builders
+pub mod snapshot;
+/// Exposes DmTable & related builder
+pub mod table;
 /// Expose util functions
 pub mod util;
 /// Exposes the DmVerityTarget & related builder
 pub mod verity;
+/// Exposes DmZeroTarget & related builder
+pub mod zero;
 // Expose loopdevice
 pub mod loopdevice;
 
 mod sys;
-use crypt::DmCryptTarget;
+use crypt::{DmCryptInfo, DmCryptTarget};
+use integrity::DmIntegrityTarget;
 use sys::*;
+use table::DmTable;
 use util::*;
-use verity::DmVerityTarget;
+use verity::{DmVerityStatus, DmVerityTarget};
 
 nix::ioctl_readwrite!(_dm_dev_create, DM_IOCTL, Cmd::DM_DEV_CREATE, DmIoctl);
 nix::ioctl_readwrite!(_dm_dev_suspend, DM_IOCTL, Cmd::DM_DEV_SUSPEND, DmIoctl);
 nix::ioctl_readwrite!(_dm_table_load, DM_IOCTL, Cmd::DM_TABLE_LOAD, DmIoctl);
 nix::ioctl_readwrite!(_dm_dev_remove, DM_IOCTL, Cmd::DM_DEV_REMOVE, DmIoctl);
+nix::ioctl_readwrite!(_dm_list_devices, DM_IOCTL, Cmd::DM_LIST_DEVICES, DmIoctl);
+nix::ioctl_readwrite!(_dm_dev_status, DM_IOCTL, Cmd::DM_DEV_STATUS, DmIoctl);
+nix::ioctl_readwrite!(_dm_table_status, DM_IOCTL, Cmd::DM_TABLE_STATUS, DmIoctl);
 
 /// Create a new (mapper) device
 fn dm_dev_create(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
@@ -85,10 +102,28 @@ fn dm_dev_remove(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
     Ok(unsafe { _dm_dev_remove(dm.0.as_raw_fd(), ioctl) }?)
 }
 
+fn dm_list_devices(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` is copied into the kernel, which then writes the result back within the
+    // `data_size` bytes of the buffer pointed by `ioctl`.
+    Ok(unsafe { _dm_list_devices(dm.0.as_raw_fd(), ioctl) }?)
+}
+
+fn dm_dev_status(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` is copied into the kernel, which then writes the result back within the
+    // `data_size` bytes of the buffer pointed by `ioctl`.
+    Ok(unsafe { _dm_dev_status(dm.0.as_raw_fd(), ioctl) }?)
+}
+
+fn dm_table_status(dm: &DeviceMapper, ioctl: *mut DmIoctl) -> Result<i32> {
+    // SAFETY: `ioctl` is copied into the kernel, which then writes the result back within the
+    // `data_size` bytes of the buffer pointed by `ioctl`.
+    Ok(unsafe { _dm_table_status(dm.0.as_raw_fd(), ioctl) }?)
+}
+
 // `DmTargetSpec` is the header of the data structure for a device-mapper target. When doing the
 // ioctl, one of more `DmTargetSpec` (and its body) are appened to the `DmIoctl` struct.
 #[repr(C)]
-#[derive(Copy, Clone, AsBytes, FromZeroes)]
+#[derive(Copy, Clone, AsBytes, FromZeroes, FromBytes)]
 struct DmTargetSpec {
     sector_start: u64,
     length: u64, // number of 512 sectors
@@ -125,6 +160,77 @@ impl DmIoctl {
     }
 }
 
+/// Information about a mapper device, as returned by `DeviceMapper::device_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmDeviceInfo {
+    /// Name of the device.
+    pub name: String,
+    /// UUID of the device.
+    pub uuid: String,
+    /// Major number of the mapped block device.
+    pub major: u64,
+    /// Minor number of the mapped block device.
+    pub minor: u64,
+    /// Number of references to the mapped block device.
+    pub open_count: i32,
+    /// Number of events, e.g. detected corruptions, that occurred on the device.
+    pub event_nr: u32,
+    /// Number of targets in the active table.
+    pub target_count: u32,
+    /// Whether I/Os to the device are suspended.
+    pub suspended: bool,
+    /// Whether the device is read-only.
+    pub read_only: bool,
+    /// Whether the device has an active table.
+    pub active_table_present: bool,
+}
+
+impl DmDeviceInfo {
+    fn from(data: &DmIoctl) -> Result<Self> {
+        Ok(DmDeviceInfo {
+            name: from_c_str(&data.name)?,
+            uuid: from_c_str(&data.uuid)?,
+            major: nix::sys::stat::major(data.dev),
+            minor: nix::sys::stat::minor(data.dev),
+            open_count: data.open_count,
+            event_nr: data.event_nr,
+            target_count: data.target_count,
+            suspended: data.flags.contains(Flag::DM_SUSPEND_FLAG),
+            read_only: data.flags.contains(Flag::DM_READONLY_FLAG),
+            active_table_present: data.flags.contains(Flag::DM_ACTIVE_PRESENT_FLAG),
+        })
+    }
+}
+
+/// A mapper device, as returned by `DeviceMapper::list_devices`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmDeviceListEntry {
+    /// Name of the device.
+    pub name: String,
+    /// Major number of the mapped block device.
+    pub major: u64,
+    /// Minor number of the mapped block device.
+    pub minor: u64,
+}
+
+/// The status of a target in the active table of a mapper device, as returned by
+/// `DeviceMapper::table_status`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmTargetStatus {
+    /// First sector of the mapper device that is mapped by the target.
+    pub sector_start: u64,
+    /// Number of 512-byte sectors mapped by the target.
+    pub length: u64,
+    /// Type of the target, e.g. "verity".
+    pub target_type: String,
+    /// Target-specific status, e.g. "V" for a verity target that hasn't detected any corruption.
+    pub status: String,
+}
+
+// Initial size of the buffer receiving the result of an ioctl. It is grown when the kernel reports
+// that the result doesn't fit.
+const INITIAL_RESULT_SIZE: usize = 16 * 1024;
+
 /// `DeviceMapper` is the entry point for the device mapper framework. It essentially is a file
 /// handle to "/dev/mapper/control".
 pub struct DeviceMapper(File);
@@ -154,13 +260,48 @@ impl DeviceMapper {
     /// Creates a (crypt) device and configure it according to the `target` specification.
     /// The path to the generated device is "/dev/mapper/<name>".
     pub fn create_crypt_device(&self, name: &str, target: &DmCryptTarget) -> Result<PathBuf> {
-        self.create_device(name, target.as_slice(), uuid("crypto".as_bytes())?, true)
+        self.create_device(name, target.as_slice(), 1, uuid("crypto".as_bytes())?, true)
+    }
+
+    /// Creates an (integrity) device and configure it according to the `target` specification.
+    /// The path to the generated device is "/dev/mapper/<name>".
+    pub fn create_integrity_device(
+        &self,
+        name: &str,
+        target: &DmIntegrityTarget,
+    ) -> Result<PathBuf> {
+        self.create_device(name, target.as_slice(), 1, uuid("integr".as_bytes())?, true)
     }
 
     /// Creates a (verity) device and configure it according to the `target` specification.
     /// The path to the generated device is "/dev/mapper/<name>".
     pub fn create_verity_device(&self, name: &str, target: &DmVerityTarget) -> Result<PathBuf> {
-        self.create_device(name, target.as_slice(), uuid("apkver".as_bytes())?, false)
+        self.create_device(name, target.as_slice(), 1, uuid("apkver".as_bytes())?, false)
+    }
+
+    /// Creates a device made of all the targets of `table`, e.g. a linear or snapshot device. The
+    /// path to the generated device is "/dev/mapper/<name>".
+    pub fn create_table_device(
+        &self,
+        name: &str,
+        table: &DmTable,
+        writable: bool,
+    ) -> Result<PathBuf> {
+        self.create_device(
+            name,
+            table.as_slice(),
+            table.target_count(),
+            uuid("dmtabl".as_bytes())?,
+            writable,
+        )
+    }
+
+    /// Removes a mapper device immediately. This fails if the device is still in use.
+    pub fn delete_device(&self, name: &str) -> Result<()> {
+        let mut data = DmIoctl::new(name)?;
+        dm_dev_remove(self, &mut data)
+            .context(format!("failed to remove device with name {}", &name))?;
+        Ok(())
     }
 
     /// Removes a mapper device.
@@ -172,10 +313,137 @@ impl DeviceMapper {
         Ok(())
     }
 
+    /// Returns the information about a mapper device, e.g. its device number and open count.
+    pub fn device_info(&self, name: &str) -> Result<DmDeviceInfo> {
+        let mut data = DmIoctl::new(name)?;
+        dm_dev_status(self, &mut data)
+            .context(format!("failed to get the status of device with name {}", &name))?;
+        DmDeviceInfo::from(&data)
+    }
+
+    /// Lists the mapper devices.
+    pub fn list_devices(&self) -> Result<Vec<DmDeviceListEntry>> {
+        let (_, result) = self
+            .ioctl_with_result(DmIoctl::new("")?, dm_list_devices)
+            .context("failed to list devices")?;
+
+        let mut devices = Vec::new();
+        let mut offset = 0;
+        loop {
+            let entry = result.get(offset..).context("invalid offset in the device list")?;
+            let header = DmNameList::read_from_prefix(entry).context("truncated device list")?;
+            let (dev, next) = (header.dev, header.next);
+            if dev == 0 {
+                // The list is empty.
+                break;
+            }
+            devices.push(DmDeviceListEntry {
+                name: from_c_str(&entry[size_of::<DmNameList>()..])?,
+                major: nix::sys::stat::major(dev),
+                minor: nix::sys::stat::minor(dev),
+            });
+            if next == 0 {
+                break;
+            }
+            offset += next as usize;
+        }
+        Ok(devices)
+    }
+
+    /// Returns the status of each target in the active table of a mapper device.
+    pub fn table_status(&self, name: &str) -> Result<Vec<DmTargetStatus>> {
+        self.get_table_status(name, Flag::empty())
+    }
+
+    /// Returns whether the verity target of a mapper device has detected a corruption.
+    pub fn verity_status(&self, name: &str) -> Result<DmVerityStatus> {
+        let status = self.get_single_target_status(name, "verity", Flag::empty())?;
+        DmVerityStatus::parse(&status)
+    }
+
+    /// Returns the configuration of the crypt target of a mapper device, except for its key.
+    pub fn crypt_info(&self, name: &str) -> Result<DmCryptInfo> {
+        // The crypt target doesn't report any status. Its table is parsed instead. Ask the kernel
+        // to wipe its buffers, as the table contains the key.
+        let flags = Flag::DM_STATUS_TABLE_FLAG | Flag::DM_SECURE_DATA_FLAG;
+        let table = self.get_single_target_status(name, "crypt", flags)?;
+        DmCryptInfo::parse(&table)
+    }
+
+    fn get_table_status(&self, name: &str, flags: Flag) -> Result<Vec<DmTargetStatus>> {
+        let mut data = DmIoctl::new(name)?;
+        data.flags |= flags;
+        let (data, result) = self
+            .ioctl_with_result(data, dm_table_status)
+            .context(format!("failed to get the table status of device with name {}", &name))?;
+
+        let mut targets = Vec::with_capacity(data.target_count as usize);
+        let mut offset = 0;
+        for _ in 0..data.target_count {
+            let entry = result.get(offset..).context("invalid offset in the table status")?;
+            let spec = DmTargetSpec::read_from_prefix(entry).context("truncated table status")?;
+            targets.push(DmTargetStatus {
+                sector_start: spec.sector_start,
+                length: spec.length,
+                target_type: from_c_str(&spec.target_type)?,
+                status: from_c_str(&entry[size_of::<DmTargetSpec>()..])?,
+            });
+            // Unlike when loading a table, `next` is relative to the start of the result.
+            offset = spec.next as usize;
+        }
+        Ok(targets)
+    }
+
+    fn get_single_target_status(
+        &self,
+        name: &str,
+        target_type: &str,
+        flags: Flag,
+    ) -> Result<String> {
+        let mut targets = self.get_table_status(name, flags)?;
+        ensure!(
+            targets.len() == 1 && targets[0].target_type == target_type,
+            "device with name {} is not a single {} target",
+            &name,
+            target_type
+        );
+        Ok(targets.remove(0).status)
+    }
+
+    // Issues an ioctl whose result is written by the kernel after the `DmIoctl` header. The buffer
+    // is grown until the result fits in it. Returns the updated header and the result.
+    fn ioctl_with_result(
+        &self,
+        mut data: DmIoctl,
+        ioctl: fn(&DeviceMapper, *mut DmIoctl) -> Result<i32>,
+    ) -> Result<(DmIoctl, Vec<u8>)> {
+        let mut payload_size = size_of::<DmIoctl>() + INITIAL_RESULT_SIZE;
+        loop {
+            data.data_size = payload_size as u32;
+            data.data_start = size_of::<DmIoctl>() as u32;
+
+            let mut payload = vec![0; payload_size];
+            payload[..size_of::<DmIoctl>()].copy_from_slice(data.as_bytes());
+            ioctl(self, payload.as_mut_ptr() as *mut DmIoctl)?;
+
+            // Unwrap is safe because the payload is larger than `DmIoctl`.
+            let data = DmIoctl::read_from_prefix(payload.as_slice()).unwrap();
+            if data.flags.contains(Flag::DM_BUFFER_FULL_FLAG) {
+                payload_size *= 2;
+                continue;
+            }
+            let result = payload
+                .get(data.data_start as usize..data.data_size as usize)
+                .context("invalid result range")?;
+            return Ok((data, result.to_vec()));
+        }
+    }
+
     fn create_device(
         &self,
         name: &str,
-        target: &[u8],
+        targets: &[u8],
+        target_count: u32,
         uid: String,
         writable: bool,
     ) -> Result<PathBuf> {
@@ -186,12 +454,12 @@ impl DeviceMapper {
             .context(format!("failed to create an empty device with name {}", &name))?;
 
         // Step 2: load table onto the device
-        let payload_size = size_of::<DmIoctl>() + target.len();
+        let payload_size = size_of::<DmIoctl>() + targets.len();
 
         let mut data = DmIoctl::new(name)?;
         data.data_size = payload_size as u32;
         data.data_start = size_of::<DmIoctl>() as u32;
-        data.target_count = 1;
+        data.target_count = target_count;
 
         if !writable {
             data.flags |= Flag::DM_READONLY_FLAG;
@@ -199,7 +467,7 @@ impl DeviceMapper {
 
         let mut payload = Vec::with_capacity(payload_size);
         payload.extend_from_slice(data.as_bytes());
-        payload.extend_from_slice(target);
+        payload.extend_from_slice(targets);
         dm_table_load(self, payload.as_mut_ptr() as *mut DmIoctl)
             .context("failed to load table")?;
 
@@ -215,6 +483,12 @@ impl DeviceMapper {
     }
 }
 
+/// Converts a null-terminated string in a buffer from the kernel into a `String`.
+fn from_c_str(buf: &[u8]) -> Result<String> {
+    let s = CStr::from_bytes_until_nul(buf).context("string is not null-terminated")?;
+    Ok(s.to_str().context("string is not encoded in utf8")?.to_owned())
+}
+
 /// Used to derive a UUID that uniquely identifies a device mapper device when creating it.
 fn uuid(node_id: &[u8]) -> Result<String> {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -235,10 +509,16 @@ rdroidtest::test_main!();
 mod tests {
     use super::*;
     use crypt::{CipherType, DmCryptTargetBuilder};
+    use integrity::DmIntegrityTargetBuilder;
+    use linear::DmLinearTargetBuilder;
     use rdroidtest::{ignore_if, rdroidtest};
     use rustutils::system_properties;
+    use snapshot::{DmSnapshotTargetBuilder, SnapshotPersistence};
     use std::fs::{read, File, OpenOptions};
-    use std::io::Write;
+    use std::io::{Read, Write};
+    use table::DmTableBuilder;
+    use verity::{DmVerityCorruptionMode, DmVerityTargetBuilder};
+    use zero::DmZeroTargetBuilder;
 
     // Just a logical set of keys to make testing easy. This has no real meaning.
     struct KeySet<'a> {
@@ -280,6 +560,13 @@ mod tests {
         Ok(())
     }
 
+    // Returns the table of the target, i.e. the body following the `DmTargetSpec` header.
+    fn target_table(target: &[u8]) -> &str {
+        let body = &target[size_of::<DmTargetSpec>()..];
+        let len = body.iter().position(|b| *b == 0).unwrap();
+        std::str::from_utf8(&body[..len]).unwrap()
+    }
+
     fn is_hctr2_supported() -> bool {
         // hctr2 is NOT enabled in kernel 5.10 or lower. We run Microdroid tests on kernel versions
         // 5.10 or above & therefore,  we don't really care to skip test on other versions.
@@ -404,4 +691,373 @@ mod tests {
         let crypt = read(crypt_device).unwrap();
         assert_ne!(inputimg, crypt.as_slice());
     }
+
+    #[rdroidtest]
+    fn aead_data_inaccessible_with_diff_key() {
+        // This test creates an AEAD crypt device on top of an integrity device -> Write data ->
+        // Check the data is read back -> Recreate the crypt device with a different key -> Check
+        // that reading fails instead of returning garbage.
+        let dm = DeviceMapper::new().unwrap();
+        let inputimg = include_bytes!("../testdata/rand8k");
+        let sz = 1 << 20;
+        let cipher = CipherType::AES256GCM;
+        let key = KEY_SET_HCTR2.key;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let backing_file = prepare_tmpfile(test_dir.path(), "storage", sz);
+        let data_device = loopdevice::attach(
+            backing_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap();
+        scopeguard::defer! {
+            let _ignored1 = delete_device(&dm, "name6_crypt");
+            let _ignored2 = delete_device(&dm, "name6_crypt_diff");
+            let _ignored3 = delete_device(&dm, "name6_integrity");
+            loopdevice::detach(&data_device).unwrap();
+        }
+
+        let integrity_target = |size| {
+            DmIntegrityTargetBuilder::default()
+                .data_device(&data_device, size)
+                .tag_size(cipher.aead_tag_size().unwrap())
+                .block_size(4096)
+                .build()
+                .unwrap()
+        };
+        // Let the kernel format the integrity device.
+        dm.create_integrity_device("name6_format", &integrity_target(4096)).unwrap();
+        dm.delete_device("name6_format").unwrap();
+        assert!(integrity::is_formatted(&data_device).unwrap());
+
+        let size = integrity::provided_data_size(&data_device).unwrap() & !4095;
+        let integrity_device =
+            dm.create_integrity_device("name6_integrity", &integrity_target(size)).unwrap();
+        let crypt_target = |key| {
+            DmCryptTargetBuilder::default()
+                .data_device(&integrity_device, size)
+                .cipher(cipher)
+                .key(key)
+                .opt_param("sector_size:4096")
+                .build()
+                .unwrap()
+        };
+
+        let crypt_device = dm.create_crypt_device("name6_crypt", &crypt_target(key)).unwrap();
+        write_to_dev(&crypt_device, inputimg);
+        let mut buf = vec![0; inputimg.len()];
+        File::open(&crypt_device).unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(inputimg, buf.as_slice());
+        dm.delete_device("name6_crypt").unwrap();
+
+        let crypt_device = dm
+            .create_crypt_device("name6_crypt_diff", &crypt_target(KEY_SET_HCTR2.different_key))
+            .unwrap();
+        File::open(&crypt_device).unwrap().read_exact(&mut buf).expect_err("Should fail");
+    }
+
+    #[rdroidtest]
+    fn crypt_device_introspection() {
+        let dm = DeviceMapper::new().unwrap();
+        let sz = 8192;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let backing_file = prepare_tmpfile(test_dir.path(), "storage", sz);
+        let data_device = loopdevice::attach(
+            backing_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap();
+        let device = "name5";
+        scopeguard::defer! {
+            loopdevice::detach(&data_device).unwrap();
+            let _ignored = delete_device(&dm, device);
+        }
+
+        let target = DmCryptTargetBuilder::default()
+            .data_device(&data_device, sz)
+            .cipher(KEY_SET_XTS.cipher)
+            .key(KEY_SET_XTS.key)
+            .build()
+            .unwrap();
+        dm.create_crypt_device(device, &target).unwrap();
+
+        let info = dm.device_info(device).unwrap();
+        assert_eq!(device, info.name);
+        assert_eq!(1, info.target_count);
+        assert!(info.active_table_present);
+        assert!(!info.suspended);
+        assert!(!info.read_only);
+
+        let listed = dm.list_devices().unwrap();
+        let entry = listed.iter().find(|entry| entry.name == device).unwrap();
+        assert_eq!((info.major, info.minor), (entry.major, entry.minor));
+
+        let targets = dm.table_status(device).unwrap();
+        assert_eq!(1, targets.len());
+        assert_eq!("crypt", targets[0].target_type);
+        assert_eq!(sz / 512, targets[0].length);
+
+        let crypt_info = dm.crypt_info(device).unwrap();
+        assert_eq!("aes-xts-plain64", crypt_info.cipher);
+        assert_eq!(0, crypt_info.iv_offset);
+        assert_eq!(0, crypt_info.offset);
+        assert!(dm.verity_status(device).is_err());
+
+        dm.delete_device(device).unwrap();
+        assert!(dm.device_info(device).is_err());
+    }
+
+    #[rdroidtest]
+    fn integrity_target_with_opt_params() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let data_device = prepare_tmpfile(test_dir.path(), "data", 8192);
+
+        let target = DmIntegrityTargetBuilder::default()
+            .data_device(&data_device, 8192)
+            .tag_size(28)
+            .block_size(4096)
+            .opt_param("journal_sectors:64")
+            .build()
+            .unwrap();
+
+        let table = target_table(target.as_slice());
+        let expected = format!(
+            "{} 0 28 J 2 block_size:4096 journal_sectors:64",
+            data_device.to_str().unwrap()
+        );
+        assert_eq!(expected, table);
+
+        let res = DmIntegrityTargetBuilder::default()
+            .data_device(&data_device, 6144)
+            .tag_size(28)
+            .block_size(4096)
+            .build();
+        assert!(res.is_err());
+    }
+
+    #[rdroidtest]
+    fn crypt_target_with_aead_cipher() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let data_device = prepare_tmpfile(test_dir.path(), "data", 8192);
+
+        let target = DmCryptTargetBuilder::default()
+            .data_device(&data_device, 8192)
+            .cipher(CipherType::AES256GCM)
+            .key(KEY_SET_HCTR2.key)
+            .opt_param("sector_size:4096")
+            .build()
+            .unwrap();
+
+        let table = target_table(target.as_slice());
+        assert!(table.starts_with("capi:gcm(aes)-random "), "Unexpected table: {}", table);
+        assert!(
+            table.ends_with(" 2 sector_size:4096 integrity:28:aead "),
+            "Unexpected table: {}",
+            table
+        );
+    }
+
+    #[rdroidtest]
+    fn verity_target_with_opt_params() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let data_device = prepare_tmpfile(test_dir.path(), "data", 8192);
+        let hash_device = prepare_tmpfile(test_dir.path(), "hash", 4096);
+        let fec_device = prepare_tmpfile(test_dir.path(), "fec", 8192);
+
+        let target = DmVerityTargetBuilder::default()
+            .data_device(&data_device, 8192)
+            .hash_device(&hash_device)
+            .root_digest(&[0xaa; 32])
+            .corruption_mode(DmVerityCorruptionMode::Restart)
+            .check_at_most_once(true)
+            .fec(&fec_device, 2, 3, 1)
+            .build()
+            .unwrap();
+
+        let table = target_table(target.as_slice());
+        let expected_opt_params = format!(
+            " 10 restart_on_corruption check_at_most_once use_fec_from_device {} fec_roots 2 \
+            fec_blocks 3 fec_start 1",
+            fec_device.to_str().unwrap()
+        );
+        assert!(table.ends_with(&expected_opt_params), "Unexpected table: {}", table);
+    }
+
+    #[rdroidtest]
+    fn verity_target_with_invalid_fec_roots() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let data_device = prepare_tmpfile(test_dir.path(), "data", 8192);
+        let hash_device = prepare_tmpfile(test_dir.path(), "hash", 4096);
+
+        for roots in [1, 25] {
+            let res = DmVerityTargetBuilder::default()
+                .data_device(&data_device, 8192)
+                .hash_device(&hash_device)
+                .root_digest(&[0xaa; 32])
+                .fec(&hash_device, roots, 2, 0)
+                .build();
+            assert!(res.is_err());
+        }
+    }
+
+    #[rdroidtest]
+    fn verity_target_with_hash_tree_overlapping_data() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let device = prepare_tmpfile(test_dir.path(), "data_and_hash", 12288);
+
+        let mut builder = DmVerityTargetBuilder::default();
+        builder.data_device(&device, 8192).hash_device(&device).root_digest(&[0xaa; 32]);
+        assert!(builder.hash_start_block(1).build().is_err());
+
+        let target = builder.hash_start_block(2).build().unwrap();
+        let table = target_table(target.as_slice());
+        assert!(table.contains(" 2 2 sha256 "), "Unexpected table: {}", table);
+    }
+
+    #[rdroidtest]
+    fn linear_device_padded_with_zeroes() {
+        // This test maps a data device followed by zeroes -> Check the data is read back, followed
+        // by the zeroes.
+        let dm = DeviceMapper::new().unwrap();
+        let inputimg = include_bytes!("../testdata/rand8k");
+        let sz = inputimg.len() as u64;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let backing_file = prepare_tmpfile(test_dir.path(), "storage", sz);
+        std::fs::write(&backing_file, inputimg).unwrap();
+        let data_device = loopdevice::attach(
+            backing_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ false,
+        )
+        .unwrap();
+        let device = "name7";
+        scopeguard::defer! {
+            loopdevice::detach(&data_device).unwrap();
+            let _ignored = delete_device(&dm, device);
+        }
+
+        let linear =
+            DmLinearTargetBuilder::default().data_device(&data_device, sz).build().unwrap();
+        let zero = DmZeroTargetBuilder::default().size(4096).build().unwrap();
+        let table = DmTableBuilder::default().target(&linear).target(&zero).build().unwrap();
+        let linear_device = dm.create_table_device(device, &table, false).unwrap();
+
+        let content = read(linear_device).unwrap();
+        assert_eq!(inputimg.len() + 4096, content.len());
+        assert_eq!(inputimg, &content[..inputimg.len()]);
+        assert!(content[inputimg.len()..].iter().all(|b| *b == 0));
+
+        let targets = dm.table_status(device).unwrap();
+        assert_eq!(2, targets.len());
+        assert_eq!(("linear", 0), (targets[0].target_type.as_str(), targets[0].sector_start));
+        assert_eq!(("zero", sz / 512), (targets[1].target_type.as_str(), targets[1].sector_start));
+    }
+
+    #[rdroidtest]
+    fn snapshot_keeps_origin_intact() {
+        // This test creates a snapshot of a read-only device -> Write data on the snapshot ->
+        // Check the data is visible on the snapshot but not on the origin device.
+        let dm = DeviceMapper::new().unwrap();
+        let inputimg = include_bytes!("../testdata/rand8k");
+        let sz = inputimg.len() as u64;
+
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let origin_file = prepare_tmpfile(test_dir.path(), "origin", sz);
+        let cow_file = prepare_tmpfile(test_dir.path(), "cow", 1 << 20);
+        let origin_device = loopdevice::attach(
+            origin_file,
+            0,
+            sz,
+            /* direct_io */ true,
+            /* writable */ false,
+        )
+        .unwrap();
+        let cow_device = loopdevice::attach(
+            cow_file,
+            0,
+            1 << 20,
+            /* direct_io */ true,
+            /* writable */ true,
+        )
+        .unwrap();
+        let device = "name8";
+        scopeguard::defer! {
+            let _ignored = delete_device(&dm, device);
+            loopdevice::detach(&cow_device).unwrap();
+            loopdevice::detach(&origin_device).unwrap();
+        }
+
+        let target = DmSnapshotTargetBuilder::default()
+            .origin_device(&origin_device, sz)
+            .cow_device(&cow_device)
+            .persistence(SnapshotPersistence::Transient)
+            .build()
+            .unwrap();
+        let table = DmTableBuilder::default().target(&target).build().unwrap();
+        let snapshot_device = dm.create_table_device(device, &table, true).unwrap();
+
+        write_to_dev(&snapshot_device, inputimg);
+        let mut buf = vec![0; inputimg.len()];
+        File::open(&snapshot_device).unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(inputimg, buf.as_slice());
+        assert_eq!(vec![0; inputimg.len()], read(&origin_device).unwrap());
+    }
+
+    #[rdroidtest]
+    fn table_with_multiple_targets() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let data_device = prepare_tmpfile(test_dir.path(), "data", 8192);
+
+        let linear = DmLinearTargetBuilder::default()
+            .data_device(&data_device, 8192)
+            .offset(4)
+            .build()
+            .unwrap();
+        let zero = DmZeroTargetBuilder::default().size(1024).build().unwrap();
+        let table =
+            DmTableBuilder::default().target(&zero).target(&linear).target(&zero).build().unwrap();
+        assert_eq!(3, table.target_count());
+
+        let mut targets = Vec::new();
+        let mut buf = table.as_slice();
+        while !buf.is_empty() {
+            let spec = DmTargetSpec::read_from_prefix(buf).unwrap();
+            targets.push((spec.sector_start, spec.length, target_table(buf).to_owned()));
+            buf = &buf[spec.next as usize..];
+        }
+        let linear_table = format!("{} 4", data_device.to_str().unwrap());
+        assert_eq!(
+            vec![(0, 2, String::new()), (2, 16, linear_table), (18, 2, String::new())],
+            targets
+        );
+
+        assert!(DmTableBuilder::default().build().is_err());
+        assert!(DmZeroTargetBuilder::default().size(1000).build().is_err());
+    }
+
+    #[rdroidtest]
+    fn snapshot_target_with_invalid_chunk_size() {
+        let test_dir = tempfile::TempDir::new().unwrap();
+        let origin_device = prepare_tmpfile(test_dir.path(), "origin", 8192);
+        let cow_device = prepare_tmpfile(test_dir.path(), "cow", 8192);
+
+        let mut builder = DmSnapshotTargetBuilder::default();
+        builder.origin_device(&origin_device, 8192).cow_device(&cow_device);
+        let target = builder.build().unwrap();
+        let expected =
+            format!("{} {} P 8", origin_device.to_str().unwrap(), cow_device.to_str().unwrap());
+        assert_eq!(expected, target_table(target.as_slice()));
+
+        assert!(builder.chunk_size(12).build().is_err());
+    }
 }
@@ -0,0 +1,76 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// `linear` module implements the "linear" target in the device mapper framework. Specifically,
+/// it provides `DmLinearTargetBuilder` struct which is used to construct a `DmLinearTarget`
+/// struct which maps a range of sectors of the mapper device onto another device.
+use crate::table::{flatten_target, DmTarget};
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+// The UAPI for the linear target is at:
+// Documentation/admin-guide/device-mapper/linear.rst
+
+pub struct DmLinearTarget(Box<[u8]>);
+
+impl DmLinearTarget {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl DmTarget for DmLinearTarget {
+    fn as_slice(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+#[derive(Default)]
+pub struct DmLinearTargetBuilder<'a> {
+    device_path: Option<&'a Path>,
+    device_size: u64,
+    offset: u64,
+}
+
+impl<'a> DmLinearTargetBuilder<'a> {
+    /// Sets the device that the sectors are mapped onto, and the size of the mapped range.
+    pub fn data_device(&mut self, p: &'a Path, size: u64) -> &mut Self {
+        self.device_path = Some(p);
+        self.device_size = size;
+        self
+    }
+
+    /// Starting sector within the device
+    pub fn offset(&mut self, offset: u64) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Constructs a `DmLinearTarget`.
+    pub fn build(&self) -> Result<DmLinearTarget> {
+        let device_path = self
+            .device_path
+            .context("data device is not set")?
+            .to_str()
+            .context("data device path is not encoded in utf8")?;
+
+        // <device path> <offset>
+        let body = format!("{} {}", device_path, self.offset);
+        Ok(DmLinearTarget(flatten_target("linear", self.device_size, &body)?))
+    }
+}
@@ -15,7 +15,8 @@
  */
 
 // `loopdevice` module provides `attach` and `detach` functions that are for attaching and
-// detaching a regular file to and from a loop device. Note that
+// detaching a regular file to and from a loop device. It also provides functions for querying and
+// resizing the attached loop devices. Note that
 // `loopdev`(https://crates.io/crates/loopdev) is a public alternative to this. In-house
 // implementation was chosen to make Android-specific changes (like the use of the new
 // LOOP_CONFIGURE instead of the legacy LOOP_SET_FD + LOOP_SET_STATUS64 combo which is considerably
@@ -26,8 +27,10 @@ mod sys;
 use crate::util::*;
 use anyhow::{Context, Result};
 use libc::O_DIRECT;
-use std::fs::{File, OpenOptions};
-use std::os::unix::fs::OpenOptionsExt;
+use nix::errno::Errno;
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::thread;
@@ -40,6 +43,8 @@ use crate::loopdevice::sys::*;
 nix::ioctl_none_bad!(_loop_ctl_get_free, LOOP_CTL_GET_FREE);
 nix::ioctl_write_ptr_bad!(_loop_configure, LOOP_CONFIGURE, loop_config);
 nix::ioctl_none_bad!(_loop_clr_fd, LOOP_CLR_FD);
+nix::ioctl_read_bad!(_loop_get_status64, LOOP_GET_STATUS64, loop_info64);
+nix::ioctl_none_bad!(_loop_set_capacity, LOOP_SET_CAPACITY);
 
 fn loop_ctl_get_free(ctrl_file: &File) -> Result<i32> {
     // SAFETY: this ioctl changes the state in kernel, but not the state in this process.
@@ -59,6 +64,80 @@ pub fn loop_clr_fd(device_file: &File) -> Result<i32> {
     Ok(unsafe { _loop_clr_fd(device_file.as_raw_fd()) }?)
 }
 
+fn loop_get_status64(device_file: &File, info: &mut loop_info64) -> Result<i32, Errno> {
+    // SAFETY: this ioctl doesn't change the state in kernel. The kernel writes the status of the
+    // loop device to `info`, which is a valid `loop_info64` struct.
+    unsafe { _loop_get_status64(device_file.as_raw_fd(), info) }
+}
+
+fn loop_set_capacity(device_file: &File) -> Result<i32> {
+    // SAFETY: this ioctl changes the state in kernel, but not the state in this process.
+    Ok(unsafe { _loop_set_capacity(device_file.as_raw_fd()) }?)
+}
+
+/// Options of a loop device, other than the range of the backing file that it exposes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoopConfigOptions {
+    /// Whether the backing file is accessed with direct I/O, bypassing the page cache.
+    pub direct_io: bool,
+    /// Whether the loop device is writable.
+    pub writable: bool,
+    /// Whether the loop device is detached automatically when it is closed for the last time.
+    pub autoclear: bool,
+    /// Whether the kernel scans the partition table of the loop device, and creates the devices
+    /// of the partitions.
+    pub partscan: bool,
+}
+
+/// A loop device that has been attached, along with a file that keeps it open.
+#[derive(Debug)]
+pub struct LoopDevice {
+    /// Path to the loop device, e.g. "/dev/loop0".
+    pub path: PathBuf,
+    /// The opened loop device. A loop device attached with `LoopConfigOptions::autoclear` is
+    /// detached once this file, and any other file opened on the device, is closed.
+    pub file: File,
+}
+
+/// Status of an attached loop device, as reported by the kernel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopDeviceInfo {
+    /// Name of the backing file, as given when it was attached. The kernel truncates it to 63
+    /// bytes.
+    pub backing_file: PathBuf,
+    /// Device number of the file system of the backing file.
+    pub backing_device: u64,
+    /// Inode number of the backing file.
+    pub backing_inode: u64,
+    /// Offset in bytes of the data exposed by the loop device within the backing file.
+    pub offset: u64,
+    /// Maximum size in bytes of the data exposed by the loop device. 0 means that the data
+    /// extends to the end of the backing file.
+    pub size_limit: u64,
+    pub read_only: bool,
+    pub autoclear: bool,
+    pub partscan: bool,
+    pub direct_io: bool,
+}
+
+impl LoopDeviceInfo {
+    fn from(info: &loop_info64) -> Result<Self> {
+        let name = CStr::from_bytes_until_nul(&info.lo_file_name)
+            .context("backing file name is not null-terminated")?;
+        Ok(Self {
+            backing_file: PathBuf::from(name.to_str().context("backing file name isn't utf8")?),
+            backing_device: info.lo_device,
+            backing_inode: info.lo_inode,
+            offset: info.lo_offset,
+            size_limit: info.lo_sizelimit,
+            read_only: info.lo_flags.contains(Flag::LO_FLAGS_READ_ONLY),
+            autoclear: info.lo_flags.contains(Flag::LO_FLAGS_AUTOCLEAR),
+            partscan: info.lo_flags.contains(Flag::LO_FLAGS_PARTSCAN),
+            direct_io: info.lo_flags.contains(Flag::LO_FLAGS_DIRECT_IO),
+        })
+    }
+}
+
 /// Creates a loop device and attach the given file at `path` as the backing store.
 pub fn attach<P: AsRef<Path>>(
     path: P,
@@ -67,6 +146,18 @@ pub fn attach<P: AsRef<Path>>(
     direct_io: bool,
     writable: bool,
 ) -> Result<PathBuf> {
+    let options = LoopConfigOptions { direct_io, writable, ..Default::default() };
+    Ok(attach_with_options(path, offset, size_limit, &options)?.path)
+}
+
+/// Creates a loop device with the given `options` and attach the given file at `path` as the
+/// backing store.
+pub fn attach_with_options<P: AsRef<Path>>(
+    path: P,
+    offset: u64,
+    size_limit: u64,
+    options: &LoopConfigOptions,
+) -> Result<LoopDevice> {
     // Attaching a file to a loop device can make a race condition; a loop device number obtained
     // from LOOP_CTL_GET_FREE might have been used by another thread or process. In that case the
     // subsequent LOOP_CONFIGURE ioctl returns with EBUSY. Try until it succeeds.
@@ -80,7 +171,7 @@ pub fn attach<P: AsRef<Path>>(
 
     let begin = Instant::now();
     loop {
-        match try_attach(&path, offset, size_limit, direct_io, writable) {
+        match try_attach(&path, offset, size_limit, options) {
             Ok(loop_dev) => return Ok(loop_dev),
             Err(e) => {
                 if begin.elapsed() > TIMEOUT {
@@ -102,9 +193,8 @@ fn try_attach<P: AsRef<Path>>(
     path: P,
     offset: u64,
     size_limit: u64,
-    direct_io: bool,
-    writable: bool,
-) -> Result<PathBuf> {
+    options: &LoopConfigOptions,
+) -> Result<LoopDevice> {
     // Get a free loop device
     wait_for_path(LOOP_CONTROL)?;
     let ctrl_file = OpenOptions::new()
@@ -117,8 +207,8 @@ fn try_attach<P: AsRef<Path>>(
     // Construct the loop_info64 struct
     let backing_file = OpenOptions::new()
         .read(true)
-        .write(writable)
-        .custom_flags(if direct_io { O_DIRECT } else { 0 })
+        .write(options.writable)
+        .custom_flags(if options.direct_io { O_DIRECT } else { 0 })
         .open(&path)
         .context(format!("failed to open {:?}", path.as_ref()))?;
     let mut config = loop_config::new_zeroed();
@@ -127,14 +217,27 @@ fn try_attach<P: AsRef<Path>>(
     config.info.lo_offset = offset;
     config.info.lo_sizelimit = size_limit;
 
-    if !writable {
+    if !options.writable {
         config.info.lo_flags = Flag::LO_FLAGS_READ_ONLY;
     }
 
-    if direct_io {
+    if options.direct_io {
         config.info.lo_flags.insert(Flag::LO_FLAGS_DIRECT_IO);
     }
 
+    if options.autoclear {
+        config.info.lo_flags.insert(Flag::LO_FLAGS_AUTOCLEAR);
+    }
+
+    if options.partscan {
+        config.info.lo_flags.insert(Flag::LO_FLAGS_PARTSCAN);
+    }
+
+    // The name of the backing file is only informative, e.g. for `info`.
+    let name = path.as_ref().as_os_str().as_encoded_bytes();
+    let len = name.len().min(LO_NAME_SIZE - 1);
+    config.info.lo_file_name[..len].copy_from_slice(&name[..len]);
+
     // Configure the loop device to attach the backing file
     let device_path = format!("{}{}", LOOP_DEV_PREFIX, num);
     wait_for_path(&device_path)?;
@@ -146,7 +249,7 @@ fn try_attach<P: AsRef<Path>>(
     loop_configure(&device_file, &config)
         .context(format!("Failed to configure {:?}", &device_path))?;
 
-    Ok(PathBuf::from(device_path))
+    Ok(LoopDevice { path: PathBuf::from(device_path), file: device_file })
 }
 
 /// Detaches backing file from the loop device `path`.
@@ -156,6 +259,52 @@ pub fn detach<P: AsRef<Path>>(path: P) -> Result<()> {
     Ok(())
 }
 
+/// Returns the status of the loop device `path`. This fails if no file is attached to it.
+pub fn info<P: AsRef<Path>>(path: P) -> Result<LoopDeviceInfo> {
+    let device_file = File::open(&path).context(format!("failed to open {:?}", path.as_ref()))?;
+    let mut info = loop_info64::new_zeroed();
+    loop_get_status64(&device_file, &mut info)
+        .context(format!("Failed to get the status of {:?}", path.as_ref()))?;
+    LoopDeviceInfo::from(&info)
+}
+
+/// Updates the size of the loop device `path` after its backing file has been resized.
+pub fn set_capacity<P: AsRef<Path>>(path: P) -> Result<()> {
+    let device_file = File::open(&path).context(format!("failed to open {:?}", path.as_ref()))?;
+    loop_set_capacity(&device_file)
+        .context(format!("Failed to set the capacity of {:?}", path.as_ref()))?;
+    Ok(())
+}
+
+/// Returns the loop device to which the file at `path` is attached, if any. The file is identified
+/// by its inode, so that it is found whatever path it was attached with.
+pub fn find_by_backing_file<P: AsRef<Path>>(path: P) -> Result<Option<PathBuf>> {
+    let metadata = fs::metadata(&path).context(format!("failed to stat {:?}", path.as_ref()))?;
+    for entry in fs::read_dir("/sys/block").context("failed to list block devices")? {
+        let name = entry?.file_name();
+        let Some(num) = name.to_str().and_then(|name| name.strip_prefix("loop")) else {
+            continue;
+        };
+        let device_path = PathBuf::from(format!("{}{}", LOOP_DEV_PREFIX, num));
+        let Ok(device_file) = File::open(&device_path) else {
+            continue;
+        };
+        let mut info = loop_info64::new_zeroed();
+        match loop_get_status64(&device_file, &mut info) {
+            Ok(_) => {}
+            // No file is attached to the loop device.
+            Err(Errno::ENXIO) => continue,
+            Err(e) => {
+                return Err(e).context(format!("Failed to get the status of {:?}", device_path))
+            }
+        }
+        if info.lo_device == metadata.dev() && info.lo_inode == metadata.ino() {
+            return Ok(Some(device_path));
+        }
+    }
+    Ok(None)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,4 +366,53 @@ mod tests {
         assert!(is_direct_io(&dev));
         assert!(is_direct_io_writable(&dev));
     }
+
+    #[test]
+    fn attach_loop_device_with_options() {
+        let a_dir = tempfile::TempDir::new().unwrap();
+        let a_file = a_dir.path().join("test");
+        let a_size = 8192u64;
+        create_empty_file(&a_file, a_size);
+        let options = LoopConfigOptions {
+            writable: true,
+            autoclear: true,
+            partscan: true,
+            ..Default::default()
+        };
+        let dev = attach_with_options(&a_file, 4096, 0, &options).unwrap();
+
+        let dev_info = info(&dev.path).unwrap();
+        assert_eq!(a_file, dev_info.backing_file);
+        assert_eq!((4096, 0), (dev_info.offset, dev_info.size_limit));
+        assert!(dev_info.autoclear && dev_info.partscan);
+        assert!(!dev_info.read_only && !dev_info.direct_io);
+        assert_eq!(Some(dev.path.clone()), find_by_backing_file(&a_file).unwrap());
+
+        // The loop device is detached once it is closed.
+        let path = dev.path.clone();
+        drop(dev);
+        let begin = Instant::now();
+        while info(&path).is_ok() {
+            assert!(begin.elapsed() < Duration::from_secs(1), "{:?} not detached", path);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(None, find_by_backing_file(&a_file).unwrap());
+    }
+
+    #[test]
+    fn resize_loop_device() {
+        let a_dir = tempfile::TempDir::new().unwrap();
+        let a_file = a_dir.path().join("test");
+        create_empty_file(&a_file, 4096);
+        let dev = attach(&a_file, 0, 0, /*direct_io*/ false, /*writable*/ true).unwrap();
+        scopeguard::defer! {
+            detach(&dev).unwrap();
+        }
+        assert_eq!(4096, blkgetsize64(&dev).unwrap());
+
+        File::options().write(true).open(&a_file).unwrap().set_len(16384).unwrap();
+        assert_eq!(4096, blkgetsize64(&dev).unwrap());
+        set_capacity(&dev).unwrap();
+        assert_eq!(16384, blkgetsize64(&dev).unwrap());
+    }
 }
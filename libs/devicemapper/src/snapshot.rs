@@ -0,0 +1,191 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// `snapshot` module implements the "snapshot-origin" and "snapshot" targets in the device mapper
+/// framework. Specifically, it provides `DmSnapshotOriginTargetBuilder` and
+/// `DmSnapshotTargetBuilder` structs which are used to construct `DmSnapshotOriginTarget` and
+/// `DmSnapshotTarget` structs which are then given to `DeviceMapper` to create mapper devices.
+///
+/// A snapshot device is a writable copy-on-write layer over an origin device: the chunks written
+/// to the snapshot are stored on the COW device, leaving the origin device untouched. The
+/// snapshot-origin target is only needed when the origin device itself has to be written to while
+/// the snapshots are kept intact.
+use crate::table::{flatten_target, DmTarget};
+
+use anyhow::{ensure, Context, Result};
+use std::path::Path;
+
+// The UAPI for the snapshot targets is at:
+// Documentation/admin-guide/device-mapper/snapshot.rst
+
+/// The default size of the chunks copied to the COW device, in 512-byte sectors.
+const DEFAULT_CHUNK_SIZE: u64 = 8;
+
+/// Whether the snapshot survives the mapper device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SnapshotPersistence {
+    /// The metadata is stored on the COW device, so that the snapshot can be recreated from it.
+    /// This is the default. A COW device starting with a zeroed sector is initialized as an empty
+    /// snapshot.
+    #[default]
+    Persistent,
+    /// The metadata is kept in memory. The COW device is discarded with the mapper device.
+    Transient,
+}
+
+impl SnapshotPersistence {
+    fn get_kernel_name(&self) -> &str {
+        match *self {
+            SnapshotPersistence::Persistent => "P",
+            SnapshotPersistence::Transient => "N",
+        }
+    }
+}
+
+pub struct DmSnapshotOriginTarget(Box<[u8]>);
+
+impl DmSnapshotOriginTarget {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl DmTarget for DmSnapshotOriginTarget {
+    fn as_slice(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+pub struct DmSnapshotTarget(Box<[u8]>);
+
+impl DmSnapshotTarget {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl DmTarget for DmSnapshotTarget {
+    fn as_slice(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+#[derive(Default)]
+pub struct DmSnapshotOriginTargetBuilder<'a> {
+    origin_path: Option<&'a Path>,
+    origin_size: u64,
+}
+
+impl<'a> DmSnapshotOriginTargetBuilder<'a> {
+    /// Sets the origin device and its size.
+    pub fn origin_device(&mut self, p: &'a Path, size: u64) -> &mut Self {
+        self.origin_path = Some(p);
+        self.origin_size = size;
+        self
+    }
+
+    /// Constructs a `DmSnapshotOriginTarget`.
+    pub fn build(&self) -> Result<DmSnapshotOriginTarget> {
+        let origin_path = self
+            .origin_path
+            .context("origin device is not set")?
+            .to_str()
+            .context("origin device path is not encoded in utf8")?;
+
+        // <origin device path>
+        Ok(DmSnapshotOriginTarget(flatten_target(
+            "snapshot-origin",
+            self.origin_size,
+            origin_path,
+        )?))
+    }
+}
+
+pub struct DmSnapshotTargetBuilder<'a> {
+    origin_path: Option<&'a Path>,
+    origin_size: u64,
+    cow_path: Option<&'a Path>,
+    persistence: SnapshotPersistence,
+    chunk_size: u64,
+}
+
+impl<'a> Default for DmSnapshotTargetBuilder<'a> {
+    fn default() -> Self {
+        DmSnapshotTargetBuilder {
+            origin_path: None,
+            origin_size: 0,
+            cow_path: None,
+            persistence: SnapshotPersistence::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+impl<'a> DmSnapshotTargetBuilder<'a> {
+    /// Sets the origin device and its size, which is also the size of the snapshot. The origin
+    /// device may be read-only.
+    pub fn origin_device(&mut self, p: &'a Path, size: u64) -> &mut Self {
+        self.origin_path = Some(p);
+        self.origin_size = size;
+        self
+    }
+
+    /// Sets the device where the chunks written to the snapshot are stored.
+    pub fn cow_device(&mut self, p: &'a Path) -> &mut Self {
+        self.cow_path = Some(p);
+        self
+    }
+
+    /// Sets whether the snapshot survives the mapper device.
+    pub fn persistence(&mut self, persistence: SnapshotPersistence) -> &mut Self {
+        self.persistence = persistence;
+        self
+    }
+
+    /// Sets the size of the chunks copied to the COW device, in 512-byte sectors. It must be a
+    /// power of two.
+    pub fn chunk_size(&mut self, chunk_size: u64) -> &mut Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Constructs a `DmSnapshotTarget`.
+    pub fn build(&self) -> Result<DmSnapshotTarget> {
+        let origin_path = self
+            .origin_path
+            .context("origin device is not set")?
+            .to_str()
+            .context("origin device path is not encoded in utf8")?;
+        let cow_path = self
+            .cow_path
+            .context("cow device is not set")?
+            .to_str()
+            .context("cow device path is not encoded in utf8")?;
+        ensure!(self.chunk_size.is_power_of_two(), "Invalid chunk size:{}", self.chunk_size);
+
+        // <origin device path> <cow device path> <persistence> <chunk size>
+        let body = format!(
+            "{} {} {} {}",
+            origin_path,
+            cow_path,
+            self.persistence.get_kernel_name(),
+            self.chunk_size
+        );
+        Ok(DmSnapshotTarget(flatten_target("snapshot", self.origin_size, &body)?))
+    }
+}
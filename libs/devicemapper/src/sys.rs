@@ -16,6 +16,7 @@
 
 use bitflags::bitflags;
 use zerocopy::AsBytes;
+use zerocopy::FromBytes;
 use zerocopy::FromZeroes;
 
 // UAPI for device mapper can be found at include/uapi/linux/dm-ioctl.h
@@ -45,7 +46,7 @@ pub enum Cmd {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, AsBytes, FromZeroes)]
+#[derive(Copy, Clone, AsBytes, FromZeroes, FromBytes)]
 pub struct DmIoctl {
     pub version: [u32; 3],
     pub data_size: u32,
@@ -70,7 +71,9 @@ pub const DM_UUID_LEN: usize = 129;
 pub const DM_MAX_TYPE_NAME: usize = 16;
 
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, AsBytes, FromZeroes)]
+#[derive(
+    Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, AsBytes, FromZeroes, FromBytes,
+)]
 pub struct Flag(u32);
 
 bitflags! {
@@ -94,3 +97,12 @@ bitflags! {
         const DM_INTERNAL_SUSPEND_FLAG = 1 << 18;
     }
 }
+
+/// Header of each entry in the result of `DM_LIST_DEVICES`. It is immediately followed by the
+/// null-terminated name of the device, hence the packing.
+#[repr(C, packed)]
+#[derive(Copy, Clone, FromZeroes, FromBytes)]
+pub struct DmNameList {
+    pub dev: u64,
+    pub next: u32,
+}
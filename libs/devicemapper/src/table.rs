@@ -0,0 +1,114 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// `table` module implements device mapper tables made of several targets. Specifically, it
+/// provides `DmTableBuilder` struct which is used to construct a `DmTable` struct which is then
+/// given to `DeviceMapper` to create a mapper device. Each target of the table maps a contiguous
+/// range of sectors of the mapper device, following the range of the previous target.
+use crate::DmTargetSpec;
+
+use anyhow::{ensure, Context, Result};
+use std::io::Write;
+use std::mem::size_of;
+use zerocopy::{AsBytes, FromBytes};
+
+const SECTOR_SIZE: u64 = 512;
+
+/// A target of a device mapper table. Its flattened form is a `dm_target_spec` as defined in
+/// include/uapi/linux/dm-ioctl.h, followed by the parameters of the target.
+pub trait DmTarget {
+    /// Flatten into slice
+    fn as_slice(&self) -> &[u8];
+}
+
+pub struct DmTable {
+    buf: Box<[u8]>,
+    target_count: u32,
+}
+
+impl DmTable {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.buf.as_ref()
+    }
+
+    /// Returns the number of targets in the table.
+    pub fn target_count(&self) -> u32 {
+        self.target_count
+    }
+}
+
+#[derive(Default)]
+pub struct DmTableBuilder<'a> {
+    targets: Vec<&'a dyn DmTarget>,
+}
+
+impl<'a> DmTableBuilder<'a> {
+    /// Appends a target. It maps the sectors following the ones mapped by the previous target.
+    pub fn target(&mut self, target: &'a dyn DmTarget) -> &mut Self {
+        self.targets.push(target);
+        self
+    }
+
+    /// Constructs a `DmTable`.
+    pub fn build(&self) -> Result<DmTable> {
+        ensure!(!self.targets.is_empty(), "table has no target");
+
+        let mut buf = Vec::new();
+        let mut sector_start = 0u64;
+        for (i, target) in self.targets.iter().enumerate() {
+            let target = target.as_slice();
+            let mut header = DmTargetSpec::read_from_prefix(target)
+                .with_context(|| format!("target #{} is too short", i))?;
+            ensure!(header.length > 0, "target #{} is empty", i);
+            ensure!(header.next as usize == target.len(), "target #{} is malformed", i);
+
+            header.sector_start = sector_start;
+            sector_start = sector_start.checked_add(header.length).context("table is too large")?;
+            buf.write_all(header.as_bytes())?;
+            buf.write_all(&target[size_of::<DmTargetSpec>()..])?;
+        }
+
+        Ok(DmTable { buf: buf.into_boxed_slice(), target_count: self.targets.len() as u32 })
+    }
+}
+
+/// Flattens a target mapping `size` bytes with the parameters in `body`, for the targets whose
+/// parameters don't need any processing.
+pub(crate) fn flatten_target(target_type: &str, size: u64, body: &str) -> Result<Box<[u8]>> {
+    ensure!(size > 0, "size is not set");
+    ensure!(
+        size & (SECTOR_SIZE - 1) == 0,
+        "size:{} is not a multiple of the sector size:{}",
+        size,
+        SECTOR_SIZE
+    );
+
+    let size_with_body = size_of::<DmTargetSpec>() + body.len() + 1; // null terminator
+    let aligned_size = (size_with_body + 7) & !7; // align to 8 byte boundaries
+    let padding = aligned_size - size_with_body;
+
+    let mut header = DmTargetSpec::new(target_type)?;
+    header.sector_start = 0;
+    header.length = size / SECTOR_SIZE; // number of 512-byte sectors
+    header.next = aligned_size as u32;
+
+    let mut buf = Vec::with_capacity(aligned_size);
+    buf.write_all(header.as_bytes())?;
+    buf.write_all(body.as_bytes())?;
+    buf.write_all(vec![0; padding + 1].as_slice())?;
+    Ok(buf.into_boxed_slice())
+}
@@ -18,12 +18,13 @@
 // it provides `DmVerityTargetBuilder` struct which is used to construct a `DmVerityTarget` struct
 // which is then given to `DeviceMapper` to create a mapper device.
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use std::io::Write;
 use std::mem::size_of;
 use std::path::Path;
 use zerocopy::AsBytes;
 
+use crate::table::DmTarget;
 use crate::util::*;
 use crate::DmTargetSpec;
 
@@ -49,15 +50,78 @@ pub enum DmVerityHashAlgorithm {
     SHA512,
 }
 
+/// What the verity target does when it detects a corrupted block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DmVerityCorruptionMode {
+    /// The I/O fails with EIO. This is the default of the kernel.
+    #[default]
+    Eio,
+    /// The corrupted block is logged, but the I/O proceeds normally (`ignore_corruption`).
+    Ignore,
+    /// The system is restarted (`restart_on_corruption`).
+    Restart,
+    /// The system panics (`panic_on_corruption`).
+    Panic,
+}
+
+impl DmVerityCorruptionMode {
+    fn opt_param(&self) -> Option<&str> {
+        match *self {
+            DmVerityCorruptionMode::Eio => None,
+            DmVerityCorruptionMode::Ignore => Some("ignore_corruption"),
+            DmVerityCorruptionMode::Restart => Some("restart_on_corruption"),
+            DmVerityCorruptionMode::Panic => Some("panic_on_corruption"),
+        }
+    }
+}
+
+/// Forward error correction (FEC) of the verity target. Reed-Solomon codes stored on a separate
+/// device are used to recover corrupted blocks.
+struct DmVerityFec<'a> {
+    device: &'a Path,
+    roots: u8,
+    blocks: u64,
+    start: u64,
+}
+
+// Range of the number of parity bytes supported by the kernel. See `DM_VERITY_FEC_MIN_RSN` and
+// `DM_VERITY_FEC_MAX_RSN` in drivers/md/dm-verity-fec.h.
+const DM_VERITY_FEC_MIN_ROOTS: u8 = 2;
+const DM_VERITY_FEC_MAX_ROOTS: u8 = 24;
+
+/// Status of a verity target, as returned by `DeviceMapper::verity_status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmVerityStatus {
+    /// No corruption has been detected.
+    Verified,
+    /// A corrupted block has been detected.
+    Corrupted,
+}
+
+impl DmVerityStatus {
+    pub(crate) fn parse(status: &str) -> Result<Self> {
+        // Newer kernels may report more fields after the first one.
+        match status.split(' ').next() {
+            Some("V") => Ok(DmVerityStatus::Verified),
+            Some("C") => Ok(DmVerityStatus::Corrupted),
+            _ => bail!("unknown verity status: {}", status),
+        }
+    }
+}
+
 /// A builder that constructs `DmVerityTarget` struct.
 pub struct DmVerityTargetBuilder<'a> {
     version: DmVerityVersion,
     data_device: Option<&'a Path>,
     data_size: u64,
     hash_device: Option<&'a Path>,
+    hash_start_block: u64,
     hash_algorithm: DmVerityHashAlgorithm,
     root_digest: Option<&'a [u8]>,
     salt: Option<&'a [u8]>,
+    corruption_mode: DmVerityCorruptionMode,
+    check_at_most_once: bool,
+    fec: Option<DmVerityFec<'a>>,
 }
 
 impl DmVerityTarget {
@@ -67,6 +131,12 @@ impl DmVerityTarget {
     }
 }
 
+impl DmTarget for DmVerityTarget {
+    fn as_slice(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
 impl<'a> Default for DmVerityTargetBuilder<'a> {
     fn default() -> Self {
         DmVerityTargetBuilder {
@@ -74,9 +144,13 @@ impl<'a> Default for DmVerityTargetBuilder<'a> {
             data_device: None,
             data_size: 0,
             hash_device: None,
+            hash_start_block: 0,
             hash_algorithm: DmVerityHashAlgorithm::SHA256,
             root_digest: None,
             salt: None,
+            corruption_mode: DmVerityCorruptionMode::default(),
+            check_at_most_once: false,
+            fec: None,
         }
     }
 }
@@ -95,6 +169,13 @@ impl<'a> DmVerityTargetBuilder<'a> {
         self
     }
 
+    /// Sets the offset, in hash blocks, of the root block of the merkle tree in the hash device.
+    /// This allows the data and the merkle tree to be on the same device.
+    pub fn hash_start_block(&mut self, block: u64) -> &mut Self {
+        self.hash_start_block = block;
+        self
+    }
+
     /// Sets the hash algorithm that the merkle tree is using.
     pub fn hash_algorithm(&mut self, algo: DmVerityHashAlgorithm) -> &mut Self {
         self.hash_algorithm = algo;
@@ -114,6 +195,27 @@ impl<'a> DmVerityTargetBuilder<'a> {
         self
     }
 
+    /// Sets what to do when a corrupted block is detected.
+    pub fn corruption_mode(&mut self, mode: DmVerityCorruptionMode) -> &mut Self {
+        self.corruption_mode = mode;
+        self
+    }
+
+    /// Sets whether each data block is verified only the first time it is read. This reduces the
+    /// overhead of verity, at the cost of not detecting a block corrupted after it was read.
+    pub fn check_at_most_once(&mut self, check_at_most_once: bool) -> &mut Self {
+        self.check_at_most_once = check_at_most_once;
+        self
+    }
+
+    /// Enables forward error correction with the Reed-Solomon codes in `device`. `roots` is the
+    /// number of parity bytes per codeword, `blocks` the number of data blocks covered by the
+    /// codes and `start` the offset, in data blocks, of the codes in `device`.
+    pub fn fec(&mut self, device: &'a Path, roots: u8, blocks: u64, start: u64) -> &mut Self {
+        self.fec = Some(DmVerityFec { device, roots, blocks, start });
+        self
+    }
+
     /// Constructs a `DmVerityTarget`.
     pub fn build(&self) -> Result<DmVerityTarget> {
         // The `DmVerityTarget` struct actually is a flattened data consisting of a header and
@@ -162,6 +264,45 @@ impl<'a> DmVerityTargetBuilder<'a> {
             hex::encode(self.salt.unwrap())
         };
 
+        if self.hash_device == self.data_device {
+            ensure!(
+                self.hash_start_block * hash_block_size as u64 >= data_size,
+                "merkle tree at hash block {} overlaps with the data",
+                self.hash_start_block
+            );
+        }
+
+        let mut opt_params = Vec::new();
+        if let Some(param) = self.corruption_mode.opt_param() {
+            opt_params.push(param.to_string());
+        }
+        if self.check_at_most_once {
+            opt_params.push("check_at_most_once".to_string());
+        }
+        if let Some(fec) = &self.fec {
+            let fec_device_path =
+                fec.device.to_str().context("fec device path is not encoded in utf8")?;
+            ensure!(
+                (DM_VERITY_FEC_MIN_ROOTS..=DM_VERITY_FEC_MAX_ROOTS).contains(&fec.roots),
+                "fec roots must be between {} and {}, but is {}",
+                DM_VERITY_FEC_MIN_ROOTS,
+                DM_VERITY_FEC_MAX_ROOTS,
+                fec.roots
+            );
+            ensure!(
+                fec.blocks >= num_data_blocks,
+                "fec blocks ({}) must cover all the data blocks ({})",
+                fec.blocks,
+                num_data_blocks
+            );
+            opt_params.push(format!("use_fec_from_device {}", fec_device_path));
+            opt_params.push(format!("fec_roots {}", fec.roots));
+            opt_params.push(format!("fec_blocks {}", fec.blocks));
+            opt_params.push(format!("fec_start {}", fec.start));
+        }
+        // Each parameter with a value counts as two.
+        let num_opt_params: usize = opt_params.iter().map(|p| p.split(' ').count()).sum();
+
         // Step2: serialize the information according to the spec, which is ...
         // DmTargetSpec{...}
         // <version> <dev> <hash_dev>
@@ -171,7 +312,6 @@ impl<'a> DmVerityTargetBuilder<'a> {
         // [<#opt_params> <opt_params>]
         // null terminator
 
-        // TODO(jiyong): support the optional parameters... if needed.
         let mut body = String::new();
         use std::fmt::Write;
         write!(&mut body, "{} ", version)?;
@@ -180,10 +320,13 @@ impl<'a> DmVerityTargetBuilder<'a> {
         write!(&mut body, "{} ", data_block_size)?;
         write!(&mut body, "{} ", hash_block_size)?;
         write!(&mut body, "{} ", num_data_blocks)?;
-        write!(&mut body, "{} ", 0)?; // hash_start_block
+        write!(&mut body, "{} ", self.hash_start_block)?;
         write!(&mut body, "{} ", hash_algorithm)?;
         write!(&mut body, "{} ", root_digest)?;
         write!(&mut body, "{}", salt)?;
+        if !opt_params.is_empty() {
+            write!(&mut body, " {} {}", num_opt_params, opt_params.join(" "))?;
+        }
         write!(&mut body, "\0")?; // null terminator
 
         let size = size_of::<DmTargetSpec>() + body.len();
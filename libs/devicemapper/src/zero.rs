@@ -0,0 +1,59 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// `zero` module implements the "zero" target in the device mapper framework. Specifically, it
+/// provides `DmZeroTargetBuilder` struct which is used to construct a `DmZeroTarget` struct which
+/// maps a range of sectors that reads as zeroes and discards writes.
+use crate::table::{flatten_target, DmTarget};
+
+use anyhow::Result;
+
+// The UAPI for the zero target is at:
+// Documentation/admin-guide/device-mapper/zero.rst
+
+pub struct DmZeroTarget(Box<[u8]>);
+
+impl DmZeroTarget {
+    /// Flatten into slice
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl DmTarget for DmZeroTarget {
+    fn as_slice(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+#[derive(Default)]
+pub struct DmZeroTargetBuilder {
+    size: u64,
+}
+
+impl DmZeroTargetBuilder {
+    /// Sets the size in bytes of the mapped range.
+    pub fn size(&mut self, size: u64) -> &mut Self {
+        self.size = size;
+        self
+    }
+
+    /// Constructs a `DmZeroTarget`.
+    pub fn build(&self) -> Result<DmZeroTarget> {
+        // The zero target has no parameter.
+        Ok(DmZeroTarget(flatten_target("zero", self.size, "")?))
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structural comparison of device trees.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+
+use crate::dts::{format_value, phandle_specifiers};
+use crate::overlay::{node_path, PHANDLE_PROPS};
+use crate::{Fdt, FdtNode, Result};
+
+/// Difference between two device trees, as reported by [`Fdt::diff`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FdtChange {
+    /// A node only exists in the new DT. Its descendants aren't reported.
+    NodeAdded {
+        /// Path of the node.
+        path: String,
+    },
+    /// A node only exists in the old DT. Its descendants aren't reported.
+    NodeRemoved {
+        /// Path of the node.
+        path: String,
+    },
+    /// A property only exists in the new DT.
+    PropertyAdded {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        name: String,
+        /// Value of the property.
+        value: Vec<u8>,
+    },
+    /// A property only exists in the old DT.
+    PropertyRemoved {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        name: String,
+        /// Value of the property.
+        value: Vec<u8>,
+    },
+    /// A property exists in both DTs, with different values.
+    PropertyChanged {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        name: String,
+        /// Value of the property in the old DT.
+        old: Vec<u8>,
+        /// Value of the property in the new DT.
+        new: Vec<u8>,
+    },
+}
+
+impl fmt::Display for FdtChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NodeAdded { path } => write!(f, "+ {path}"),
+            Self::NodeRemoved { path } => write!(f, "- {path}"),
+            Self::PropertyAdded { path, name, value } if value.is_empty() => {
+                write!(f, "+ {path}:{name}")
+            }
+            Self::PropertyAdded { path, name, value } => {
+                write!(f, "+ {path}:{name} = {}", format_value(value))
+            }
+            Self::PropertyRemoved { path, name, .. } => write!(f, "- {path}:{name}"),
+            Self::PropertyChanged { path, name, old, new } => {
+                write!(f, "~ {path}:{name}: {} -> {}", format_value(old), format_value(new))
+            }
+        }
+    }
+}
+
+/// Returns the changes turning `old` into `new`.
+pub(crate) fn diff(old: &Fdt, new: &Fdt) -> Result<Vec<FdtChange>> {
+    let mut changes = Vec::new();
+    diff_nodes(&old.root(), &new.root(), &mut changes)?;
+
+    Ok(changes)
+}
+
+fn diff_nodes(old: &FdtNode, new: &FdtNode, changes: &mut Vec<FdtChange>) -> Result<()> {
+    let path = node_path(old)?;
+
+    let mut prop = old.first_property()?;
+    while let Some(property) = prop {
+        let name = property.name()?;
+        let old_value = property.value()?;
+        match new.getprop(name)? {
+            None => changes.push(FdtChange::PropertyRemoved {
+                path: path.clone(),
+                name: name.to_string_lossy().into(),
+                value: old_value.into(),
+            }),
+            Some(new_value) if !same_value(old.fdt, new.fdt, name, old_value, new_value)? => {
+                changes.push(FdtChange::PropertyChanged {
+                    path: path.clone(),
+                    name: name.to_string_lossy().into(),
+                    old: old_value.into(),
+                    new: new_value.into(),
+                })
+            }
+            Some(_) => {}
+        }
+        prop = property.next_property()?;
+    }
+    let mut prop = new.first_property()?;
+    while let Some(property) = prop {
+        let name = property.name()?;
+        if old.getprop(name)?.is_none() {
+            changes.push(FdtChange::PropertyAdded {
+                path: path.clone(),
+                name: name.to_string_lossy().into(),
+                value: property.value()?.into(),
+            });
+        }
+        prop = property.next_property()?;
+    }
+
+    // Don't look subnodes up by name, as libfdt would match "node" with "node@1".
+    for old_subnode in old.subnodes()? {
+        match find_subnode(new, old_subnode.name()?)? {
+            Some(new_subnode) => diff_nodes(&old_subnode, &new_subnode, changes)?,
+            None => changes.push(FdtChange::NodeRemoved { path: node_path(&old_subnode)? }),
+        }
+    }
+    for new_subnode in new.subnodes()? {
+        if find_subnode(old, new_subnode.name()?)?.is_none() {
+            changes.push(FdtChange::NodeAdded { path: node_path(&new_subnode)? });
+        }
+    }
+
+    Ok(())
+}
+
+fn find_subnode<'a>(node: &FdtNode<'a>, name: &CStr) -> Result<Option<FdtNode<'a>>> {
+    for subnode in node.subnodes()? {
+        if subnode.name()? == name {
+            return Ok(Some(subnode));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Compares property values, ignoring phandle renumbering.
+///
+/// The phandles held by known phandle properties are compared through the paths of the nodes
+/// they reference.
+fn same_value(
+    old: &Fdt,
+    new: &Fdt,
+    name: &CStr,
+    old_value: &[u8],
+    new_value: &[u8],
+) -> Result<bool> {
+    if PHANDLE_PROPS.contains(&name.to_bytes()) {
+        return Ok(true);
+    }
+    let Ok(name) = name.to_str() else {
+        return Ok(old_value == new_value);
+    };
+    let (Some(old_specifiers), Some(new_specifiers)) =
+        (phandle_specifiers(old, name, old_value)?, phandle_specifiers(new, name, new_value)?)
+    else {
+        return Ok(old_value == new_value);
+    };
+
+    if old_specifiers.len() != new_specifiers.len() {
+        return Ok(false);
+    }
+    for ((old_node, old_args), (new_node, new_args)) in old_specifiers.iter().zip(&new_specifiers) {
+        if old_args != new_args || node_path(old_node)? != node_path(new_node)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,1261 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversions between flattened device trees and their DTS source, without relying on dtc.
+//!
+//! The DTS emitted by [`to_dts`] is canonical: compiling it with [`compile_dts`] results in the
+//! same blob, as long as the original one was packed and laid out like dtc does.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+use core::iter;
+use core::mem;
+
+use crate::libfdt::Libfdt;
+use crate::{Fdt, FdtError, FdtNode, FdtProperty, NodeOffset, Phandle, Result};
+
+/// Properties holding phandles, each followed by as many cells as given by the named property of
+/// the referenced node, if any.
+const REFERENCE_PROPS: [(&str, Option<&CStr>); 12] = [
+    ("clocks", Some(c"#clock-cells")),
+    ("dmas", Some(c"#dma-cells")),
+    ("interrupt-parent", None),
+    ("interrupts-extended", Some(c"#interrupt-cells")),
+    ("iommus", Some(c"#iommu-cells")),
+    ("mboxes", Some(c"#mbox-cells")),
+    ("memory-region", None),
+    ("msi-parent", None),
+    ("phys", Some(c"#phy-cells")),
+    ("power-domains", Some(c"#power-domain-cells")),
+    ("pwms", Some(c"#pwm-cells")),
+    ("resets", Some(c"#reset-cells")),
+];
+
+/// Version of the blobs generated by [`compile_dts`].
+const FDT_VERSION: u32 = 17;
+/// Oldest version the blobs generated by [`compile_dts`] are compatible with.
+const FDT_LAST_COMP_VERSION: u32 = 16;
+/// Size of the FDT header, which is directly followed by the memory reservation block.
+const FDT_HEADER_SIZE: usize = 40;
+
+/// Returns the DTS source of the DT.
+pub(crate) fn to_dts(fdt: &Fdt) -> Result<String> {
+    DtsWriter::new(fdt)?.write()
+}
+
+struct DtsWriter<'a> {
+    fdt: &'a Fdt,
+    /// Labels of the nodes, from `__symbols__` or generated from their phandle.
+    labels: BTreeMap<NodeOffset, Vec<String>>,
+    out: String,
+}
+
+impl<'a> DtsWriter<'a> {
+    fn new(fdt: &'a Fdt) -> Result<Self> {
+        let mut labels: BTreeMap<_, Vec<String>> = BTreeMap::new();
+        if let Some(symbols) = fdt.symbols()? {
+            let mut prop = symbols.first_property()?;
+            while let Some(symbol) = prop {
+                let label = symbol.name()?.to_str().ok().filter(|l| is_label(l));
+                let path = CStr::from_bytes_with_nul(symbol.value()?).ok();
+                // Ignore symbols that can't be printed as labels instead of failing.
+                if let (Some(label), Some(node)) = (label, path.and_then(|p| fdt.node(p).ok()?)) {
+                    labels.entry(node.offset).or_default().push(label.to_string());
+                }
+                prop = symbol.next_property()?;
+            }
+        }
+
+        let mut used: BTreeSet<_> = labels.values().flatten().cloned().collect();
+        let root = fdt.root();
+        for node in iter::once(root).chain(root.descendants().map(|(node, _)| node)) {
+            let Some(phandle) = node.get_phandle()? else {
+                continue;
+            };
+            let node_labels = labels.entry(node.offset).or_default();
+            if node_labels.is_empty() {
+                let mut label = format!("phandle_{:x}", u32::from(phandle));
+                while used.contains(&label) {
+                    label.push('_');
+                }
+                used.insert(label.clone());
+                node_labels.push(label);
+            }
+        }
+
+        Ok(Self { fdt, labels, out: String::new() })
+    }
+
+    fn write(mut self) -> Result<String> {
+        self.out.push_str("/dts-v1/;\n\n");
+        let reservations = self.fdt.num_mem_rsv()?;
+        for i in 0..reservations {
+            let (address, size) = self.fdt.get_mem_rsv(i)?;
+            self.out.push_str(&format!("/memreserve/ {address:#018x} {size:#018x};\n"));
+        }
+        if reservations > 0 {
+            self.out.push('\n');
+        }
+        self.write_node(&self.fdt.root(), 0)?;
+
+        Ok(self.out)
+    }
+
+    fn write_node(&mut self, node: &FdtNode<'a>, depth: usize) -> Result<()> {
+        self.out.push_str(&"\t".repeat(depth));
+        for label in self.labels.get(&node.offset).into_iter().flatten() {
+            self.out.push_str(label);
+            self.out.push_str(": ");
+        }
+        if depth == 0 {
+            self.out.push('/');
+        } else {
+            self.out.push_str(to_str(node.name()?)?);
+        }
+        self.out.push_str(" {\n");
+
+        let mut prop = node.first_property()?;
+        while let Some(property) = prop {
+            self.write_property(&property, depth + 1)?;
+            prop = property.next_property()?;
+        }
+        for subnode in node.subnodes()? {
+            self.out.push('\n');
+            self.write_node(&subnode, depth + 1)?;
+        }
+
+        self.out.push_str(&"\t".repeat(depth));
+        self.out.push_str("};\n");
+
+        Ok(())
+    }
+
+    fn write_property(&mut self, property: &FdtProperty<'a>, depth: usize) -> Result<()> {
+        let name = to_str(property.name()?)?;
+        let value = property.value()?;
+
+        self.out.push_str(&"\t".repeat(depth));
+        self.out.push_str(name);
+        if !value.is_empty() {
+            self.out.push_str(" = ");
+            match self.references(name, value)? {
+                Some(references) => self.out.push_str(&references),
+                None => self.out.push_str(&format_value(value)),
+            }
+        }
+        self.out.push_str(";\n");
+
+        Ok(())
+    }
+
+    /// Formats the cells of a known phandle property, with phandles replaced by references.
+    fn references(&self, name: &str, value: &[u8]) -> Result<Option<String>> {
+        let Some(specifiers) = phandle_specifiers(self.fdt, name, value)? else {
+            return Ok(None);
+        };
+
+        let mut cells = Vec::new();
+        for (node, args) in specifiers {
+            // All the nodes with a phandle have been given a label.
+            let Some(label) = self.labels.get(&node.offset).and_then(|labels| labels.first())
+            else {
+                return Ok(None);
+            };
+            cells.push(format!("&{label}"));
+            cells.extend(args.iter().map(|arg| format!("{arg:#x}")));
+        }
+
+        Ok(Some(format!("<{}>", cells.join(" "))))
+    }
+}
+
+/// Formats a property value like dtc, as strings, cells or bytes.
+pub(crate) fn format_value(value: &[u8]) -> String {
+    if let Some(strings) = as_strings(value) {
+        let strings: Vec<_> = strings
+            .map(|string| {
+                let mut quoted = String::from('"');
+                for &c in string {
+                    if c == b'"' || c == b'\\' {
+                        quoted.push('\\');
+                    }
+                    quoted.push(c.into());
+                }
+                quoted.push('"');
+                quoted
+            })
+            .collect();
+        strings.join(", ")
+    } else if let Some(cells) = as_cells(value) {
+        let cells: Vec<_> = cells.map(|cell| format!("{cell:#x}")).collect();
+        format!("<{}>", cells.join(" "))
+    } else {
+        let bytes: Vec<_> = value.iter().map(|byte| format!("{byte:02x}")).collect();
+        format!("[{}]", bytes.join(" "))
+    }
+}
+
+/// Node referenced from a phandle property, with the argument cells following its phandle.
+pub(crate) type PhandleSpecifier<'a> = (FdtNode<'a>, Vec<u32>);
+
+/// Splits the value of a known phandle property into the referenced nodes and their arguments.
+///
+/// Returns `None` for other properties or if the value doesn't match the layout expected from the
+/// referenced nodes.
+pub(crate) fn phandle_specifiers<'a>(
+    fdt: &'a Fdt,
+    name: &str,
+    value: &[u8],
+) -> Result<Option<Vec<PhandleSpecifier<'a>>>> {
+    let Some((_, args_prop)) = REFERENCE_PROPS.iter().find(|(prop, _)| *prop == name) else {
+        return Ok(None);
+    };
+    let Some(mut cells) = as_cells(value) else {
+        return Ok(None);
+    };
+
+    let mut specifiers = Vec::new();
+    while let Some(phandle) = cells.next() {
+        let Some(phandle) = Phandle::new(phandle) else {
+            return Ok(None);
+        };
+        let Some(node) = fdt.node_with_phandle(phandle)? else {
+            return Ok(None);
+        };
+        let count = match args_prop {
+            Some(prop) => match node.getprop_u32(prop) {
+                Ok(Some(count)) => count.try_into().unwrap(),
+                _ => return Ok(None),
+            },
+            None => 0,
+        };
+        let args: Vec<_> = cells.by_ref().take(count).collect();
+        if args.len() != count {
+            return Ok(None);
+        }
+        specifiers.push((node, args));
+    }
+
+    Ok(Some(specifiers))
+}
+
+fn to_str(s: &CStr) -> Result<&str> {
+    s.to_str().map_err(|_| FdtError::BadValue)
+}
+
+/// Returns the big-endian cells of the value, if its size is a multiple of the cell size.
+fn as_cells(value: &[u8]) -> Option<impl Iterator<Item = u32> + '_> {
+    let chunks = value.chunks_exact(4);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+
+    Some(chunks.map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap())))
+}
+
+/// Returns the strings of the value, if it looks like a list of printable strings to dtc.
+fn as_strings(value: &[u8]) -> Option<impl Iterator<Item = &[u8]>> {
+    let (&last, strings) = value.split_last()?;
+    let nuls = value.iter().filter(|&&c| c == 0).count();
+    let printable = value.iter().all(|&c| c == 0 || (b' '..=b'~').contains(&c));
+    if last != 0 || !printable || nuls >= value.len() - nuls {
+        return None;
+    }
+
+    Some(strings.split(|&c| c == 0))
+}
+
+fn is_label(s: &str) -> bool {
+    s.bytes().next().is_some_and(|c| !c.is_ascii_digit()) && s.bytes().all(is_label_char)
+}
+
+fn is_label_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn is_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b",._+*#?@-".contains(&c)
+}
+
+/// Options of [`compile_dts`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DtsOptions {
+    /// Generates a `/__symbols__` node for the labels of the source, like `dtc -@`.
+    pub symbols: bool,
+}
+
+/// Error reported by [`compile_dts`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DtsError {
+    /// Line of the source where the error was found, starting from 1.
+    pub line: usize,
+    /// Column of the source where the error was found, in characters and starting from 1.
+    pub column: usize,
+    /// Description of the error.
+    pub message: String,
+}
+
+impl DtsError {
+    fn new(source: &str, pos: usize, message: impl Into<String>) -> Self {
+        let before = &source[..pos];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for DtsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Compiles DTS source into a packed FDT blob.
+///
+/// This supports the subset of the dtc syntax used in practice for virtual platforms and overlays
+/// (`/plugin/`, labels, `&label` and `&{/path}` references, `/memreserve/`, `/bits/`, integer
+/// expressions, `/delete-node/` and `/delete-property/`) but not the C preprocessor, `/include/`
+/// nor `/incbin/`. As with dtc, referenced nodes are given phandles and plugins get their
+/// `__fixups__` and `__local_fixups__` nodes.
+pub fn compile_dts(source: &str, options: DtsOptions) -> core::result::Result<Vec<u8>, DtsError> {
+    let mut tree = Parser::new(source).parse()?;
+    tree.check_labels(source)?;
+    tree.resolve_paths(source)?;
+    let mut phandles = PhandleAllocator::new(&tree.root);
+    let fixups = tree.resolve_phandles(source, &mut phandles)?;
+    if options.symbols {
+        tree.add_symbols(&mut phandles);
+    }
+    if tree.plugin {
+        tree.add_fixups(fixups);
+    }
+
+    tree.flatten().ok_or_else(|| DtsError::new(source, source.len(), "Device tree is too large"))
+}
+
+type ParseResult<T> = core::result::Result<T, DtsError>;
+
+/// Reference to a node, by label or by path.
+#[derive(Clone)]
+struct Reference {
+    target: String,
+    /// Position of the reference in the source.
+    pos: usize,
+}
+
+impl Reference {
+    fn is_path(&self) -> bool {
+        self.target.starts_with('/')
+    }
+
+    fn not_found(&self, source: &str) -> DtsError {
+        DtsError::new(source, self.pos, format!("Label or path {} not found", self.target))
+    }
+}
+
+enum Data {
+    Bytes(Vec<u8>),
+    /// Phandle of the referenced node, as a 32-bit cell.
+    Phandle(Reference),
+    /// Path of the referenced node, as a string.
+    Path(Reference),
+}
+
+impl Data {
+    fn len(&self) -> usize {
+        match self {
+            Self::Bytes(bytes) => bytes.len(),
+            Self::Phandle(_) => 4,
+            // Resolved before the offsets of phandles are needed.
+            Self::Path(_) => 0,
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    value: Vec<Data>,
+}
+
+#[derive(Default)]
+struct Node {
+    name: String,
+    /// Labels of the node, with their position in the source.
+    labels: Vec<(String, usize)>,
+    properties: Vec<Property>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn new(name: &str) -> Self {
+        Self { name: name.into(), ..Default::default() }
+    }
+
+    /// Returns the named child, creating it if needed.
+    fn child_mut(&mut self, name: &str) -> &mut Self {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(Self::new(name));
+                self.children.len() - 1
+            }
+        };
+
+        &mut self.children[index]
+    }
+
+    fn set_property(&mut self, name: &str, value: Vec<Data>) {
+        match self.properties.iter_mut().find(|prop| prop.name == name) {
+            Some(prop) => prop.value = value,
+            None => self.properties.push(Property { name: name.into(), value }),
+        }
+    }
+
+    fn append_property(&mut self, name: &str, data: Data) {
+        match self.properties.iter_mut().find(|prop| prop.name == name) {
+            Some(prop) => prop.value.push(data),
+            None => self.properties.push(Property { name: name.into(), value: vec![data] }),
+        }
+    }
+
+    fn phandle(&self) -> Option<u32> {
+        ["phandle", "linux,phandle"].iter().find_map(|name| {
+            let prop = self.properties.iter().find(|prop| prop.name == *name)?;
+            match prop.value.as_slice() {
+                [Data::Bytes(bytes)] => Some(u32::from_be_bytes(bytes.as_slice().try_into().ok()?)),
+                _ => None,
+            }
+        })
+    }
+
+    fn get(&self, indices: &[usize]) -> &Self {
+        indices.iter().fold(self, |node, &i| &node.children[i])
+    }
+
+    fn get_mut(&mut self, indices: &[usize]) -> &mut Self {
+        indices.iter().fold(self, |node, &i| &mut node.children[i])
+    }
+
+    /// Returns the indices of the children leading to the referenced node.
+    fn find(&self, reference: &Reference) -> Option<Vec<usize>> {
+        if !reference.is_path() {
+            return self.find_label(&reference.target);
+        }
+        let mut node = self;
+        let mut indices = Vec::new();
+        for name in reference.target.split('/').filter(|name| !name.is_empty()) {
+            let index = node.children.iter().position(|child| child.name == name)?;
+            node = &node.children[index];
+            indices.push(index);
+        }
+
+        Some(indices)
+    }
+
+    fn find_label(&self, label: &str) -> Option<Vec<usize>> {
+        if self.labels.iter().any(|(l, _)| l == label) {
+            return Some(Vec::new());
+        }
+        self.children.iter().enumerate().find_map(|(i, child)| {
+            let mut indices = child.find_label(label)?;
+            indices.insert(0, i);
+            Some(indices)
+        })
+    }
+
+    fn path(&self, indices: &[usize]) -> String {
+        if indices.is_empty() {
+            return String::from("/");
+        }
+        let mut path = String::new();
+        let mut node = self;
+        for &i in indices {
+            node = &node.children[i];
+            path.push('/');
+            path.push_str(&node.name);
+        }
+
+        path
+    }
+
+    /// Calls `f` on the node and its descendants, in depth-first order.
+    fn walk(&self, indices: &mut Vec<usize>, f: &mut impl FnMut(&[usize], &Self)) {
+        f(indices, self);
+        for (i, child) in self.children.iter().enumerate() {
+            indices.push(i);
+            child.walk(indices, f);
+            indices.pop();
+        }
+    }
+
+    fn flatten(&self, structure: &mut Vec<u8>, strings: &mut Vec<u8>) -> Option<()> {
+        structure.extend_from_slice(&libfdt_bindgen::FDT_BEGIN_NODE.to_be_bytes());
+        structure.extend_from_slice(self.name.as_bytes());
+        structure.push(0);
+        structure.resize(structure.len().next_multiple_of(4), 0);
+
+        for prop in &self.properties {
+            let len: u32 = prop.value.iter().map(Data::len).sum::<usize>().try_into().ok()?;
+            structure.extend_from_slice(&libfdt_bindgen::FDT_PROP.to_be_bytes());
+            structure.extend_from_slice(&len.to_be_bytes());
+            structure.extend_from_slice(&string_offset(strings, &prop.name)?.to_be_bytes());
+            for data in &prop.value {
+                // References have all been resolved by now.
+                if let Data::Bytes(bytes) = data {
+                    structure.extend_from_slice(bytes);
+                }
+            }
+            structure.resize(structure.len().next_multiple_of(4), 0);
+        }
+
+        for child in &self.children {
+            child.flatten(structure, strings)?;
+        }
+        structure.extend_from_slice(&libfdt_bindgen::FDT_END_NODE.to_be_bytes());
+
+        Some(())
+    }
+}
+
+/// Returns the offset of `name` in the strings block, appending it if needed.
+///
+/// Like dtc, this reuses the suffixes of existing strings.
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> Option<u32> {
+    let name = name.as_bytes();
+    let offset = strings
+        .windows(name.len() + 1)
+        .position(|s| s.ends_with(&[0]) && s.starts_with(name))
+        .unwrap_or_else(|| {
+            let offset = strings.len();
+            strings.extend_from_slice(name);
+            strings.push(0);
+            offset
+        });
+
+    offset.try_into().ok()
+}
+
+/// Allocates phandles to the nodes that need one, picking the lowest unused values like dtc.
+struct PhandleAllocator {
+    used: BTreeSet<u32>,
+    next: u32,
+}
+
+impl PhandleAllocator {
+    fn new(root: &Node) -> Self {
+        let mut used = BTreeSet::new();
+        root.walk(&mut Vec::new(), &mut |_, node| {
+            used.extend(node.phandle());
+        });
+
+        Self { used, next: 1 }
+    }
+
+    /// Returns the phandle of the node, adding a `phandle` property to it if it has none.
+    fn get_or_assign(&mut self, root: &mut Node, indices: &[usize]) -> u32 {
+        let node = root.get_mut(indices);
+        if let Some(phandle) = node.phandle() {
+            return phandle;
+        }
+        while self.used.contains(&self.next) {
+            self.next += 1;
+        }
+        let phandle = self.next;
+        self.used.insert(phandle);
+        node.set_property("phandle", vec![Data::Bytes(phandle.to_be_bytes().into())]);
+
+        phandle
+    }
+}
+
+/// References to nodes of a plugin, to be resolved when applying it.
+#[derive(Default)]
+struct Fixups {
+    /// Locations of the references to each unknown label, as `path:property:offset` strings.
+    external: Vec<(String, Vec<u8>)>,
+    /// Locations of the references to nodes of the plugin, as (path, property, offset).
+    local: Vec<(String, String, u32)>,
+}
+
+struct Tree {
+    plugin: bool,
+    reservations: Vec<(u64, u64)>,
+    root: Node,
+}
+
+impl Tree {
+    fn check_labels(&self, source: &str) -> ParseResult<()> {
+        let mut labels = BTreeSet::new();
+        let mut duplicate = None;
+        self.root.walk(&mut Vec::new(), &mut |_, node| {
+            for (label, pos) in &node.labels {
+                if !labels.insert(label.clone()) && duplicate.is_none() {
+                    duplicate = Some((label.clone(), *pos));
+                }
+            }
+        });
+
+        match duplicate {
+            Some((label, pos)) => {
+                Err(DtsError::new(source, pos, format!("Duplicate label {label}")))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the location of the values matching `f`, in depth-first order.
+    fn find_data(&self, f: impl Fn(&Data) -> bool) -> Vec<(Vec<usize>, usize, usize)> {
+        let mut found = Vec::new();
+        self.root.walk(&mut Vec::new(), &mut |indices, node| {
+            for (i, prop) in node.properties.iter().enumerate() {
+                for (j, data) in prop.value.iter().enumerate() {
+                    if f(data) {
+                        found.push((indices.to_vec(), i, j));
+                    }
+                }
+            }
+        });
+
+        found
+    }
+
+    fn resolve_paths(&mut self, source: &str) -> ParseResult<()> {
+        for (indices, i, j) in self.find_data(|data| matches!(data, Data::Path(_))) {
+            let Data::Path(reference) = &self.root.get(&indices).properties[i].value[j] else {
+                continue;
+            };
+            let target = self.root.find(reference).ok_or_else(|| reference.not_found(source))?;
+            let mut path = self.root.path(&target).into_bytes();
+            path.push(0);
+            self.root.get_mut(&indices).properties[i].value[j] = Data::Bytes(path);
+        }
+
+        Ok(())
+    }
+
+    fn resolve_phandles(
+        &mut self,
+        source: &str,
+        phandles: &mut PhandleAllocator,
+    ) -> ParseResult<Fixups> {
+        let mut fixups = Fixups::default();
+        for (indices, i, j) in self.find_data(|data| matches!(data, Data::Phandle(_))) {
+            let prop = &self.root.get(&indices).properties[i];
+            let Data::Phandle(reference) = &prop.value[j] else {
+                continue;
+            };
+            let reference = reference.clone();
+            let name = prop.name.clone();
+            let offset: u32 =
+                prop.value[..j].iter().map(Data::len).sum::<usize>().try_into().unwrap();
+
+            let phandle = match self.root.find(&reference) {
+                Some(target) => {
+                    if self.plugin {
+                        fixups.local.push((self.root.path(&indices), name, offset));
+                    }
+                    phandles.get_or_assign(&mut self.root, &target)
+                }
+                None if self.plugin && !reference.is_path() => {
+                    let location = format!("{}:{name}:{offset}\0", self.root.path(&indices));
+                    match fixups.external.iter_mut().find(|(label, _)| *label == reference.target) {
+                        Some((_, locations)) => locations.extend_from_slice(location.as_bytes()),
+                        None => fixups.external.push((reference.target, location.into_bytes())),
+                    }
+                    u32::MAX
+                }
+                None => return Err(reference.not_found(source)),
+            };
+            let data = Data::Bytes(phandle.to_be_bytes().into());
+            self.root.get_mut(&indices).properties[i].value[j] = data;
+        }
+
+        Ok(fixups)
+    }
+
+    fn add_symbols(&mut self, phandles: &mut PhandleAllocator) {
+        let mut labels = Vec::new();
+        self.root.walk(&mut Vec::new(), &mut |indices, node| {
+            for (label, _) in &node.labels {
+                labels.push((label.clone(), indices.to_vec()));
+            }
+        });
+
+        // Like dtc, don't generate an empty node.
+        if labels.is_empty() {
+            return;
+        }
+        let mut symbols = Vec::new();
+        for (label, indices) in labels {
+            phandles.get_or_assign(&mut self.root, &indices);
+            let mut path = self.root.path(&indices).into_bytes();
+            path.push(0);
+            symbols.push((label, path));
+        }
+        let node = self.root.child_mut("__symbols__");
+        for (label, path) in symbols {
+            node.set_property(&label, vec![Data::Bytes(path)]);
+        }
+    }
+
+    fn add_fixups(&mut self, fixups: Fixups) {
+        if !fixups.external.is_empty() {
+            let node = self.root.child_mut("__fixups__");
+            for (label, locations) in fixups.external {
+                node.set_property(&label, vec![Data::Bytes(locations)]);
+            }
+        }
+        for (path, prop, offset) in fixups.local {
+            let mut node = self.root.child_mut("__local_fixups__");
+            for name in path.split('/').filter(|name| !name.is_empty()) {
+                node = node.child_mut(name);
+            }
+            node.append_property(&prop, Data::Bytes(offset.to_be_bytes().into()));
+        }
+    }
+
+    fn flatten(&self) -> Option<Vec<u8>> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        self.root.flatten(&mut structure, &mut strings)?;
+        structure.extend_from_slice(&libfdt_bindgen::FDT_END.to_be_bytes());
+
+        let rsvmap_size = (self.reservations.len() + 1) * 2 * mem::size_of::<u64>();
+        let off_dt_struct = FDT_HEADER_SIZE + rsvmap_size;
+        let off_dt_strings = off_dt_struct + structure.len();
+        let totalsize = off_dt_strings + strings.len();
+        let header = [
+            libfdt_bindgen::FDT_MAGIC,
+            totalsize.try_into().ok()?,
+            off_dt_struct.try_into().ok()?,
+            off_dt_strings.try_into().ok()?,
+            FDT_HEADER_SIZE.try_into().ok()?,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            strings.len().try_into().ok()?,
+            structure.len().try_into().ok()?,
+        ];
+
+        let mut fdt = Vec::with_capacity(totalsize);
+        for field in header {
+            fdt.extend_from_slice(&field.to_be_bytes());
+        }
+        for &(address, size) in self.reservations.iter().chain(iter::once(&(0, 0))) {
+            fdt.extend_from_slice(&address.to_be_bytes());
+            fdt.extend_from_slice(&size.to_be_bytes());
+        }
+        fdt.extend_from_slice(&structure);
+        fdt.extend_from_slice(&strings);
+
+        Some(fdt)
+    }
+}
+
+/// Binary operators supported in integer expressions, longest first, with their precedence.
+const BINARY_OPERATORS: [(&str, u8); 18] = [
+    ("||", 1),
+    ("&&", 2),
+    ("==", 6),
+    ("!=", 6),
+    ("<=", 7),
+    (">=", 7),
+    ("<<", 8),
+    (">>", 8),
+    ("|", 3),
+    ("^", 4),
+    ("&", 5),
+    ("<", 7),
+    (">", 7),
+    ("+", 9),
+    ("-", 9),
+    ("*", 10),
+    ("/", 10),
+    ("%", 10),
+];
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+    /// Number of fragments created for `&ref { ... };` in a plugin.
+    fragments: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { source, pos: 0, fragments: 0 }
+    }
+
+    fn error<T>(&self, pos: usize, message: impl Into<String>) -> ParseResult<T> {
+        Err(DtsError::new(self.source, pos, message))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) -> ParseResult<()> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if let Some(comment) = trimmed.strip_prefix("/*") {
+                let Some(end) = comment.find("*/") else {
+                    return self.error(self.pos, "Unterminated comment");
+                };
+                self.pos += end + 4;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Consumes `token` if it comes next.
+    fn eat(&mut self, token: &str) -> ParseResult<bool> {
+        self.skip_whitespace()?;
+        let found = self.rest().starts_with(token);
+        if found {
+            self.pos += token.len();
+        }
+
+        Ok(found)
+    }
+
+    fn expect(&mut self, token: &str) -> ParseResult<()> {
+        if !self.eat(token)? {
+            return self.error(self.pos, format!("Expected '{token}'"));
+        }
+
+        Ok(())
+    }
+
+    /// Consumes the (ASCII) characters matching `f`, up to `max` of them.
+    fn take_while(&mut self, max: usize, f: impl Fn(u8) -> bool) -> &'a str {
+        let start = self.pos;
+        self.pos += self.rest().bytes().take(max).take_while(|&c| f(c)).count();
+
+        &self.source[start..self.pos]
+    }
+
+    fn parse(mut self) -> ParseResult<Tree> {
+        self.expect("/dts-v1/")?;
+        self.expect(";")?;
+        let plugin = self.eat("/plugin/")?;
+        if plugin {
+            self.expect(";")?;
+        }
+
+        let mut reservations = Vec::new();
+        loop {
+            let start = self.pos;
+            let labels = self.labels()?;
+            if self.eat("/memreserve/")? {
+                reservations.push((self.primary()?, self.primary()?));
+                self.expect(";")?;
+            } else if !labels.is_empty() {
+                return self.error(start, "Expected '/memreserve/'");
+            } else {
+                break;
+            }
+        }
+
+        let mut root = Node::default();
+        loop {
+            self.skip_whitespace()?;
+            let start = self.pos;
+            if self.peek().is_none() {
+                break;
+            }
+            if self.eat("/delete-node/")? {
+                let reference = self.reference()?;
+                self.expect(";")?;
+                let indices =
+                    root.find(&reference).ok_or_else(|| reference.not_found(self.source))?;
+                let Some((&last, parent)) = indices.split_last() else {
+                    return self.error(reference.pos, "Can't delete the root node");
+                };
+                root.get_mut(parent).children.remove(last);
+            } else if self.eat("/")? {
+                self.node_body(&mut root)?;
+            } else if self.peek() == Some(b'&') {
+                let reference = self.reference()?;
+                if plugin {
+                    let mut fragment = Node::new(&format!("fragment@{}", self.fragments));
+                    self.fragments += 1;
+                    if reference.is_path() {
+                        let mut path = reference.target.into_bytes();
+                        path.push(0);
+                        fragment.set_property("target-path", vec![Data::Bytes(path)]);
+                    } else {
+                        fragment.set_property("target", vec![Data::Phandle(reference)]);
+                    }
+                    self.node_body(fragment.child_mut("__overlay__"))?;
+                    root.children.push(fragment);
+                } else {
+                    let indices =
+                        root.find(&reference).ok_or_else(|| reference.not_found(self.source))?;
+                    self.node_body(root.get_mut(&indices))?;
+                }
+            } else {
+                return self.error(start, "Expected a node definition");
+            }
+        }
+
+        Ok(Tree { plugin, reservations, root })
+    }
+
+    /// Parses the labels of the definition that follows.
+    fn labels(&mut self) -> ParseResult<Vec<(String, usize)>> {
+        let mut labels = Vec::new();
+        loop {
+            self.skip_whitespace()?;
+            let start = self.pos;
+            let label = self.take_while(usize::MAX, is_label_char);
+            if !is_label(label) || self.peek() != Some(b':') {
+                self.pos = start;
+                return Ok(labels);
+            }
+            self.pos += 1;
+            labels.push((label.into(), start));
+        }
+    }
+
+    fn name(&mut self) -> ParseResult<&'a str> {
+        self.skip_whitespace()?;
+        let name = self.take_while(usize::MAX, is_name_char);
+        if name.is_empty() {
+            return self.error(self.pos, "Expected a node or property name");
+        }
+
+        Ok(name)
+    }
+
+    /// Parses `{ ... };` into `node`, merging it with the existing definitions.
+    fn node_body(&mut self, node: &mut Node) -> ParseResult<()> {
+        self.expect("{")?;
+        while !self.eat("}")? {
+            if self.eat("/delete-property/")? {
+                let name = self.name()?;
+                node.properties.retain(|prop| prop.name != name);
+                self.expect(";")?;
+                continue;
+            }
+            if self.eat("/delete-node/")? {
+                let name = self.name()?;
+                node.children.retain(|child| child.name != name);
+                self.expect(";")?;
+                continue;
+            }
+
+            let labels = self.labels()?;
+            let name = self.name()?;
+            if self.eat("=")? {
+                let value = self.value()?;
+                node.set_property(name, value);
+                self.expect(";")?;
+            } else if self.eat(";")? {
+                node.set_property(name, Vec::new());
+            } else {
+                let child = node.child_mut(name);
+                child.labels.extend(labels);
+                self.node_body(child)?;
+            }
+        }
+
+        self.expect(";")
+    }
+
+    fn value(&mut self) -> ParseResult<Vec<Data>> {
+        let mut value = Vec::new();
+        loop {
+            // Labels within values are only meaningful for dtc's assembly output.
+            self.labels()?;
+            let start = self.pos;
+            match self.peek() {
+                Some(b'"') => value.push(Data::Bytes(self.string()?)),
+                Some(b'[') => {
+                    self.pos += 1;
+                    value.push(Data::Bytes(self.bytes()?));
+                }
+                Some(b'&') => value.push(Data::Path(self.reference()?)),
+                Some(b'<') => {
+                    self.pos += 1;
+                    self.cells(32, &mut value)?;
+                }
+                _ if self.eat("/bits/")? => {
+                    let bits_pos = self.pos;
+                    let bits = self.primary()?;
+                    if ![8, 16, 32, 64].contains(&bits) {
+                        return self.error(bits_pos, format!("Invalid cell size {bits}"));
+                    }
+                    self.expect("<")?;
+                    self.cells(bits, &mut value)?;
+                }
+                _ => return self.error(start, "Expected a property value"),
+            }
+            self.labels()?;
+            if !self.eat(",")? {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> ParseResult<Vec<u8>> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut string = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(b'\n') => return self.error(start, "Unterminated string"),
+                Some(b'"') => break,
+                Some(b'\\') => string.push(self.escape()?),
+                Some(c) => {
+                    string.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.pos += 1;
+        string.push(0);
+
+        Ok(string)
+    }
+
+    fn char_literal(&mut self) -> ParseResult<u64> {
+        let start = self.pos;
+        self.pos += 1;
+        let c = match self.peek() {
+            Some(b'\\') => self.escape()?,
+            Some(c) if c.is_ascii() && c != b'\'' => {
+                self.pos += 1;
+                c
+            }
+            _ => return self.error(start, "Invalid character literal"),
+        };
+        if self.peek() != Some(b'\'') {
+            return self.error(start, "Unterminated character literal");
+        }
+        self.pos += 1;
+
+        Ok(c.into())
+    }
+
+    fn escape(&mut self) -> ParseResult<u8> {
+        let start = self.pos;
+        self.pos += 1;
+        let c = match self.peek() {
+            Some(c) if c.is_ascii() => c,
+            _ => return self.error(start, "Invalid escape sequence"),
+        };
+        self.pos += 1;
+
+        let (digits, radix) = match c {
+            b'a' => return Ok(0x07),
+            b'b' => return Ok(0x08),
+            b't' => return Ok(b'\t'),
+            b'n' => return Ok(b'\n'),
+            b'v' => return Ok(0x0b),
+            b'f' => return Ok(0x0c),
+            b'r' => return Ok(b'\r'),
+            b'x' => (self.take_while(2, |c| c.is_ascii_hexdigit()), 16),
+            b'0'..=b'7' => {
+                self.pos -= 1;
+                (self.take_while(3, |c| (b'0'..=b'7').contains(&c)), 8)
+            }
+            c => return Ok(c),
+        };
+
+        u8::from_str_radix(digits, radix).or_else(|_| self.error(start, "Invalid escape sequence"))
+    }
+
+    fn bytes(&mut self) -> ParseResult<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while !self.eat("]")? {
+            let start = self.pos;
+            let byte = self.take_while(2, |c| c.is_ascii_hexdigit());
+            if byte.len() != 2 {
+                return self.error(start, "Expected a byte");
+            }
+            bytes.push(u8::from_str_radix(byte, 16).unwrap());
+        }
+
+        Ok(bytes)
+    }
+
+    fn cells(&mut self, bits: u64, value: &mut Vec<Data>) -> ParseResult<()> {
+        let mut bytes = Vec::new();
+        loop {
+            self.labels()?;
+            let start = self.pos;
+            if self.eat(">")? {
+                break;
+            }
+            if self.peek() == Some(b'&') {
+                if bits != 32 {
+                    return self.error(start, "References are only allowed in 32-bit cells");
+                }
+                let reference = self.reference()?;
+                if !bytes.is_empty() {
+                    value.push(Data::Bytes(mem::take(&mut bytes)));
+                }
+                value.push(Data::Phandle(reference));
+                continue;
+            }
+            let cell = self.primary()?.to_be_bytes();
+            // Like dtc, truncate values that don't fit in a cell.
+            bytes.extend_from_slice(&cell[cell.len() - usize::try_from(bits / 8).unwrap()..]);
+        }
+        if !bytes.is_empty() {
+            value.push(Data::Bytes(bytes));
+        }
+
+        Ok(())
+    }
+
+    /// Parses a `&label` or `&{/path}` reference.
+    fn reference(&mut self) -> ParseResult<Reference> {
+        self.skip_whitespace()?;
+        let pos = self.pos;
+        self.expect("&")?;
+        let target = if self.peek() == Some(b'{') {
+            self.pos += 1;
+            let path = self.take_while(usize::MAX, |c| c == b'/' || is_name_char(c));
+            if !path.starts_with('/') || self.peek() != Some(b'}') {
+                return self.error(pos, "Invalid path reference");
+            }
+            self.pos += 1;
+            path
+        } else {
+            let label = self.take_while(usize::MAX, is_label_char);
+            if !is_label(label) {
+                return self.error(pos, "Invalid label reference");
+            }
+            label
+        };
+
+        Ok(Reference { target: target.into(), pos })
+    }
+
+    /// Parses an integer literal, a character literal or a parenthesized expression.
+    fn primary(&mut self) -> ParseResult<u64> {
+        self.skip_whitespace()?;
+        let start = self.pos;
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let value = self.expression()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(b'\'') => self.char_literal(),
+            Some(c) if c.is_ascii_digit() => {
+                let literal = self.take_while(usize::MAX, |c| c.is_ascii_alphanumeric());
+                let digits = literal.trim_end_matches(['U', 'L', 'u', 'l']);
+                let value = if let Some(hex) = digits.strip_prefix("0x") {
+                    u64::from_str_radix(hex, 16)
+                } else if let Some(hex) = digits.strip_prefix("0X") {
+                    u64::from_str_radix(hex, 16)
+                } else if digits.len() > 1 && digits.starts_with('0') {
+                    u64::from_str_radix(&digits[1..], 8)
+                } else {
+                    digits.parse()
+                };
+                value.or_else(|_| self.error(start, format!("Invalid integer {literal}")))
+            }
+            _ => self.error(start, "Expected an integer"),
+        }
+    }
+
+    fn expression(&mut self) -> ParseResult<u64> {
+        let condition = self.binary(1)?;
+        if !self.eat("?")? {
+            return Ok(condition);
+        }
+        let if_true = self.expression()?;
+        self.expect(":")?;
+        let if_false = self.expression()?;
+
+        Ok(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> ParseResult<u64> {
+        let mut lhs = self.unary()?;
+        loop {
+            self.skip_whitespace()?;
+            let rest = self.rest();
+            let Some(&(op, precedence)) =
+                BINARY_OPERATORS.iter().find(|(op, _)| rest.starts_with(op))
+            else {
+                return Ok(lhs);
+            };
+            if precedence < min_precedence {
+                return Ok(lhs);
+            }
+            let start = self.pos;
+            self.pos += op.len();
+            let rhs = self.binary(precedence + 1)?;
+            let shift = |f: fn(u64, u32) -> Option<u64>| {
+                u32::try_from(rhs).ok().and_then(|rhs| f(lhs, rhs)).unwrap_or(0)
+            };
+            lhs = match op {
+                "||" => (lhs != 0 || rhs != 0).into(),
+                "&&" => (lhs != 0 && rhs != 0).into(),
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "==" => (lhs == rhs).into(),
+                "!=" => (lhs != rhs).into(),
+                "<" => (lhs < rhs).into(),
+                ">" => (lhs > rhs).into(),
+                "<=" => (lhs <= rhs).into(),
+                ">=" => (lhs >= rhs).into(),
+                "<<" => shift(u64::checked_shl),
+                ">>" => shift(u64::checked_shr),
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "/" | "%" if rhs == 0 => return self.error(start, "Division by zero"),
+                "/" => lhs / rhs,
+                "%" => lhs % rhs,
+                _ => unreachable!(),
+            };
+        }
+    }
+
+    fn unary(&mut self) -> ParseResult<u64> {
+        if self.eat("-")? {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("~")? {
+            Ok(!self.unary()?)
+        } else if self.eat("!")? {
+            Ok((self.unary()? == 0).into())
+        } else {
+            self.primary()
+        }
+    }
+}
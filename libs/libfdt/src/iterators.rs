@@ -14,10 +14,12 @@
 
 //! Iterators over cells, and various layers on top of them.
 
+use crate::libfdt::Libfdt;
 use crate::Fdt;
 use crate::FdtError;
 use crate::FdtNode;
 use crate::FdtProperty;
+use crate::ReservedMemory;
 use crate::{AddrCells, SizeCells};
 use core::ffi::CStr;
 use core::marker::PhantomData;
@@ -173,6 +175,45 @@ impl<'a> Iterator for MemRegIterator<'a> {
     }
 }
 
+/// Entry of the memory reservation block of a DT, as declared with `/memreserve/` in DTS.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemReservation {
+    /// Base address of the reserved region.
+    pub addr: u64,
+    /// Size of the reserved region.
+    pub size: u64,
+}
+
+/// Iterator over the entries of the memory reservation block.
+#[derive(Debug)]
+pub struct MemReservationIterator<'a> {
+    fdt: &'a Fdt,
+    index: usize,
+    count: usize,
+}
+
+impl<'a> MemReservationIterator<'a> {
+    pub(crate) fn new(fdt: &'a Fdt) -> Result<Self, FdtError> {
+        let count = fdt.num_mem_rsv()?;
+
+        Ok(Self { fdt, index: 0, count })
+    }
+}
+
+impl<'a> Iterator for MemReservationIterator<'a> {
+    type Item = MemReservation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+        let (addr, size) = self.fdt.get_mem_rsv(self.index).ok()?;
+        self.index += 1;
+
+        Some(Self::Item { addr, size })
+    }
+}
+
 /// Iterator over the 'ranges' property of a DT node.
 #[derive(Debug)]
 pub struct RangesIterator<'a, A, P, S> {
@@ -253,7 +294,7 @@ impl FromAddrCells for (u32, u64) {
     }
 }
 
-trait FromSizeCells: Sized {
+pub(crate) trait FromSizeCells: Sized {
     fn from_size_cells(cells: &mut CellIterator, cell_count: SizeCells) -> Option<Self>;
 }
 
@@ -311,6 +352,28 @@ impl<'a> Iterator for SubnodeIterator<'a> {
     }
 }
 
+/// Iterator over the regions of the /reserved-memory node.
+#[derive(Debug)]
+pub struct ReservedMemoryIterator<'a> {
+    subnodes: SubnodeIterator<'a>,
+}
+
+impl<'a> ReservedMemoryIterator<'a> {
+    pub(crate) fn new(node: &FdtNode<'a>) -> Result<Self, FdtError> {
+        let subnodes = SubnodeIterator::new(node)?;
+
+        Ok(Self { subnodes })
+    }
+}
+
+impl<'a> Iterator for ReservedMemoryIterator<'a> {
+    type Item = ReservedMemory<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.subnodes.next().map(ReservedMemory::new)
+    }
+}
+
 /// Iterator over descendants
 #[derive(Debug)]
 pub struct DescendantsIterator<'a> {
@@ -17,18 +17,33 @@
 
 #![no_std]
 
+extern crate alloc;
+
+mod diff;
+mod dts;
 mod iterators;
 mod libfdt;
+mod overlay;
+mod reserved_memory;
 mod result;
 mod safe_types;
+mod schema;
 
+pub use diff::FdtChange;
+pub use dts::{compile_dts, DtsError, DtsOptions};
 pub use iterators::{
     AddressRange, CellIterator, CompatibleIterator, DescendantsIterator, MemRegIterator,
-    PropertyIterator, RangesIterator, Reg, RegIterator, SubnodeIterator,
+    MemReservation, MemReservationIterator, PropertyIterator, RangesIterator, Reg, RegIterator,
+    ReservedMemoryIterator, SubnodeIterator,
 };
+pub use overlay::OverlayError;
+pub use reserved_memory::ReservedMemory;
 pub use result::{FdtError, Result};
 pub use safe_types::{FdtHeader, NodeOffset, Phandle, PropOffset, StringOffset};
+pub use schema::{FdtValidationError, NodeSchema, NodeSelector, PropertySchema, ValueSchema};
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::ffi::{c_void, CStr};
 use core::ops::Range;
 use cstr::cstr;
@@ -36,6 +51,7 @@ use libfdt::get_slice_at_ptr;
 use zerocopy::AsBytes as _;
 
 use crate::libfdt::{Libfdt, LibfdtMut};
+use crate::overlay::ResolvedOverlay;
 
 /// Value of a #address-cells property.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -688,6 +704,56 @@ impl Fdt {
         Ok(self)
     }
 
+    /// Applies a DT overlay on the base DT, without relying on libfdt's `fdt_overlay_apply()`.
+    ///
+    /// The `__fixups__` and `__local_fixups__` of `overlay` are resolved and its phandles are
+    /// renumbered to follow the ones of `self`, but `overlay` itself is left untouched. As the
+    /// whole overlay is resolved before `self` gets modified, `self` may only be left partially
+    /// modified by libfdt errors (e.g. [`FdtError::NoSpace`]) while merging the fragments.
+    pub fn merge_overlay(&mut self, overlay: &Fdt) -> core::result::Result<(), OverlayError> {
+        ResolvedOverlay::new(self, overlay)?.apply(self)
+    }
+
+    /// Checks that [`Fdt::merge_overlay`] would be able to resolve `overlay` against this DT,
+    /// without modifying either of them.
+    ///
+    /// Note that this doesn't check whether the buffer of `self` is large enough for the result.
+    pub fn validate_overlay(&self, overlay: &Fdt) -> core::result::Result<(), OverlayError> {
+        ResolvedOverlay::new(self, overlay).map(|_| ())
+    }
+
+    /// Returns the DTS source of this DT.
+    ///
+    /// The nodes are labelled after `__symbols__` or, for other nodes with a phandle, after the
+    /// phandle itself, so that well-known phandle properties (e.g. `interrupt-parent`) are
+    /// printed as references. Passing the result to [`compile_dts`] gives back the same DT.
+    pub fn to_dts(&self) -> Result<String> {
+        dts::to_dts(self)
+    }
+
+    /// Returns the structural differences between this DT and `new`.
+    ///
+    /// Nodes and properties are matched by path and name, regardless of their order. Phandle
+    /// values are not compared, so that renumbering phandles isn't reported as a change, and the
+    /// values of known phandle properties (e.g. `interrupt-parent`) are compared through the
+    /// paths of the nodes they reference.
+    pub fn diff(&self, new: &Fdt) -> Result<Vec<FdtChange>> {
+        diff::diff(self, new)
+    }
+
+    /// Checks this DT against a declarative schema, returning all the violations found.
+    pub fn validate(
+        &self,
+        schema: &[NodeSchema],
+    ) -> core::result::Result<(), Vec<FdtValidationError>> {
+        let errors = schema::validate(self, schema);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     /// Returns an iterator of memory banks specified the "/memory" node.
     /// Throws an error when the "/memory" is not found in the device tree.
     ///
@@ -705,6 +771,90 @@ impl Fdt {
         self.memory()?.next().ok_or(FdtError::NotFound)
     }
 
+    /// Returns an iterator over the entries of the memory reservation block.
+    pub fn mem_reservations(&self) -> Result<MemReservationIterator> {
+        MemReservationIterator::new(self)
+    }
+
+    /// Adds an entry to the memory reservation block.
+    pub fn add_mem_reservation(&mut self, addr: u64, size: u64) -> Result<()> {
+        self.add_mem_rsv(addr, size)
+    }
+
+    /// Removes the entry at the given index from the memory reservation block.
+    ///
+    /// The entries following it are moved down by one.
+    pub fn remove_mem_reservation(&mut self, index: usize) -> Result<()> {
+        self.del_mem_rsv(index)
+    }
+
+    /// Returns an iterator over the regions of the standard /reserved-memory node.
+    pub fn reserved_memory(&self) -> Result<Option<ReservedMemoryIterator>> {
+        self.node(reserved_memory::RESERVED_MEMORY_PATH)?
+            .map(|node| ReservedMemoryIterator::new(&node))
+            .transpose()
+    }
+
+    /// Returns the standard /reserved-memory node as mutable.
+    pub fn reserved_memory_mut(&mut self) -> Result<Option<FdtNodeMut>> {
+        self.node_mut(reserved_memory::RESERVED_MEMORY_PATH)
+    }
+
+    /// Adds a statically allocated region to /reserved-memory, creating the node if needed.
+    ///
+    /// Flags such as `no-map` can then be set on the returned node.
+    pub fn add_reserved_memory(&mut self, name: &CStr, addr: u64, size: u64) -> Result<FdtNodeMut> {
+        reserved_memory::add_static(self, name, addr, size)
+    }
+
+    /// Adds a dynamically allocated region to /reserved-memory, creating the node if needed.
+    ///
+    /// Flags such as `reusable` and `alloc-ranges` can then be set on the returned node.
+    pub fn add_dynamic_reserved_memory(
+        &mut self,
+        name: &CStr,
+        size: u64,
+        alignment: Option<u64>,
+    ) -> Result<FdtNodeMut> {
+        reserved_memory::add_dynamic(self, name, size, alignment)
+    }
+
+    /// Returns the standard /aliases node.
+    pub fn aliases(&self) -> Result<Option<FdtNode>> {
+        self.root().subnode(cstr!("aliases"))
+    }
+
+    /// Returns the path that the given alias stands for.
+    pub fn alias(&self, name: &CStr) -> Result<Option<&CStr>> {
+        let Some(path) = self.aliases()?.map(|node| node.getprop(name)).transpose()?.flatten()
+        else {
+            return Ok(None);
+        };
+
+        CStr::from_bytes_with_nul(path).map(Some).map_err(|_| FdtError::BadValue)
+    }
+
+    /// Returns a node by its alias.
+    pub fn node_with_alias(&self, name: &CStr) -> Result<Option<FdtNode>> {
+        let offset = self.alias_offset(name)?;
+
+        Ok(offset.map(|offset| FdtNode { fdt: self, offset }))
+    }
+
+    /// Returns a mutable node by its alias.
+    pub fn node_mut_with_alias(&mut self, name: &CStr) -> Result<Option<FdtNodeMut>> {
+        let offset = self.alias_offset(name)?;
+
+        Ok(offset.map(|offset| FdtNodeMut { fdt: self, offset }))
+    }
+
+    fn alias_offset(&self, name: &CStr) -> Result<Option<NodeOffset>> {
+        match self.alias(name)? {
+            Some(path) => self.path_offset_namelen(path.to_bytes()),
+            None => Ok(None),
+        }
+    }
+
     /// Returns the standard /chosen node.
     pub fn chosen(&self) -> Result<Option<FdtNode>> {
         self.root().subnode(cstr!("chosen"))
@@ -309,6 +309,28 @@ pub(crate) unsafe trait Libfdt {
         CStr::from_bytes_until_nul(bytes).map_err(|_| FdtError::Internal)
     }
 
+    /// Safe wrapper around `fdt_num_mem_rsv()` (C function).
+    fn num_mem_rsv(&self) -> Result<usize> {
+        let fdt = self.as_fdt_slice().as_ptr().cast();
+        // SAFETY: Accesses (read-only) are constrained to the DT totalsize.
+        let ret = unsafe { libfdt_bindgen::fdt_num_mem_rsv(fdt) };
+
+        FdtRawResult::from(ret).try_into()
+    }
+
+    /// Safe wrapper around `fdt_get_mem_rsv()` (C function).
+    fn get_mem_rsv(&self, index: usize) -> Result<(u64, u64)> {
+        let fdt = self.as_fdt_slice().as_ptr().cast();
+        let n = index.try_into().map_err(|_| FdtError::NotFound)?;
+        let (mut address, mut size) = (0, 0);
+        // SAFETY: Accesses (read-only) are constrained to the DT totalsize.
+        let ret = unsafe { libfdt_bindgen::fdt_get_mem_rsv(fdt, n, &mut address, &mut size) };
+
+        () = FdtRawResult::from(ret).try_into()?;
+
+        Ok((address, size))
+    }
+
     /// Safe wrapper around `fdt_open_into()` (C function).
     #[allow(dead_code)]
     fn open_into(&self, dest: &mut [u8]) -> Result<()> {
@@ -469,6 +491,25 @@ pub(crate) unsafe trait LibfdtMut {
         FdtRawResult::from(ret).try_into()
     }
 
+    /// Safe wrapper around `fdt_add_mem_rsv()` (C function).
+    fn add_mem_rsv(&mut self, address: u64, size: u64) -> Result<()> {
+        let fdt = self.as_fdt_slice_mut().as_mut_ptr().cast();
+        // SAFETY: New entry is constrained to the DT totalsize (validated by underlying libfdt).
+        let ret = unsafe { libfdt_bindgen::fdt_add_mem_rsv(fdt, address, size) };
+
+        FdtRawResult::from(ret).try_into()
+    }
+
+    /// Safe wrapper around `fdt_del_mem_rsv()` (C function).
+    fn del_mem_rsv(&mut self, index: usize) -> Result<()> {
+        let fdt = self.as_fdt_slice_mut().as_mut_ptr().cast();
+        let n = index.try_into().map_err(|_| FdtError::NotFound)?;
+        // SAFETY: Accesses are constrained to the DT totalsize (validated by ctor).
+        let ret = unsafe { libfdt_bindgen::fdt_del_mem_rsv(fdt, n) };
+
+        FdtRawResult::from(ret).try_into()
+    }
+
     /// Safe and aliasing-compatible wrapper around `fdt_open_into()` (C function).
     ///
     /// The C API allows both input (`const void*`) and output (`void *`) to point to the same
@@ -0,0 +1,491 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Native implementation of device tree overlays.
+//!
+//! Unlike `fdt_overlay_apply()`, the overlay is never modified: `__fixups__`, `__local_fixups__`
+//! and phandle renumbering are resolved upfront and applied to the property values as they are
+//! copied into the base tree.
+
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+use cstr::cstr;
+
+use crate::libfdt::{Libfdt, LibfdtMut};
+use crate::{Fdt, FdtError, FdtNode, NodeOffset, Phandle};
+
+/// Error type for the native DT overlay engine.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OverlayError {
+    /// A libfdt operation failed on the node at `path`.
+    Fdt {
+        /// Path of the node (in the base DT, except while resolving the overlay).
+        path: String,
+        /// Underlying error.
+        error: FdtError,
+    },
+    /// A fragment has neither a `target` nor a `target-path` property.
+    MissingTarget {
+        /// Path of the fragment in the overlay.
+        fragment: String,
+    },
+    /// A fragment targets a phandle that doesn't exist in the base DT.
+    UnknownTargetPhandle {
+        /// Path of the fragment in the overlay.
+        fragment: String,
+        /// Value of its `target` property, after fixups.
+        phandle: u32,
+    },
+    /// A fragment targets a path that doesn't exist in the base DT.
+    UnknownTargetPath {
+        /// Path of the fragment in the overlay.
+        fragment: String,
+        /// Value of its `target-path` property.
+        target: String,
+    },
+    /// A label of `__fixups__` doesn't refer to a node with a phandle through the base
+    /// `__symbols__`.
+    UnresolvedSymbol {
+        /// Name of the label.
+        label: String,
+    },
+    /// An entry of `__fixups__` or `__local_fixups__` doesn't refer to a valid property cell.
+    BadFixup {
+        /// Path of the referenced node in the overlay.
+        path: String,
+        /// Name of the referenced property.
+        property: String,
+    },
+    /// An entry of the overlay `__symbols__` isn't a valid path.
+    BadSymbol {
+        /// Name of the label.
+        label: String,
+    },
+    /// Renumbering the overlay phandles would exceed the maximum phandle value.
+    PhandleOverflow,
+}
+
+impl fmt::Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fdt { path, error } => write!(f, "Failed to process {path}: {error}"),
+            Self::MissingTarget { fragment } => {
+                write!(f, "Fragment {fragment} has no target or target-path")
+            }
+            Self::UnknownTargetPhandle { fragment, phandle } => {
+                write!(f, "Fragment {fragment} targets unknown phandle {phandle:#x}")
+            }
+            Self::UnknownTargetPath { fragment, target } => {
+                write!(f, "Fragment {fragment} targets unknown path {target}")
+            }
+            Self::UnresolvedSymbol { label } => {
+                write!(f, "Symbol {label} can't be resolved in the base DT")
+            }
+            Self::BadFixup { path, property } => {
+                write!(f, "Invalid fixup for property {property} of {path}")
+            }
+            Self::BadSymbol { label } => write!(f, "Invalid overlay symbol {label}"),
+            Self::PhandleOverflow => write!(f, "Overlay phandles can't be renumbered"),
+        }
+    }
+}
+
+type Result<T> = core::result::Result<T, OverlayError>;
+
+/// Names of the properties holding the phandle of a node.
+pub(crate) const PHANDLE_PROPS: [&[u8]; 2] = [b"phandle", b"linux,phandle"];
+
+/// Returns the full path of the node.
+pub(crate) fn node_path(node: &FdtNode) -> core::result::Result<String, FdtError> {
+    if node.offset == NodeOffset::ROOT {
+        return Ok(String::from("/"));
+    }
+    let mut path = String::new();
+    for depth in 1.. {
+        let ancestor = node.supernode_at_depth(depth)?;
+        path.push('/');
+        path.push_str(&ancestor.name()?.to_string_lossy());
+        if ancestor == *node {
+            break;
+        }
+    }
+    Ok(path)
+}
+
+fn fdt_error(node: &FdtNode, error: FdtError) -> OverlayError {
+    let path = node_path(node).unwrap_or_else(|_| String::from("<unknown>"));
+    OverlayError::Fdt { path, error }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum PatchKind {
+    /// Reference to a phandle of the overlay, to be renumbered.
+    Local,
+    /// Reference to a phandle of the base DT.
+    External(Phandle),
+}
+
+/// Modification of a big-endian cell of an overlay property.
+#[derive(Debug)]
+struct Patch<'a> {
+    node: NodeOffset,
+    name: &'a [u8],
+    offset: usize,
+    kind: PatchKind,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Target<'a> {
+    Phandle(Phandle),
+    Path(&'a CStr),
+}
+
+#[derive(Debug)]
+struct Fragment<'a> {
+    node: FdtNode<'a>,
+    content: FdtNode<'a>,
+    target: Target<'a>,
+}
+
+/// Overlay whose fixups and fragment targets were resolved against a base DT.
+#[derive(Debug)]
+pub(crate) struct ResolvedOverlay<'a> {
+    delta: u32,
+    patches: Vec<Patch<'a>>,
+    fragments: Vec<Fragment<'a>>,
+    symbols: Vec<(&'a CStr, String)>,
+}
+
+impl<'a> ResolvedOverlay<'a> {
+    /// Resolves `overlay` against `base`, without modifying either.
+    pub(crate) fn new(base: &Fdt, overlay: &'a Fdt) -> Result<Self> {
+        let root = overlay.root();
+        let delta = max_phandle(base)?;
+        let overlay_max = max_phandle(overlay)?;
+        if !delta.checked_add(overlay_max).is_some_and(|max| max <= Phandle::MAX.into()) {
+            return Err(OverlayError::PhandleOverflow);
+        }
+
+        let mut resolved =
+            Self { delta, patches: Vec::new(), fragments: Vec::new(), symbols: Vec::new() };
+
+        if let Some(local_fixups) = subnode(&root, cstr!("__local_fixups__"))? {
+            resolved.add_local_fixups(&local_fixups, &root)?;
+        }
+        if let Some(fixups) = subnode(&root, cstr!("__fixups__"))? {
+            resolved.add_fixups(base, &fixups)?;
+        }
+        resolved.add_fragments(base, &root)?;
+        if let Some(symbols) = subnode(&root, cstr!("__symbols__"))? {
+            resolved.add_symbols(base, &symbols)?;
+        }
+
+        Ok(resolved)
+    }
+
+    fn add_local_fixups(&mut self, fixups: &FdtNode<'a>, node: &FdtNode<'a>) -> Result<()> {
+        let mut prop = fixups.first_property().map_err(|e| fdt_error(fixups, e))?;
+        while let Some(p) = prop {
+            let name = p.name().map_err(|e| fdt_error(fixups, e))?.to_bytes();
+            let offsets = p.value().map_err(|e| fdt_error(fixups, e))?;
+            let invalid = || bad_fixup(node, name);
+            let value = node.fdt.getprop_namelen(node.offset, name).ok().flatten();
+            let value = value.ok_or_else(invalid)?;
+            let offsets = offsets.chunks_exact(4);
+            if !offsets.remainder().is_empty() {
+                return Err(invalid());
+            }
+            for offset in offsets {
+                let offset = u32::from_be_bytes(offset.try_into().unwrap()).try_into().unwrap();
+                let cell = read_cell(value, offset).ok_or_else(invalid)?;
+                if cell.checked_add(self.delta).and_then(Phandle::new).is_none() {
+                    return Err(OverlayError::PhandleOverflow);
+                }
+                let patch = Patch { node: node.offset, name, offset, kind: PatchKind::Local };
+                self.patches.push(patch);
+            }
+            prop = p.next_property().map_err(|e| fdt_error(fixups, e))?;
+        }
+
+        for fixups_child in fixups.subnodes().map_err(|e| fdt_error(fixups, e))? {
+            let name = fixups_child.name().map_err(|e| fdt_error(&fixups_child, e))?;
+            let child = subnode(node, name)?.ok_or_else(|| bad_fixup(node, name.to_bytes()))?;
+            self.add_local_fixups(&fixups_child, &child)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_fixups(&mut self, base: &Fdt, fixups: &FdtNode<'a>) -> Result<()> {
+        let overlay = fixups.fdt;
+        let base_symbols = base.symbols().map_err(|e| fdt_error(&base.root(), e))?;
+        let mut prop = fixups.first_property().map_err(|e| fdt_error(fixups, e))?;
+        while let Some(p) = prop {
+            let label = p.name().map_err(|e| fdt_error(fixups, e))?;
+            let unresolved =
+                || OverlayError::UnresolvedSymbol { label: label.to_string_lossy().into_owned() };
+            let target = match base_symbols {
+                Some(symbols) => symbols.getprop(label).ok().flatten(),
+                None => None,
+            };
+            let target = target.and_then(|path| CStr::from_bytes_with_nul(path).ok());
+            let target = target.and_then(|path| base.node(path).ok().flatten());
+            let phandle = target.and_then(|node| node.get_phandle().ok().flatten());
+            let phandle = phandle.ok_or_else(unresolved)?;
+
+            let value = p.value().map_err(|e| fdt_error(fixups, e))?;
+            let entries =
+                value.strip_suffix(b"\0").ok_or_else(|| bad_fixup(fixups, label.to_bytes()))?;
+            for entry in entries.split(|&c| c == b'\0') {
+                let (path, name, offset) =
+                    parse_fixup(entry).ok_or_else(|| bad_fixup(fixups, label.to_bytes()))?;
+                let node = overlay.path_offset_namelen(path).ok().flatten();
+                let node = node.ok_or_else(|| bad_fixup_at(path, name))?;
+                let value = overlay.getprop_namelen(node, name).ok().flatten();
+                let value = value.ok_or_else(|| bad_fixup_at(path, name))?;
+                read_cell(value, offset).ok_or_else(|| bad_fixup_at(path, name))?;
+                let kind = PatchKind::External(phandle);
+                self.patches.push(Patch { node, name, offset, kind });
+            }
+            prop = p.next_property().map_err(|e| fdt_error(fixups, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn add_fragments(&mut self, base: &Fdt, root: &FdtNode<'a>) -> Result<()> {
+        for node in root.subnodes().map_err(|e| fdt_error(root, e))? {
+            // Nodes without __overlay__ (e.g. __fixups__) aren't fragments.
+            let Some(content) = subnode(&node, cstr!("__overlay__"))? else {
+                continue;
+            };
+            let fragment = || node_path(&node).unwrap_or_default();
+            let target = if let Some(value) = self.getprop(&node, cstr!("target"))? {
+                let phandle = read_cell(&value, 0).filter(|_| value.len() == 4);
+                let phandle = phandle.ok_or_else(|| fdt_error(&node, FdtError::BadValue))?;
+                let unknown =
+                    || OverlayError::UnknownTargetPhandle { fragment: fragment(), phandle };
+                let phandle = Phandle::new(phandle).ok_or_else(unknown)?;
+                base.node_with_phandle(phandle).ok().flatten().ok_or_else(unknown)?;
+                Target::Phandle(phandle)
+            } else if let Some(path) =
+                node.getprop(cstr!("target-path")).map_err(|e| fdt_error(&node, e))?
+            {
+                let path = CStr::from_bytes_with_nul(path)
+                    .map_err(|_| fdt_error(&node, FdtError::BadValue))?;
+                if base.node(path).ok().flatten().is_none() {
+                    let target = path.to_string_lossy().into_owned();
+                    return Err(OverlayError::UnknownTargetPath { fragment: fragment(), target });
+                }
+                Target::Path(path)
+            } else {
+                return Err(OverlayError::MissingTarget { fragment: fragment() });
+            };
+            self.fragments.push(Fragment { node, content, target });
+        }
+
+        Ok(())
+    }
+
+    fn add_symbols(&mut self, base: &Fdt, symbols: &FdtNode<'a>) -> Result<()> {
+        let mut prop = symbols.first_property().map_err(|e| fdt_error(symbols, e))?;
+        while let Some(p) = prop {
+            let label = p.name().map_err(|e| fdt_error(symbols, e))?;
+            let value = p.value().map_err(|e| fdt_error(symbols, e))?;
+            let bad_symbol =
+                || OverlayError::BadSymbol { label: label.to_string_lossy().into_owned() };
+            let path = CStr::from_bytes_with_nul(value).map_err(|_| bad_symbol())?;
+            if let Some(path) = self.symbol_path(base, path.to_bytes()).ok_or_else(bad_symbol)? {
+                self.symbols.push((label, path));
+            }
+            prop = p.next_property().map_err(|e| fdt_error(symbols, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Translates the path of an overlay symbol into its path in the base DT.
+    ///
+    /// Returns `Some(None)` for symbols that don't end up in the base DT and `None` if the path is
+    /// invalid.
+    fn symbol_path(&self, base: &Fdt, path: &[u8]) -> Option<Option<String>> {
+        let path = path.strip_prefix(b"/")?;
+        // Only symbols of the form /<fragment>/__overlay__[/<path>] end up in the base DT.
+        let Some(sep) = path.iter().position(|&c| c == b'/') else {
+            return Some(None);
+        };
+        let (fragment_name, rest) = path.split_at(sep);
+        let relative_path = match rest.strip_prefix(b"/__overlay__") {
+            Some(b"") => "",
+            Some(rest) if rest.len() > 1 && rest.starts_with(b"/") => {
+                core::str::from_utf8(&rest[1..]).ok()?
+            }
+            _ => return Some(None),
+        };
+        let fragment = self
+            .fragments
+            .iter()
+            .find(|f| f.node.name().is_ok_and(|name| name.to_bytes() == fragment_name))?;
+        let target = fragment.target.node(base).ok()?;
+        let mut target = node_path(&target).ok()?;
+        if !relative_path.is_empty() {
+            if target != "/" {
+                target.push('/');
+            }
+            target.push_str(relative_path);
+        }
+
+        Some(Some(target))
+    }
+
+    /// Merges the resolved fragments and symbols into `base`.
+    pub(crate) fn apply(&self, base: &mut Fdt) -> Result<()> {
+        for fragment in &self.fragments {
+            let target = fragment.target.node(base).map_err(|e| fdt_error(&base.root(), e))?;
+            let target = target.offset;
+            self.merge_node(base, target, &fragment.content)?;
+        }
+
+        if self.symbols.is_empty() {
+            return Ok(());
+        }
+        let symbols = match base.subnode_offset_namelen(NodeOffset::ROOT, b"__symbols__") {
+            Ok(Some(node)) => node,
+            Ok(None) => base
+                .add_subnode_namelen(NodeOffset::ROOT, b"__symbols__")
+                .map_err(|e| fdt_error(&base.root(), e))?,
+            Err(e) => return Err(fdt_error(&base.root(), e)),
+        };
+        for (label, path) in &self.symbols {
+            let mut value = Vec::with_capacity(path.len() + 1);
+            value.extend_from_slice(path.as_bytes());
+            value.push(b'\0');
+            base.setprop(symbols, label, &value)
+                .map_err(|e| fdt_error(&FdtNode { fdt: base, offset: symbols }, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn merge_node(&self, base: &mut Fdt, target: NodeOffset, node: &FdtNode<'a>) -> Result<()> {
+        let base_error = |base: &Fdt, e| fdt_error(&FdtNode { fdt: base, offset: target }, e);
+
+        let mut prop = node.first_property().map_err(|e| fdt_error(node, e))?;
+        while let Some(p) = prop {
+            let name = p.name().map_err(|e| fdt_error(node, e))?;
+            let value =
+                self.getprop(node, name)?.ok_or_else(|| fdt_error(node, FdtError::Internal))?;
+            base.setprop(target, name, &value).map_err(|e| base_error(base, e))?;
+            prop = p.next_property().map_err(|e| fdt_error(node, e))?;
+        }
+
+        for child in node.subnodes().map_err(|e| fdt_error(node, e))? {
+            let name = child.name().map_err(|e| fdt_error(&child, e))?.to_bytes();
+            let base_child = match base.subnode_offset_namelen(target, name) {
+                Ok(Some(offset)) => offset,
+                Ok(None) => {
+                    base.add_subnode_namelen(target, name).map_err(|e| base_error(base, e))?
+                }
+                Err(e) => return Err(base_error(base, e)),
+            };
+            self.merge_node(base, base_child, &child)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of an overlay property, with its fixups applied.
+    fn getprop(&self, node: &FdtNode<'a>, name: &CStr) -> Result<Option<Cow<'a, [u8]>>> {
+        let name = name.to_bytes();
+        let Some(value) =
+            node.fdt.getprop_namelen(node.offset, name).map_err(|e| fdt_error(node, e))?
+        else {
+            return Ok(None);
+        };
+
+        let mut value = Cow::Borrowed(value);
+        if self.delta != 0 && PHANDLE_PROPS.contains(&name) {
+            if let Some(phandle) = read_cell(&value, 0) {
+                // Overflows were ruled out when resolving the overlay.
+                write_cell(value.to_mut(), 0, phandle + self.delta);
+            }
+        }
+        for patch in self.patches.iter().filter(|p| p.node == node.offset && p.name == name) {
+            let cell = match patch.kind {
+                PatchKind::Local => read_cell(&value, patch.offset).unwrap() + self.delta,
+                PatchKind::External(phandle) => phandle.into(),
+            };
+            write_cell(value.to_mut(), patch.offset, cell);
+        }
+
+        Ok(Some(value))
+    }
+}
+
+impl Target<'_> {
+    fn node<'b>(&self, base: &'b Fdt) -> core::result::Result<FdtNode<'b>, FdtError> {
+        match *self {
+            Self::Phandle(phandle) => base.node_with_phandle(phandle),
+            Self::Path(path) => base.node(path),
+        }?
+        .ok_or(FdtError::NotFound)
+    }
+}
+
+fn max_phandle(fdt: &Fdt) -> Result<u32> {
+    match fdt.max_phandle() {
+        Ok(phandle) => Ok(phandle.into()),
+        // No phandle in the tree.
+        Err(FdtError::BadPhandle) => Ok(0),
+        Err(e) => Err(fdt_error(&fdt.root(), e)),
+    }
+}
+
+fn subnode<'a>(node: &FdtNode<'a>, name: &CStr) -> Result<Option<FdtNode<'a>>> {
+    node.subnode(name).map_err(|e| fdt_error(node, e))
+}
+
+fn bad_fixup(node: &FdtNode, property: &[u8]) -> OverlayError {
+    let path = node_path(node).unwrap_or_default();
+    let property = String::from_utf8_lossy(property).into_owned();
+    OverlayError::BadFixup { path, property }
+}
+
+fn bad_fixup_at(path: &[u8], property: &[u8]) -> OverlayError {
+    let path = String::from_utf8_lossy(path).into_owned();
+    let property = String::from_utf8_lossy(property).into_owned();
+    OverlayError::BadFixup { path, property }
+}
+
+/// Parses a `__fixups__` entry of the form `<path>:<property>:<offset>`.
+fn parse_fixup(entry: &[u8]) -> Option<(&[u8], &[u8], usize)> {
+    let mut fields = entry.splitn(3, |&c| c == b':');
+    let path = fields.next()?;
+    let name = fields.next()?;
+    let offset = core::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+
+    Some((path, name, offset))
+}
+
+fn read_cell(value: &[u8], offset: usize) -> Option<u32> {
+    let cell = value.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(cell.try_into().unwrap()))
+}
+
+fn write_cell(value: &mut [u8], offset: usize, cell: u32) {
+    value[offset..(offset + 4)].copy_from_slice(&cell.to_be_bytes());
+}
@@ -0,0 +1,156 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Regions described by the children of the standard `/reserved-memory` node.
+
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use cstr::cstr;
+
+use crate::iterators::{FromSizeCells, RegIterator};
+use crate::{Fdt, FdtError, FdtNode, FdtNodeMut, Result, SizeCells};
+
+pub(crate) const RESERVED_MEMORY_PATH: &CStr = cstr!("/reserved-memory");
+
+/// Region of memory reserved by a child node of `/reserved-memory`.
+///
+/// Statically allocated regions have a `reg` while dynamically allocated ones have a `size` and,
+/// optionally, an `alignment` and `alloc-ranges` constraining where they can be placed.
+#[derive(Clone, Copy, Debug)]
+pub struct ReservedMemory<'a> {
+    node: FdtNode<'a>,
+}
+
+impl<'a> ReservedMemory<'a> {
+    pub(crate) fn new(node: FdtNode<'a>) -> Self {
+        Self { node }
+    }
+
+    /// Returns the node describing the region.
+    pub fn node(&self) -> FdtNode<'a> {
+        self.node
+    }
+
+    /// Returns the name of the node describing the region.
+    pub fn name(&self) -> Result<&'a CStr> {
+        self.node.name()
+    }
+
+    /// Returns the address ranges of a statically allocated region.
+    pub fn reg(&self) -> Result<Option<RegIterator<'a>>> {
+        self.node.reg()
+    }
+
+    /// Returns the size of a dynamically allocated region.
+    pub fn size(&self) -> Result<Option<u64>> {
+        self.getprop_size(cstr!("size"))
+    }
+
+    /// Returns the alignment required by a dynamically allocated region.
+    pub fn alignment(&self) -> Result<Option<u64>> {
+        self.getprop_size(cstr!("alignment"))
+    }
+
+    /// Returns the address ranges from which a dynamically allocated region may be allocated.
+    pub fn alloc_ranges(&self) -> Result<Option<RegIterator<'a>>> {
+        let Some(cells) = self.node.getprop_cells(cstr!("alloc-ranges"))? else {
+            return Ok(None);
+        };
+        let parent = self.node.parent()?;
+
+        Ok(Some(RegIterator::new(cells, parent.address_cells()?, parent.size_cells()?)))
+    }
+
+    /// Returns whether the OS must not create a virtual mapping of the region.
+    pub fn no_map(&self) -> Result<bool> {
+        Ok(self.node.getprop(cstr!("no-map"))?.is_some())
+    }
+
+    /// Returns whether the OS may use the region while its owner isn't using it.
+    pub fn reusable(&self) -> Result<bool> {
+        Ok(self.node.getprop(cstr!("reusable"))?.is_some())
+    }
+
+    fn getprop_size(&self, name: &CStr) -> Result<Option<u64>> {
+        let Some(mut cells) = self.node.getprop_cells(name)? else {
+            return Ok(None);
+        };
+        let size_cells = self.node.parent()?.size_cells()?;
+        if size_cells == SizeCells::None {
+            return Err(FdtError::BadNCells);
+        }
+
+        u64::from_size_cells(&mut cells, size_cells).ok_or(FdtError::BadValue).map(Some)
+    }
+}
+
+/// Adds a statically allocated region, creating `/reserved-memory` if needed.
+pub(crate) fn add_static<'a>(
+    fdt: &'a mut Fdt,
+    name: &CStr,
+    addr: u64,
+    size: u64,
+) -> Result<FdtNodeMut<'a>> {
+    let mut node = reserved_memory_mut(fdt)?.add_subnode(name)?;
+    node.appendprop_addrrange(cstr!("reg"), addr, size)?;
+
+    Ok(node)
+}
+
+/// Adds a dynamically allocated region, creating `/reserved-memory` if needed.
+pub(crate) fn add_dynamic<'a>(
+    fdt: &'a mut Fdt,
+    name: &CStr,
+    size: u64,
+    alignment: Option<u64>,
+) -> Result<FdtNodeMut<'a>> {
+    let node = reserved_memory_mut(fdt)?;
+    let size_cells = node.as_node().size_cells()?;
+
+    let mut node = node.add_subnode(name)?;
+    node.setprop(cstr!("size"), &to_size_cells(size, size_cells)?)?;
+    if let Some(alignment) = alignment {
+        node.setprop(cstr!("alignment"), &to_size_cells(alignment, size_cells)?)?;
+    }
+
+    Ok(node)
+}
+
+/// Returns `/reserved-memory`, creating it with the cell sizes of the root as required by the DT
+/// specification if it doesn't exist.
+fn reserved_memory_mut(fdt: &mut Fdt) -> Result<FdtNodeMut> {
+    if fdt.node(RESERVED_MEMORY_PATH)?.is_none() {
+        let root = fdt.root();
+        let addr_cells = root.address_cells()? as u32;
+        let size_cells = root.size_cells()? as u32;
+
+        let mut node = fdt.root_mut().add_subnode(cstr!("reserved-memory"))?;
+        node.setprop(cstr!("#address-cells"), &addr_cells.to_be_bytes())?;
+        node.setprop(cstr!("#size-cells"), &size_cells.to_be_bytes())?;
+        node.setprop_empty(cstr!("ranges"))?;
+    }
+
+    fdt.node_mut(RESERVED_MEMORY_PATH)?.ok_or(FdtError::Internal)
+}
+
+fn to_size_cells(value: u64, size_cells: SizeCells) -> Result<Vec<u8>> {
+    match size_cells {
+        SizeCells::None => Err(FdtError::BadNCells),
+        SizeCells::Single => {
+            let value = u32::try_from(value).map_err(|_| FdtError::BadValue)?;
+            Ok(value.to_be_bytes().into())
+        }
+        SizeCells::Double => Ok(value.to_be_bytes().into()),
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Declarative validation of device trees.
+//!
+//! Constraints are declared once as (typically `static`) slices of [`NodeSchema`], which can be
+//! checked against any DT with [`Fdt::validate`]:
+//!
+//! ```
+//! use cstr::cstr;
+//! use libfdt::{NodeSchema, NodeSelector, PropertySchema, ValueSchema};
+//!
+//! static SCHEMA: &[NodeSchema] = &[NodeSchema {
+//!     selector: NodeSelector::Compatible(cstr!("arm,armv8-timer")),
+//!     required: true,
+//!     compatible: &[],
+//!     properties: &[PropertySchema::required(cstr!("interrupts"), ValueSchema::Cells(12..=12))],
+//! }];
+//! ```
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt;
+use core::mem::size_of;
+use core::ops::RangeInclusive;
+use cstr::cstr;
+
+use crate::overlay::node_path;
+use crate::{Fdt, FdtError, FdtNode, Phandle};
+
+/// Selects the nodes a [`NodeSchema`] applies to.
+#[derive(Clone, Debug)]
+pub enum NodeSelector<'a> {
+    /// The node with the given full path.
+    Path(&'a CStr),
+    /// All nodes compatible with the given string.
+    Compatible(&'a CStr),
+    /// All nodes with the given `device_type`.
+    DeviceType(&'a CStr),
+}
+
+impl fmt::Display for NodeSelector<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.to_string_lossy()),
+            Self::Compatible(compatible) => write!(f, "compatible = {compatible:?}"),
+            Self::DeviceType(device_type) => write!(f, "device_type = {device_type:?}"),
+        }
+    }
+}
+
+/// Constraints on the nodes matched by a [`NodeSelector`].
+#[derive(Clone, Debug)]
+pub struct NodeSchema<'a> {
+    /// Nodes to which the constraints apply.
+    pub selector: NodeSelector<'a>,
+    /// Whether at least one node must match the selector.
+    pub required: bool,
+    /// If not empty, the nodes must be compatible with at least one of these strings.
+    pub compatible: &'a [&'a CStr],
+    /// Constraints on the properties of the nodes.
+    pub properties: &'a [PropertySchema<'a>],
+}
+
+/// Constraints on a property.
+#[derive(Clone, Debug)]
+pub struct PropertySchema<'a> {
+    /// Name of the property.
+    pub name: &'a CStr,
+    /// Whether the property must be present.
+    pub required: bool,
+    /// Constraints on the value of the property, if present.
+    pub value: ValueSchema<'a>,
+}
+
+impl<'a> PropertySchema<'a> {
+    /// Declares a property that must be present.
+    pub const fn required(name: &'a CStr, value: ValueSchema<'a>) -> Self {
+        Self { name, required: true, value }
+    }
+
+    /// Declares a property that may be absent.
+    pub const fn optional(name: &'a CStr, value: ValueSchema<'a>) -> Self {
+        Self { name, required: false, value }
+    }
+}
+
+/// Constraints on the value of a property.
+#[derive(Clone, Debug)]
+pub enum ValueSchema<'a> {
+    /// Any value.
+    Any,
+    /// No value, as for boolean properties.
+    Empty,
+    /// A single string.
+    String,
+    /// A single string, out of the given ones.
+    OneOf(&'a [&'a CStr]),
+    /// A single 32-bit cell, within the given range.
+    U32(RangeInclusive<u32>),
+    /// A single 64-bit value (2 cells), within the given range.
+    U64(RangeInclusive<u64>),
+    /// A number of 32-bit cells within the given range.
+    Cells(RangeInclusive<usize>),
+    /// A number of (address, size) pairs within the given range, sized after the
+    /// `#address-cells` and `#size-cells` of the parent node.
+    Reg(RangeInclusive<usize>),
+    /// A phandle to an existing node.
+    Phandle,
+}
+
+/// Error type for [`Fdt::validate`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FdtValidationError {
+    /// A libfdt operation failed on the node at `path`.
+    Fdt {
+        /// Path of the node.
+        path: String,
+        /// Underlying error.
+        error: FdtError,
+    },
+    /// No node matches the selector of a required [`NodeSchema`].
+    MissingNode {
+        /// Description of the selector.
+        selector: String,
+    },
+    /// A node isn't compatible with any of the expected strings.
+    Incompatible {
+        /// Path of the node.
+        path: String,
+    },
+    /// A required property is missing.
+    MissingProperty {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        property: String,
+    },
+    /// The value of a property doesn't have the expected size.
+    BadLength {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        property: String,
+        /// Size of the value, in bytes.
+        len: usize,
+    },
+    /// The value of a property is out of the expected range.
+    OutOfRange {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        property: String,
+        /// The value or, for cell lists, the number of elements.
+        value: u64,
+    },
+    /// The value of a property isn't one of the expected strings.
+    BadString {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        property: String,
+    },
+    /// The value of a property doesn't refer to an existing node.
+    BadPhandle {
+        /// Path of the node.
+        path: String,
+        /// Name of the property.
+        property: String,
+        /// Value of the property.
+        phandle: u32,
+    },
+}
+
+impl fmt::Display for FdtValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fdt { path, error } => write!(f, "Failed to validate {path}: {error}"),
+            Self::MissingNode { selector } => write!(f, "No node matching {selector}"),
+            Self::Incompatible { path } => write!(f, "{path} has no expected compatible string"),
+            Self::MissingProperty { path, property } => {
+                write!(f, "{path} has no {property} property")
+            }
+            Self::BadLength { path, property, len } => {
+                write!(f, "{path}:{property} has unexpected size {len}")
+            }
+            Self::OutOfRange { path, property, value } => {
+                write!(f, "{path}:{property} value {value:#x} is out of range")
+            }
+            Self::BadString { path, property } => {
+                write!(f, "{path}:{property} isn't one of the expected strings")
+            }
+            Self::BadPhandle { path, property, phandle } => {
+                write!(f, "{path}:{property} references unknown phandle {phandle:#x}")
+            }
+        }
+    }
+}
+
+/// Returns all the violations of `schema` found in `fdt`.
+pub(crate) fn validate(fdt: &Fdt, schema: &[NodeSchema]) -> Vec<FdtValidationError> {
+    let mut errors = Vec::new();
+    for node_schema in schema {
+        let nodes = match matching_nodes(fdt, &node_schema.selector) {
+            Ok(nodes) => nodes,
+            Err(error) => {
+                let path = node_schema.selector.to_string();
+                errors.push(FdtValidationError::Fdt { path, error });
+                continue;
+            }
+        };
+        if nodes.is_empty() && node_schema.required {
+            let selector = node_schema.selector.to_string();
+            errors.push(FdtValidationError::MissingNode { selector });
+        }
+        for node in nodes {
+            if let Err(error) = validate_node(&node, node_schema, &mut errors) {
+                let path = node_path(&node).unwrap_or_else(|_| String::from("<unknown>"));
+                errors.push(FdtValidationError::Fdt { path, error });
+            }
+        }
+    }
+
+    errors
+}
+
+fn matching_nodes<'a>(
+    fdt: &'a Fdt,
+    selector: &NodeSelector<'a>,
+) -> Result<Vec<FdtNode<'a>>, FdtError> {
+    match selector {
+        NodeSelector::Path(path) => Ok(fdt.node(path)?.into_iter().collect()),
+        NodeSelector::Compatible(compatible) => Ok(fdt.compatible_nodes(compatible)?.collect()),
+        NodeSelector::DeviceType(device_type) => {
+            let mut nodes = Vec::new();
+            for (node, _) in fdt.root().descendants() {
+                if node.device_type()? == Some(*device_type) {
+                    nodes.push(node);
+                }
+            }
+            Ok(nodes)
+        }
+    }
+}
+
+fn validate_node(
+    node: &FdtNode,
+    schema: &NodeSchema,
+    errors: &mut Vec<FdtValidationError>,
+) -> Result<(), FdtError> {
+    let path = node_path(node)?;
+
+    if !schema.compatible.is_empty() && !is_compatible(node, schema.compatible)? {
+        errors.push(FdtValidationError::Incompatible { path: path.clone() });
+    }
+
+    for property in schema.properties {
+        let name = property.name.to_string_lossy();
+        match node.getprop(property.name)? {
+            None if property.required => {
+                let (path, property) = (path.clone(), name.into());
+                errors.push(FdtValidationError::MissingProperty { path, property });
+            }
+            None => {}
+            Some(value) => {
+                if let Some(error) = validate_value(node, &path, &name, &property.value, value)? {
+                    errors.push(error);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_compatible(node: &FdtNode, expected: &[&CStr]) -> Result<bool, FdtError> {
+    let Some(compatible) = node.getprop(cstr!("compatible"))? else {
+        return Ok(false);
+    };
+
+    Ok(compatible
+        .split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .any(|s| expected.iter().any(|e| e.to_bytes() == s)))
+}
+
+fn validate_value(
+    node: &FdtNode,
+    path: &str,
+    name: &str,
+    schema: &ValueSchema,
+    value: &[u8],
+) -> Result<Option<FdtValidationError>, FdtError> {
+    let (path, property) = (path.into(), name.into());
+    let bad_length =
+        |path, property| FdtValidationError::BadLength { path, property, len: value.len() };
+
+    let error = match schema {
+        ValueSchema::Any => None,
+        ValueSchema::Empty if value.is_empty() => None,
+        ValueSchema::Empty => Some(bad_length(path, property)),
+        ValueSchema::String => match CStr::from_bytes_with_nul(value) {
+            Ok(_) => None,
+            Err(_) => Some(FdtValidationError::BadString { path, property }),
+        },
+        ValueSchema::OneOf(expected) => match CStr::from_bytes_with_nul(value) {
+            Ok(s) if expected.contains(&s) => None,
+            _ => Some(FdtValidationError::BadString { path, property }),
+        },
+        ValueSchema::U32(range) => match <[u8; 4]>::try_from(value) {
+            Ok(bytes) if range.contains(&u32::from_be_bytes(bytes)) => None,
+            Ok(bytes) => {
+                let value = u32::from_be_bytes(bytes).into();
+                Some(FdtValidationError::OutOfRange { path, property, value })
+            }
+            Err(_) => Some(bad_length(path, property)),
+        },
+        ValueSchema::U64(range) => match <[u8; 8]>::try_from(value) {
+            Ok(bytes) if range.contains(&u64::from_be_bytes(bytes)) => None,
+            Ok(bytes) => {
+                let value = u64::from_be_bytes(bytes);
+                Some(FdtValidationError::OutOfRange { path, property, value })
+            }
+            Err(_) => Some(bad_length(path, property)),
+        },
+        ValueSchema::Cells(range) => validate_count(value, size_of::<u32>(), range, path, property),
+        ValueSchema::Reg(range) => {
+            let parent = node.parent()?;
+            let cells = parent.address_cells()? as usize + parent.size_cells()? as usize;
+            validate_count(value, cells * size_of::<u32>(), range, path, property)
+        }
+        ValueSchema::Phandle => match <[u8; 4]>::try_from(value) {
+            Ok(bytes) => {
+                let phandle = u32::from_be_bytes(bytes);
+                let found = match Phandle::new(phandle) {
+                    Some(p) => node.fdt().node_with_phandle(p)?.is_some(),
+                    None => false,
+                };
+                (!found).then_some(FdtValidationError::BadPhandle { path, property, phandle })
+            }
+            Err(_) => Some(bad_length(path, property)),
+        },
+    };
+
+    Ok(error)
+}
+
+fn validate_count(
+    value: &[u8],
+    element_size: usize,
+    range: &RangeInclusive<usize>,
+    path: String,
+    property: String,
+) -> Option<FdtValidationError> {
+    let count = value.len() / element_size;
+    if count * element_size != value.len() {
+        Some(FdtValidationError::BadLength { path, property, len: value.len() })
+    } else if !range.contains(&count) {
+        Some(FdtValidationError::OutOfRange { path, property, value: count as u64 })
+    } else {
+        None
+    }
+}
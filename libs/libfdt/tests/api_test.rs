@@ -18,7 +18,10 @@
 
 use core::ffi::CStr;
 use cstr::cstr;
-use libfdt::{Fdt, FdtError, FdtNodeMut, Phandle};
+use libfdt::{
+    compile_dts, DtsOptions, Fdt, FdtChange, FdtError, FdtNodeMut, FdtValidationError,
+    MemReservation, NodeSchema, NodeSelector, OverlayError, Phandle, PropertySchema, ValueSchema,
+};
 use std::collections::HashSet;
 use std::ffi::CString;
 use std::fs;
@@ -30,6 +33,10 @@ const TEST_TREE_WITH_MULTIPLE_MEMORY_RANGES_PATH: &str =
 const TEST_TREE_WITH_EMPTY_MEMORY_RANGE_PATH: &str = "data/test_tree_empty_memory_range.dtb";
 const TEST_TREE_WITH_NO_MEMORY_NODE_PATH: &str = "data/test_tree_no_memory_node.dtb";
 const TEST_TREE_PHANDLE_PATH: &str = "data/test_tree_phandle.dtb";
+const TEST_TREE_OVERLAY_BASE_PATH: &str = "data/test_tree_overlay_base.dtb";
+const TEST_OVERLAY_FIXUPS_PATH: &str = "data/test_overlay_fixups.dtb";
+const TEST_OVERLAY_UNRESOLVED_SYMBOL_PATH: &str = "data/test_overlay_unresolved_symbol.dtb";
+const TEST_OVERLAY_UNKNOWN_TARGET_PATH_PATH: &str = "data/test_overlay_unknown_target_path.dtb";
 
 #[test]
 fn retrieving_memory_from_fdt_with_one_memory_range_succeeds() {
@@ -545,3 +552,572 @@ fn node_descendants_lifetime() {
     };
     assert_eq!(Ok(cstr!("node_a")), first_descendant_name);
 }
+
+fn read_overlay_base() -> Vec<u8> {
+    let mut data = fs::read(TEST_TREE_OVERLAY_BASE_PATH).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    Fdt::from_mut_slice(&mut data).unwrap().unpack().unwrap();
+    data
+}
+
+#[test]
+fn merge_overlay_resolves_fixups() {
+    let mut data = read_overlay_base();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+    let base_max_phandle = fdt.max_phandle().unwrap();
+
+    fdt.merge_overlay(overlay).unwrap();
+
+    let intc = fdt.node(cstr!("/interrupt-controller")).unwrap().unwrap();
+    let intc_phandle = u32::from(intc.get_phandle().unwrap().unwrap());
+    let uart = fdt.node(cstr!("/bus/serial@2000")).unwrap().unwrap();
+    assert_eq!(uart.getprop_u32(cstr!("interrupt-parent")), Ok(Some(intc_phandle)));
+    let uart_phandle = uart.get_phandle().unwrap().unwrap();
+    let dma = fdt.node(cstr!("/bus/dma@3000")).unwrap().unwrap();
+    let dma_phandle = dma.get_phandle().unwrap().unwrap();
+    assert!(uart_phandle > base_max_phandle);
+    assert!(dma_phandle > base_max_phandle);
+
+    let client = fdt.node(cstr!("/bus/client@4000")).unwrap().unwrap();
+    let dmas: Vec<u32> = client.getprop_cells(cstr!("dmas")).unwrap().unwrap().collect();
+    assert_eq!(dmas, [dma_phandle.into(), 1]);
+    assert_eq!(client.getprop_u32(cstr!("serial")), Ok(Some(uart_phandle.into())));
+
+    let chosen = fdt.chosen().unwrap().unwrap();
+    assert_eq!(chosen.getprop_str(cstr!("stdout-path")), Ok(Some(cstr!("/bus/serial@2000"))));
+
+    let symbols = fdt.symbols().unwrap().unwrap();
+    assert_eq!(symbols.getprop_str(cstr!("uart")), Ok(Some(cstr!("/bus/serial@2000"))));
+    assert_eq!(symbols.getprop_str(cstr!("dma")), Ok(Some(cstr!("/bus/dma@3000"))));
+    assert_eq!(symbols.getprop_str(cstr!("bus")), Ok(Some(cstr!("/bus"))));
+}
+
+#[test]
+fn merge_overlay_leaves_overlay_untouched() {
+    let mut data = read_overlay_base();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+
+    fdt.merge_overlay(overlay).unwrap();
+
+    assert_eq!(overlay.as_slice(), fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap());
+}
+
+#[test]
+fn merge_overlay_twice_renumbers_phandles() {
+    let mut data = read_overlay_base();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+
+    fdt.merge_overlay(overlay).unwrap();
+    let max_phandle = fdt.max_phandle().unwrap();
+    fdt.merge_overlay(overlay).unwrap();
+
+    let dma = fdt.node(cstr!("/bus/dma@3000")).unwrap().unwrap();
+    let dma_phandle = dma.get_phandle().unwrap().unwrap();
+    assert!(dma_phandle > max_phandle);
+    assert_eq!(fdt.node_with_phandle(dma_phandle), Ok(Some(dma)));
+}
+
+#[test]
+fn validate_overlay_leaves_trees_untouched() {
+    let data = read_overlay_base();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+
+    assert_eq!(fdt.validate_overlay(overlay), Ok(()));
+
+    assert_eq!(data, read_overlay_base());
+    assert_eq!(overlay_data, fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap());
+}
+
+#[test]
+fn validate_overlay_with_unresolved_symbol_fails() {
+    let data = read_overlay_base();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_UNRESOLVED_SYMBOL_PATH).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+
+    let expected = OverlayError::UnresolvedSymbol { label: "missing_dma".into() };
+    assert_eq!(fdt.validate_overlay(overlay), Err(expected));
+}
+
+#[test]
+fn merge_overlay_with_unknown_target_path_fails_without_modifying_base() {
+    let mut data = read_overlay_base();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_UNKNOWN_TARGET_PATH_PATH).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+
+    let expected = OverlayError::UnknownTargetPath {
+        fragment: "/fragment@0".into(),
+        target: "/missing".into(),
+    };
+    assert_eq!(fdt.merge_overlay(overlay), Err(expected));
+    assert_eq!(data, read_overlay_base());
+}
+
+#[test]
+fn merge_overlay_without_space_reports_target() {
+    let mut data = fs::read(TEST_TREE_OVERLAY_BASE_PATH).unwrap();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+
+    let expected = OverlayError::Fdt { path: "/bus".into(), error: FdtError::NoSpace };
+    assert_eq!(fdt.merge_overlay(overlay), Err(expected));
+}
+
+#[test]
+fn merge_overlay_onto_empty_tree() {
+    let mut overlay_data = vec![0_u8; 256];
+    let overlay = Fdt::create_empty_tree(&mut overlay_data).unwrap();
+    let mut fragment = overlay.root_mut().add_subnode(cstr!("fragment@0")).unwrap();
+    fragment.setprop(cstr!("target-path"), b"/\0").unwrap();
+    let content = fragment.add_subnode(cstr!("__overlay__")).unwrap();
+    let mut avf = content.add_subnode(cstr!("avf")).unwrap();
+    avf.setprop(cstr!("digest"), &[0xaa, 0xbb]).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+    let mut data = vec![0_u8; 256];
+    let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+
+    fdt.merge_overlay(overlay).unwrap();
+
+    let avf = fdt.node(cstr!("/avf")).unwrap().unwrap();
+    assert_eq!(avf.getprop(cstr!("digest")), Ok(Some([0xaa, 0xbb].as_slice())));
+    assert_eq!(fdt.node(cstr!("/fragment@0")), Ok(None));
+}
+
+#[test]
+fn to_dts_round_trips_through_compile_dts() {
+    for path in [TEST_TREE_PHANDLE_PATH, TEST_TREE_OVERLAY_BASE_PATH, TEST_OVERLAY_FIXUPS_PATH] {
+        let data = fs::read(path).unwrap();
+        let dts = Fdt::from_slice(&data).unwrap().to_dts().unwrap();
+
+        assert_eq!(compile_dts(&dts, DtsOptions::default()), Ok(data), "{path}:\n{dts}");
+    }
+}
+
+#[test]
+fn to_dts_prints_references_to_labels() {
+    let data = fs::read(TEST_TREE_OVERLAY_BASE_PATH).unwrap();
+    let dts = Fdt::from_slice(&data).unwrap().to_dts().unwrap();
+
+    assert!(dts.contains("\tintc: interrupt-controller {\n"), "{dts}");
+    assert!(dts.contains("\t\tinterrupt-parent = <&intc>;\n"), "{dts}");
+}
+
+#[test]
+fn compile_dts_then_to_dts_is_identity() {
+    const DTS: &str = r#"/dts-v1/;
+
+/memreserve/ 0x0000000080000000 0x0000000000001000;
+
+/ {
+	#address-cells = <0x1>;
+	compatible = "test,dts", "test,\"quoted\"";
+	empty;
+	bytes = [01 02 03];
+
+	gic: interrupt-controller {
+		#interrupt-cells = <0x1>;
+		phandle = <0x1>;
+	};
+
+	device {
+		interrupt-parent = <&gic>;
+		interrupts-extended = <&gic 0x5>;
+	};
+
+	__symbols__ {
+		gic = "/interrupt-controller";
+	};
+};
+"#;
+    let data = compile_dts(DTS, DtsOptions { symbols: true }).unwrap();
+
+    assert_eq!(Fdt::from_slice(&data).unwrap().to_dts().unwrap(), DTS);
+}
+
+#[test]
+fn compile_dts_plugin_can_be_merged() {
+    const DTS: &str = "/dts-v1/;
+/plugin/;
+
+&bus {
+    dma: dma@3000 {
+        #dma-cells = <1>;
+    };
+
+    client {
+        interrupt-parent = <&intc>;
+        dmas = <&dma (1 << 4)>;
+    };
+};
+";
+    let overlay_data = compile_dts(DTS, DtsOptions { symbols: true }).unwrap();
+    let overlay = Fdt::from_slice(&overlay_data).unwrap();
+    let mut data = read_overlay_base();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+    fdt.merge_overlay(overlay).unwrap();
+
+    let intc = fdt.node(cstr!("/interrupt-controller")).unwrap().unwrap();
+    let intc_phandle = u32::from(intc.get_phandle().unwrap().unwrap());
+    let dma = fdt.node(cstr!("/bus/dma@3000")).unwrap().unwrap();
+    let dma_phandle = u32::from(dma.get_phandle().unwrap().unwrap());
+    let client = fdt.node(cstr!("/bus/client")).unwrap().unwrap();
+    assert_eq!(client.getprop_u32(cstr!("interrupt-parent")), Ok(Some(intc_phandle)));
+    let dmas: Vec<u32> = client.getprop_cells(cstr!("dmas")).unwrap().unwrap().collect();
+    assert_eq!(dmas, [dma_phandle, 16]);
+}
+
+#[test]
+fn compile_dts_reports_unknown_references() {
+    const DTS: &str = "/dts-v1/;\n\n/ {\n\tnode {\n\t\tprop = <&missing>;\n\t};\n};\n";
+
+    let error = compile_dts(DTS, DtsOptions::default()).unwrap_err();
+
+    assert_eq!((error.line, error.column), (5, 11));
+    assert_eq!(error.to_string(), "5:11: Label or path missing not found");
+}
+
+#[test]
+fn diff_of_identical_trees_is_empty() {
+    let data = fs::read(TEST_TREE_OVERLAY_BASE_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    assert_eq!(fdt.diff(fdt), Ok(vec![]));
+}
+
+#[test]
+fn diff_reports_merged_overlay() {
+    let old_data = read_overlay_base();
+    let old = Fdt::from_slice(&old_data).unwrap();
+    let mut new_data = read_overlay_base();
+    let new = Fdt::from_mut_slice(&mut new_data).unwrap();
+    let overlay_data = fs::read(TEST_OVERLAY_FIXUPS_PATH).unwrap();
+    new.merge_overlay(Fdt::from_slice(&overlay_data).unwrap()).unwrap();
+
+    let mut changes: Vec<_> = old.diff(new).unwrap().iter().map(ToString::to_string).collect();
+
+    // The order of the added nodes and properties depends on where libfdt inserts them.
+    changes.sort();
+    let expected = [
+        "+ /__symbols__:dma = \"/bus/dma@3000\"",
+        "+ /__symbols__:uart = \"/bus/serial@2000\"",
+        "+ /bus/client@4000",
+        "+ /bus/dma@3000",
+        "+ /bus/serial@2000",
+        "+ /chosen:stdout-path = \"/bus/serial@2000\"",
+    ];
+    assert_eq!(changes, expected);
+}
+
+#[test]
+fn diff_compares_phandles_through_paths() {
+    const OLD: &str = "/dts-v1/; / {
+        a { phandle = <1>; };
+        b { phandle = <2>; };
+        dev { interrupt-parent = <&{/a}>; };
+    };";
+    const RENUMBERED: &str = "/dts-v1/; / {
+        a { phandle = <2>; };
+        b { phandle = <1>; };
+        dev { interrupt-parent = <&{/a}>; };
+    };";
+    const RETARGETED: &str = "/dts-v1/; / {
+        a { phandle = <1>; };
+        b { phandle = <2>; };
+        dev { interrupt-parent = <&{/b}>; };
+    };";
+    let old_data = compile_dts(OLD, DtsOptions::default()).unwrap();
+    let old = Fdt::from_slice(&old_data).unwrap();
+    let renumbered_data = compile_dts(RENUMBERED, DtsOptions::default()).unwrap();
+    let renumbered = Fdt::from_slice(&renumbered_data).unwrap();
+    let retargeted_data = compile_dts(RETARGETED, DtsOptions::default()).unwrap();
+    let retargeted = Fdt::from_slice(&retargeted_data).unwrap();
+
+    assert_eq!(old.diff(renumbered), Ok(vec![]));
+    let expected = FdtChange::PropertyChanged {
+        path: "/dev".into(),
+        name: "interrupt-parent".into(),
+        old: 1_u32.to_be_bytes().into(),
+        new: 2_u32.to_be_bytes().into(),
+    };
+    assert_eq!(old.diff(retargeted), Ok(vec![expected]));
+}
+
+#[test]
+fn diff_reports_removed_nodes_and_properties() {
+    let old_data = fs::read(TEST_TREE_OVERLAY_BASE_PATH).unwrap();
+    let old = Fdt::from_slice(&old_data).unwrap();
+    let mut new_data = old_data.clone();
+    let new = Fdt::from_mut_slice(&mut new_data).unwrap();
+    new.node_mut(cstr!("/bus/serial@1000")).unwrap().unwrap().nop().unwrap();
+    new.node_mut(cstr!("/bus")).unwrap().unwrap().delprop(cstr!("interrupt-parent")).unwrap();
+
+    let intc = old.node(cstr!("/interrupt-controller")).unwrap().unwrap();
+    let intc_phandle = u32::from(intc.get_phandle().unwrap().unwrap());
+    let expected = [
+        FdtChange::PropertyRemoved {
+            path: "/bus".into(),
+            name: "interrupt-parent".into(),
+            value: intc_phandle.to_be_bytes().into(),
+        },
+        FdtChange::NodeRemoved { path: "/bus/serial@1000".into() },
+    ];
+    assert_eq!(old.diff(new), Ok(expected.into()));
+}
+
+#[test]
+fn mem_reservations() {
+    const DTS: &str = "/dts-v1/; /memreserve/ 0x1000 0x2000; /memreserve/ 0x8000 0x100; / { };";
+    let mut data = compile_dts(DTS, DtsOptions::default()).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+
+    let entries: Vec<_> = fdt.mem_reservations().unwrap().collect();
+    assert_eq!(
+        entries,
+        [
+            MemReservation { addr: 0x1000, size: 0x2000 },
+            MemReservation { addr: 0x8000, size: 0x100 },
+        ]
+    );
+
+    fdt.add_mem_reservation(0x10000, 0x1000).unwrap();
+    fdt.remove_mem_reservation(0).unwrap();
+    let entries: Vec<_> = fdt.mem_reservations().unwrap().collect();
+    assert_eq!(
+        entries,
+        [
+            MemReservation { addr: 0x8000, size: 0x100 },
+            MemReservation { addr: 0x10000, size: 0x1000 },
+        ]
+    );
+    assert_eq!(fdt.remove_mem_reservation(2), Err(FdtError::NotFound));
+}
+
+#[test]
+fn reserved_memory() {
+    const DTS: &str = "/dts-v1/;
+    / {
+        #address-cells = <2>;
+        #size-cells = <2>;
+        reserved-memory {
+            #address-cells = <2>;
+            #size-cells = <1>;
+            ranges;
+            dice {
+                reg = <0x0 0x1000 0x2000>;
+                no-map;
+            };
+            swiotlb {
+                size = <0x100000>;
+                alignment = <0x1000>;
+                alloc-ranges = <0x0 0x80000000 0x10000000>;
+                reusable;
+            };
+        };
+    };";
+    let data = compile_dts(DTS, DtsOptions::default()).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    let regions: Vec<_> = fdt.reserved_memory().unwrap().unwrap().collect();
+    assert_eq!(regions.len(), 2);
+
+    let dice = regions[0];
+    assert_eq!(dice.name(), Ok(cstr!("dice")));
+    let reg: Vec<_> = dice.reg().unwrap().unwrap().collect();
+    assert_eq!(reg.len(), 1);
+    assert_eq!((reg[0].addr, reg[0].size), (0x1000, Some(0x2000)));
+    assert_eq!(dice.size(), Ok(None));
+    assert_eq!(dice.no_map(), Ok(true));
+    assert_eq!(dice.reusable(), Ok(false));
+
+    let swiotlb = regions[1];
+    assert!(swiotlb.reg().unwrap().is_none());
+    assert_eq!(swiotlb.size(), Ok(Some(0x100000)));
+    assert_eq!(swiotlb.alignment(), Ok(Some(0x1000)));
+    let ranges: Vec<_> = swiotlb.alloc_ranges().unwrap().unwrap().collect();
+    assert_eq!(ranges.len(), 1);
+    assert_eq!((ranges[0].addr, ranges[0].size), (0x8000_0000, Some(0x1000_0000)));
+    assert_eq!(swiotlb.no_map(), Ok(false));
+    assert_eq!(swiotlb.reusable(), Ok(true));
+}
+
+#[test]
+fn add_reserved_memory_creates_node() {
+    let mut data = vec![0_u8; 1000];
+    let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+    assert!(fdt.reserved_memory().unwrap().is_none());
+
+    let mut dice = fdt.add_reserved_memory(cstr!("dice"), 0x1000, 0x2000).unwrap();
+    dice.setprop_empty(cstr!("no-map")).unwrap();
+    let mut pool = fdt.add_dynamic_reserved_memory(cstr!("pool"), 0x4000, Some(0x1000)).unwrap();
+    pool.appendprop_addrrange(cstr!("alloc-ranges"), 0x8000_0000, 0x1000_0000).unwrap();
+
+    let node = fdt.node(cstr!("/reserved-memory")).unwrap().unwrap();
+    assert_eq!(node.getprop(cstr!("ranges")), Ok(Some(&[][..])));
+    let mut regions = fdt.reserved_memory().unwrap().unwrap();
+    let dice = regions.find(|region| region.name() == Ok(cstr!("dice"))).unwrap();
+    let reg = dice.reg().unwrap().unwrap().next().unwrap();
+    assert_eq!((reg.addr, reg.size), (0x1000, Some(0x2000)));
+    assert_eq!(dice.no_map(), Ok(true));
+    let mut regions = fdt.reserved_memory().unwrap().unwrap();
+    let pool = regions.find(|region| region.name() == Ok(cstr!("pool"))).unwrap();
+    assert_eq!(pool.size(), Ok(Some(0x4000)));
+    assert_eq!(pool.alignment(), Ok(Some(0x1000)));
+    let range = pool.alloc_ranges().unwrap().unwrap().next().unwrap();
+    assert_eq!((range.addr, range.size), (0x8000_0000, Some(0x1000_0000)));
+}
+
+#[test]
+fn node_with_alias() {
+    const DTS: &str = "/dts-v1/;
+    / {
+        aliases {
+            serial0 = \"/bus/serial@1000\";
+            missing = \"/bus/serial@2000\";
+        };
+        bus { serial@1000 { }; };
+    };";
+    let mut data = compile_dts(DTS, DtsOptions::default()).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+
+    assert_eq!(fdt.alias(cstr!("serial0")), Ok(Some(cstr!("/bus/serial@1000"))));
+    assert_eq!(fdt.alias(cstr!("serial1")), Ok(None));
+    let node = fdt.node_with_alias(cstr!("serial0")).unwrap().unwrap();
+    assert_eq!(node, fdt.node(cstr!("/bus/serial@1000")).unwrap().unwrap());
+    assert_eq!(fdt.node_with_alias(cstr!("missing")), Ok(None));
+    assert_eq!(fdt.node_with_alias(cstr!("serial1")), Ok(None));
+
+    let mut node = fdt.node_mut_with_alias(cstr!("serial0")).unwrap().unwrap();
+    node.setprop_empty(cstr!("status")).unwrap();
+    let node = fdt.node(cstr!("/bus/serial@1000")).unwrap().unwrap();
+    assert_eq!(node.getprop(cstr!("status")), Ok(Some(&[][..])));
+}
+
+static SCHEMA: &[NodeSchema] = &[
+    NodeSchema {
+        selector: NodeSelector::Path(cstr!("/chosen")),
+        required: true,
+        compatible: &[],
+        properties: &[PropertySchema::optional(cstr!("bootargs"), ValueSchema::String)],
+    },
+    NodeSchema {
+        selector: NodeSelector::DeviceType(cstr!("cpu")),
+        required: true,
+        compatible: &[cstr!("arm,armv8")],
+        properties: &[
+            PropertySchema::required(cstr!("reg"), ValueSchema::Reg(1..=1)),
+            PropertySchema::optional(cstr!("enable-method"), ValueSchema::OneOf(&[cstr!("psci")])),
+        ],
+    },
+    NodeSchema {
+        selector: NodeSelector::Compatible(cstr!("arm,pl011")),
+        required: false,
+        compatible: &[],
+        properties: &[
+            PropertySchema::required(cstr!("clock-frequency"), ValueSchema::U32(1..=u32::MAX)),
+            PropertySchema::optional(cstr!("interrupt-parent"), ValueSchema::Phandle),
+            PropertySchema::optional(cstr!("interrupts"), ValueSchema::Cells(3..=3)),
+        ],
+    },
+    NodeSchema {
+        selector: NodeSelector::Compatible(cstr!("arm,armv8-timer")),
+        required: true,
+        compatible: &[],
+        properties: &[],
+    },
+];
+
+#[test]
+fn validate_accepts_valid_tree() {
+    const DTS: &str = "/dts-v1/;
+    / {
+        chosen { bootargs = \"console=ttyS0\"; };
+        cpus {
+            #address-cells = <1>;
+            #size-cells = <0>;
+            cpu@0 { device_type = \"cpu\"; compatible = \"arm,armv8\"; reg = <0>; };
+            cpu@1 {
+                device_type = \"cpu\";
+                compatible = \"arm,cortex-a55\", \"arm,armv8\";
+                reg = <1>;
+                enable-method = \"psci\";
+            };
+        };
+        intc: intc { };
+        uart { compatible = \"arm,pl011\"; clock-frequency = <24000000>; interrupt-parent = <&intc>; };
+        timer { compatible = \"arm,armv8-timer\"; };
+    };";
+    let data = compile_dts(DTS, DtsOptions::default()).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    assert_eq!(fdt.validate(SCHEMA), Ok(()));
+}
+
+#[test]
+fn validate_reports_all_errors_with_paths() {
+    const DTS: &str = "/dts-v1/;
+    / {
+        cpus {
+            #address-cells = <1>;
+            #size-cells = <0>;
+            cpu@0 { device_type = \"cpu\"; compatible = \"arm,armv7\"; reg = <0 1>; };
+            cpu@1 { device_type = \"cpu\"; compatible = \"arm,armv8\"; enable-method = \"spin-table\"; };
+        };
+        uart {
+            compatible = \"arm,pl011\";
+            clock-frequency = <0>;
+            interrupt-parent = <0x42>;
+            interrupts = <0 1>;
+        };
+    };";
+    let data = compile_dts(DTS, DtsOptions::default()).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    let expected = [
+        FdtValidationError::MissingNode { selector: "/chosen".into() },
+        FdtValidationError::Incompatible { path: "/cpus/cpu@0".into() },
+        FdtValidationError::OutOfRange {
+            path: "/cpus/cpu@0".into(),
+            property: "reg".into(),
+            value: 2,
+        },
+        FdtValidationError::MissingProperty { path: "/cpus/cpu@1".into(), property: "reg".into() },
+        FdtValidationError::BadString {
+            path: "/cpus/cpu@1".into(),
+            property: "enable-method".into(),
+        },
+        FdtValidationError::OutOfRange {
+            path: "/uart".into(),
+            property: "clock-frequency".into(),
+            value: 0,
+        },
+        FdtValidationError::BadPhandle {
+            path: "/uart".into(),
+            property: "interrupt-parent".into(),
+            phandle: 0x42,
+        },
+        FdtValidationError::OutOfRange {
+            path: "/uart".into(),
+            property: "interrupts".into(),
+            value: 2,
+        },
+        FdtValidationError::MissingNode { selector: "compatible = \"arm,armv8-timer\"".into() },
+    ];
+    assert_eq!(fdt.validate(SCHEMA), Err(expected.into()));
+}
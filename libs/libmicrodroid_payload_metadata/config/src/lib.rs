@@ -56,6 +56,12 @@ pub struct VmPayloadConfig {
     /// https://docs.kernel.org/admin-guide/mm/transhuge.html
     #[serde(default)]
     pub hugepages: bool,
+
+    /// Whether the encrypted storage uses authenticated encryption, which detects tampering of
+    /// the storage by the host. A storage formatted without it can't be opened with it, and vice
+    /// versa.
+    #[serde(default)]
+    pub authenticated_encrypted_storage: bool,
 }
 
 /// OS config
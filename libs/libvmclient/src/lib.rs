@@ -216,7 +216,39 @@ impl VmInstance {
 
         let vm =
             service.createVm(config, console_out.as_ref(), console_in.as_ref(), log.as_ref())?;
+        Self::new(vm, callback)
+    }
+
+    /// Creates (but doesn't start) a new VM restored from a snapshot, taken with
+    /// [`VmInstance::snapshot`] of a VM with the same configuration.
+    pub fn restore(
+        service: &dyn IVirtualizationService,
+        config: &VirtualMachineConfig,
+        snapshot: File,
+        console_out: Option<File>,
+        console_in: Option<File>,
+        log: Option<File>,
+        callback: Option<Box<dyn VmCallback + Send + Sync>>,
+    ) -> BinderResult<Self> {
+        let snapshot = ParcelFileDescriptor::new(snapshot);
+        let console_out = console_out.map(ParcelFileDescriptor::new);
+        let console_in = console_in.map(ParcelFileDescriptor::new);
+        let log = log.map(ParcelFileDescriptor::new);
+
+        let vm = service.restoreVm(
+            config,
+            &snapshot,
+            console_out.as_ref(),
+            console_in.as_ref(),
+            log.as_ref(),
+        )?;
+        Self::new(vm, callback)
+    }
 
+    fn new(
+        vm: Strong<dyn IVirtualMachine>,
+        callback: Option<Box<dyn VmCallback + Send + Sync>>,
+    ) -> BinderResult<Self> {
         let cid = vm.getCid()?;
 
         // Register callback before starting VM, in case it dies immediately.
@@ -246,6 +278,11 @@ impl VmInstance {
         self.vm.getState()
     }
 
+    /// Writes a snapshot of the state of the running VM to `output`.
+    pub fn snapshot(&self, output: File) -> BinderResult<()> {
+        self.vm.snapshot(&ParcelFileDescriptor::new(output))
+    }
+
     /// Blocks until the VM or the VirtualizationService itself dies, and then returns the reason
     /// why it died.
     pub fn wait_for_death(&self) -> DeathReason {
@@ -0,0 +1,558 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Creation and signing of VBMeta images, standalone or appended to partition images.
+
+use avb_bindgen::{
+    AvbAlgorithmType, AvbChainPartitionDescriptor, AvbDescriptor, AvbDescriptorTag, AvbFooter,
+    AvbHashDescriptor, AvbHashtreeDescriptor, AvbKernelCmdlineDescriptor, AvbPropertyDescriptor,
+    AvbVBMetaImageHeader, AVB_FOOTER_MAGIC, AVB_FOOTER_MAGIC_LEN, AVB_FOOTER_VERSION_MAJOR,
+    AVB_FOOTER_VERSION_MINOR, AVB_MAGIC, AVB_MAGIC_LEN, AVB_RELEASE_STRING_SIZE, AVB_VERSION_MAJOR,
+};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{HasPublic, PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::descriptor::Descriptor;
+use crate::digest::{hash_image, hash_tree, is_valid_block_size};
+
+/// Size of the blocks that footers, and the VBMeta images they point to, are aligned to.
+const BLOCK_SIZE: u64 = 4096;
+
+/// Release string recorded in the images created by the builder.
+const RELEASE_STRING: &str = "libvbmeta_rust";
+
+/// Errors from building a VBMeta image.
+#[derive(Debug, Error)]
+pub enum VbMetaImageBuildError {
+    /// There was an IO error.
+    #[error("IO error")]
+    Io(#[from] io::Error),
+    /// There was an error hashing or signing the image.
+    #[error("Cannot hash or sign the image")]
+    Crypto(#[from] ErrorStack),
+    /// The size of the signing key doesn't match the algorithm.
+    #[error("Algorithm {algorithm:?} requires a {expected}-bit key but got {actual} bits")]
+    KeySizeMismatch {
+        /// Requested signing algorithm.
+        algorithm: Algorithm,
+        /// Key size required by the algorithm.
+        expected: u32,
+        /// Size of the supplied key.
+        actual: u32,
+    },
+    /// The image, with its footer, doesn't fit in the requested partition size.
+    #[error("Image needs {required} bytes but the partition size is {partition_size}")]
+    PartitionTooSmall {
+        /// Minimum size of the partition.
+        required: u64,
+        /// Requested size of the partition.
+        partition_size: u64,
+    },
+    /// The block size of a hashtree isn't a power of two from 512 to 4096, as dm-verity requires.
+    #[error("Invalid hashtree block size {0}")]
+    InvalidBlockSize(u32),
+}
+
+/// Algorithm used to sign a VBMeta image.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    Sha256Rsa2048,
+    Sha256Rsa4096,
+    Sha256Rsa8192,
+    Sha512Rsa2048,
+    Sha512Rsa4096,
+    Sha512Rsa8192,
+}
+
+impl Algorithm {
+    fn algorithm_type(self) -> AvbAlgorithmType {
+        match self {
+            Self::Sha256Rsa2048 => AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA256_RSA2048,
+            Self::Sha256Rsa4096 => AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA256_RSA4096,
+            Self::Sha256Rsa8192 => AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA256_RSA8192,
+            Self::Sha512Rsa2048 => AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA512_RSA2048,
+            Self::Sha512Rsa4096 => AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA512_RSA4096,
+            Self::Sha512Rsa8192 => AvbAlgorithmType::AVB_ALGORITHM_TYPE_SHA512_RSA8192,
+        }
+    }
+
+    fn digest(self) -> MessageDigest {
+        match self {
+            Self::Sha256Rsa2048 | Self::Sha256Rsa4096 | Self::Sha256Rsa8192 => {
+                MessageDigest::sha256()
+            }
+            Self::Sha512Rsa2048 | Self::Sha512Rsa4096 | Self::Sha512Rsa8192 => {
+                MessageDigest::sha512()
+            }
+        }
+    }
+
+    fn key_bits(self) -> u32 {
+        match self {
+            Self::Sha256Rsa2048 | Self::Sha512Rsa2048 => 2048,
+            Self::Sha256Rsa4096 | Self::Sha512Rsa4096 => 4096,
+            Self::Sha256Rsa8192 | Self::Sha512Rsa8192 => 8192,
+        }
+    }
+}
+
+/// Hash algorithm of a hash or hashtree descriptor.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    fn digest(self) -> MessageDigest {
+        match self {
+            Self::Sha256 => MessageDigest::sha256(),
+            Self::Sha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+/// A builder that creates VBMeta images, optionally signed.
+#[derive(Clone, Default)]
+pub struct VbMetaImageBuilder<'a> {
+    key: Option<(Algorithm, &'a Rsa<Private>)>,
+    rollback_index: u64,
+    rollback_index_location: u32,
+    flags: u32,
+    descriptors: Vec<Vec<u8>>,
+}
+
+impl<'a> VbMetaImageBuilder<'a> {
+    /// Signs the image with `key` using `algorithm`. Images are left unsigned otherwise.
+    pub fn signing_key(&mut self, algorithm: Algorithm, key: &'a Rsa<Private>) -> &mut Self {
+        self.key = Some((algorithm, key));
+        self
+    }
+
+    /// Sets the rollback index of the image.
+    pub fn rollback_index(&mut self, rollback_index: u64) -> &mut Self {
+        self.rollback_index = rollback_index;
+        self
+    }
+
+    /// Sets the location of the rollback index of the image.
+    pub fn rollback_index_location(&mut self, location: u32) -> &mut Self {
+        self.rollback_index_location = location;
+        self
+    }
+
+    /// Sets the flags of the image (`AVB_VBMETA_IMAGE_FLAGS_*`).
+    pub fn flags(&mut self, flags: u32) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Adds a property descriptor.
+    pub fn add_property(&mut self, key: &str, value: &[u8]) -> &mut Self {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        body.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        pad_fields::<AvbPropertyDescriptor>(&mut body);
+        for data in [key.as_bytes(), value] {
+            body.extend_from_slice(data);
+            body.push(0);
+        }
+        self.add_descriptor_body(AvbDescriptorTag::AVB_DESCRIPTOR_TAG_PROPERTY, body)
+    }
+
+    /// Adds a kernel command line descriptor with the `AVB_KERNEL_CMDLINE_FLAGS_*` flags.
+    pub fn add_kernel_cmdline(&mut self, kernel_cmdline: &str, flags: u32) -> &mut Self {
+        let mut body = Vec::new();
+        body.extend_from_slice(&flags.to_be_bytes());
+        body.extend_from_slice(&(kernel_cmdline.len() as u32).to_be_bytes());
+        pad_fields::<AvbKernelCmdlineDescriptor>(&mut body);
+        body.extend_from_slice(kernel_cmdline.as_bytes());
+        self.add_descriptor_body(AvbDescriptorTag::AVB_DESCRIPTOR_TAG_KERNEL_CMDLINE, body)
+    }
+
+    /// Adds a chain partition descriptor, delegating the verification of `partition_name` to the
+    /// VBMeta image of that partition signed by `public_key`, in the format returned by
+    /// [`encode_public_key`].
+    pub fn add_chain_partition(
+        &mut self,
+        partition_name: &str,
+        rollback_index_location: u32,
+        public_key: &[u8],
+        flags: u32,
+    ) -> &mut Self {
+        let mut body = Vec::new();
+        body.extend_from_slice(&rollback_index_location.to_be_bytes());
+        body.extend_from_slice(&(partition_name.len() as u32).to_be_bytes());
+        body.extend_from_slice(&(public_key.len() as u32).to_be_bytes());
+        body.extend_from_slice(&flags.to_be_bytes());
+        pad_fields::<AvbChainPartitionDescriptor>(&mut body);
+        body.extend_from_slice(partition_name.as_bytes());
+        body.extend_from_slice(public_key);
+        self.add_descriptor_body(AvbDescriptorTag::AVB_DESCRIPTOR_TAG_CHAIN_PARTITION, body)
+    }
+
+    /// Adds a descriptor copied from another VBMeta image, e.g. to include the descriptors of a
+    /// partition in the top-level image.
+    pub fn add_descriptor(&mut self, descriptor: &Descriptor) -> &mut Self {
+        match descriptor {
+            Descriptor::Property(data)
+            | Descriptor::Hashtree(data)
+            | Descriptor::Hash(data)
+            | Descriptor::KernelCmdline(data)
+            | Descriptor::ChainPartition(data) => self.descriptors.push(data.to_vec()),
+            Descriptor::Unknown => {}
+        }
+        self
+    }
+
+    /// Creates the VBMeta image.
+    pub fn build(&self) -> Result<Vec<u8>, VbMetaImageBuildError> {
+        let public_key = match self.key {
+            Some((algorithm, key)) => {
+                if key.size() * 8 != algorithm.key_bits() {
+                    return Err(VbMetaImageBuildError::KeySizeMismatch {
+                        algorithm,
+                        expected: algorithm.key_bits(),
+                        actual: key.size() * 8,
+                    });
+                }
+                encode_public_key(key)?
+            }
+            None => Vec::new(),
+        };
+
+        // The auxiliary data block holds the descriptors followed by the public key.
+        let descriptors = self.descriptors.concat();
+        let mut aux = [descriptors.as_slice(), &public_key].concat();
+        let public_key_metadata_offset = aux.len() as u64;
+        aux.resize(aux.len().next_multiple_of(64), 0);
+
+        let (algorithm_type, hash_size, signature_size) = match self.key {
+            Some((algorithm, key)) => {
+                (algorithm.algorithm_type(), algorithm.digest().size(), key.size() as usize)
+            }
+            None => (AvbAlgorithmType::AVB_ALGORITHM_TYPE_NONE, 0, 0),
+        };
+        let auth_size = (hash_size + signature_size).next_multiple_of(64);
+        // Rollback index locations other than 0 were introduced in libavb 1.2.
+        let required_minor = if self.rollback_index_location == 0 { 0 } else { 2 };
+
+        let mut header = Vec::with_capacity(size_of::<AvbVBMetaImageHeader>());
+        header.extend_from_slice(&AVB_MAGIC[..AVB_MAGIC_LEN as usize]);
+        header.extend_from_slice(&AVB_VERSION_MAJOR.to_be_bytes());
+        header.extend_from_slice(&(required_minor as u32).to_be_bytes());
+        header.extend_from_slice(&(auth_size as u64).to_be_bytes());
+        header.extend_from_slice(&(aux.len() as u64).to_be_bytes());
+        header.extend_from_slice(&(algorithm_type as u32).to_be_bytes());
+        for value in [
+            0,
+            hash_size as u64,
+            hash_size as u64,
+            signature_size as u64,
+            descriptors.len() as u64,
+            public_key.len() as u64,
+            public_key_metadata_offset,
+            0,
+            0,
+            descriptors.len() as u64,
+            self.rollback_index,
+        ] {
+            header.extend_from_slice(&value.to_be_bytes());
+        }
+        header.extend_from_slice(&self.flags.to_be_bytes());
+        header.extend_from_slice(&self.rollback_index_location.to_be_bytes());
+        let mut release_string = [0u8; AVB_RELEASE_STRING_SIZE as usize];
+        release_string[..RELEASE_STRING.len()].copy_from_slice(RELEASE_STRING.as_bytes());
+        header.extend_from_slice(&release_string);
+        header.resize(size_of::<AvbVBMetaImageHeader>(), 0);
+
+        // The authentication data block holds the hash followed by the signature, both covering
+        // the header and the auxiliary data block.
+        let mut auth = Vec::with_capacity(auth_size);
+        if let Some((algorithm, key)) = self.key {
+            let signed = [header.as_slice(), &aux].concat();
+            auth.extend_from_slice(&hash(algorithm.digest(), &signed)?);
+            let key = PKey::from_rsa(key.clone())?;
+            let mut signer = Signer::new(algorithm.digest(), &key)?;
+            signer.update(&signed)?;
+            auth.extend_from_slice(&signer.sign_to_vec()?);
+        }
+        auth.resize(auth_size, 0);
+
+        Ok([header, auth, aux].concat())
+    }
+
+    /// Appends a VBMeta image with a hash descriptor covering the whole partition image, followed
+    /// by a footer pointing to it. The image must not already have a footer.
+    pub fn add_hash_footer<P: AsRef<Path>>(
+        &self,
+        path: P,
+        footer: &FooterBuilder,
+    ) -> Result<(), VbMetaImageBuildError> {
+        let mut image = OpenOptions::new().read(true).write(true).open(path)?;
+        let image_size = image.metadata()?.len();
+        let digest =
+            hash_image(&mut image, image_size, footer.hash_algorithm.digest(), footer.salt)?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&image_size.to_be_bytes());
+        body.extend_from_slice(&hash_algorithm_field(footer.hash_algorithm));
+        body.extend_from_slice(&(footer.partition_name.len() as u32).to_be_bytes());
+        body.extend_from_slice(&(footer.salt.len() as u32).to_be_bytes());
+        body.extend_from_slice(&(digest.len() as u32).to_be_bytes());
+        body.extend_from_slice(&footer.descriptor_flags.to_be_bytes());
+        pad_fields::<AvbHashDescriptor>(&mut body);
+        body.extend_from_slice(footer.partition_name.as_bytes());
+        body.extend_from_slice(footer.salt);
+        body.extend_from_slice(&digest);
+
+        let mut vbmeta = self.clone();
+        vbmeta.insert_descriptor_body(AvbDescriptorTag::AVB_DESCRIPTOR_TAG_HASH, body);
+        append_footer(&mut image, image_size, &[], &vbmeta.build()?, footer.partition_size)
+    }
+
+    /// Appends the dm-verity hashtree of the partition image, then a VBMeta image with a hashtree
+    /// descriptor for it, followed by a footer pointing to it. The image must not already have a
+    /// footer.
+    pub fn add_hashtree_footer<P: AsRef<Path>>(
+        &self,
+        path: P,
+        footer: &FooterBuilder,
+    ) -> Result<(), VbMetaImageBuildError> {
+        let block_size = footer.block_size;
+        if !is_valid_block_size(block_size as usize) {
+            return Err(VbMetaImageBuildError::InvalidBlockSize(block_size));
+        }
+        let mut image = OpenOptions::new().read(true).write(true).open(path)?;
+        let original_size = image.metadata()?.len();
+        // The data covered by the tree is zero-padded to a whole number of blocks. The tree is
+        // computed over the original data, whose last block `hash_tree` pads the same way, so that
+        // the image is left untouched until the layout is known to fit in the partition.
+        let image_size = original_size.next_multiple_of(block_size.into());
+        let tree = hash_tree(
+            &mut image,
+            original_size,
+            block_size as usize,
+            block_size as usize,
+            footer.hash_algorithm.digest(),
+            footer.salt,
+        )?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&1u32.to_be_bytes()); // dm_verity_version
+        body.extend_from_slice(&image_size.to_be_bytes());
+        body.extend_from_slice(&image_size.next_multiple_of(BLOCK_SIZE).to_be_bytes());
+        body.extend_from_slice(&(tree.tree().len() as u64).to_be_bytes());
+        body.extend_from_slice(&block_size.to_be_bytes());
+        body.extend_from_slice(&block_size.to_be_bytes());
+        // No forward error correction: fec_num_roots, fec_offset and fec_size are all 0.
+        body.extend_from_slice(&[0; 4 + 8 + 8]);
+        body.extend_from_slice(&hash_algorithm_field(footer.hash_algorithm));
+        body.extend_from_slice(&(footer.partition_name.len() as u32).to_be_bytes());
+        body.extend_from_slice(&(footer.salt.len() as u32).to_be_bytes());
+        body.extend_from_slice(&(tree.root_digest.len() as u32).to_be_bytes());
+        body.extend_from_slice(&footer.descriptor_flags.to_be_bytes());
+        pad_fields::<AvbHashtreeDescriptor>(&mut body);
+        body.extend_from_slice(footer.partition_name.as_bytes());
+        body.extend_from_slice(footer.salt);
+        body.extend_from_slice(&tree.root_digest);
+
+        let mut vbmeta = self.clone();
+        vbmeta.insert_descriptor_body(AvbDescriptorTag::AVB_DESCRIPTOR_TAG_HASHTREE, body);
+        append_footer(&mut image, image_size, &tree.tree(), &vbmeta.build()?, footer.partition_size)
+    }
+
+    fn add_descriptor_body(&mut self, tag: AvbDescriptorTag::Type, body: Vec<u8>) -> &mut Self {
+        self.descriptors.push(descriptor(tag, body));
+        self
+    }
+
+    /// Inserts the descriptor of the partition ahead of the others, as avbtool does.
+    fn insert_descriptor_body(&mut self, tag: AvbDescriptorTag::Type, body: Vec<u8>) {
+        self.descriptors.insert(0, descriptor(tag, body));
+    }
+}
+
+/// A builder that describes the partition covered by a hash or hashtree footer.
+pub struct FooterBuilder<'a> {
+    partition_name: &'a str,
+    partition_size: Option<u64>,
+    hash_algorithm: HashAlgorithm,
+    salt: &'a [u8],
+    block_size: u32,
+    descriptor_flags: u32,
+}
+
+impl<'a> FooterBuilder<'a> {
+    /// Describes the partition `partition_name`, hashed with SHA-256 and no salt.
+    pub fn new(partition_name: &'a str) -> Self {
+        FooterBuilder {
+            partition_name,
+            partition_size: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            salt: &[],
+            block_size: BLOCK_SIZE as u32,
+            descriptor_flags: 0,
+        }
+    }
+
+    /// Sets the size of the partition, with the footer at its very end. By default, the
+    /// partition is just large enough to hold the image, its hashtree, VBMeta image and footer.
+    pub fn partition_size(&mut self, size: u64) -> &mut Self {
+        self.partition_size = Some(size);
+        self
+    }
+
+    /// Sets the hash algorithm of the descriptor.
+    pub fn hash_algorithm(&mut self, hash_algorithm: HashAlgorithm) -> &mut Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    /// Sets the salt prepended to the hashed data.
+    pub fn salt(&mut self, salt: &'a [u8]) -> &mut Self {
+        self.salt = salt;
+        self
+    }
+
+    /// Sets the size of the data and hash blocks of a hashtree.
+    pub fn block_size(&mut self, block_size: u32) -> &mut Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the flags of the descriptor (`AVB_HASH_DESCRIPTOR_FLAGS_*` or
+    /// `AVB_HASHTREE_DESCRIPTOR_FLAGS_*`).
+    pub fn descriptor_flags(&mut self, flags: u32) -> &mut Self {
+        self.descriptor_flags = flags;
+        self
+    }
+}
+
+/// Encodes an RSA public key in the `AvbRSAPublicKeyHeader` format used in VBMeta images and
+/// chain partition descriptors.
+pub fn encode_public_key<T>(key: &Rsa<T>) -> Result<Vec<u8>, ErrorStack>
+where
+    T: HasPublic,
+{
+    let bits = key.size() * 8;
+    let len = key.size() as i32;
+    let n = key.n();
+
+    // n0inv = -1 / n[0] (mod 2^32), with the inverse of the odd n[0] found by Newton's method.
+    let n_bytes = n.to_vec_padded(len)?;
+    let n0 = u32::from_be_bytes(n_bytes[n_bytes.len() - 4..].try_into().unwrap());
+    let mut inverse = n0;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inverse)));
+    }
+    let n0inv = inverse.wrapping_neg();
+    // rr = (2^bits)^2 (mod n)
+    let mut ctx = BigNumContext::new()?;
+    let one = BigNum::from_u32(1)?;
+    let mut exponent = BigNum::new()?;
+    exponent.lshift(&one, 2 * bits as i32)?;
+    let mut rr = BigNum::new()?;
+    rr.nnmod(&exponent, n, &mut ctx)?;
+
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(&bits.to_be_bytes());
+    encoded.extend_from_slice(&n0inv.to_be_bytes());
+    encoded.extend_from_slice(&n_bytes);
+    encoded.extend_from_slice(&rr.to_vec_padded(len)?);
+    Ok(encoded)
+}
+
+/// Wraps the body of a descriptor with its tag and size, padded to 8 bytes.
+fn descriptor(tag: AvbDescriptorTag::Type, mut body: Vec<u8>) -> Vec<u8> {
+    body.resize(body.len().next_multiple_of(8), 0);
+    let mut descriptor = Vec::with_capacity(size_of::<AvbDescriptor>() + body.len());
+    descriptor.extend_from_slice(&u64::from(tag).to_be_bytes());
+    descriptor.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    descriptor.extend_from_slice(&body);
+    descriptor
+}
+
+/// Zero-pads the fixed-size fields of a descriptor body up to the size of the libavb struct `T`,
+/// which starts with the generic descriptor header.
+fn pad_fields<T>(body: &mut Vec<u8>) {
+    body.resize(size_of::<T>() - size_of::<AvbDescriptor>(), 0);
+}
+
+fn hash_algorithm_field(hash_algorithm: HashAlgorithm) -> [u8; 32] {
+    let mut field = [0u8; 32];
+    let name = hash_algorithm.name().as_bytes();
+    field[..name.len()].copy_from_slice(name);
+    field
+}
+
+/// Lays out the end of a partition image: the hashtree, if any, and the VBMeta image each start
+/// on a block boundary after the `image_size` bytes of data, then the footer ends the partition.
+fn append_footer(
+    image: &mut File,
+    image_size: u64,
+    tree: &[u8],
+    vbmeta: &[u8],
+    partition_size: Option<u64>,
+) -> Result<(), VbMetaImageBuildError> {
+    let tree_offset = image_size.next_multiple_of(BLOCK_SIZE);
+    let vbmeta_offset = (tree_offset + tree.len() as u64).next_multiple_of(BLOCK_SIZE);
+    let vbmeta_end = (vbmeta_offset + vbmeta.len() as u64).next_multiple_of(BLOCK_SIZE);
+    let required = vbmeta_end + size_of::<AvbFooter>() as u64;
+    let partition_size = match partition_size {
+        Some(partition_size) if partition_size < required => {
+            return Err(VbMetaImageBuildError::PartitionTooSmall { required, partition_size });
+        }
+        Some(partition_size) => partition_size,
+        // Like avbtool, put the footer at the end of a block of its own.
+        None => vbmeta_end + BLOCK_SIZE,
+    };
+
+    let mut footer = Vec::with_capacity(size_of::<AvbFooter>());
+    footer.extend_from_slice(&AVB_FOOTER_MAGIC[..AVB_FOOTER_MAGIC_LEN as usize]);
+    footer.extend_from_slice(&AVB_FOOTER_VERSION_MAJOR.to_be_bytes());
+    footer.extend_from_slice(&AVB_FOOTER_VERSION_MINOR.to_be_bytes());
+    footer.extend_from_slice(&image_size.to_be_bytes());
+    footer.extend_from_slice(&vbmeta_offset.to_be_bytes());
+    footer.extend_from_slice(&(vbmeta.len() as u64).to_be_bytes());
+    footer.resize(size_of::<AvbFooter>(), 0);
+
+    // Leave the gaps between the regions sparse. This also zero-pads the data up to `image_size`.
+    image.set_len(partition_size)?;
+    image.seek(SeekFrom::Start(tree_offset))?;
+    image.write_all(tree)?;
+    image.seek(SeekFrom::Start(vbmeta_offset))?;
+    image.write_all(vbmeta)?;
+    image.seek(SeekFrom::Start(partition_size - footer.len() as u64))?;
+    image.write_all(&footer)?;
+    Ok(())
+}
@@ -0,0 +1,230 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of the partitions described by a VBMeta image, following chain descriptors.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::descriptor::{Descriptor, HashDescriptor, HashtreeDescriptor};
+use crate::digest::{hash_image, hash_tree, message_digest};
+use crate::{VbMetaImage, VbMetaImageParseError, VbMetaImageVerificationError};
+
+/// Errors from verifying the partitions described by a VBMeta image.
+#[derive(Debug, Error)]
+pub enum PartitionVerificationError {
+    /// There was an IO error reading or hashing the image of a partition.
+    #[error("IO error reading partition {0}")]
+    Io(String, #[source] io::Error),
+    /// There was an error parsing the VBMeta image.
+    #[error("Cannot parse VBMeta image")]
+    ParseError(#[from] VbMetaImageParseError),
+    /// The VBMeta image of a chained partition did not verify.
+    #[error("Cannot verify VBMeta image of partition {0}")]
+    ChainedVbMeta(String, #[source] VbMetaImageVerificationError),
+    /// No image was supplied for a partition described by the VBMeta image.
+    #[error("No image for partition {0}")]
+    MissingImage(String),
+    /// A chained partition isn't signed with the public key of its chain descriptor.
+    #[error("Partition {0} isn't signed with the key of its chain descriptor")]
+    PublicKeyMismatch(String),
+    /// A chained partition has chain descriptors itself.
+    #[error("Chained partition {0} has chain descriptors")]
+    NestedChain(String),
+    /// A descriptor uses a hash algorithm that isn't supported.
+    #[error("Unsupported hash algorithm {0}")]
+    UnsupportedHashAlgorithm(String),
+    /// The contents of a partition don't match the digest of its descriptor.
+    #[error("Digest mismatch for partition {0}")]
+    DigestMismatch(String),
+}
+
+/// A VBMeta image verified along with the partitions it describes.
+#[derive(Debug)]
+pub struct VerifiedVbMeta {
+    /// Name of the partition holding the VBMeta image, or `None` for the top-level image.
+    pub partition_name: Option<String>,
+    /// Public key that signed the VBMeta image, if any.
+    pub public_key: Option<Vec<u8>>,
+    /// Rollback index of the VBMeta image.
+    pub rollback_index: u64,
+    /// Partitions whose contents were verified against a hash or hashtree descriptor.
+    pub partitions: Vec<VerifiedPartition>,
+    /// VBMeta images of the chained partitions.
+    pub chained_partitions: Vec<VerifiedVbMeta>,
+}
+
+/// A partition whose contents matched a hash or hashtree descriptor.
+#[derive(Debug)]
+pub struct VerifiedPartition {
+    /// Name of the partition.
+    pub name: String,
+    /// Kind of the descriptor the partition was verified against.
+    pub kind: DigestKind,
+    /// Name of the hash algorithm, e.g. "sha256".
+    pub hash_algorithm: String,
+    /// Digest of the partition, or root digest of its hashtree.
+    pub digest: Vec<u8>,
+}
+
+/// Kind of digest covering a partition.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DigestKind {
+    /// Digest of the whole partition, from a hash descriptor.
+    Hash,
+    /// Root digest of the dm-verity hashtree of the partition, from a hashtree descriptor.
+    Hashtree,
+}
+
+impl VbMetaImage {
+    /// Verify the partitions described by the VBMeta image against the supplied images, indexed
+    /// by partition name.
+    ///
+    /// Chained partitions must be signed with the public key of their chain descriptor and the
+    /// partitions they describe are verified too. Trusting the public key of the top-level image
+    /// is left to the caller.
+    pub fn verify_partitions(
+        &self,
+        images: &HashMap<String, PathBuf>,
+    ) -> Result<VerifiedVbMeta, PartitionVerificationError> {
+        verify_vbmeta(self, None, images)
+    }
+}
+
+fn verify_vbmeta(
+    vbmeta: &VbMetaImage,
+    partition_name: Option<&str>,
+    images: &HashMap<String, PathBuf>,
+) -> Result<VerifiedVbMeta, PartitionVerificationError> {
+    let mut partitions = Vec::new();
+    let mut chained_partitions = Vec::new();
+    for descriptor in vbmeta.descriptors()?.iter() {
+        match descriptor {
+            Descriptor::Hash(_) => partitions.push(verify_hash(&descriptor.to_hash()?, images)?),
+            Descriptor::Hashtree(_) => {
+                partitions.push(verify_hashtree(&descriptor.to_hashtree()?, images)?)
+            }
+            Descriptor::ChainPartition(_) => {
+                // Like libavb, only allow chaining from the top-level image.
+                if let Some(name) = partition_name {
+                    return Err(PartitionVerificationError::NestedChain(name.to_owned()));
+                }
+                let chain = descriptor.to_chain_partition()?;
+                let name = chain.partition_name();
+                let chained = VbMetaImage::verify_path(image_path(images, name)?)
+                    .map_err(|e| PartitionVerificationError::ChainedVbMeta(name.to_owned(), e))?;
+                if chained.public_key() != Some(chain.public_key()) {
+                    return Err(PartitionVerificationError::PublicKeyMismatch(name.to_owned()));
+                }
+                chained_partitions.push(verify_vbmeta(&chained, Some(name), images)?);
+            }
+            Descriptor::Property(_) | Descriptor::KernelCmdline(_) | Descriptor::Unknown => {}
+        }
+    }
+
+    Ok(VerifiedVbMeta {
+        partition_name: partition_name.map(ToOwned::to_owned),
+        public_key: vbmeta.public_key().map(ToOwned::to_owned),
+        rollback_index: vbmeta.rollback_index(),
+        partitions,
+        chained_partitions,
+    })
+}
+
+fn verify_hash(
+    descriptor: &HashDescriptor,
+    images: &HashMap<String, PathBuf>,
+) -> Result<VerifiedPartition, PartitionVerificationError> {
+    let name = descriptor.partition_name();
+    let hash_algorithm = descriptor.hash_algorithm();
+    let digest = message_digest(hash_algorithm).ok_or_else(|| unsupported(hash_algorithm))?;
+    let mut image = open_image(images, name)?;
+    let actual = hash_image(&mut image, descriptor.image_size(), digest, descriptor.salt())
+        .map_err(|e| io_error(name, e))?;
+
+    check_digest(name, DigestKind::Hash, hash_algorithm, actual, descriptor.digest())
+}
+
+fn verify_hashtree(
+    descriptor: &HashtreeDescriptor,
+    images: &HashMap<String, PathBuf>,
+) -> Result<VerifiedPartition, PartitionVerificationError> {
+    let name = descriptor.partition_name();
+    let hash_algorithm = descriptor.hash_algorithm();
+    let digest = message_digest(hash_algorithm).ok_or_else(|| unsupported(hash_algorithm))?;
+    let mut image = open_image(images, name)?;
+    let tree = hash_tree(
+        &mut image,
+        descriptor.image_size(),
+        descriptor.data_block_size() as usize,
+        descriptor.hash_block_size() as usize,
+        digest,
+        descriptor.salt(),
+    )
+    .map_err(|e| io_error(name, e))?;
+
+    check_digest(
+        name,
+        DigestKind::Hashtree,
+        hash_algorithm,
+        tree.root_digest,
+        descriptor.root_digest(),
+    )
+}
+
+fn check_digest(
+    name: &str,
+    kind: DigestKind,
+    hash_algorithm: &str,
+    actual: Vec<u8>,
+    expected: &[u8],
+) -> Result<VerifiedPartition, PartitionVerificationError> {
+    if actual != expected {
+        return Err(PartitionVerificationError::DigestMismatch(name.to_owned()));
+    }
+    Ok(VerifiedPartition {
+        name: name.to_owned(),
+        kind,
+        hash_algorithm: hash_algorithm.to_owned(),
+        digest: actual,
+    })
+}
+
+fn unsupported(hash_algorithm: &str) -> PartitionVerificationError {
+    PartitionVerificationError::UnsupportedHashAlgorithm(hash_algorithm.to_owned())
+}
+
+fn image_path<'a>(
+    images: &'a HashMap<String, PathBuf>,
+    name: &str,
+) -> Result<&'a Path, PartitionVerificationError> {
+    images
+        .get(name)
+        .map(PathBuf::as_path)
+        .ok_or_else(|| PartitionVerificationError::MissingImage(name.to_owned()))
+}
+
+fn open_image(
+    images: &HashMap<String, PathBuf>,
+    name: &str,
+) -> Result<File, PartitionVerificationError> {
+    File::open(image_path(images, name)?).map_err(|e| io_error(name, e))
+}
+
+fn io_error(name: &str, error: io::Error) -> PartitionVerificationError {
+    PartitionVerificationError::Io(name.to_owned(), error)
+}
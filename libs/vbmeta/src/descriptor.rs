@@ -13,13 +13,18 @@
 // limitations under the License.
 
 use avb_bindgen::{
-    avb_descriptor_foreach, avb_descriptor_validate_and_byteswap,
-    avb_hashtree_descriptor_validate_and_byteswap, AvbDescriptor, AvbDescriptorTag,
-    AvbHashtreeDescriptor,
+    avb_chain_partition_descriptor_validate_and_byteswap, avb_descriptor_foreach,
+    avb_descriptor_validate_and_byteswap, avb_hash_descriptor_validate_and_byteswap,
+    avb_hashtree_descriptor_validate_and_byteswap,
+    avb_kernel_cmdline_descriptor_validate_and_byteswap,
+    avb_property_descriptor_validate_and_byteswap, AvbChainPartitionDescriptor, AvbDescriptor,
+    AvbDescriptorTag, AvbHashDescriptor, AvbHashtreeDescriptor, AvbKernelCmdlineDescriptor,
+    AvbPropertyDescriptor,
 };
 use std::ffi::c_void;
-use std::mem::{size_of, MaybeUninit};
+use std::mem::{offset_of, size_of, MaybeUninit};
 use std::slice;
+use std::str;
 
 use super::VbMetaImageParseError;
 
@@ -39,10 +44,39 @@ pub enum Descriptor<'a> {
     Unknown,
 }
 
+/// A property descriptor.
+pub struct PropertyDescriptor<'a> {
+    key: &'a str,
+    value: &'a [u8],
+}
+
 /// A hashtree descriptor.
 pub struct HashtreeDescriptor<'a> {
     descriptor: AvbHashtreeDescriptor,
     data: &'a [u8],
+    hash_algorithm: &'a str,
+    partition_name: &'a str,
+}
+
+/// A hash descriptor.
+pub struct HashDescriptor<'a> {
+    descriptor: AvbHashDescriptor,
+    data: &'a [u8],
+    hash_algorithm: &'a str,
+    partition_name: &'a str,
+}
+
+/// A kernel command-line descriptor.
+pub struct KernelCmdlineDescriptor<'a> {
+    flags: u32,
+    kernel_cmdline: &'a str,
+}
+
+/// A chain partition descriptor.
+pub struct ChainPartitionDescriptor<'a> {
+    descriptor: AvbChainPartitionDescriptor,
+    partition_name: &'a str,
+    public_key: &'a [u8],
 }
 
 impl Descriptors<'_> {
@@ -101,6 +135,21 @@ impl Descriptors<'_> {
     }
 }
 
+impl<'a> Descriptors<'a> {
+    /// Get the value of the property descriptor with the given key, if any.
+    pub fn property(&self, key: &str) -> Result<Option<&'a [u8]>, VbMetaImageParseError> {
+        for descriptor in &self.descriptors {
+            if let Descriptor::Property(_) = descriptor {
+                let property = descriptor.to_property()?;
+                if property.key() == key {
+                    return Ok(Some(property.value()));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
 impl<'a> IntoIterator for Descriptors<'a> {
     type Item = Descriptor<'a>;
     type IntoIter = std::vec::IntoIter<Self::Item>;
@@ -110,32 +159,148 @@ impl<'a> IntoIterator for Descriptors<'a> {
     }
 }
 
-impl Descriptor<'_> {
+impl<'a> Descriptor<'a> {
+    /// Parse the descriptor as a property descriptor.
+    pub fn to_property(&self) -> Result<PropertyDescriptor<'a>, VbMetaImageParseError> {
+        let Self::Property(data) = self else {
+            return Err(VbMetaImageParseError::InvalidDescriptor);
+        };
+        // SAFETY: data contains the entire descriptor.
+        let descriptor =
+            unsafe { validate_and_byteswap(data, avb_property_descriptor_validate_and_byteswap) }?;
+        // The key and the value are each followed by a NUL byte, as checked by libavb.
+        let mut fields = Fields::new(data, size_of::<AvbPropertyDescriptor>());
+        let key = to_str(fields.take(descriptor.key_num_bytes)?)?;
+        fields.take(1)?;
+        let value = fields.take(descriptor.value_num_bytes)?;
+        Ok(PropertyDescriptor { key, value })
+    }
+
     /// Parse the descriptor as a hashtree descriptor.
-    pub fn to_hashtree(&self) -> Result<HashtreeDescriptor, VbMetaImageParseError> {
-        match self {
-            Self::Hashtree(data) => {
-                // SAFETY: data contains the entire descriptor.
-                let descriptor = unsafe {
-                    let mut desc = MaybeUninit::uninit();
-                    let src = data.as_ptr() as *const _ as *const AvbHashtreeDescriptor;
-                    if !avb_hashtree_descriptor_validate_and_byteswap(src, desc.as_mut_ptr()) {
-                        return Err(VbMetaImageParseError::InvalidDescriptor);
-                    }
-                    desc.assume_init()
-                };
-                Ok(HashtreeDescriptor { descriptor, data })
-            }
-            _ => Err(VbMetaImageParseError::InvalidDescriptor),
-        }
+    pub fn to_hashtree(&self) -> Result<HashtreeDescriptor<'a>, VbMetaImageParseError> {
+        let Self::Hashtree(data) = self else {
+            return Err(VbMetaImageParseError::InvalidDescriptor);
+        };
+        // SAFETY: data contains the entire descriptor.
+        let descriptor =
+            unsafe { validate_and_byteswap(data, avb_hashtree_descriptor_validate_and_byteswap) }?;
+        let mut fields = Fields::new(data, offset_of!(AvbHashtreeDescriptor, hash_algorithm));
+        let hash_algorithm = to_str(nul_terminated(fields.take(descriptor.hash_algorithm.len())?))?;
+        let mut fields = Fields::new(data, size_of::<AvbHashtreeDescriptor>());
+        let partition_name = to_str(fields.take(descriptor.partition_name_len)?)?;
+        Ok(HashtreeDescriptor { descriptor, data, hash_algorithm, partition_name })
+    }
+
+    /// Parse the descriptor as a hash descriptor.
+    pub fn to_hash(&self) -> Result<HashDescriptor<'a>, VbMetaImageParseError> {
+        let Self::Hash(data) = self else {
+            return Err(VbMetaImageParseError::InvalidDescriptor);
+        };
+        // SAFETY: data contains the entire descriptor.
+        let descriptor =
+            unsafe { validate_and_byteswap(data, avb_hash_descriptor_validate_and_byteswap) }?;
+        let mut fields = Fields::new(data, offset_of!(AvbHashDescriptor, hash_algorithm));
+        let hash_algorithm = to_str(nul_terminated(fields.take(descriptor.hash_algorithm.len())?))?;
+        let mut fields = Fields::new(data, size_of::<AvbHashDescriptor>());
+        let partition_name = to_str(fields.take(descriptor.partition_name_len)?)?;
+        Ok(HashDescriptor { descriptor, data, hash_algorithm, partition_name })
+    }
+
+    /// Parse the descriptor as a kernel command-line descriptor.
+    pub fn to_kernel_cmdline(&self) -> Result<KernelCmdlineDescriptor<'a>, VbMetaImageParseError> {
+        let Self::KernelCmdline(data) = self else {
+            return Err(VbMetaImageParseError::InvalidDescriptor);
+        };
+        // SAFETY: data contains the entire descriptor.
+        let descriptor = unsafe {
+            validate_and_byteswap(data, avb_kernel_cmdline_descriptor_validate_and_byteswap)
+        }?;
+        let mut fields = Fields::new(data, size_of::<AvbKernelCmdlineDescriptor>());
+        let kernel_cmdline = to_str(fields.take(descriptor.kernel_cmdline_length)?)?;
+        Ok(KernelCmdlineDescriptor { flags: descriptor.flags, kernel_cmdline })
+    }
+
+    /// Parse the descriptor as a chain partition descriptor.
+    pub fn to_chain_partition(
+        &self,
+    ) -> Result<ChainPartitionDescriptor<'a>, VbMetaImageParseError> {
+        let Self::ChainPartition(data) = self else {
+            return Err(VbMetaImageParseError::InvalidDescriptor);
+        };
+        // SAFETY: data contains the entire descriptor.
+        let descriptor = unsafe {
+            validate_and_byteswap(data, avb_chain_partition_descriptor_validate_and_byteswap)
+        }?;
+        let mut fields = Fields::new(data, size_of::<AvbChainPartitionDescriptor>());
+        let partition_name = to_str(fields.take(descriptor.partition_name_len)?)?;
+        let public_key = fields.take(descriptor.public_key_len)?;
+        Ok(ChainPartitionDescriptor { descriptor, partition_name, public_key })
+    }
+}
+
+impl<'a> PropertyDescriptor<'a> {
+    /// Get the key of the property.
+    pub fn key(&self) -> &'a str {
+        self.key
     }
 
-    // TODO: handle other descriptor type as required
+    /// Get the value of the property.
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
 }
 
-impl HashtreeDescriptor<'_> {
+impl<'a> HashtreeDescriptor<'a> {
+    /// Get the name of the partition the hashtree covers.
+    pub fn partition_name(&self) -> &'a str {
+        self.partition_name
+    }
+
+    /// Get the dm-verity version used by the hashtree.
+    pub fn dm_verity_version(&self) -> u32 {
+        self.descriptor.dm_verity_version
+    }
+
+    /// Get the size of the data covered by the hashtree.
+    pub fn image_size(&self) -> u64 {
+        self.descriptor.image_size
+    }
+
+    /// Get the offset of the hashtree in the partition.
+    pub fn tree_offset(&self) -> u64 {
+        self.descriptor.tree_offset
+    }
+
+    /// Get the size of the hashtree.
+    pub fn tree_size(&self) -> u64 {
+        self.descriptor.tree_size
+    }
+
+    /// Get the size of the data blocks.
+    pub fn data_block_size(&self) -> u32 {
+        self.descriptor.data_block_size
+    }
+
+    /// Get the size of the hash blocks.
+    pub fn hash_block_size(&self) -> u32 {
+        self.descriptor.hash_block_size
+    }
+
+    /// Get the name of the hash algorithm, e.g. "sha256".
+    pub fn hash_algorithm(&self) -> &'a str {
+        self.hash_algorithm
+    }
+
+    /// Get the salt prepended to the hashed blocks.
+    pub fn salt(&self) -> &'a [u8] {
+        let begin =
+            size_of::<AvbHashtreeDescriptor>() + self.descriptor.partition_name_len as usize;
+        let end = begin + self.descriptor.salt_len as usize;
+        &self.data[begin..end]
+    }
+
     /// Get the root digest of the hashtree.
-    pub fn root_digest(&self) -> &[u8] {
+    pub fn root_digest(&self) -> &'a [u8] {
         let begin = size_of::<AvbHashtreeDescriptor>()
             + self.descriptor.partition_name_len as usize
             + self.descriptor.salt_len as usize;
@@ -143,5 +308,131 @@ impl HashtreeDescriptor<'_> {
         &self.data[begin..end]
     }
 
-    // TODO: expose other fields as required
+    /// Get the flags of the descriptor (`AVB_HASHTREE_DESCRIPTOR_FLAGS_*`).
+    pub fn flags(&self) -> u32 {
+        self.descriptor.flags
+    }
+}
+
+impl<'a> HashDescriptor<'a> {
+    /// Get the name of the hashed partition.
+    pub fn partition_name(&self) -> &'a str {
+        self.partition_name
+    }
+
+    /// Get the size of the hashed data.
+    pub fn image_size(&self) -> u64 {
+        self.descriptor.image_size
+    }
+
+    /// Get the name of the hash algorithm, e.g. "sha256".
+    pub fn hash_algorithm(&self) -> &'a str {
+        self.hash_algorithm
+    }
+
+    /// Get the salt prepended to the hashed data.
+    pub fn salt(&self) -> &'a [u8] {
+        let begin = size_of::<AvbHashDescriptor>() + self.descriptor.partition_name_len as usize;
+        let end = begin + self.descriptor.salt_len as usize;
+        &self.data[begin..end]
+    }
+
+    /// Get the digest of the partition.
+    pub fn digest(&self) -> &'a [u8] {
+        let begin = size_of::<AvbHashDescriptor>()
+            + self.descriptor.partition_name_len as usize
+            + self.descriptor.salt_len as usize;
+        let end = begin + self.descriptor.digest_len as usize;
+        &self.data[begin..end]
+    }
+
+    /// Get the flags of the descriptor (`AVB_HASH_DESCRIPTOR_FLAGS_*`).
+    pub fn flags(&self) -> u32 {
+        self.descriptor.flags
+    }
+}
+
+impl<'a> KernelCmdlineDescriptor<'a> {
+    /// Get the kernel command-line snippet.
+    pub fn kernel_cmdline(&self) -> &'a str {
+        self.kernel_cmdline
+    }
+
+    /// Get the flags of the descriptor (`AVB_KERNEL_CMDLINE_FLAGS_*`).
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+}
+
+impl<'a> ChainPartitionDescriptor<'a> {
+    /// Get the name of the chained partition.
+    pub fn partition_name(&self) -> &'a str {
+        self.partition_name
+    }
+
+    /// Get the rollback index location of the chained partition.
+    pub fn rollback_index_location(&self) -> u32 {
+        self.descriptor.rollback_index_location
+    }
+
+    /// Get the public key the chained partition must be signed with.
+    pub fn public_key(&self) -> &'a [u8] {
+        self.public_key
+    }
+
+    /// Get the flags of the descriptor (`AVB_CHAIN_PARTITION_DESCRIPTOR_FLAGS_*`).
+    pub fn flags(&self) -> u32 {
+        self.descriptor.flags
+    }
+}
+
+/// Validate a descriptor and convert it to host byte order with the given libavb function.
+///
+/// # Safety
+///
+/// `validate` must be the libavb validation function for descriptors of type `T`.
+unsafe fn validate_and_byteswap<T>(
+    data: &[u8],
+    validate: unsafe extern "C" fn(*const T, *mut T) -> bool,
+) -> Result<T, VbMetaImageParseError> {
+    // libavb reads the whole struct before checking the size of the descriptor.
+    if data.len() < size_of::<T>() {
+        return Err(VbMetaImageParseError::InvalidDescriptor);
+    }
+    let mut descriptor = MaybeUninit::uninit();
+    // SAFETY: data contains at least a T, which validate() checks the trailing fields against.
+    if !unsafe { validate(data.as_ptr() as *const _ as *const T, descriptor.as_mut_ptr()) } {
+        return Err(VbMetaImageParseError::InvalidDescriptor);
+    }
+    // SAFETY: validate() returned true so it initialized the descriptor.
+    Ok(unsafe { descriptor.assume_init() })
+}
+
+/// Cursor over the variable-length fields following a descriptor struct.
+struct Fields<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8], offset: usize) -> Self {
+        Self { data, offset }
+    }
+
+    fn take<N: TryInto<usize>>(&mut self, len: N) -> Result<&'a [u8], VbMetaImageParseError> {
+        let len = len.try_into().map_err(|_| VbMetaImageParseError::InvalidDescriptor)?;
+        let end = self.offset.checked_add(len).ok_or(VbMetaImageParseError::InvalidDescriptor)?;
+        let field =
+            self.data.get(self.offset..end).ok_or(VbMetaImageParseError::InvalidDescriptor)?;
+        self.offset = end;
+        Ok(field)
+    }
+}
+
+fn nul_terminated(bytes: &[u8]) -> &[u8] {
+    bytes.split(|b| *b == 0).next().unwrap_or(bytes)
+}
+
+fn to_str(bytes: &[u8]) -> Result<&str, VbMetaImageParseError> {
+    str::from_utf8(bytes).map_err(|_| VbMetaImageParseError::InvalidDescriptor)
 }
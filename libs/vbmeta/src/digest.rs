@@ -0,0 +1,234 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Digests of partition images, as covered by hash and hashtree descriptors.
+
+use openssl::hash::{Hasher, MessageDigest};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use thiserror::Error;
+
+use crate::descriptor::HashtreeDescriptor;
+
+/// Returns the digest named by a hash or hashtree descriptor.
+pub(crate) fn message_digest(hash_algorithm: &str) -> Option<MessageDigest> {
+    match hash_algorithm {
+        "sha1" => Some(MessageDigest::sha1()),
+        "sha256" => Some(MessageDigest::sha256()),
+        "sha512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+/// Computes the salted digest of the first `image_size` bytes of an image.
+pub(crate) fn hash_image(
+    image: &mut impl Read,
+    image_size: u64,
+    digest: MessageDigest,
+    salt: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut hasher = Hasher::new(digest)?;
+    hasher.update(salt)?;
+    let mut remaining = image_size;
+    let mut buf = vec![0u8; 64 * 1024];
+    while remaining > 0 {
+        let len = remaining.min(buf.len() as u64) as usize;
+        image.read_exact(&mut buf[..len])?;
+        hasher.update(&buf[..len])?;
+        remaining -= len as u64;
+    }
+    Ok(hasher.finish()?.to_vec())
+}
+
+/// A dm-verity hashtree.
+pub(crate) struct HashTree {
+    /// The levels of the tree, starting from the hashes of the data blocks.
+    levels: Vec<Vec<u8>>,
+    /// The root digest of the tree.
+    pub(crate) root_digest: Vec<u8>,
+}
+
+impl HashTree {
+    /// Returns the levels of the tree, starting from the one closest to the root, as laid out on
+    /// disk.
+    pub(crate) fn tree(&self) -> Vec<u8> {
+        self.levels.iter().rev().flatten().copied().collect()
+    }
+}
+
+/// Returns whether dm-verity supports `block_size` as the data or hash block size of a hashtree.
+pub(crate) fn is_valid_block_size(block_size: usize) -> bool {
+    block_size.is_power_of_two() && (512..=4096).contains(&block_size)
+}
+
+/// Computes the dm-verity hashtree of the first `image_size` bytes of an image. The last data
+/// block is zero-padded if the image size isn't a multiple of the data block size.
+pub(crate) fn hash_tree(
+    image: &mut impl Read,
+    image_size: u64,
+    data_block_size: usize,
+    hash_block_size: usize,
+    digest: MessageDigest,
+    salt: &[u8],
+) -> io::Result<HashTree> {
+    // The block sizes may come from an untrusted descriptor. Hash blocks must also hold more than
+    // one digest for the tree to converge.
+    if !is_valid_block_size(data_block_size) || !is_valid_block_size(hash_block_size) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid hashtree block size"));
+    }
+
+    let mut level = Vec::new();
+    let mut block = vec![0u8; data_block_size];
+    let mut remaining = image_size;
+    while remaining > 0 {
+        let len = remaining.min(data_block_size as u64) as usize;
+        image.read_exact(&mut block[..len])?;
+        block[len..].fill(0);
+        append_hash(&mut level, digest, salt, &block)?;
+        remaining -= len as u64;
+    }
+    pad_to_block(&mut level, hash_block_size);
+
+    hash_levels(level, hash_block_size, digest, salt)
+}
+
+/// Completes a hashtree from the hashes of its data blocks by hashing each level of hash blocks
+/// until a single block is left.
+fn hash_levels(
+    mut level: Vec<u8>,
+    hash_block_size: usize,
+    digest: MessageDigest,
+    salt: &[u8],
+) -> io::Result<HashTree> {
+    let mut levels = Vec::new();
+    while level.len() > hash_block_size {
+        let mut next_level = Vec::new();
+        for block in level.chunks(hash_block_size) {
+            append_hash(&mut next_level, digest, salt, block)?;
+        }
+        pad_to_block(&mut next_level, hash_block_size);
+        levels.push(level);
+        level = next_level;
+    }
+
+    let mut hasher = Hasher::new(digest)?;
+    hasher.update(salt)?;
+    hasher.update(&level)?;
+    let root_digest = hasher.finish()?.to_vec();
+    levels.push(level);
+
+    Ok(HashTree { levels, root_digest })
+}
+
+/// Appends the salted hash of a block to a level of the hashtree, padding the digest to a power
+/// of two as dm-verity expects.
+fn append_hash(
+    level: &mut Vec<u8>,
+    digest: MessageDigest,
+    salt: &[u8],
+    block: &[u8],
+) -> io::Result<()> {
+    let mut hasher = Hasher::new(digest)?;
+    hasher.update(salt)?;
+    hasher.update(block)?;
+    level.extend_from_slice(&hasher.finish()?);
+    level.resize(level.len() + digest.size().next_power_of_two() - digest.size(), 0);
+    Ok(())
+}
+
+fn pad_to_block(level: &mut Vec<u8>, block_size: usize) {
+    level.resize(level.len().div_ceil(block_size).max(1) * block_size, 0);
+}
+
+/// Errors from checking the data of a partition image against its hashtree descriptor.
+#[derive(Debug, Error)]
+pub enum HashtreeVerificationError {
+    /// There was an IO error reading the image.
+    #[error("IO error")]
+    Io(#[from] io::Error),
+    /// The descriptor uses a hash algorithm that isn't supported.
+    #[error("Unsupported hash algorithm {0}")]
+    UnsupportedHashAlgorithm(String),
+    /// The data doesn't match the root digest of the descriptor.
+    #[error("Data blocks {}..{} don't match the hashtree", blocks.start, blocks.end)]
+    Mismatch {
+        /// Range of the data blocks that don't match the hashtree. This covers all the blocks if
+        /// the hashtree stored in the image doesn't match the root digest either.
+        blocks: Range<u64>,
+    },
+}
+
+impl HashtreeDescriptor<'_> {
+    /// Recomputes the hashtree of the partition image starting at `offset` in `image` and checks
+    /// it against the root digest of the descriptor.
+    ///
+    /// On mismatch, the hashtree stored in the image locates the data blocks that differ.
+    pub fn verify_reader_region<R: Read + Seek>(
+        &self,
+        mut image: R,
+        offset: u64,
+    ) -> Result<(), HashtreeVerificationError> {
+        let digest = message_digest(self.hash_algorithm()).ok_or_else(|| {
+            HashtreeVerificationError::UnsupportedHashAlgorithm(self.hash_algorithm().to_owned())
+        })?;
+        let data_block_size = self.data_block_size() as usize;
+        let hash_block_size = self.hash_block_size() as usize;
+        image.seek(SeekFrom::Start(offset))?;
+        let tree = hash_tree(
+            &mut image,
+            self.image_size(),
+            data_block_size,
+            hash_block_size,
+            digest,
+            self.salt(),
+        )?;
+        if tree.root_digest == self.root_digest() {
+            return Ok(());
+        }
+
+        let num_blocks = self.image_size().div_ceil(data_block_size as u64);
+        let mut blocks = 0..num_blocks;
+        // The data block hashes are the last level stored in the image. If they are consistent
+        // with the root digest, comparing them with the computed ones finds the corrupted blocks.
+        let data_hashes = &tree.levels[0];
+        if (data_hashes.len() as u64) <= self.tree_size() {
+            let mut stored = vec![0u8; data_hashes.len()];
+            image.seek(SeekFrom::Start(
+                offset + self.tree_offset() + self.tree_size() - stored.len() as u64,
+            ))?;
+            image.read_exact(&mut stored)?;
+            let stored_root =
+                hash_levels(stored.clone(), hash_block_size, digest, self.salt())?.root_digest;
+            if stored_root == self.root_digest() {
+                let entry_size = digest.size().next_power_of_two();
+                let mismatches = data_hashes
+                    .chunks(entry_size)
+                    .zip(stored.chunks(entry_size))
+                    .take(num_blocks as usize)
+                    .enumerate()
+                    .filter(|(_, (computed, stored))| computed != stored)
+                    .map(|(i, _)| i as u64);
+                let (mut first, mut last) = (None, 0);
+                for block in mismatches {
+                    first.get_or_insert(block);
+                    last = block;
+                }
+                if let Some(first) = first {
+                    blocks = first..last + 1;
+                }
+            }
+        }
+        Err(HashtreeVerificationError::Mismatch { blocks })
+    }
+}
@@ -14,7 +14,10 @@
 
 //! A library to verify and parse VBMeta images.
 
+mod builder;
+mod chain;
 mod descriptor;
+mod digest;
 
 use avb_bindgen::{
     avb_footer_validate_and_byteswap, avb_vbmeta_image_header_to_host_byte_order,
@@ -28,7 +31,16 @@ use std::path::Path;
 use std::ptr::null_mut;
 use thiserror::Error;
 
-pub use crate::descriptor::{Descriptor, Descriptors};
+pub use crate::builder::{
+    encode_public_key, Algorithm, FooterBuilder, HashAlgorithm, VbMetaImageBuildError,
+    VbMetaImageBuilder,
+};
+pub use crate::chain::{DigestKind, PartitionVerificationError, VerifiedPartition, VerifiedVbMeta};
+pub use crate::descriptor::{
+    ChainPartitionDescriptor, Descriptor, Descriptors, HashDescriptor, HashtreeDescriptor,
+    KernelCmdlineDescriptor, PropertyDescriptor,
+};
+pub use crate::digest::HashtreeVerificationError;
 
 /// Errors from parsing a VBMeta image.
 #[derive(Debug, Error)]
@@ -153,6 +165,16 @@ impl VbMetaImage {
         self.header.rollback_index
     }
 
+    /// Returns the rollback_index_location of the VBMeta image.
+    pub fn rollback_index_location(&self) -> u32 {
+        self.header.rollback_index_location
+    }
+
+    /// Returns the flags of the VBMeta image (`AVB_VBMETA_IMAGE_FLAGS_*`).
+    pub fn flags(&self) -> u32 {
+        self.header.flags
+    }
+
     /// Get the raw VBMeta image.
     pub fn data(&self) -> &[u8] {
         &self.data
@@ -200,7 +222,9 @@ fn read_avb_footer<R: Read + Seek>(image: &mut R) -> Result<AvbFooter, VbMetaIma
 #[cfg(test)]
 mod tests {
     use super::*;
-    use anyhow::{Context, Result};
+    use anyhow::{ensure, Context, Result};
+    use openssl::rsa::Rsa;
+    use std::collections::HashMap;
     use std::fs::{self, OpenOptions};
     use std::os::unix::fs::FileExt;
     use std::process::Command;
@@ -303,4 +327,421 @@ mod tests {
         assert_eq!(0, vbmeta.rollback_index());
         Ok(())
     }
+
+    fn avbtool(args: &[&str]) -> Result<()> {
+        let status = Command::new("./avbtool").args(args).status().context(args[0].to_owned())?;
+        ensure!(status.success(), "avbtool {} failed", args[0]);
+        Ok(())
+    }
+
+    fn extract_public_key(key: &str, output: &Path) -> Result<()> {
+        avbtool(&["extract_public_key", "--key", key, "--output", output.to_str().unwrap()])
+    }
+
+    fn make_partition(path: &Path, footer: &str, name: &str, extra_args: &[&str]) -> Result<()> {
+        fs::write(path, [0x5a; 3 * 4096 + 100]).context("write partition image")?;
+        let mut args = vec![
+            footer,
+            "--image",
+            path.to_str().unwrap(),
+            "--partition_name",
+            name,
+            "--partition_size",
+            "1048576",
+        ];
+        args.extend_from_slice(extra_args);
+        avbtool(&args)
+    }
+
+    #[test]
+    fn test_typed_descriptors() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let pubkey_file = test_dir.path().join("test.pubkey");
+        extract_public_key("data/testkey_rsa2048.pem", &pubkey_file)?;
+        let test_file = test_dir.path().join("test.img");
+        avbtool(&[
+            "make_vbmeta_image",
+            "--output",
+            test_file.to_str().unwrap(),
+            "--prop",
+            "com.android.foo:bar",
+            "--kernel_cmdline",
+            "console=hvc0",
+            "--chain_partition",
+            &format!("vendor:3:{}", pubkey_file.to_str().unwrap()),
+        ])?;
+
+        let vbmeta = VbMetaImage::verify_path(&test_file).context("verify_path")?;
+        let descriptors = vbmeta.descriptors()?;
+        assert_eq!(Some(&b"bar"[..]), descriptors.property("com.android.foo")?);
+        assert_eq!(None, descriptors.property("com.android.baz")?);
+
+        let mut descriptors = descriptors.iter();
+        let property = descriptors.next().unwrap().to_property()?;
+        assert_eq!("com.android.foo", property.key());
+        assert_eq!(b"bar", property.value());
+        let cmdline = descriptors.next().unwrap().to_kernel_cmdline()?;
+        assert_eq!("console=hvc0", cmdline.kernel_cmdline());
+        let chain = descriptors.next().unwrap().to_chain_partition()?;
+        assert_eq!("vendor", chain.partition_name());
+        assert_eq!(3, chain.rollback_index_location());
+        assert_eq!(fs::read(&pubkey_file)?, chain.public_key());
+        assert!(descriptors.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_descriptor() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let test_file = test_dir.path().join("boot.img");
+        make_partition(&test_file, "add_hash_footer", "boot", &["--salt", "0011"])?;
+
+        let vbmeta = VbMetaImage::verify_path(&test_file).context("verify_path")?;
+        let descriptors = vbmeta.descriptors()?;
+        let hash = descriptors.iter().next().unwrap().to_hash()?;
+        assert_eq!("boot", hash.partition_name());
+        assert_eq!(3 * 4096 + 100, hash.image_size());
+        assert_eq!("sha256", hash.hash_algorithm());
+        assert_eq!([0x00, 0x11], hash.salt());
+        assert_eq!(32, hash.digest().len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_chained_partitions() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let vendor_key = "data/testkey_rsa2048.pem";
+        let vendor_pubkey = test_dir.path().join("vendor.pubkey");
+        extract_public_key(vendor_key, &vendor_pubkey)?;
+
+        let system = test_dir.path().join("system.img");
+        make_partition(&system, "add_hashtree_footer", "system", &["--do_not_generate_fec"])?;
+        let vendor = test_dir.path().join("vendor.img");
+        make_partition(
+            &vendor,
+            "add_hash_footer",
+            "vendor",
+            &["--algorithm", "SHA256_RSA2048", "--key", vendor_key, "--rollback_index", "7"],
+        )?;
+        let top = test_dir.path().join("vbmeta.img");
+        avbtool(&[
+            "make_vbmeta_image",
+            "--output",
+            top.to_str().unwrap(),
+            "--algorithm",
+            "SHA256_RSA4096",
+            "--key",
+            "data/testkey_rsa4096.pem",
+            "--include_descriptors_from_image",
+            system.to_str().unwrap(),
+            "--chain_partition",
+            &format!("vendor:1:{}", vendor_pubkey.to_str().unwrap()),
+        ])?;
+
+        let images = HashMap::from([
+            ("system".to_owned(), system.clone()),
+            ("vendor".to_owned(), vendor.clone()),
+        ]);
+        let vbmeta = VbMetaImage::verify_path(&top).context("verify_path")?;
+        let verified = vbmeta.verify_partitions(&images)?;
+        assert_eq!(None, verified.partition_name);
+        assert_eq!(vbmeta.public_key(), verified.public_key.as_deref());
+        assert_eq!(1, verified.partitions.len());
+        assert_eq!("system", verified.partitions[0].name);
+        assert_eq!(DigestKind::Hashtree, verified.partitions[0].kind);
+        let system_vbmeta = VbMetaImage::verify_path(&system)?;
+        let system_descriptors = system_vbmeta.descriptors()?;
+        let hashtree = system_descriptors.iter().next().unwrap().to_hashtree()?;
+        assert_eq!(hashtree.root_digest(), verified.partitions[0].digest);
+
+        assert_eq!(1, verified.chained_partitions.len());
+        let chained = &verified.chained_partitions[0];
+        assert_eq!(Some("vendor"), chained.partition_name.as_deref());
+        assert_eq!(Some(fs::read(&vendor_pubkey)?), chained.public_key);
+        assert_eq!(7, chained.rollback_index);
+        assert_eq!(1, chained.partitions.len());
+        assert_eq!("vendor", chained.partitions[0].name);
+        assert_eq!(DigestKind::Hash, chained.partitions[0].kind);
+
+        // Without the chained image, verification can't complete.
+        let missing = HashMap::from([("system".to_owned(), system.clone())]);
+        assert!(matches!(
+            vbmeta.verify_partitions(&missing),
+            Err(PartitionVerificationError::MissingImage(name)) if name == "vendor"
+        ));
+
+        // Corrupt the contents of the chained partition.
+        let file = OpenOptions::new().write(true).open(&vendor).context("open vendor image")?;
+        file.write_all_at(&[0xa5], 4096).context("corrupt vendor image")?;
+        assert!(matches!(
+            vbmeta.verify_partitions(&images),
+            Err(PartitionVerificationError::DigestMismatch(name)) if name == "vendor"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_chained_partition_with_wrong_key() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let pubkey = test_dir.path().join("test.pubkey");
+        extract_public_key("data/testkey_rsa4096.pem", &pubkey)?;
+
+        let vendor = test_dir.path().join("vendor.img");
+        make_partition(
+            &vendor,
+            "add_hash_footer",
+            "vendor",
+            &["--algorithm", "SHA256_RSA2048", "--key", "data/testkey_rsa2048.pem"],
+        )?;
+        let top = test_dir.path().join("vbmeta.img");
+        avbtool(&[
+            "make_vbmeta_image",
+            "--output",
+            top.to_str().unwrap(),
+            "--chain_partition",
+            &format!("vendor:1:{}", pubkey.to_str().unwrap()),
+        ])?;
+
+        let images = HashMap::from([("vendor".to_owned(), vendor)]);
+        let vbmeta = VbMetaImage::verify_path(&top).context("verify_path")?;
+        assert!(matches!(
+            vbmeta.verify_partitions(&images),
+            Err(PartitionVerificationError::PublicKeyMismatch(name)) if name == "vendor"
+        ));
+        Ok(())
+    }
+
+    fn load_key(path: &str) -> Result<Rsa<openssl::pkey::Private>> {
+        Ok(Rsa::private_key_from_pem(&fs::read(path)?)?)
+    }
+
+    #[test]
+    fn test_build_signed_image() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let key = load_key("data/testkey_rsa4096.pem")?;
+        let chained_key = test_dir.path().join("chained.pubkey");
+        extract_public_key("data/testkey_rsa2048.pem", &chained_key)?;
+        let chained_key = fs::read(chained_key)?;
+
+        let image = VbMetaImageBuilder::default()
+            .signing_key(Algorithm::Sha256Rsa4096, &key)
+            .rollback_index(3)
+            .rollback_index_location(2)
+            .flags(1)
+            .add_property("com.android.foo", b"bar")
+            .add_kernel_cmdline("console=hvc0", 0)
+            .add_chain_partition("vendor", 1, &chained_key, 0)
+            .build()?;
+        // A nonzero rollback index location requires libavb 1.2.
+        assert_eq!(2, u32::from_be_bytes(image[8..12].try_into()?));
+        let test_file = test_dir.path().join("test.img");
+        fs::write(&test_file, image)?;
+
+        let vbmeta = VbMetaImage::verify_path(&test_file).context("verify_path")?;
+        let pubkey_file = test_dir.path().join("test.pubkey");
+        extract_public_key("data/testkey_rsa4096.pem", &pubkey_file)?;
+        assert_eq!(Some(fs::read(pubkey_file)?.as_slice()), vbmeta.public_key());
+        assert_eq!(3, vbmeta.rollback_index());
+        assert_eq!(2, vbmeta.rollback_index_location());
+        assert_eq!(1, vbmeta.flags());
+
+        let descriptors = vbmeta.descriptors()?;
+        assert_eq!(Some(&b"bar"[..]), descriptors.property("com.android.foo")?);
+        let mut descriptors = descriptors.iter().skip(1);
+        let cmdline = descriptors.next().unwrap().to_kernel_cmdline()?;
+        assert_eq!("console=hvc0", cmdline.kernel_cmdline());
+        let chain = descriptors.next().unwrap().to_chain_partition()?;
+        assert_eq!("vendor", chain.partition_name());
+        assert_eq!(chained_key, chain.public_key());
+        assert!(descriptors.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_unsigned_image() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let test_file = test_dir.path().join("test.img");
+        fs::write(&test_file, VbMetaImageBuilder::default().build()?)?;
+        let vbmeta = VbMetaImage::verify_path(test_file).context("verify_path")?;
+        assert!(vbmeta.public_key().is_none());
+        assert_eq!(0, vbmeta.descriptors()?.iter().count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_wrong_key_size() -> Result<()> {
+        let key = load_key("data/testkey_rsa2048.pem")?;
+        assert!(matches!(
+            VbMetaImageBuilder::default().signing_key(Algorithm::Sha512Rsa4096, &key).build(),
+            Err(VbMetaImageBuildError::KeySizeMismatch { expected: 4096, actual: 2048, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_footers() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let vendor_key = load_key("data/testkey_rsa2048.pem")?;
+        let system = test_dir.path().join("system.img");
+        fs::write(&system, [0x5a; 100 * 4096 + 100])?;
+        VbMetaImageBuilder::default().add_hashtree_footer(
+            &system,
+            FooterBuilder::new("system").hash_algorithm(HashAlgorithm::Sha512).salt(b"salt"),
+        )?;
+        let vendor = test_dir.path().join("vendor.img");
+        fs::write(&vendor, [0xa5; 3 * 4096 + 100])?;
+        VbMetaImageBuilder::default()
+            .signing_key(Algorithm::Sha256Rsa2048, &vendor_key)
+            .rollback_index(7)
+            .add_hash_footer(&vendor, FooterBuilder::new("vendor").partition_size(1 << 20))?;
+        assert_eq!(1 << 20, fs::metadata(&vendor)?.len());
+
+        let system_vbmeta = VbMetaImage::verify_path(&system).context("verify system")?;
+        let system_descriptors = system_vbmeta.descriptors()?;
+        let hashtree = system_descriptors.iter().next().unwrap().to_hashtree()?;
+        assert_eq!("system", hashtree.partition_name());
+        assert_eq!(101 * 4096, hashtree.image_size());
+        assert_eq!("sha512", hashtree.hash_algorithm());
+        assert_eq!(b"salt", hashtree.salt());
+        let vendor_vbmeta = VbMetaImage::verify_path(&vendor).context("verify vendor")?;
+        assert_eq!(7, vendor_vbmeta.rollback_index());
+
+        let key = load_key("data/testkey_rsa4096.pem")?;
+        let mut builder = VbMetaImageBuilder::default();
+        builder.signing_key(Algorithm::Sha256Rsa4096, &key);
+        for descriptor in system_descriptors.iter() {
+            builder.add_descriptor(descriptor);
+        }
+        builder.add_chain_partition("vendor", 1, &encode_public_key(&vendor_key)?, 0);
+        let top = test_dir.path().join("vbmeta.img");
+        fs::write(&top, builder.build()?)?;
+
+        let images = HashMap::from([("system".to_owned(), system), ("vendor".to_owned(), vendor)]);
+        let verified = VbMetaImage::verify_path(&top)?.verify_partitions(&images)?;
+        assert_eq!("system", verified.partitions[0].name);
+        assert_eq!(hashtree.root_digest(), verified.partitions[0].digest);
+        assert_eq!("vendor", verified.chained_partitions[0].partitions[0].name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_footer_partition_too_small() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let test_file = test_dir.path().join("boot.img");
+        fs::write(&test_file, [0; 4096])?;
+        assert!(matches!(
+            VbMetaImageBuilder::default()
+                .add_hash_footer(&test_file, FooterBuilder::new("boot").partition_size(8192)),
+            Err(VbMetaImageBuildError::PartitionTooSmall { partition_size: 8192, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashtree_footer_partition_too_small_leaves_image_untouched() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let test_file = test_dir.path().join("system.img");
+        fs::write(&test_file, [0x5a; 4096 + 100])?;
+        assert!(matches!(
+            VbMetaImageBuilder::default()
+                .add_hashtree_footer(&test_file, FooterBuilder::new("system").partition_size(8192)),
+            Err(VbMetaImageBuildError::PartitionTooSmall { partition_size: 8192, .. })
+        ));
+        assert_eq!(fs::read(&test_file)?, [0x5a; 4096 + 100]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashtree_mismatch_reports_blocks() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let test_file = test_dir.path().join("system.img");
+        fs::write(&test_file, [0x5a; 300 * 4096])?;
+        VbMetaImageBuilder::default()
+            .add_hashtree_footer(&test_file, FooterBuilder::new("system").salt(b"salt"))?;
+        let vbmeta = VbMetaImage::verify_path(&test_file)?;
+        let descriptors = vbmeta.descriptors()?;
+        let hashtree = descriptors.iter().next().unwrap().to_hashtree()?;
+        let file = OpenOptions::new().read(true).write(true).open(&test_file)?;
+        hashtree.verify_reader_region(&file, 0)?;
+
+        file.write_all_at(&[0], 5 * 4096 + 1)?;
+        file.write_all_at(&[0], 7 * 4096 + 2)?;
+        assert!(matches!(
+            hashtree.verify_reader_region(&file, 0),
+            Err(HashtreeVerificationError::Mismatch { blocks }) if blocks == (5..8)
+        ));
+
+        // Once the stored hashtree is corrupted too, all the blocks are reported.
+        // The 300 data block hashes fill the last 3 hash blocks of the tree.
+        let data_hashes_offset = hashtree.tree_offset() + hashtree.tree_size() - 3 * 4096;
+        let mut data = [0; 1];
+        file.read_exact_at(&mut data, data_hashes_offset)?;
+        file.write_all_at(&[!data[0]], data_hashes_offset)?;
+        assert!(matches!(
+            hashtree.verify_reader_region(&file, 0),
+            Err(HashtreeVerificationError::Mismatch { blocks }) if blocks == (0..300)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashtree_footer_invalid_block_size() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let test_file = test_dir.path().join("system.img");
+        fs::write(&test_file, [0x5a; 4096])?;
+        for block_size in [0, 32, 256, 3000, 8192] {
+            assert!(matches!(
+                VbMetaImageBuilder::default().add_hashtree_footer(
+                    &test_file,
+                    FooterBuilder::new("system").block_size(block_size)
+                ),
+                Err(VbMetaImageBuildError::InvalidBlockSize(size)) if size == block_size
+            ));
+        }
+        assert_eq!(fs::read(&test_file)?, [0x5a; 4096]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashtree_descriptor_with_invalid_block_size_is_rejected() -> Result<()> {
+        let test_dir = TempDir::new().unwrap();
+        let test_file = test_dir.path().join("system.img");
+        fs::write(&test_file, [0x5a; 300 * 4096])?;
+        VbMetaImageBuilder::default()
+            .add_hashtree_footer(&test_file, &FooterBuilder::new("system"))?;
+        let image = fs::read(&test_file)?;
+        let vbmeta = VbMetaImage::verify_path(&test_file)?;
+        let descriptors = vbmeta.descriptors()?;
+        let hashtree = descriptors.iter().next().unwrap().to_hashtree()?;
+        // The block sizes follow the tree offset and size in the descriptor.
+        let mut fields = hashtree.tree_offset().to_be_bytes().to_vec();
+        fields.extend_from_slice(&hashtree.tree_size().to_be_bytes());
+        fields.extend_from_slice(&4096u32.to_be_bytes());
+        fields.extend_from_slice(&4096u32.to_be_bytes());
+        let fields_offset = image.windows(fields.len()).position(|w| w == fields).unwrap();
+        let data_block_size_offset = (fields_offset + 16) as u64;
+        let hash_block_size_offset = data_block_size_offset + 4;
+
+        // The image is unsigned, so a crafted descriptor is accepted until its hashtree is checked.
+        // A hash block no larger than a digest would never reduce the tree to a single block, and
+        // a huge data block would be allocated.
+        for (offset, block_size) in
+            [(hash_block_size_offset, 32u32), (data_block_size_offset, 1 << 31)]
+        {
+            let file = OpenOptions::new().read(true).write(true).open(&test_file)?;
+            file.write_all_at(&image, 0)?;
+            file.write_all_at(&block_size.to_be_bytes(), offset)?;
+            let vbmeta = VbMetaImage::verify_path(&test_file)?;
+            let descriptors = vbmeta.descriptors()?;
+            let hashtree = descriptors.iter().next().unwrap().to_hashtree()?;
+            assert!(matches!(
+                hashtree.verify_reader_region(&file, 0),
+                Err(HashtreeVerificationError::Io(e)) if e.kind() == io::ErrorKind::InvalidInput
+            ));
+
+            let images = HashMap::from([("system".to_owned(), test_file.clone())]);
+            assert!(vbmeta.verify_partitions(&images).is_err());
+        }
+        Ok(())
+    }
 }
@@ -978,6 +978,37 @@ public class MicrodroidHostTests extends MicrodroidHostTestCaseBase {
         }
     }
 
+    @Test
+    public void testVmCommandsControlVmOfAnotherClient() throws Exception {
+        final String configPath = "assets/vm_config.json";
+        final String vmName = "test_vm_commands_control_vm_of_another_client";
+        mMicrodroidDevice =
+                MicrodroidBuilder.fromDevicePath(getPathForPackage(PACKAGE_NAME), configPath)
+                        .debugLevel("full")
+                        .memoryMib(minMemorySize())
+                        .cpuTopology("match_host")
+                        .protectedVm(mProtectedVm)
+                        .gki(mGki)
+                        .name(vmName)
+                        .build(getAndroidDevice());
+        mMicrodroidDevice.waitForBootComplete(BOOT_COMPLETE_TIMEOUT);
+        CommandRunner microdroid = new CommandRunner(mMicrodroidDevice);
+
+        // The VM belongs to the virtmgr of the `vm run-app` process started by the builder, while
+        // each of these commands gets a virtmgr of its own.
+        CommandRunner android = new CommandRunner(getDevice());
+        android.run(VIRT_APEX + "bin/vm", "suspend", vmName);
+        android.run(VIRT_APEX + "bin/vm", "resume", vmName);
+        assertThat(android.run(VIRT_APEX + "bin/vm", "balloon", vmName))
+                .containsMatch("Memory balloon: \\d+ bytes");
+
+        assertThat(microdroid.run("echo", "alive")).isEqualTo("alive");
+
+        CommandResult result = android.runForResult(VIRT_APEX + "bin/vm", "suspend", "no_such_vm");
+        assertThat(result.getStatus()).isNotEqualTo(CommandStatus.SUCCESS);
+        assertThat(result.getStderr()).contains("No VM named no_such_vm");
+    }
+
     @Test
     public void testPathToBinaryIsRejected() throws Exception {
         CommandRunner android = new CommandRunner(getDevice());